@@ -0,0 +1,92 @@
+//! PeerCompressor against stateless `maybe_compress`, and the per-codec
+//! durations the compressor records
+//!
+//! Wall-clock timings, so they only mean something in a release build on
+//! a quiet machine and are reported rather than asserted:
+//! `cargo bench --bench peer_compressor`
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use zks_tunnel_relay::{maybe_compress, CompressionKind, PeerCompressor};
+
+fn time(rounds: usize, mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..rounds {
+        f();
+    }
+    start.elapsed()
+}
+
+/// One large chat message, compressed over and over
+fn large_message() {
+    let msg = format!(r#"{{"type":"chat","msg":"{}"}}"#, "hello ".repeat(400));
+    let rounds = 200;
+
+    let stateless = time(rounds, || {
+        black_box(maybe_compress(&msg));
+    });
+    let mut compressor = PeerCompressor::new();
+    let reused = time(rounds, || {
+        black_box(compressor.compress(&msg));
+    });
+
+    println!("large message: stateless {stateless:?}, reused {reused:?}");
+}
+
+/// Many messages just over the compression threshold, where setting up
+/// a fresh deflate context is most of the cost
+fn small_messages() {
+    let messages: Vec<String> = (0..64)
+        .map(|i| {
+            format!(
+                r#"{{"type":"stats","seq":{},"pad":"{}"}}"#,
+                i,
+                "ab".repeat(550)
+            )
+        })
+        .collect();
+    let rounds = 50;
+    let total = (rounds * messages.len()) as f64;
+
+    let fresh = time(rounds, || {
+        for msg in &messages {
+            black_box(maybe_compress(msg));
+        }
+    });
+    let mut compressor = PeerCompressor::new();
+    let reused = time(rounds, || {
+        for msg in &messages {
+            black_box(compressor.compress(msg));
+        }
+    });
+
+    println!(
+        "small messages: fresh {:.0} msg/s, reused {:.0} msg/s",
+        total / fresh.as_secs_f64(),
+        total / reused.as_secs_f64()
+    );
+}
+
+/// What the compressor's own CodecTimings record per codec
+fn codec_timings() {
+    let msg = format!(r#"{{"type":"data","payload":"{}"}}"#, "x".repeat(8192));
+    let mut compressor = PeerCompressor::new();
+    for kind in [CompressionKind::Gzip, CompressionKind::Deflate] {
+        for _ in 0..100 {
+            black_box(compressor.compress_as(&msg, kind));
+        }
+        let stat = compressor.timings().get(kind);
+        println!(
+            "{kind:?}: {} messages, {} bytes in {:?}",
+            stat.compressed,
+            stat.compress_bytes,
+            Duration::from_nanos(stat.compress_ns)
+        );
+    }
+}
+
+fn main() {
+    large_message();
+    small_messages();
+    codec_timings();
+}
@@ -0,0 +1,239 @@
+//! Compression-ratio anomalies as an abuse signal
+//!
+//! Ordinary JSON traffic compresses to somewhere between a few percent
+//! and about half its size. A peer whose messages consistently don't
+//! compress at all is likely sending random junk. One whose messages
+//! shrink to almost nothing is likely sending padding. Both are
+//! expensive to relay for what they carry.
+//!
+//! RatioAnomalyDetector keeps each peer's last `window` compression
+//! ratios (compressed / original, as in `PeerStats`) and flags the peer
+//! once most of that window falls beyond one of the bounds. Only messages
+//! of at least `min_len` are recorded: small ones are sent raw and would
+//! read as incompressible. The detector only reports. Whether to throttle,
+//! alert or disconnect is up to the relay.
+//!
+//! Rooms that carry ciphertext (VPN tunnels) are incompressible by design
+//! and should run with `incompressible_above: None`.
+
+use std::collections::{HashMap, VecDeque};
+
+/// Which bound a peer's ratios are beyond
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RatioAnomaly {
+    /// Messages barely shrink: random or pre-compressed data
+    Incompressible,
+    /// Messages shrink to almost nothing: padding or repetition
+    Padding,
+}
+
+/// When a peer's ratio history counts as anomalous
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnomalyThresholds {
+    /// Ratios kept per peer
+    pub window: usize,
+    /// Ratios needed before a peer can be flagged
+    pub min_samples: usize,
+    /// Smallest message (original bytes) whose ratio is recorded
+    pub min_len: usize,
+    /// Ratios above this are incompressible (None = not checked)
+    pub incompressible_above: Option<f64>,
+    /// Ratios below this are padding (None = not checked)
+    pub padding_below: Option<f64>,
+    /// Share of the window beyond one bound that flags the peer
+    pub outlier_fraction: f64,
+}
+
+impl Default for AnomalyThresholds {
+    /// Last 32 messages of 1KB or more, at least 8 of them, flagged when
+    /// three quarters come out above 0.9 or below 0.02
+    fn default() -> Self {
+        Self {
+            window: 32,
+            min_samples: 8,
+            min_len: 1024,
+            incompressible_above: Some(0.9),
+            padding_below: Some(0.02),
+            outlier_fraction: 0.75,
+        }
+    }
+}
+
+/// Per-peer compression-ratio history
+#[allow(dead_code)]
+pub struct RatioAnomalyDetector {
+    thresholds: AnomalyThresholds,
+    history: HashMap<String, VecDeque<f64>>,
+}
+
+#[allow(dead_code)]
+impl RatioAnomalyDetector {
+    pub fn new(thresholds: AnomalyThresholds) -> Self {
+        Self {
+            thresholds: AnomalyThresholds {
+                window: thresholds.window.max(1),
+                ..thresholds
+            },
+            history: HashMap::new(),
+        }
+    }
+
+    /// Note a message from `peer_id` that compressed from `original_len`
+    /// to `compressed_len` bytes (equal if it was sent raw because it
+    /// didn't shrink)
+    pub fn record(&mut self, peer_id: &str, original_len: usize, compressed_len: usize) {
+        if original_len == 0 || original_len < self.thresholds.min_len {
+            return;
+        }
+        let window = self.thresholds.window;
+        let history = self.history.entry(peer_id.to_string()).or_default();
+        if history.len() == window {
+            history.pop_front();
+        }
+        history.push_back(compressed_len as f64 / original_len as f64);
+    }
+
+    /// The anomaly `peer_id`'s recent ratios show, if any
+    pub fn anomaly(&self, peer_id: &str) -> Option<RatioAnomaly> {
+        let t = &self.thresholds;
+        let history = self.history.get(peer_id)?;
+        if history.len() < t.min_samples.max(1) {
+            return None;
+        }
+        let share = |beyond: &dyn Fn(f64) -> bool| {
+            history.iter().filter(|&&r| beyond(r)).count() as f64 / history.len() as f64
+        };
+        if let Some(above) = t.incompressible_above {
+            if share(&|r| r > above) >= t.outlier_fraction {
+                return Some(RatioAnomaly::Incompressible);
+            }
+        }
+        if let Some(below) = t.padding_below {
+            if share(&|r| r < below) >= t.outlier_fraction {
+                return Some(RatioAnomaly::Padding);
+            }
+        }
+        None
+    }
+
+    pub fn is_anomalous(&self, peer_id: &str) -> bool {
+        self.anomaly(peer_id).is_some()
+    }
+
+    /// Forget a peer that left
+    pub fn remove(&mut self, peer_id: &str) {
+        self.history.remove(peer_id);
+    }
+}
+
+impl Default for RatioAnomalyDetector {
+    fn default() -> Self {
+        Self::new(AnomalyThresholds::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_optimizer::PeerCompressor;
+
+    /// Feed `msg` from `peer` through a real compressor
+    fn send(detector: &mut RatioAnomalyDetector, peer: &str, msg: &str) {
+        let result = PeerCompressor::new().compress(msg);
+        detector.record(peer, result.original_len, result.data.len());
+    }
+
+    /// Deterministic noise that deflate can't do much with
+    fn junk(seed: u64, len: usize) -> String {
+        let mut x = seed | 1;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                char::from(b'!' + (x % 94) as u8)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_normal_traffic_not_flagged() {
+        let mut detector = RatioAnomalyDetector::default();
+        for i in 0..64 {
+            let msg = format!(
+                r#"{{"type":"chat","id":{},"msg":"meeting moved to {} o'clock","history":[{}]}}"#,
+                i,
+                i % 12,
+                (0..120)
+                    .map(|n| format!(r#"{{"from":"peer-{}","seq":{}}}"#, n % 7, n * i))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
+            send(&mut detector, "alice", &msg);
+            assert!(!detector.is_anomalous("alice"), "message {}", i);
+        }
+        // Small messages never count, however they compress
+        for _ in 0..64 {
+            send(&mut detector, "bob", r#"{"type":"ping"}"#);
+        }
+        assert_eq!(detector.anomaly("bob"), None);
+    }
+
+    #[test]
+    fn test_junk_and_padding_flagged() {
+        let mut detector = RatioAnomalyDetector::default();
+        for i in 0..7 {
+            send(&mut detector, "mallory", &junk(i, 4096));
+            send(&mut detector, "padder", &" ".repeat(65_536));
+        }
+        // Not enough samples yet
+        assert!(!detector.is_anomalous("mallory"));
+        send(&mut detector, "mallory", &junk(7, 4096));
+        send(&mut detector, "padder", &" ".repeat(65_536));
+        assert_eq!(
+            detector.anomaly("mallory"),
+            Some(RatioAnomaly::Incompressible)
+        );
+        assert_eq!(detector.anomaly("padder"), Some(RatioAnomaly::Padding));
+
+        // The window is bounded: enough normal traffic clears the flag
+        let normal = format!(
+            r#"{{"type":"data","payload":"{}"}}"#,
+            "lorem ipsum ".repeat(200)
+        );
+        for _ in 0..25 {
+            detector.record("mallory", normal.len(), normal.len() / 3);
+        }
+        assert!(!detector.is_anomalous("mallory"));
+
+        detector.remove("padder");
+        assert!(!detector.is_anomalous("padder"));
+    }
+
+    #[test]
+    fn test_thresholds_configurable() {
+        // A tunnel room: ciphertext is expected to be incompressible
+        let tunnel = AnomalyThresholds {
+            incompressible_above: None,
+            min_samples: 4,
+            ..AnomalyThresholds::default()
+        };
+        let mut detector = RatioAnomalyDetector::new(tunnel);
+        for i in 0..16 {
+            send(&mut detector, "exit", &junk(i, 2048));
+        }
+        assert!(!detector.is_anomalous("exit"));
+
+        let strict = AnomalyThresholds {
+            window: 4,
+            min_samples: 4,
+            outlier_fraction: 0.5,
+            ..AnomalyThresholds::default()
+        };
+        let mut detector = RatioAnomalyDetector::new(strict);
+        for ratio in [0.3, 0.95, 0.4, 0.99] {
+            detector.record("p", 1000 * 2, (2000.0 * ratio) as usize);
+        }
+        assert_eq!(detector.anomaly("p"), Some(RatioAnomaly::Incompressible));
+    }
+}
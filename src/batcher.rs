@@ -0,0 +1,492 @@
+//! Coalescing of small Low-priority messages into a single frame
+//!
+//! Messages are batched as a JSON array (`[m1,m2,...]`). Compression is
+//! applied to the flushed batch as a whole: repetitive stats/heartbeat
+//! text compresses far better as one unit, and individual messages are
+//! usually below the compression threshold anyway. Do not compress
+//! messages before pushing them here.
+//!
+//! A batch is full at `max_messages`, or (with `with_max_bytes`) once its
+//! serialized size would reach the byte cap. The size is tracked as
+//! messages are pushed (see `estimated_batch_size`), never by serializing.
+//! Before pushing, `fits` says whether a message would carry the batch
+//! past the byte cap; if not, flush first and start a new batch, so no
+//! batch outgrows a peer's frame limit. Only a lone message larger than
+//! the cap makes an oversized batch.
+//! With `with_flush_interval` a batch also comes due once its oldest
+//! message (pushed via `push_at`) has waited that long; the relay polls
+//! `due_for_flush` each tick. A Critical arrival makes any batch due at
+//! once (`flush_needed`), so it never waits behind the timer.
+//!
+//! `unbatch` is the receive-side inverse: it splits a batch back into its
+//! individual messages so each can be classified on its own.
+//!
+//! `encode_batch`/`decode_batch` are a binary alternative for batches of
+//! many tiny messages: each message is prefixed with its length as an
+//! LEB128 varint, so a message under 128 bytes costs one byte of framing.
+
+use crate::message_optimizer::{maybe_compress, MessagePriority, OptimizerError};
+use serde::de::{Error as _, SeqAccess, Visitor};
+use serde_json::value::RawValue;
+
+/// Default cap on elements accepted by `unbatch`
+pub const MAX_BATCH_ELEMENTS: usize = 1024;
+
+/// Collects JSON messages until flushed as one array
+#[allow(dead_code)]
+pub struct Batcher {
+    pending: Vec<String>,
+    max_messages: usize,
+    max_bytes: Option<usize>,
+    /// Sum of pending message lengths
+    pending_bytes: usize,
+    flush_interval_ms: Option<u64>,
+    /// When the oldest pending message was pushed via `push_at`
+    first_queued_ms: Option<u64>,
+}
+
+#[allow(dead_code)]
+impl Batcher {
+    /// A batch is considered full once it holds `max_messages`
+    pub fn new(max_messages: usize) -> Self {
+        Self {
+            pending: Vec::new(),
+            max_messages,
+            max_bytes: None,
+            pending_bytes: 0,
+            flush_interval_ms: None,
+            first_queued_ms: None,
+        }
+    }
+
+    /// Make a batch due `interval_ms` after its first message was queued
+    pub fn with_flush_interval(mut self, interval_ms: u64) -> Self {
+        self.flush_interval_ms = Some(interval_ms);
+        self
+    }
+
+    /// Also consider the batch full once its JSON array reaches `max_bytes`
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Add an (uncompressed) JSON message.
+    /// Returns true when the batch is full and should be flushed.
+    pub fn push(&mut self, msg: String) -> bool {
+        self.pending_bytes += msg.len();
+        self.pending.push(msg);
+        self.pending.len() >= self.max_messages
+            || self
+                .max_bytes
+                .is_some_and(|max| self.estimated_size() >= max)
+    }
+
+    /// Whether `msg` can join without the batch passing `max_bytes`. An
+    /// empty batch takes anything.
+    pub fn fits(&self, msg: &str) -> bool {
+        self.is_empty()
+            || self.max_bytes.is_none_or(|max| {
+                array_size(self.pending_bytes + msg.len(), self.pending.len() + 1) <= max
+            })
+    }
+
+    /// `push`, recording `now_ms` if this starts a new batch
+    pub fn push_at(&mut self, msg: String, now_ms: u64) -> bool {
+        self.first_queued_ms.get_or_insert(now_ms);
+        self.push(msg)
+    }
+
+    /// Whether the oldest pending message has waited out the flush interval
+    pub fn due_for_flush(&self, now_ms: u64) -> bool {
+        match (self.flush_interval_ms, self.first_queued_ms) {
+            (Some(interval), Some(first)) => now_ms.saturating_sub(first) >= interval,
+            _ => false,
+        }
+    }
+
+    /// Whether to flush before handling a message of `arriving` priority:
+    /// always for Critical, otherwise only once the timer is due
+    pub fn flush_needed(&self, arriving: MessagePriority, now_ms: u64) -> bool {
+        !self.is_empty() && (arriving.is_critical() || self.due_for_flush(now_ms))
+    }
+
+    /// Byte length `flush` would produce
+    pub fn estimated_size(&self) -> usize {
+        array_size(self.pending_bytes, self.pending.len())
+    }
+
+    /// Drain pending messages into one JSON array (None if empty)
+    pub fn flush(&mut self) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        let mut batch =
+            String::with_capacity(self.pending.iter().map(|m| m.len() + 1).sum::<usize>() + 1);
+        batch.push('[');
+        for (i, msg) in self.pending.drain(..).enumerate() {
+            if i > 0 {
+                batch.push(',');
+            }
+            batch.push_str(&msg);
+        }
+        batch.push(']');
+        self.pending_bytes = 0;
+        self.first_queued_ms = None;
+        Some(batch)
+    }
+
+    /// Flush and pass the whole batch through `maybe_compress`
+    pub fn flush_compressed(&mut self) -> Option<(Vec<u8>, bool)> {
+        self.flush().map(|batch| maybe_compress(&batch))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Byte length of the batch array `msgs` would be flushed as: their
+/// lengths plus brackets and separating commas
+#[allow(dead_code)]
+pub fn estimated_batch_size(msgs: &[&str]) -> usize {
+    array_size(msgs.iter().map(|m| m.len()).sum(), msgs.len())
+}
+
+pub(crate) fn array_size(content_bytes: usize, count: usize) -> usize {
+    content_bytes + count.saturating_sub(1) + 2
+}
+
+/// Split a batch envelope back into its individual JSON messages
+#[allow(dead_code)]
+pub fn unbatch(payload: &[u8]) -> Result<Vec<String>, String> {
+    unbatch_with_limit(payload, MAX_BATCH_ELEMENTS)
+}
+
+/// Like `unbatch`, rejecting batches with more than `max_batch_elements`.
+/// The count is enforced while parsing, so an oversized batch is refused
+/// before its elements are collected.
+pub fn unbatch_with_limit(
+    payload: &[u8],
+    max_batch_elements: usize,
+) -> Result<Vec<String>, String> {
+    let mut de = serde_json::Deserializer::from_slice(payload);
+    let elements = serde::Deserializer::deserialize_seq(
+        &mut de,
+        BoundedBatch {
+            max: max_batch_elements,
+        },
+    )
+    .map_err(|e| format!("Invalid batch: {}", e))?;
+    de.end().map_err(|e| format!("Invalid batch: {}", e))?;
+
+    Ok(elements
+        .into_iter()
+        .map(|raw| raw.get().to_string())
+        .collect())
+}
+
+/// Collects batch elements as raw JSON, failing past `max`
+struct BoundedBatch {
+    max: usize,
+}
+
+impl<'de> Visitor<'de> for BoundedBatch {
+    type Value = Vec<&'de RawValue>;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a JSON array of at most {} messages", self.max)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut out = Vec::new();
+        while let Some(element) = seq.next_element::<&'de RawValue>()? {
+            if out.len() == self.max {
+                return Err(A::Error::custom(format!(
+                    "batch exceeds {} elements",
+                    self.max
+                )));
+            }
+            out.push(element);
+        }
+        Ok(out)
+    }
+}
+
+/// Concatenate messages, each prefixed with its varint-encoded length
+#[allow(dead_code)]
+pub fn encode_batch(messages: &[&str]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(messages.iter().map(|m| m.len() + 1).sum());
+    for msg in messages {
+        write_varint(&mut out, msg.len() as u64);
+        out.extend_from_slice(msg.as_bytes());
+    }
+    out
+}
+
+/// Split an `encode_batch` payload, rejecting more than `MAX_BATCH_ELEMENTS`
+#[allow(dead_code)]
+pub fn decode_batch(mut payload: &[u8]) -> Result<Vec<String>, OptimizerError> {
+    let mut out = Vec::new();
+    while !payload.is_empty() {
+        if out.len() == MAX_BATCH_ELEMENTS {
+            return Err(OptimizerError::TooLarge {
+                limit: MAX_BATCH_ELEMENTS,
+            });
+        }
+        let len = read_varint(&mut payload)?;
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= payload.len())
+            .ok_or(OptimizerError::Truncated)?;
+        let (msg, rest) = payload.split_at(len);
+        let msg = std::str::from_utf8(msg)
+            .map_err(|e| OptimizerError::Malformed(format!("batch element: {}", e)))?;
+        out.push(msg.to_string());
+        payload = rest;
+    }
+    Ok(out)
+}
+
+/// Append `value` as an unsigned LEB128 varint
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Read an unsigned LEB128 varint, advancing `input` past it
+pub fn read_varint(input: &mut &[u8]) -> Result<u64, OptimizerError> {
+    let mut value = 0u64;
+    for (i, &byte) in input.iter().enumerate() {
+        let shift = 7 * i as u32;
+        // A u64 needs at most 10 bytes, the last holding a single bit
+        if (shift == 63 && byte > 1) || shift > 63 {
+            return Err(OptimizerError::Malformed(
+                "varint overflows u64".to_string(),
+            ));
+        }
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            *input = &input[i + 1..];
+            return Ok(value);
+        }
+    }
+    Err(OptimizerError::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_optimizer::maybe_decompress;
+
+    fn stats_message(i: usize) -> String {
+        format!(
+            r#"{{"type":"stats","peer":"peer-{}","rx_bytes":1048576,"tx_bytes":524288,"rtt_ms":42}}"#,
+            i
+        )
+    }
+
+    #[test]
+    fn test_batch_flush() {
+        let mut batcher = Batcher::new(3);
+        assert!(batcher.flush().is_none());
+
+        assert!(!batcher.push(r#"{"type":"ping"}"#.to_string()));
+        assert!(!batcher.push(r#"{"type":"pong"}"#.to_string()));
+        assert!(batcher.push(r#"{"type":"stats"}"#.to_string()));
+
+        assert_eq!(
+            batcher.flush().unwrap(),
+            r#"[{"type":"ping"},{"type":"pong"},{"type":"stats"}]"#
+        );
+        assert!(batcher.is_empty());
+    }
+
+    #[test]
+    fn test_estimated_batch_size() {
+        assert_eq!(estimated_batch_size(&[]), "[]".len());
+        for count in [1, 2, 17] {
+            let messages: Vec<String> = (0..count).map(stats_message).collect();
+            let refs: Vec<&str> = messages.iter().map(String::as_str).collect();
+
+            let mut batcher = Batcher::new(usize::MAX);
+            for msg in &messages {
+                batcher.push(msg.clone());
+            }
+            assert_eq!(batcher.estimated_size(), estimated_batch_size(&refs));
+            assert_eq!(estimated_batch_size(&refs), batcher.flush().unwrap().len());
+
+            // Same length serde_json gives the array
+            let raw: Vec<&RawValue> = refs
+                .iter()
+                .map(|m| serde_json::from_str(m).unwrap())
+                .collect();
+            let serialized = serde_json::to_string(&raw).unwrap();
+            assert_eq!(estimated_batch_size(&refs), serialized.len());
+        }
+    }
+
+    #[test]
+    fn test_batch_full_by_size() {
+        let msg = r#"{"type":"ping"}"#;
+        // Three pings plus brackets and commas: 3 * 15 + 4 = 49 bytes
+        let mut batcher = Batcher::new(100).with_max_bytes(49);
+        assert!(!batcher.push(msg.to_string()));
+        assert!(!batcher.push(msg.to_string()));
+        assert!(batcher.push(msg.to_string()));
+        assert_eq!(batcher.flush().unwrap().len(), 49);
+
+        // Size tracking restarts after a flush
+        assert_eq!(batcher.estimated_size(), 2);
+        assert!(!batcher.push(msg.to_string()));
+
+        // A fourth ping would pass the cap; a lone oversized one is taken
+        let mut batcher = Batcher::new(100).with_max_bytes(49);
+        for _ in 0..3 {
+            assert!(batcher.fits(msg));
+            batcher.push(msg.to_string());
+        }
+        assert!(!batcher.fits(msg));
+        batcher.flush();
+        assert!(batcher.fits(&"x".repeat(100)));
+    }
+
+    #[test]
+    fn test_batch_due_by_time() {
+        let mut batcher = Batcher::new(100).with_flush_interval(50);
+        assert!(!batcher.due_for_flush(1_000));
+
+        batcher.push_at(stats_message(0), 1_000);
+        batcher.push_at(stats_message(1), 1_040);
+        // Timed from the first message, whatever the count
+        assert!(!batcher.due_for_flush(1_049));
+        assert!(batcher.due_for_flush(1_050));
+        assert_eq!(
+            unbatch(batcher.flush().unwrap().as_bytes()).unwrap().len(),
+            2
+        );
+
+        // The next batch starts its own window
+        assert!(!batcher.due_for_flush(2_000));
+        batcher.push_at(stats_message(2), 2_000);
+        assert!(!batcher.due_for_flush(2_010));
+
+        // Without an interval only the count (or size) makes a batch full
+        let mut untimed = Batcher::new(100);
+        untimed.push_at(stats_message(0), 0);
+        assert!(!untimed.due_for_flush(u64::MAX));
+    }
+
+    #[test]
+    fn test_critical_forces_flush() {
+        let mut batcher = Batcher::new(100).with_flush_interval(1_000);
+        assert!(!batcher.flush_needed(MessagePriority::Critical, 0));
+
+        batcher.push_at(stats_message(0), 0);
+        assert!(batcher.flush_needed(MessagePriority::Critical, 1));
+        assert!(!batcher.flush_needed(MessagePriority::High, 1));
+        assert!(!batcher.flush_needed(MessagePriority::Low, 1));
+        assert!(batcher.flush_needed(MessagePriority::Low, 1_000));
+    }
+
+    #[test]
+    fn test_batch_compresses_as_unit() {
+        let messages: Vec<String> = (0..50).map(stats_message).collect();
+
+        // Individually every message is below the threshold and sent raw
+        let individual: usize = messages.iter().map(|m| maybe_compress(m).0.len()).sum();
+
+        let mut batcher = Batcher::new(50);
+        for msg in &messages {
+            batcher.push(msg.clone());
+        }
+        let (data, compressed) = batcher.flush_compressed().unwrap();
+
+        assert!(compressed);
+        assert!(data.len() * 5 < individual);
+
+        let batch = maybe_decompress(&data, compressed).unwrap();
+        assert_eq!(batch, format!("[{}]", messages.join(",")));
+    }
+
+    #[test]
+    fn test_batch_unbatch_roundtrip() {
+        let messages: Vec<String> = (0..10).map(stats_message).collect();
+        let mut batcher = Batcher::new(10);
+        for msg in &messages {
+            batcher.push(msg.clone());
+        }
+
+        let batch = batcher.flush().unwrap();
+        assert_eq!(unbatch(batch.as_bytes()).unwrap(), messages);
+
+        let (data, compressed) = maybe_compress(&batch);
+        let decompressed = maybe_decompress(&data, compressed).unwrap();
+        assert_eq!(unbatch(decompressed.as_bytes()).unwrap(), messages);
+    }
+
+    #[test]
+    fn test_unbatch_limits_and_errors() {
+        let three = br#"[{"type":"ping"},{"type":"pong"},{"type":"ping"}]"#;
+        assert_eq!(unbatch_with_limit(three, 3).unwrap().len(), 3);
+
+        let err = unbatch_with_limit(three, 2).unwrap_err();
+        assert!(err.contains("exceeds 2 elements"), "{}", err);
+
+        assert!(unbatch(b"[]").unwrap().is_empty());
+        assert!(unbatch(br#"{"type":"ping"}"#).is_err());
+        assert!(unbatch(b"[1,2").is_err());
+        assert!(unbatch(b"[1] trailing").is_err());
+    }
+
+    #[test]
+    fn test_varint_batch_roundtrip() {
+        let long = "x".repeat(300);
+        let messages = [r#"{"type":"ping"}"#, "", long.as_str()];
+        let encoded = encode_batch(&messages);
+        // 1-byte prefixes below 128 bytes, 2 bytes for the 300-byte message
+        assert_eq!(encoded.len(), messages[0].len() + 300 + 1 + 1 + 2);
+        assert_eq!(decode_batch(&encoded).unwrap(), messages);
+
+        let tiny = vec!["a"; 1000];
+        let encoded = encode_batch(&tiny);
+        assert_eq!(encoded.len(), 2000);
+        // Fixed 4-byte prefixes would need 5000 bytes
+        assert_eq!(tiny.len() * (4 + 1) - encoded.len(), 3000);
+        assert_eq!(decode_batch(&encoded).unwrap(), tiny);
+    }
+
+    #[test]
+    fn test_varint_batch_errors() {
+        assert!(decode_batch(&[]).unwrap().is_empty());
+        // Length says 5, only 2 bytes follow
+        assert_eq!(
+            decode_batch(&[5, b'h', b'i']),
+            Err(OptimizerError::Truncated)
+        );
+        // Continuation bit set on the last byte
+        assert_eq!(decode_batch(&[0x80]), Err(OptimizerError::Truncated));
+        assert!(matches!(
+            decode_batch(&[0xff; 11]),
+            Err(OptimizerError::Malformed(_))
+        ));
+        assert!(matches!(
+            decode_batch(&[1, 0xff]),
+            Err(OptimizerError::Malformed(_))
+        ));
+        assert_eq!(
+            decode_batch(&encode_batch(&vec!["a"; MAX_BATCH_ELEMENTS + 1])),
+            Err(OptimizerError::TooLarge {
+                limit: MAX_BATCH_ELEMENTS
+            })
+        );
+    }
+}
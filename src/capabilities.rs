@@ -0,0 +1,305 @@
+//! Peer capability advertisement and negotiation
+//!
+//! Peers advertise what they can decode in `auth_init`/`auth_response` as
+//! a list of names, e.g. `"capabilities":["gzip","deflate","checksum"]`.
+//! The relay intersects that with its own set and never sends a peer a
+//! frame it didn't say it could read. Unknown names are ignored, so newer
+//! clients can advertise features this relay doesn't know yet.
+//!
+//! A peer that never advertised is assumed to be a legacy client that only
+//! understands gzip, which is all the relay used to send.
+//!
+//! `multicast` sends one message to many peers with a single compression
+//! pass per codec: every recipient that negotiated the same codec shares
+//! one encoded frame (an `Arc<[u8]>`), so a large room costs as many
+//! deflate runs as there are distinct codecs, not one per peer.
+
+use crate::message_optimizer::{CompressionKind, Frame, Message, MessageType, PeerCompressor};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Set of codecs and frame features a peer supports
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "Vec<String>", into = "Vec<String>")]
+pub struct Capabilities(u32);
+
+#[allow(dead_code)]
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    pub const GZIP: Self = Self(1 << 0);
+    pub const DEFLATE: Self = Self(1 << 1);
+    /// Reserved: the Workers build can't encode zstd, so the relay never
+    /// advertises it and negotiation never selects it
+    pub const ZSTD: Self = Self(1 << 2);
+    /// CRC32 frame trailer
+    pub const CHECKSUM: Self = Self(1 << 3);
+    /// Preset-dictionary deflate frames
+    pub const DICTIONARY: Self = Self(1 << 4);
+
+    const NAMES: [(Self, &'static str); 5] = [
+        (Self::GZIP, "gzip"),
+        (Self::DEFLATE, "deflate"),
+        (Self::ZSTD, "zstd"),
+        (Self::CHECKSUM, "checksum"),
+        (Self::DICTIONARY, "dictionary"),
+    ];
+
+    /// Everything this relay can encode
+    pub fn relay() -> Self {
+        Self::GZIP | Self::DEFLATE | Self::CHECKSUM | Self::DICTIONARY
+    }
+
+    /// Assumed for peers that never advertised
+    pub fn legacy() -> Self {
+        Self::GZIP
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Preferred codec both sides support: gzip, then raw deflate, then
+    /// uncompressed when there's no overlap
+    pub fn best_codec(self) -> CompressionKind {
+        if self.contains(Self::GZIP) {
+            CompressionKind::Gzip
+        } else if self.contains(Self::DEFLATE) {
+            CompressionKind::Deflate
+        } else {
+            CompressionKind::None
+        }
+    }
+
+    /// Whether a peer with these capabilities can read `frame`
+    pub fn can_read(self, frame: &Frame) -> bool {
+        let codec = match frame.kind {
+            CompressionKind::None => Self::NONE,
+            CompressionKind::Gzip => Self::GZIP,
+            CompressionKind::Deflate => Self::DEFLATE,
+        };
+        let checksum = if frame.checksum {
+            Self::CHECKSUM
+        } else {
+            Self::NONE
+        };
+        let dictionary = if frame.dictionary.is_some() {
+            Self::DICTIONARY
+        } else {
+            Self::NONE
+        };
+        self.contains(codec | checksum | dictionary)
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl From<Vec<String>> for Capabilities {
+    fn from(names: Vec<String>) -> Self {
+        names
+            .iter()
+            .filter_map(|name| Self::NAMES.iter().find(|(_, n)| n == name))
+            .fold(Self::NONE, |caps, (bit, _)| caps | *bit)
+    }
+}
+
+impl From<Capabilities> for Vec<String> {
+    fn from(caps: Capabilities) -> Self {
+        Capabilities::NAMES
+            .iter()
+            .filter(|(bit, _)| caps.contains(*bit))
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+}
+
+/// Features both sides can use
+#[allow(dead_code)]
+pub fn negotiate(local: Capabilities, remote: Capabilities) -> Capabilities {
+    local & remote
+}
+
+/// Negotiated capabilities of every peer in a room
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+pub struct PeerCapabilities {
+    peers: HashMap<String, Capabilities>,
+}
+
+#[allow(dead_code)]
+impl PeerCapabilities {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record what a peer advertised in its handshake. Other message
+    /// types (and handshakes without the field) are ignored.
+    pub fn observe(&mut self, peer_id: &str, msg: &Message) {
+        let handshake = matches!(
+            msg.msg_type,
+            MessageType::AuthInit | MessageType::AuthResponse
+        );
+        if let (true, Some(remote)) = (handshake, msg.capabilities) {
+            self.peers.insert(
+                peer_id.to_string(),
+                negotiate(Capabilities::relay(), remote),
+            );
+        }
+    }
+
+    /// Negotiated set, or the legacy set for peers that never advertised
+    pub fn get(&self, peer_id: &str) -> Capabilities {
+        self.peers
+            .get(peer_id)
+            .copied()
+            .unwrap_or_else(Capabilities::legacy)
+    }
+
+    /// Whether `frame` may be sent to `peer_id`
+    pub fn can_send(&self, peer_id: &str, frame: &Frame) -> bool {
+        self.get(peer_id).can_read(frame)
+    }
+
+    pub fn remove(&mut self, peer_id: &str) {
+        self.peers.remove(peer_id);
+    }
+
+    /// Encode `msg` once per distinct best codec among `peers` (with
+    /// `compressor`), returning each peer and its frame. Peers sharing a
+    /// codec share the buffer.
+    pub fn multicast<'a>(
+        &self,
+        msg: &str,
+        peers: impl IntoIterator<Item = &'a str>,
+        compressor: &mut PeerCompressor,
+    ) -> Vec<(&'a str, Arc<[u8]>)> {
+        let mut frames: HashMap<CompressionKind, Arc<[u8]>> = HashMap::new();
+        peers
+            .into_iter()
+            .map(|peer_id| {
+                let kind = self.get(peer_id).best_codec();
+                let frame = frames.entry(kind).or_insert_with(|| {
+                    let result = compressor.compress_as(msg, kind);
+                    Frame::with_kind(result.kind, result.data).encode().into()
+                });
+                (peer_id, Arc::clone(frame))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_intersections() {
+        let gzip_deflate = Capabilities::GZIP | Capabilities::DEFLATE;
+
+        // A zstd-capable peer still gets gzip: the relay can't encode zstd
+        let remote = Capabilities::ZSTD | Capabilities::GZIP;
+        let caps = negotiate(Capabilities::relay(), remote);
+        assert_eq!(caps, Capabilities::GZIP);
+        assert_eq!(caps.best_codec(), CompressionKind::Gzip);
+        assert!(!caps.contains(Capabilities::ZSTD));
+
+        // Two zstd-capable endpoints would keep it
+        let both = Capabilities::ZSTD | Capabilities::GZIP;
+        assert!(negotiate(both, both).contains(Capabilities::ZSTD));
+
+        let deflate_only = negotiate(gzip_deflate, Capabilities::DEFLATE);
+        assert_eq!(deflate_only.best_codec(), CompressionKind::Deflate);
+    }
+
+    #[test]
+    fn test_negotiate_no_overlap() {
+        let caps = negotiate(Capabilities::relay(), Capabilities::ZSTD);
+        assert!(caps.is_empty());
+        assert_eq!(caps.best_codec(), CompressionKind::None);
+        assert!(caps.can_read(&Frame::uncompressed(b"{}".to_vec())));
+        assert!(!caps.can_read(&Frame::with_kind(CompressionKind::Gzip, vec![])));
+    }
+
+    #[test]
+    fn test_multicast_compresses_once_per_codec() {
+        // zstd can't be encoded here (see `ZSTD`), so the room is all gzip
+        let mut peers = PeerCapabilities::new();
+        let ids: Vec<String> = (0..50).map(|i| format!("peer-{}", i)).collect();
+        let gzip =
+            Message::parse(r#"{"type":"auth_init","capabilities":["gzip","zstd"]}"#).unwrap();
+        for id in &ids {
+            peers.observe(id, &gzip);
+        }
+        let msg = format!(r#"{{"type":"chat","msg":"{}"}}"#, "hello room ".repeat(200));
+
+        let mut compressor = PeerCompressor::new();
+        let sent = peers.multicast(&msg, ids.iter().map(String::as_str), &mut compressor);
+        assert_eq!(sent.len(), 50);
+        assert_eq!(compressor.stats().messages, 1);
+        assert_eq!(
+            compressor.timings().get(CompressionKind::Gzip).compressed,
+            1
+        );
+        assert!(sent.iter().all(|(_, frame)| Arc::ptr_eq(frame, &sent[0].1)));
+        assert_eq!(Frame::decode(&sent[0].1).unwrap(), msg);
+        assert_eq!(sent[7].0, "peer-7");
+
+        // A deflate-only peer adds one more pass, not one per peer
+        let deflate = Message::parse(r#"{"type":"auth_init","capabilities":["deflate"]}"#).unwrap();
+        peers.observe("peer-0", &deflate);
+        let mut compressor = PeerCompressor::new();
+        let sent = peers.multicast(&msg, ids.iter().map(String::as_str), &mut compressor);
+        assert_eq!(compressor.stats().messages, 2);
+        assert_eq!(
+            Frame::parse(&sent[0].1).unwrap().kind,
+            CompressionKind::Deflate
+        );
+        assert!(sent[1..].iter().all(|(_, f)| Arc::ptr_eq(f, &sent[1].1)));
+    }
+
+    #[test]
+    fn test_peer_capabilities_from_handshake() {
+        let mut peers = PeerCapabilities::new();
+        let auth = Message::parse(
+            r#"{"type":"auth_init","capabilities":["deflate","checksum","brotli"]}"#,
+        )
+        .unwrap();
+        peers.observe("alice", &auth);
+
+        // Only the handshake counts
+        let chat = Message::parse(r#"{"type":"chat","capabilities":["gzip"]}"#).unwrap();
+        peers.observe("alice", &chat);
+
+        let alice = peers.get("alice");
+        assert_eq!(alice, Capabilities::DEFLATE | Capabilities::CHECKSUM);
+        let deflate = Frame::with_kind(CompressionKind::Deflate, vec![]);
+        assert!(peers.can_send("alice", &deflate.clone().with_checksum()));
+        assert!(!peers.can_send("alice", &Frame::with_kind(CompressionKind::Gzip, vec![])));
+        assert!(!peers.can_send("alice", &deflate.with_dictionary(1)));
+
+        // Never advertised: gzip only
+        assert_eq!(peers.get("bob"), Capabilities::legacy());
+        assert_eq!(
+            serde_json::to_string(&alice).unwrap(),
+            r#"["deflate","checksum"]"#
+        );
+    }
+}
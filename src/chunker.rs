@@ -0,0 +1,390 @@
+//! Splitting oversized `data` messages into chunks and reassembling them
+//!
+//! VPN payloads can exceed the frame size. Chunker cuts a message into
+//! `data_chunk` messages that share an id and carry their index and the
+//! chunk count; Reassembler buffers them until the set is complete. Sets
+//! that stay incomplete past a timeout are evicted, so a lost chunk can't
+//! pin memory forever. Both sides cap a set at `Reassembler::MAX_CHUNKS`
+//! chunks; Chunker refuses a message that would need more.
+//!
+//! `Chunker::for_frame_limit` sizes chunks by their serialized form, so
+//! every chunk fits under the relay's message size limit. JSON escaping
+//! can grow a payload by up to 6x, so capping the raw payload isn't
+//! enough. The relay keeps one Reassembler per sending peer, which keys
+//! its buffers by (peer, message id). A per-peer byte cap,
+//! `with_max_bytes`, bounds what a single peer can make the relay buffer.
+//!
+//! Chunks classify as Normal, like the `data` they came from.
+
+use crate::message_optimizer::MessagePriority;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// One piece of a split message
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "data_chunk")]
+pub struct Chunk {
+    /// Shared by every chunk of one message
+    pub id: u64,
+    pub index: u32,
+    pub total: u32,
+    pub payload: String,
+}
+
+#[allow(dead_code)]
+impl Chunk {
+    pub fn priority(&self) -> MessagePriority {
+        MessagePriority::Normal
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Splits messages into chunks of at most `max_chunk` payload bytes
+#[allow(dead_code)]
+pub struct Chunker {
+    max_chunk: usize,
+    /// Cap on each chunk's serialized JSON (None = payload cap only)
+    max_frame: Option<usize>,
+    next_id: u64,
+}
+
+/// Bytes `c` takes inside a JSON string, as serde_json escapes it
+fn json_escaped_len(c: char) -> usize {
+    match c {
+        '"' | '\\' | '\n' | '\r' | '\t' | '\u{08}' | '\u{0c}' => 2,
+        c if (c as u32) < 0x20 => 6,
+        c => c.len_utf8(),
+    }
+}
+
+#[allow(dead_code)]
+impl Chunker {
+    pub fn new(max_chunk: usize) -> Self {
+        Self {
+            // A chunk must fit at least one UTF-8 character
+            max_chunk: max_chunk.max(4),
+            max_frame: None,
+            next_id: 1,
+        }
+    }
+
+    /// Chunker whose every `Chunk::to_json` is at most `max_message_bytes`
+    /// long. Limits too small to fit the chunk envelope and one escaped
+    /// character are raised to that minimum.
+    pub fn for_frame_limit(max_message_bytes: usize) -> Self {
+        Self {
+            max_frame: Some(max_message_bytes),
+            ..Self::new(max_message_bytes)
+        }
+    }
+
+    /// Whether `msg` is too large to send unsplit under `max_message_bytes`
+    pub fn needs_split(msg: &str, max_message_bytes: usize) -> bool {
+        msg.len() > max_message_bytes
+    }
+
+    /// Escaped payload bytes a chunk of message `id` can carry
+    fn payload_budget(&self, id: u64) -> usize {
+        let Some(max_frame) = self.max_frame else {
+            return usize::MAX;
+        };
+        let envelope = Chunk {
+            id,
+            index: Reassembler::MAX_CHUNKS - 1,
+            total: Reassembler::MAX_CHUNKS,
+            payload: String::new(),
+        }
+        .to_json()
+        .len();
+        max_frame.saturating_sub(envelope).max(6)
+    }
+
+    /// Split `msg` under a fresh id. Cuts fall on character boundaries,
+    /// so a chunk may be a few bytes short of `max_chunk`. None if it
+    /// would take more than `Reassembler::MAX_CHUNKS` chunks, which the
+    /// receiver would discard.
+    pub fn split(&mut self, msg: &str) -> Option<Vec<Chunk>> {
+        let id = self.next_id;
+        let budget = self.payload_budget(id);
+
+        let mut pieces = Vec::with_capacity(msg.len() / self.max_chunk + 1);
+        let (mut start, mut raw, mut escaped) = (0, 0, 0);
+        for (i, c) in msg.char_indices() {
+            let (r, e) = (c.len_utf8(), json_escaped_len(c));
+            if i > start && (raw + r > self.max_chunk || escaped + e > budget) {
+                pieces.push(&msg[start..i]);
+                if pieces.len() == Reassembler::MAX_CHUNKS as usize {
+                    return None;
+                }
+                (start, raw, escaped) = (i, 0, 0);
+            }
+            raw += r;
+            escaped += e;
+        }
+        pieces.push(&msg[start..]);
+
+        self.next_id = self.next_id.wrapping_add(1);
+        let total = u32::try_from(pieces.len()).ok()?;
+        let chunks = pieces
+            .into_iter()
+            .zip(0..)
+            .map(|(piece, index)| Chunk {
+                id,
+                index,
+                total,
+                payload: piece.to_string(),
+            })
+            .collect();
+        Some(chunks)
+    }
+}
+
+/// A message whose chunks are still arriving
+struct Partial {
+    parts: Vec<Option<String>>,
+    received: u32,
+    /// Payload bytes buffered so far
+    bytes: usize,
+    first_seen_ms: u64,
+}
+
+/// Reassembles one sending peer's chunks
+#[allow(dead_code)]
+pub struct Reassembler {
+    timeout_ms: u64,
+    partial: HashMap<u64, Partial>,
+    max_bytes: usize,
+    /// Payload bytes buffered across all incomplete sets
+    buffered: usize,
+}
+
+#[allow(dead_code)]
+impl Reassembler {
+    /// Chunk count accepted per message, bounding what one set can buffer
+    pub const MAX_CHUNKS: u32 = 4096;
+    /// Incomplete sets buffered at once; chunks starting another are ignored
+    pub const MAX_PENDING: usize = 64;
+    /// Default cap on buffered payload bytes for one peer
+    pub const DEFAULT_MAX_BYTES: usize = 4 * 1024 * 1024;
+
+    /// Evict sets still incomplete `timeout_ms` after their first chunk
+    pub fn new(timeout_ms: u64) -> Self {
+        Self {
+            timeout_ms,
+            partial: HashMap::new(),
+            max_bytes: Self::DEFAULT_MAX_BYTES,
+            buffered: 0,
+        }
+    }
+
+    /// Buffer at most `max_bytes` of payload across incomplete sets
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Buffer a chunk, returning the whole message once its last chunk
+    /// arrives. Duplicates, chunks inconsistent with their set, new sets
+    /// past `MAX_PENDING` and chunks that would exceed the byte cap are
+    /// ignored.
+    pub fn push(&mut self, chunk: Chunk, now_ms: u64) -> Option<String> {
+        if chunk.total == 0 || chunk.total > Self::MAX_CHUNKS || chunk.index >= chunk.total {
+            return None;
+        }
+        if !self.partial.contains_key(&chunk.id) && self.partial.len() >= Self::MAX_PENDING {
+            return None;
+        }
+        if self.buffered + chunk.payload.len() > self.max_bytes {
+            return None;
+        }
+        let partial = self.partial.entry(chunk.id).or_insert_with(|| Partial {
+            parts: vec![None; chunk.total as usize],
+            received: 0,
+            bytes: 0,
+            first_seen_ms: now_ms,
+        });
+        if partial.parts.len() != chunk.total as usize {
+            return None;
+        }
+        let slot = &mut partial.parts[chunk.index as usize];
+        if slot.is_some() {
+            return None;
+        }
+        partial.bytes += chunk.payload.len();
+        self.buffered += chunk.payload.len();
+        *slot = Some(chunk.payload);
+        partial.received += 1;
+
+        if partial.received < chunk.total {
+            return None;
+        }
+        let partial = self.partial.remove(&chunk.id)?;
+        self.buffered -= partial.bytes;
+        Some(partial.parts.into_iter().flatten().collect())
+    }
+
+    /// Drop sets that timed out as of `now_ms`, returning how many
+    pub fn evict_expired(&mut self, now_ms: u64) -> usize {
+        let before = self.partial.len();
+        let timeout = self.timeout_ms;
+        let buffered = &mut self.buffered;
+        self.partial.retain(|_, p| {
+            let live = now_ms.saturating_sub(p.first_seen_ms) <= timeout;
+            if !live {
+                *buffered -= p.bytes;
+            }
+            live
+        });
+        before - self.partial.len()
+    }
+
+    /// Payload bytes held for incomplete sets
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered
+    }
+
+    /// Messages with chunks still outstanding
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_message(len: usize) -> String {
+        let prefix = r#"{"type":"data","payload":""#;
+        let body = "0123456789abcdef"
+            .repeat(len / 16 + 1)
+            .chars()
+            .take(len - prefix.len() - 2)
+            .collect::<String>();
+        format!(r#"{}{}"}}"#, prefix, body)
+    }
+
+    #[test]
+    fn test_split_and_reassemble() {
+        let msg = data_message(5 * 1024);
+        let mut chunker = Chunker::new(1024);
+        let chunks = chunker.split(&msg).unwrap();
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks.iter().all(|c| c.id == chunks[0].id && c.total == 5));
+        assert!(chunks.iter().all(|c| c.payload.len() <= 1024));
+
+        // Chunks go over the wire as JSON and classify as Normal
+        let wire: Vec<String> = chunks.iter().map(Chunk::to_json).collect();
+        assert_eq!(
+            MessagePriority::from_message(&wire[0]),
+            MessagePriority::Normal
+        );
+
+        // Any arrival order works
+        let mut reassembler = Reassembler::new(5_000);
+        let mut out = None;
+        for raw in wire.iter().rev() {
+            assert!(out.is_none());
+            out = reassembler.push(serde_json::from_str(raw).unwrap(), 0);
+        }
+        assert_eq!(out.as_deref(), Some(msg.as_str()));
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_missing_chunk_times_out() {
+        let mut chunker = Chunker::new(1024);
+        let mut chunks = chunker.split(&data_message(5 * 1024)).unwrap();
+        chunks.remove(2);
+
+        let mut reassembler = Reassembler::new(5_000);
+        for chunk in chunks {
+            assert_eq!(reassembler.push(chunk, 100), None);
+        }
+        assert_eq!(reassembler.pending(), 1);
+
+        assert_eq!(reassembler.evict_expired(5_100), 0);
+        assert_eq!(reassembler.evict_expired(5_101), 1);
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(reassembler.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn test_frame_limit_counts_escaping() {
+        // Quotes and control characters grow when escaped
+        let msg = format!(
+            r#"{{"type":"data","payload":"{}"}}"#,
+            r#"\"q\" \u0001"#.repeat(400)
+        );
+        let limit = 1024;
+        assert!(Chunker::needs_split(&msg, limit));
+        let raw_capped = Chunker::new(limit).split(&msg).unwrap();
+        assert!(raw_capped.iter().any(|c| c.to_json().len() > limit));
+
+        let chunks = Chunker::for_frame_limit(limit).split(&msg).unwrap();
+        assert!(chunks.iter().all(|c| c.to_json().len() <= limit));
+        let mut reassembler = Reassembler::new(1_000);
+        let whole = chunks
+            .into_iter()
+            .find_map(|c| reassembler.push(c, 0))
+            .unwrap();
+        assert_eq!(whole, msg);
+    }
+
+    #[test]
+    fn test_reassembly_byte_cap() {
+        let mut chunker = Chunker::new(1024);
+        let first = chunker.split(&data_message(2 * 1024 + 100)).unwrap();
+        let second = chunker.split(&data_message(3 * 1024)).unwrap();
+
+        let mut reassembler = Reassembler::new(5_000).with_max_bytes(2560);
+        for chunk in first.iter().take(2).cloned() {
+            assert_eq!(reassembler.push(chunk, 0), None);
+        }
+        // The second set would take the peer past its cap
+        let pushed: Vec<_> = second
+            .iter()
+            .cloned()
+            .map(|c| reassembler.push(c, 0))
+            .collect();
+        assert!(pushed.iter().all(Option::is_none));
+        assert_eq!(reassembler.buffered_bytes(), 2048);
+        let mut other = Reassembler::new(5_000);
+        assert!(second.into_iter().find_map(|c| other.push(c, 0)).is_some());
+
+        // Completing a set frees its bytes
+        assert!(reassembler.push(first[2].clone(), 0).is_some());
+        assert_eq!(reassembler.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn test_split_caps_chunk_count() {
+        let mut chunker = Chunker::new(4);
+        let max = Reassembler::MAX_CHUNKS as usize;
+        let chunks = chunker.split(&"x".repeat(max * 4)).unwrap();
+        assert_eq!(chunks.len(), max);
+        let mut reassembler = Reassembler::new(1_000).with_max_bytes(usize::MAX);
+        assert!(chunks
+            .into_iter()
+            .find_map(|c| reassembler.push(c, 0))
+            .is_some());
+
+        // One byte more needs a chunk the receiver wouldn't accept
+        assert_eq!(chunker.split(&"x".repeat(max * 4 + 1)), None);
+    }
+
+    #[test]
+    fn test_split_on_char_boundaries() {
+        let msg = "é".repeat(10);
+        let chunks = Chunker::new(5).split(&msg).unwrap();
+        assert!(chunks.iter().all(|c| c.payload.len() == 4));
+        let mut reassembler = Reassembler::new(1_000);
+        let whole = chunks
+            .into_iter()
+            .find_map(|c| reassembler.push(c, 0))
+            .unwrap();
+        assert_eq!(whole, msg);
+    }
+}
@@ -0,0 +1,97 @@
+//! Where the relay's notion of "now" comes from
+//!
+//! The time-based pieces (aging, quiet hours, TTLs, rate-limit refill,
+//! breakers, slow start) don't read a clock themselves: every call takes
+//! `now_ms` explicitly, which already keeps them deterministic in tests.
+//! Clock is for the code that supplies that value. The Durable Objects
+//! read a SystemClock, tests drive a MockClock, and anything that needs
+//! to read the time repeatedly mid-operation (`flush_and_close`) can take
+//! `|| clock.now_ms()`.
+//!
+//! SystemClock is wall-clock milliseconds since the Unix epoch: `Date.now()`
+//! on the Workers runtime (`Instant` and `SystemTime` panic there) and
+//! `SystemTime` natively.
+
+use std::cell::Cell;
+
+/// A source of the current time in milliseconds
+#[allow(dead_code)]
+pub trait Clock {
+    fn now_ms(&self) -> u64;
+}
+
+/// The wall clock
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[cfg(target_arch = "wasm32")]
+    fn now_ms(&self) -> u64 {
+        worker::Date::now().as_millis()
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn now_ms(&self) -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    }
+}
+
+/// A clock that only moves when told to. Advancing takes `&self`, so a
+/// test can hand the clock to the code under test and still move it.
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+pub struct MockClock {
+    now_ms: Cell<u64>,
+}
+
+#[allow(dead_code)]
+impl MockClock {
+    pub fn new(start_ms: u64) -> Self {
+        Self {
+            now_ms: Cell::new(start_ms),
+        }
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.now_ms.set(self.now_ms.get().saturating_add(ms));
+    }
+
+    pub fn set(&self, now_ms: u64) {
+        self.now_ms.set(now_ms);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_ms(&self) -> u64 {
+        self.now_ms.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quota::TokenBucket;
+
+    #[test]
+    fn test_mock_clock_drives_refill() {
+        let clock = MockClock::new(1_000);
+        let as_dyn: &dyn Clock = &clock;
+        let mut bucket = TokenBucket::new(2, 10.0, as_dyn.now_ms());
+        assert!(bucket.try_take(as_dyn.now_ms()));
+        assert!(bucket.try_take(as_dyn.now_ms()));
+        assert!(!bucket.try_take(as_dyn.now_ms()));
+
+        // One token every 100ms, and no sleeping for it
+        clock.advance(50);
+        assert!(!bucket.try_take(as_dyn.now_ms()));
+        clock.advance(50);
+        assert!(bucket.try_take(as_dyn.now_ms()));
+        assert_eq!(as_dyn.now_ms(), 1_100);
+
+        clock.set(0);
+        assert_eq!(clock.now_ms(), 0);
+    }
+}
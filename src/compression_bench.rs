@@ -0,0 +1,205 @@
+//! Compression benchmark over a corpus of captured messages
+//!
+//! A tuning utility, not part of the relay path: operators run
+//! `benchmark_compression` on messages captured from their own traffic to
+//! pick a codec and level. The result is the mean compression ratio and
+//! the compress/decompress throughput of each candidate.
+//!
+//! Candidates are measured on their raw output. The relay's size
+//! threshold and its fallback to raw for frames that don't shrink are
+//! left out, so the numbers compare codecs and not policy.
+//!
+//! Timing uses `std::time::Instant`, which isn't available on the Workers
+//! target, so this module is only built natively.
+
+use crate::message_optimizer::CompressionKind;
+use flate2::read::{DeflateDecoder, GzDecoder};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+/// A codec at a deflate level (the level is ignored for `None`)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionAlgorithm {
+    pub kind: CompressionKind,
+    /// 1 (fastest) to 9 (smallest)
+    pub level: u32,
+}
+
+impl CompressionAlgorithm {
+    pub fn gzip(level: u32) -> Self {
+        Self {
+            kind: CompressionKind::Gzip,
+            level: level.clamp(1, 9),
+        }
+    }
+
+    pub fn deflate(level: u32) -> Self {
+        Self {
+            kind: CompressionKind::Deflate,
+            level: level.clamp(1, 9),
+        }
+    }
+
+    pub fn none() -> Self {
+        Self {
+            kind: CompressionKind::None,
+            level: 0,
+        }
+    }
+
+    fn compress(&self, input: &[u8]) -> Vec<u8> {
+        let level = Compression::new(self.level);
+        let written = match self.kind {
+            CompressionKind::None => return input.to_vec(),
+            CompressionKind::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder.write_all(input).and_then(|_| encoder.finish())
+            }
+            CompressionKind::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), level);
+                encoder.write_all(input).and_then(|_| encoder.finish())
+            }
+        };
+        // Writing into a Vec can't fail
+        written.unwrap_or_default()
+    }
+
+    fn decompress(&self, input: &[u8], original_len: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(original_len);
+        let read = match self.kind {
+            CompressionKind::None => return input.to_vec(),
+            CompressionKind::Gzip => GzDecoder::new(input).read_to_end(&mut out),
+            CompressionKind::Deflate => DeflateDecoder::new(input).read_to_end(&mut out),
+        };
+        read.map(|_| out).unwrap_or_default()
+    }
+}
+
+/// How one algorithm did over the corpus
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BenchResult {
+    pub algorithm: CompressionAlgorithm,
+    /// Non-empty samples measured
+    pub samples: usize,
+    pub original_bytes: usize,
+    pub compressed_bytes: usize,
+    /// Mean of each sample's compressed/original size (below 1.0 = smaller)
+    pub avg_ratio: f64,
+    /// Original bytes compressed per second
+    pub compress_bytes_per_sec: f64,
+    /// Original bytes restored per second
+    pub decompress_bytes_per_sec: f64,
+}
+
+/// Compress and decompress every sample with every algorithm, one result
+/// per algorithm in the order given. Empty samples are skipped. Panics if
+/// a sample doesn't round-trip, which would mean a broken codec build.
+pub fn benchmark_compression(
+    samples: &[Vec<u8>],
+    algorithms: &[CompressionAlgorithm],
+) -> Vec<BenchResult> {
+    algorithms
+        .iter()
+        .map(|algorithm| {
+            let mut result = BenchResult {
+                algorithm: *algorithm,
+                samples: 0,
+                original_bytes: 0,
+                compressed_bytes: 0,
+                avg_ratio: 1.0,
+                compress_bytes_per_sec: 0.0,
+                decompress_bytes_per_sec: 0.0,
+            };
+            let mut ratio_sum = 0.0;
+            let mut compress_time = Duration::ZERO;
+            let mut decompress_time = Duration::ZERO;
+
+            for sample in samples.iter().filter(|s| !s.is_empty()) {
+                let start = Instant::now();
+                let compressed = algorithm.compress(sample);
+                compress_time += start.elapsed();
+
+                let start = Instant::now();
+                let restored = algorithm.decompress(&compressed, sample.len());
+                decompress_time += start.elapsed();
+                assert!(restored == *sample, "{:?} failed to round-trip", algorithm);
+
+                result.samples += 1;
+                result.original_bytes += sample.len();
+                result.compressed_bytes += compressed.len();
+                ratio_sum += compressed.len() as f64 / sample.len() as f64;
+            }
+
+            if result.samples > 0 {
+                let bytes = result.original_bytes as f64;
+                result.avg_ratio = ratio_sum / result.samples as f64;
+                // A tiny corpus can finish below the clock's resolution
+                result.compress_bytes_per_sec = bytes / compress_time.as_secs_f64().max(1e-9);
+                result.decompress_bytes_per_sec = bytes / decompress_time.as_secs_f64().max(1e-9);
+            }
+            result
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corpus() -> Vec<Vec<u8>> {
+        let mut samples: Vec<Vec<u8>> = (0..8)
+            .map(|i| {
+                format!(
+                    r#"{{"type":"stats","peer":"peer-{}","rx":{},"tx":{},"history":[{}]}}"#,
+                    i,
+                    i * 1024,
+                    i * 2048,
+                    "0,1,2,3,4,5,6,7,8,9,".repeat(40).trim_end_matches(',')
+                )
+                .into_bytes()
+            })
+            .collect();
+        samples.push(br#"{"type":"ping"}"#.to_vec());
+        samples.push(Vec::new());
+        samples
+    }
+
+    #[test]
+    fn test_benchmark_fields_populated() {
+        let samples = corpus();
+        let algorithms = [
+            CompressionAlgorithm::none(),
+            CompressionAlgorithm::gzip(1),
+            CompressionAlgorithm::gzip(9),
+            CompressionAlgorithm::deflate(6),
+        ];
+        let results = benchmark_compression(&samples, &algorithms);
+        assert_eq!(results.len(), algorithms.len());
+
+        let original: usize = samples.iter().map(Vec::len).sum();
+        for (result, algorithm) in results.iter().zip(&algorithms) {
+            assert_eq!(result.algorithm, *algorithm);
+            assert_eq!(result.samples, 9);
+            assert_eq!(result.original_bytes, original);
+            assert!(result.compress_bytes_per_sec > 0.0);
+            assert!(result.decompress_bytes_per_sec > 0.0);
+        }
+
+        assert_eq!(results[0].avg_ratio, 1.0);
+        assert_eq!(results[0].compressed_bytes, original);
+        for result in &results[1..] {
+            assert!(result.avg_ratio < 0.5, "{:?}", result);
+        }
+        // Raw deflate skips gzip's 18 bytes of framing per sample
+        assert!(results[3].compressed_bytes < results[2].compressed_bytes);
+    }
+
+    #[test]
+    fn test_empty_corpus() {
+        let results = benchmark_compression(&[], &[CompressionAlgorithm::gzip(6)]);
+        assert_eq!(results[0].samples, 0);
+        assert_eq!(results[0].avg_ratio, 1.0);
+    }
+}
@@ -0,0 +1,551 @@
+//! Per-peer delivery reliability helpers
+//!
+//! ResendBuffer keeps the last N unacknowledged Critical/High frames so a
+//! peer that briefly drops can ask for everything after its last-seen
+//! sequence number. Normal/Low traffic is never retained.
+//!
+//! CircuitBreaker stops the relay from hammering a socket that keeps
+//! failing: after N consecutive write failures flushes pause for a
+//! cooldown, then a single probe decides whether to resume.
+//!
+//! SequenceTracker watches a peer's data-plane `seq` numbers and reports
+//! gaps, duplicates and late arrivals so the relay can count them or ask
+//! for a resend. Sequence numbers are u32 and may wrap.
+//!
+//! SlowStart ramps up how much backlog a freshly (re)connected peer gets
+//! per flush tick, so a mobile link that just came back isn't flooded
+//! with everything queued while it was away.
+//!
+//! BandwidthEstimator measures a peer's delivered bytes/sec over a sliding
+//! window. Its `policy` skips compressing mid-sized messages for peers on
+//! a fast link, where deflate would cost more time than the bytes saved.
+
+use crate::message_optimizer::{
+    CompressionKind, CompressionPolicy, Message, MessagePriority, SizeThresholdPolicy,
+};
+use std::collections::{BTreeSet, VecDeque};
+
+/// Retains recent Critical/High frames until acknowledged
+#[allow(dead_code)]
+pub struct ResendBuffer {
+    capacity: usize,
+    next_seq: u64,
+    entries: VecDeque<(u64, Vec<u8>)>,
+}
+
+#[allow(dead_code)]
+impl ResendBuffer {
+    /// Keep at most `capacity` unacked frames (oldest evicted first)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            next_seq: 1,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Assign the next sequence number to an outbound frame.
+    /// Critical/High frames are retained for replay; others only consume a seq.
+    pub fn record(&mut self, priority: MessagePriority, frame: &[u8]) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        if priority <= MessagePriority::High && self.capacity > 0 {
+            if self.entries.len() == self.capacity {
+                self.entries.pop_front();
+            }
+            self.entries.push_back((seq, frame.to_vec()));
+        }
+
+        seq
+    }
+
+    /// Cumulative ack: drop every retained frame up to and including `seq`
+    pub fn ack(&mut self, seq: u64) {
+        while self.entries.front().is_some_and(|(s, _)| *s <= seq) {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Frames the peer hasn't seen yet, for replay after a reconnect
+    pub fn replay_since(&self, last_seen: u64) -> Vec<(u64, Vec<u8>)> {
+        self.entries
+            .iter()
+            .filter(|(s, _)| *s > last_seen)
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Circuit breaker state for a peer socket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Writes flow normally
+    Closed,
+    /// Too many failures: skip flushes until the cooldown expires
+    Open,
+    /// Cooldown expired: allow a probe write to decide
+    HalfOpen,
+}
+
+/// Tracks consecutive write failures for one peer.
+///
+/// While the breaker is open the relay should shed the peer's
+/// non-critical backlog, e.g. `queue.shed_below(MessagePriority::Critical)`.
+#[allow(dead_code)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown_ms: u64,
+    consecutive_failures: u32,
+    opened_at: Option<u64>,
+}
+
+#[allow(dead_code)]
+impl CircuitBreaker {
+    /// Open after `failure_threshold` consecutive failures, for `cooldown_ms`
+    pub fn new(failure_threshold: u32, cooldown_ms: u64) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            cooldown_ms,
+            consecutive_failures: 0,
+            opened_at: None,
+        }
+    }
+
+    pub fn state(&self, now_ms: u64) -> BreakerState {
+        match self.opened_at {
+            None => BreakerState::Closed,
+            Some(at) if now_ms.saturating_sub(at) >= self.cooldown_ms => BreakerState::HalfOpen,
+            Some(_) => BreakerState::Open,
+        }
+    }
+
+    /// Whether a flush attempt should be made now
+    pub fn allow(&self, now_ms: u64) -> bool {
+        self.state(now_ms) != BreakerState::Open
+    }
+
+    /// A write succeeded: close the breaker
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.opened_at = None;
+    }
+
+    /// A write failed: open once the threshold is hit, or re-open
+    /// immediately if the half-open probe failed
+    pub fn record_failure(&mut self, now_ms: u64) {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.opened_at.is_some() || self.consecutive_failures >= self.failure_threshold {
+            self.opened_at = Some(now_ms);
+        }
+    }
+}
+
+/// How an observed sequence number relates to what came before
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeqStatus {
+    /// Exactly the next expected number (or the first one seen)
+    InOrder,
+    /// Already seen
+    Duplicate,
+    /// Jumped ahead; `missing` numbers were skipped
+    Gap { missing: u32 },
+    /// Late arrival of a number that was previously skipped
+    Reordered,
+}
+
+/// Tracks one peer's monotonically increasing (wrapping) sequence numbers
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    /// Highest sequence number seen so far
+    highest: Option<u32>,
+    /// Skipped numbers that haven't arrived yet
+    missing: BTreeSet<u32>,
+}
+
+#[allow(dead_code)]
+impl SequenceTracker {
+    /// At most this many skipped numbers are remembered for `Reordered`
+    const MAX_TRACKED_MISSING: usize = 1024;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe(&mut self, seq: u32) -> SeqStatus {
+        let Some(highest) = self.highest else {
+            self.highest = Some(seq);
+            return SeqStatus::InOrder;
+        };
+
+        // Wrapping distance ahead of the highest: values in the upper half
+        // of the u32 space mean "behind", so 0 follows u32::MAX
+        let ahead = seq.wrapping_sub(highest);
+        if ahead == 0 {
+            return SeqStatus::Duplicate;
+        }
+        if ahead > u32::MAX / 2 {
+            return if self.missing.remove(&seq) {
+                SeqStatus::Reordered
+            } else {
+                SeqStatus::Duplicate
+            };
+        }
+
+        self.highest = Some(seq);
+        let missing = ahead - 1;
+        if missing == 0 {
+            return SeqStatus::InOrder;
+        }
+        for skipped in 1..=missing {
+            if self.missing.len() >= Self::MAX_TRACKED_MISSING {
+                break;
+            }
+            self.missing.insert(seq.wrapping_sub(skipped));
+        }
+        SeqStatus::Gap { missing }
+    }
+
+    /// Skipped numbers still outstanding (e.g. for a resend request)
+    pub fn outstanding(&self) -> usize {
+        self.missing.len()
+    }
+}
+
+/// Per-tick byte budget for a peer that just (re)connected: starts at
+/// `initial_bytes`, doubles every `interval_ms` and lifts entirely once
+/// it would exceed `max_bytes`
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug)]
+pub struct SlowStart {
+    started_at: u64,
+    initial_bytes: usize,
+    max_bytes: usize,
+    interval_ms: u64,
+}
+
+#[allow(dead_code)]
+impl SlowStart {
+    pub fn new(now_ms: u64, initial_bytes: usize, max_bytes: usize, interval_ms: u64) -> Self {
+        Self {
+            started_at: now_ms,
+            initial_bytes: initial_bytes.max(1),
+            max_bytes,
+            interval_ms: interval_ms.max(1),
+        }
+    }
+
+    /// Restart the ramp after a reconnect
+    pub fn restart(&mut self, now_ms: u64) {
+        self.started_at = now_ms;
+    }
+
+    /// Bytes of non-Critical traffic allowed this tick (None = no limit)
+    pub fn tick_budget(&self, now_ms: u64) -> Option<usize> {
+        let doublings = now_ms.saturating_sub(self.started_at) / self.interval_ms;
+        let budget = u32::try_from(doublings)
+            .ok()
+            .and_then(|d| self.initial_bytes.checked_shl(d))
+            .filter(|b| b.leading_zeros() > 0)?;
+        (budget <= self.max_bytes).then_some(budget)
+    }
+}
+
+/// A peer's throughput over the last `window_ms`
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct BandwidthEstimator {
+    window_ms: u64,
+    /// (time, bytes) per delivery, oldest first
+    samples: VecDeque<(u64, usize)>,
+}
+
+#[allow(dead_code)]
+impl BandwidthEstimator {
+    /// Default measurement window
+    pub const DEFAULT_WINDOW_MS: u64 = 5_000;
+
+    pub fn new(window_ms: u64) -> Self {
+        Self {
+            window_ms: window_ms.max(1),
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Record `bytes` delivered to the peer at `now_ms`
+    pub fn record(&mut self, bytes: usize, now_ms: u64) {
+        self.samples.push_back((now_ms, bytes));
+        self.evict(now_ms);
+    }
+
+    /// Bytes/sec averaged over the window (None until anything was sent)
+    pub fn bytes_per_sec(&self, now_ms: u64) -> Option<f64> {
+        let total: usize = self
+            .samples
+            .iter()
+            .filter(|(at, _)| now_ms.saturating_sub(*at) < self.window_ms)
+            .map(|(_, bytes)| bytes)
+            .sum();
+        (!self.samples.is_empty()).then(|| total as f64 * 1000.0 / self.window_ms as f64)
+    }
+
+    /// Compression policy for this peer's link as of `now_ms`
+    pub fn policy(&self, now_ms: u64) -> LinkAwarePolicy {
+        LinkAwarePolicy {
+            bytes_per_sec: self.bytes_per_sec(now_ms),
+            ..LinkAwarePolicy::default()
+        }
+    }
+
+    fn evict(&mut self, now_ms: u64) {
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now_ms.saturating_sub(*at) >= self.window_ms)
+        {
+            self.samples.pop_front();
+        }
+    }
+}
+
+impl Default for BandwidthEstimator {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_WINDOW_MS)
+    }
+}
+
+/// `SizeThresholdPolicy` that leaves messages under `fast_min_len`
+/// uncompressed once the link carries `fast_bytes_per_sec` or more
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct LinkAwarePolicy {
+    pub base: SizeThresholdPolicy,
+    /// Measured link speed (None = unknown, treated as slow)
+    pub bytes_per_sec: Option<f64>,
+    pub fast_bytes_per_sec: f64,
+    pub fast_min_len: usize,
+}
+
+#[allow(dead_code)]
+impl LinkAwarePolicy {
+    /// About a 100 Mbit/s LAN
+    pub const FAST_BYTES_PER_SEC: f64 = 12_500_000.0;
+    /// Even on a fast link, messages this large are worth compressing
+    pub const FAST_MIN_LEN: usize = 16 * 1024;
+
+    pub fn is_fast(&self) -> bool {
+        self.bytes_per_sec
+            .is_some_and(|rate| rate >= self.fast_bytes_per_sec)
+    }
+}
+
+impl Default for LinkAwarePolicy {
+    fn default() -> Self {
+        Self {
+            base: SizeThresholdPolicy::default(),
+            bytes_per_sec: None,
+            fast_bytes_per_sec: Self::FAST_BYTES_PER_SEC,
+            fast_min_len: Self::FAST_MIN_LEN,
+        }
+    }
+}
+
+impl CompressionPolicy for LinkAwarePolicy {
+    fn should_compress(&self, msg: &Message) -> Option<CompressionKind> {
+        if self.is_fast() && msg.len < self.fast_min_len {
+            return None;
+        }
+        self.base.should_compress(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resend_retains_only_high_priority() {
+        let mut buffer = ResendBuffer::new(8);
+        assert_eq!(buffer.record(MessagePriority::Critical, b"kex"), 1);
+        assert_eq!(buffer.record(MessagePriority::Normal, b"chat"), 2);
+        assert_eq!(buffer.record(MessagePriority::High, b"join"), 3);
+        assert_eq!(buffer.record(MessagePriority::Low, b"ping"), 4);
+
+        assert_eq!(
+            buffer.replay_since(0),
+            vec![(1, b"kex".to_vec()), (3, b"join".to_vec())]
+        );
+        assert_eq!(buffer.replay_since(1), vec![(3, b"join".to_vec())]);
+    }
+
+    #[test]
+    fn test_resend_ack_and_capacity() {
+        let mut buffer = ResendBuffer::new(2);
+        for frame in [b"a", b"b", b"c"] {
+            buffer.record(MessagePriority::Critical, frame);
+        }
+
+        // Oldest frame evicted once capacity is reached
+        assert_eq!(
+            buffer.replay_since(0),
+            vec![(2, b"b".to_vec()), (3, b"c".to_vec())]
+        );
+
+        buffer.ack(2);
+        assert_eq!(buffer.replay_since(0), vec![(3, b"c".to_vec())]);
+
+        buffer.ack(3);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_breaker_transitions() {
+        let mut breaker = CircuitBreaker::new(3, 1_000);
+        assert_eq!(breaker.state(0), BreakerState::Closed);
+
+        breaker.record_failure(10);
+        breaker.record_failure(20);
+        assert_eq!(breaker.state(20), BreakerState::Closed);
+
+        // Third consecutive failure opens the breaker
+        breaker.record_failure(30);
+        assert_eq!(breaker.state(30), BreakerState::Open);
+        assert!(!breaker.allow(500));
+
+        // Cooldown expires: half-open probe allowed
+        assert_eq!(breaker.state(1_030), BreakerState::HalfOpen);
+        assert!(breaker.allow(1_030));
+
+        // Failed probe re-opens for another cooldown
+        breaker.record_failure(1_030);
+        assert_eq!(breaker.state(1_500), BreakerState::Open);
+
+        // Successful probe closes
+        assert_eq!(breaker.state(2_030), BreakerState::HalfOpen);
+        breaker.record_success();
+        assert_eq!(breaker.state(2_030), BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_breaker_success_resets_failures() {
+        let mut breaker = CircuitBreaker::new(2, 1_000);
+        breaker.record_failure(0);
+        breaker.record_success();
+        breaker.record_failure(10);
+        assert_eq!(breaker.state(10), BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_seq_in_order_and_duplicate() {
+        let mut tracker = SequenceTracker::new();
+        assert_eq!(tracker.observe(10), SeqStatus::InOrder);
+        assert_eq!(tracker.observe(11), SeqStatus::InOrder);
+        assert_eq!(tracker.observe(11), SeqStatus::Duplicate);
+        assert_eq!(tracker.observe(10), SeqStatus::Duplicate);
+        assert_eq!(tracker.observe(12), SeqStatus::InOrder);
+    }
+
+    #[test]
+    fn test_seq_single_gap_then_reordered() {
+        let mut tracker = SequenceTracker::new();
+        tracker.observe(1);
+        assert_eq!(tracker.observe(3), SeqStatus::Gap { missing: 1 });
+        assert_eq!(tracker.outstanding(), 1);
+
+        assert_eq!(tracker.observe(2), SeqStatus::Reordered);
+        assert_eq!(tracker.observe(2), SeqStatus::Duplicate);
+        assert_eq!(tracker.outstanding(), 0);
+        assert_eq!(tracker.observe(4), SeqStatus::InOrder);
+    }
+
+    #[test]
+    fn test_seq_wraparound() {
+        let mut tracker = SequenceTracker::new();
+        tracker.observe(u32::MAX - 1);
+        assert_eq!(tracker.observe(u32::MAX), SeqStatus::InOrder);
+        assert_eq!(tracker.observe(0), SeqStatus::InOrder);
+        assert_eq!(tracker.observe(u32::MAX), SeqStatus::Duplicate);
+
+        // Gap across the boundary
+        let mut tracker = SequenceTracker::new();
+        tracker.observe(u32::MAX);
+        assert_eq!(tracker.observe(1), SeqStatus::Gap { missing: 1 });
+        assert_eq!(tracker.observe(0), SeqStatus::Reordered);
+    }
+
+    #[test]
+    fn test_slow_start_ramp() {
+        let mut ramp = SlowStart::new(1_000, 1_024, 8_192, 100);
+        assert_eq!(ramp.tick_budget(1_000), Some(1_024));
+        assert_eq!(ramp.tick_budget(1_150), Some(2_048));
+        assert_eq!(ramp.tick_budget(1_300), Some(8_192));
+        assert_eq!(ramp.tick_budget(1_400), None);
+        assert_eq!(ramp.tick_budget(u64::MAX), None);
+
+        ramp.restart(5_000);
+        assert_eq!(ramp.tick_budget(5_000), Some(1_024));
+    }
+
+    #[test]
+    fn test_bandwidth_window() {
+        let mut estimator = BandwidthEstimator::new(1_000);
+        assert_eq!(estimator.bytes_per_sec(0), None);
+        estimator.record(4_000, 0);
+        estimator.record(6_000, 500);
+        assert_eq!(estimator.bytes_per_sec(900), Some(10_000.0));
+        // The first sample slides out of the window
+        assert_eq!(estimator.bytes_per_sec(1_000), Some(6_000.0));
+        estimator.record(1_000, 2_000);
+        assert_eq!(estimator.bytes_per_sec(2_000), Some(1_000.0));
+    }
+
+    #[test]
+    fn test_compression_follows_link_speed() {
+        use crate::message_optimizer::PeerCompressor;
+
+        // Over the threshold, well under FAST_MIN_LEN
+        let borderline = format!(r#"{{"type":"chat","msg":"{}"}}"#, "hello ".repeat(400));
+        let large = format!(r#"{{"type":"data","p":"{}"}}"#, "x".repeat(32 * 1024));
+        let mut compressor = PeerCompressor::new();
+
+        // Mobile peer: about 20 KB/s
+        let mut slow = BandwidthEstimator::new(1_000);
+        for i in 0..10 {
+            slow.record(2_000, i * 100);
+        }
+        let policy = slow.policy(999);
+        assert!(!policy.is_fast());
+        assert!(compressor
+            .compress_with_policy(&borderline, &policy)
+            .is_compressed());
+
+        // LAN peer: 64 MB/s
+        let mut fast = BandwidthEstimator::new(1_000);
+        for i in 0..64 {
+            fast.record(1024 * 1024, i * 10);
+        }
+        let policy = fast.policy(999);
+        assert!(policy.is_fast());
+        assert!(!compressor
+            .compress_with_policy(&borderline, &policy)
+            .is_compressed());
+        assert!(compressor
+            .compress_with_policy(&large, &policy)
+            .is_compressed());
+
+        // No measurement yet: same as the plain size threshold
+        let unknown = BandwidthEstimator::default().policy(0);
+        assert!(compressor
+            .compress_with_policy(&borderline, &unknown)
+            .is_compressed());
+    }
+}
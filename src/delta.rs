@@ -0,0 +1,386 @@
+//! Delta encoding of repetitive structured messages (`delta` feature)
+//!
+//! Stats, telemetry and entropy messages from one peer tend to repeat
+//! the previous one with a few values changed. Deflate only finds that
+//! repetition within a single message. DeltaEncoder keeps the last message
+//! per (peer, type) and sends a JSON Merge Patch (RFC 7386) against it
+//! instead, when the patch is smaller than the message:
+//!
+//! - `{"type":"delta_full","of":"stats","seq":1,"msg":{...}}` carries a
+//!   message whole and resets the base.
+//! - `{"type":"delta_patch","of":"stats","seq":2,"base":1,"patch":{...}}`
+//!   is applied to the message numbered `base`.
+//!
+//! Every `full_every`th message goes out full, so a decoder that lost its
+//! base can resync. A message that a merge patch can't express (it sets a
+//! field to null) also goes out full. DeltaDecoder keeps the same bases on
+//! the receiving side and passes anything that isn't a delta through
+//! unchanged. Full messages decode to their original text, and patched ones
+//! to an equal JSON value with its keys sorted.
+
+use crate::message_optimizer::{MessageType, MessageTypeSet};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+const TYPE_FULL: &str = "delta_full";
+const TYPE_PATCH: &str = "delta_patch";
+
+/// Why a delta message couldn't be decoded
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeltaError {
+    /// A delta message missing one of its fields
+    Malformed(String),
+    /// A patch arrived before any full message of its type
+    NoBase(MessageType),
+    /// A patch is against a different message than the stored base
+    BaseMismatch { expected: u64, actual: u64 },
+}
+
+impl std::fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeltaError::Malformed(e) => write!(f, "Malformed delta message: {}", e),
+            DeltaError::NoBase(t) => write!(f, "No delta base for {:?}", t),
+            DeltaError::BaseMismatch { expected, actual } => write!(
+                f,
+                "Delta base mismatch: have {}, patch is against {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+#[derive(Serialize)]
+struct PatchOut<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    of: MessageType,
+    seq: u64,
+    base: u64,
+    patch: &'a Map<String, Value>,
+}
+
+/// Either delta message, as received
+#[derive(Deserialize)]
+struct DeltaIn<'a> {
+    #[serde(rename = "type")]
+    kind: String,
+    of: Option<MessageType>,
+    seq: Option<u64>,
+    base: Option<u64>,
+    #[serde(borrow)]
+    msg: Option<&'a RawValue>,
+    patch: Option<Map<String, Value>>,
+}
+
+/// The last message sent for a (peer, type)
+struct EncoderBase {
+    value: Map<String, Value>,
+    seq: u64,
+    /// Messages since the last full one
+    since_full: usize,
+}
+
+/// Sender side: turns repeated messages into patches
+#[allow(dead_code)]
+pub struct DeltaEncoder {
+    full_every: usize,
+    types: MessageTypeSet,
+    bases: HashMap<(String, MessageType), EncoderBase>,
+}
+
+#[allow(dead_code)]
+impl DeltaEncoder {
+    /// Full message at least this often, per (peer, type)
+    pub const DEFAULT_FULL_EVERY: usize = 16;
+    /// Types delta-encoded by default
+    pub const DEFAULT_TYPES: [MessageType; 4] = [
+        MessageType::Entropy,
+        MessageType::Stats,
+        MessageType::Metrics,
+        MessageType::Telemetry,
+    ];
+
+    pub fn new(full_every: usize) -> Self {
+        Self {
+            full_every: full_every.max(1),
+            types: Self::DEFAULT_TYPES.into_iter().collect(),
+            bases: HashMap::new(),
+        }
+    }
+
+    /// Delta-encode `types` instead of the defaults
+    pub fn with_types(mut self, types: impl IntoIterator<Item = MessageType>) -> Self {
+        self.types = types.into_iter().collect();
+        self
+    }
+
+    /// The text to send for `text` from `peer_id`: a patch, a full delta
+    /// message, or `text` itself for types that aren't delta-encoded
+    pub fn encode(&mut self, peer_id: &str, text: &str) -> String {
+        let Ok(value) = serde_json::from_str::<Map<String, Value>>(text) else {
+            return text.to_owned();
+        };
+        let Some(of) = value
+            .get("type")
+            .and_then(Value::as_str)
+            .map(MessageType::from_name)
+            .filter(|of| self.types.contains(*of))
+        else {
+            return text.to_owned();
+        };
+
+        let full_every = self.full_every;
+        let key = (peer_id.to_string(), of);
+        if let Some(base) = self.bases.get_mut(&key) {
+            if base.since_full + 1 < full_every {
+                if let Some(patch) = diff(&base.value, &value) {
+                    let out = serde_json::to_string(&PatchOut {
+                        kind: TYPE_PATCH,
+                        of,
+                        seq: base.seq + 1,
+                        base: base.seq,
+                        patch: &patch,
+                    })
+                    .unwrap_or_default();
+                    if !out.is_empty() && out.len() < text.len() {
+                        base.value = value;
+                        base.seq += 1;
+                        base.since_full += 1;
+                        return out;
+                    }
+                }
+            }
+        }
+
+        let seq = self.bases.get(&key).map_or(1, |base| base.seq + 1);
+        self.bases.insert(
+            key,
+            EncoderBase {
+                value,
+                seq,
+                since_full: 0,
+            },
+        );
+        full_out(of, seq, text).unwrap_or_else(|| text.to_owned())
+    }
+
+    /// Forget a peer that left
+    pub fn remove_peer(&mut self, peer_id: &str) {
+        self.bases.retain(|(peer, _), _| peer != peer_id);
+    }
+}
+
+/// A `delta_full` carrying `text` verbatim, so it decodes to the same
+/// text. `text` must be a JSON object, as `encode` just parsed it.
+fn full_out(of: MessageType, seq: u64, text: &str) -> Option<String> {
+    let of = serde_json::to_string(&of).ok()?;
+    Some(format!(
+        r#"{{"type":"{}","of":{},"seq":{},"msg":{}}}"#,
+        TYPE_FULL, of, seq, text
+    ))
+}
+
+impl Default for DeltaEncoder {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_FULL_EVERY)
+    }
+}
+
+/// Receiver side: rebuilds messages from patches
+#[allow(dead_code)]
+#[derive(Default)]
+pub struct DeltaDecoder {
+    bases: HashMap<(String, MessageType), (Map<String, Value>, u64)>,
+}
+
+#[allow(dead_code)]
+impl DeltaDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The message `text` from `peer_id` stands for
+    pub fn decode(&mut self, peer_id: &str, text: &str) -> Result<String, DeltaError> {
+        let Ok(delta) = serde_json::from_str::<DeltaIn>(text) else {
+            return Ok(text.to_owned());
+        };
+        if delta.kind != TYPE_FULL && delta.kind != TYPE_PATCH {
+            return Ok(text.to_owned());
+        }
+        let missing = |field: &str| DeltaError::Malformed(format!("missing {}", field));
+        let of = delta.of.ok_or_else(|| missing("of"))?;
+        let seq = delta.seq.ok_or_else(|| missing("seq"))?;
+        let key = (peer_id.to_string(), of);
+
+        if delta.kind == TYPE_FULL {
+            let raw = delta.msg.ok_or_else(|| missing("msg"))?;
+            let value = serde_json::from_str(raw.get())
+                .map_err(|e| DeltaError::Malformed(e.to_string()))?;
+            self.bases.insert(key, (value, seq));
+            return Ok(raw.get().to_owned());
+        }
+
+        let base_seq = delta.base.ok_or_else(|| missing("base"))?;
+        let patch = delta.patch.ok_or_else(|| missing("patch"))?;
+        let (value, stored) = self.bases.get_mut(&key).ok_or(DeltaError::NoBase(of))?;
+        if *stored != base_seq {
+            return Err(DeltaError::BaseMismatch {
+                expected: *stored,
+                actual: base_seq,
+            });
+        }
+        apply(value, &patch);
+        *stored = seq;
+        Ok(Value::Object(value.clone()).to_string())
+    }
+
+    /// Forget a peer that left
+    pub fn remove_peer(&mut self, peer_id: &str) {
+        self.bases.retain(|(peer, _), _| peer != peer_id);
+    }
+}
+
+/// Merge patch turning `old` into `new`, or None if one can't express it
+/// (a merge patch can't set a member to null)
+fn diff(old: &Map<String, Value>, new: &Map<String, Value>) -> Option<Map<String, Value>> {
+    let mut patch = Map::new();
+    for (key, value) in new {
+        match (old.get(key), value) {
+            (Some(old), value) if old == value => {}
+            (Some(Value::Object(old)), Value::Object(new)) => {
+                patch.insert(key.clone(), Value::Object(diff(old, new)?));
+            }
+            (_, value) => {
+                if value.is_null() || has_null_member(value) {
+                    return None;
+                }
+                patch.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    for key in old.keys().filter(|key| !new.contains_key(*key)) {
+        patch.insert(key.clone(), Value::Null);
+    }
+    Some(patch)
+}
+
+fn has_null_member(value: &Value) -> bool {
+    match value {
+        Value::Object(members) => members.values().any(|v| v.is_null() || has_null_member(v)),
+        _ => false,
+    }
+}
+
+/// Apply a merge patch (RFC 7386 section 2)
+fn apply(target: &mut Map<String, Value>, patch: &Map<String, Value>) {
+    for (key, value) in patch {
+        match value {
+            Value::Null => {
+                target.remove(key);
+            }
+            Value::Object(patch) => {
+                let entry = target
+                    .entry(key.clone())
+                    .or_insert_with(|| Value::Object(Map::new()));
+                if !entry.is_object() {
+                    *entry = Value::Object(Map::new());
+                }
+                if let Value::Object(members) = entry {
+                    apply(members, patch);
+                }
+            }
+            value => {
+                target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(n: u64) -> String {
+        format!(
+            r#"{{"type":"stats","room":"lobby","peers":{{"alice":{{"rtt_ms":{},"sent":{}}},"bob":{{"rtt_ms":40,"sent":7}}}},"codecs":["gzip","deflate"],"build":"{}","uptime_s":{}}}"#,
+            20 + n % 3,
+            n * 10,
+            "relay-0.2.0 ".repeat(20),
+            n
+        )
+    }
+
+    fn same(a: &str, b: &str) -> bool {
+        serde_json::from_str::<Value>(a).unwrap() == serde_json::from_str::<Value>(b).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_with_resync() {
+        let mut encoder = DeltaEncoder::new(4);
+        let mut decoder = DeltaDecoder::new();
+        let mut kinds = Vec::new();
+        for n in 0..9 {
+            let text = stats(n);
+            let sent = encoder.encode("alice", &text);
+            kinds.push(if sent.contains(TYPE_FULL) { 'F' } else { 'P' });
+            let decoded = decoder.decode("alice", &sent).unwrap();
+            assert!(same(&decoded, &text), "{}: {}", n, decoded);
+            if sent.contains(TYPE_FULL) {
+                assert_eq!(decoded, text);
+            } else {
+                assert!(sent.len() < text.len() / 2, "{}", sent);
+            }
+        }
+        assert_eq!(kinds.iter().collect::<String>(), "FPPPFPPPF");
+
+        // Removed and null-valued fields
+        let text = stats(8).replace(r#","codecs":["gzip","deflate"]"#, "");
+        let sent = encoder.encode("alice", &text);
+        assert!(sent.contains(r#""patch":{"codecs":null}"#), "{}", sent);
+        assert!(same(&decoder.decode("alice", &sent).unwrap(), &text));
+        let text = r#"{"type":"stats","room":null,"peers":{"alice":{"rtt_ms":20,"sent":0}}}"#;
+        let sent = encoder.encode("alice", text);
+        assert!(sent.contains(TYPE_FULL), "{}", sent);
+        assert_eq!(decoder.decode("alice", &sent).unwrap(), text);
+    }
+
+    #[test]
+    fn test_passthrough_and_base_errors() {
+        let mut encoder = DeltaEncoder::default();
+        let mut decoder = DeltaDecoder::new();
+        let chat = r#"{"type":"chat","msg":"hi"}"#;
+        assert_eq!(encoder.encode("alice", chat), chat);
+        assert_eq!(decoder.decode("alice", chat).unwrap(), chat);
+        assert_eq!(encoder.encode("alice", "not json"), "not json");
+
+        // Bases are per peer: bob's first message is full
+        let first = encoder.encode("alice", &stats(1));
+        let patch = encoder.encode("alice", &stats(2));
+        assert!(encoder.encode("bob", &stats(2)).contains(TYPE_FULL));
+        assert_eq!(
+            decoder.decode("alice", &patch),
+            Err(DeltaError::NoBase(MessageType::Stats))
+        );
+        decoder.decode("alice", &first).unwrap();
+        decoder.decode("alice", &patch).unwrap();
+        assert_eq!(
+            decoder.decode("alice", &patch),
+            Err(DeltaError::BaseMismatch {
+                expected: 2,
+                actual: 1
+            })
+        );
+        assert!(matches!(
+            decoder.decode("alice", r#"{"type":"delta_patch","of":"stats"}"#),
+            Err(DeltaError::Malformed(_))
+        ));
+
+        encoder.remove_peer("alice");
+        assert!(encoder.encode("alice", &stats(3)).contains(TYPE_FULL));
+    }
+}
@@ -0,0 +1,180 @@
+//! Commit-before-reveal ordering for room entropy rounds
+//!
+//! Peers contribute room entropy in two phases: every peer sends an
+//! `entropy_commit`, then an `entropy_reveal`. A reveal seen before all
+//! commits are in would let a late committer pick its contribution after
+//! seeing others', so EntropyGate holds reveals until every expected peer
+//! has committed and then releases them in arrival order.
+//!
+//! At most one reveal per expected peer is held: a second reveal from
+//! the same peer is dropped, as is any reveal from a peer that isn't in
+//! the round, so the held set can't grow past the room size.
+//!
+//! A peer that leaves mid-round stops being expected, which may release
+//! the held reveals. `reset` starts the next round.
+
+use crate::message_optimizer::MessageType;
+use std::collections::HashSet;
+
+/// One room's entropy round
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+pub struct EntropyGate {
+    expected: HashSet<String>,
+    committed: HashSet<String>,
+    /// Reveals waiting for the last commit, as (sender, message)
+    held: Vec<(String, String)>,
+}
+
+#[allow(dead_code)]
+impl EntropyGate {
+    /// A round in which each of `peers` must commit
+    pub fn new<'a>(peers: impl IntoIterator<Item = &'a str>) -> Self {
+        Self {
+            expected: peers.into_iter().map(str::to_string).collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Whether every expected peer has committed
+    pub fn is_open(&self) -> bool {
+        self.expected.is_subset(&self.committed)
+    }
+
+    /// Pass a message through the gate, returning what to forward now as
+    /// (sender, message). Reveals are held while the round is incomplete;
+    /// the commit that completes it releases them after itself. Reveals
+    /// from peers outside the round, and repeats while held, are dropped.
+    pub fn ingest(
+        &mut self,
+        peer_id: &str,
+        msg_type: MessageType,
+        msg: String,
+    ) -> Vec<(String, String)> {
+        match msg_type {
+            MessageType::EntropyReveal if !self.expected.contains(peer_id) => Vec::new(),
+            MessageType::EntropyReveal if !self.is_open() => {
+                if !self.held.iter().any(|(sender, _)| sender == peer_id) {
+                    self.held.push((peer_id.to_string(), msg));
+                }
+                Vec::new()
+            }
+            MessageType::EntropyCommit => {
+                self.committed.insert(peer_id.to_string());
+                let mut out = vec![(peer_id.to_string(), msg)];
+                out.extend(self.release());
+                out
+            }
+            _ => vec![(peer_id.to_string(), msg)],
+        }
+    }
+
+    /// Add a peer that joined mid-round; reveals wait for it too
+    pub fn expect(&mut self, peer_id: &str) {
+        self.expected.insert(peer_id.to_string());
+    }
+
+    /// Stop waiting for a peer that left, returning any reveals that
+    /// releases (its own held reveals are discarded)
+    pub fn remove(&mut self, peer_id: &str) -> Vec<(String, String)> {
+        self.expected.remove(peer_id);
+        self.committed.remove(peer_id);
+        self.held.retain(|(sender, _)| sender != peer_id);
+        self.release()
+    }
+
+    /// Start a new round with the same peers
+    pub fn reset(&mut self) {
+        self.committed.clear();
+        self.held.clear();
+    }
+
+    /// Reveals currently held
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    fn release(&mut self) -> Vec<(String, String)> {
+        if self.is_open() {
+            std::mem::take(&mut self.held)
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_optimizer::{Message, MessagePriority};
+
+    fn send(gate: &mut EntropyGate, peer: &str, text: &str) -> Vec<(String, String)> {
+        let msg_type = Message::parse(text).unwrap().msg_type;
+        gate.ingest(peer, msg_type, text.to_string())
+    }
+
+    #[test]
+    fn test_reveal_held_until_all_commit() {
+        let commit = r#"{"type":"entropy_commit","hash":"aa"}"#;
+        let reveal = r#"{"type":"entropy_reveal","value":"01"}"#;
+        assert_eq!(MessagePriority::from_message(reveal), MessagePriority::High);
+
+        let mut gate = EntropyGate::new(["a", "b", "c"]);
+        assert_eq!(send(&mut gate, "a", commit).len(), 1);
+        assert_eq!(send(&mut gate, "b", commit).len(), 1);
+
+        // c hasn't committed: a's reveal waits, other traffic doesn't
+        assert!(send(&mut gate, "a", reveal).is_empty());
+        assert_eq!(gate.held(), 1);
+        assert_eq!(send(&mut gate, "b", r#"{"type":"chat"}"#).len(), 1);
+
+        // The last commit goes out first, then the held reveal
+        let out = send(&mut gate, "c", commit);
+        assert_eq!(
+            out,
+            vec![
+                ("c".to_string(), commit.to_string()),
+                ("a".to_string(), reveal.to_string()),
+            ]
+        );
+        assert!(gate.is_open());
+        // Once open, reveals pass straight through
+        assert_eq!(send(&mut gate, "b", reveal).len(), 1);
+
+        gate.reset();
+        assert!(send(&mut gate, "a", reveal).is_empty());
+    }
+
+    #[test]
+    fn test_leaving_peer_releases_round() {
+        let mut gate = EntropyGate::new(["a", "b"]);
+        send(&mut gate, "a", r#"{"type":"entropy_commit"}"#);
+        assert!(send(&mut gate, "a", r#"{"type":"entropy_reveal"}"#).is_empty());
+
+        // b never commits and leaves
+        let released = gate.remove("b");
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].0, "a");
+        assert_eq!(gate.held(), 0);
+    }
+
+    #[test]
+    fn test_held_reveals_bounded_by_round() {
+        let mut gate = EntropyGate::new(["a", "b"]);
+        let reveal = r#"{"type":"entropy_reveal","value":"01"}"#;
+        send(&mut gate, "a", r#"{"type":"entropy_commit"}"#);
+
+        // A repeated reveal doesn't pile up; the first one stands
+        assert!(send(&mut gate, "a", reveal).is_empty());
+        assert!(send(&mut gate, "a", r#"{"type":"entropy_reveal","value":"02"}"#).is_empty());
+        // Nor does one from a peer outside the round
+        assert!(send(&mut gate, "mallory", reveal).is_empty());
+        assert_eq!(gate.held(), 1);
+
+        let out = send(&mut gate, "b", r#"{"type":"entropy_commit"}"#);
+        assert_eq!(out[1], ("a".to_string(), reveal.to_string()));
+        assert_eq!(out.len(), 2);
+        // Outsiders stay shut out once the round is open
+        assert!(send(&mut gate, "mallory", reveal).is_empty());
+    }
+}
@@ -12,6 +12,7 @@
 //! - Pool stores latest contribution from each peer
 //! - ENTROPY_REQUEST returns XOR of N random peer contributions
 
+use crate::clock::{Clock, SystemClock};
 use serde::{Deserialize, Serialize};
 use worker::*;
 
@@ -70,11 +71,17 @@ pub struct EntropyPool {
     state: State,
     #[allow(dead_code)]
     env: Env,
+    /// Connect and contribution times
+    clock: Box<dyn Clock>,
 }
 
 impl DurableObject for EntropyPool {
     fn new(state: State, env: Env) -> Self {
-        Self { state, env }
+        Self {
+            state,
+            env,
+            clock: Box::new(SystemClock),
+        }
     }
 
     async fn fetch(&self, req: Request) -> Result<Response> {
@@ -102,7 +109,7 @@ impl DurableObject for EntropyPool {
 
         let session = EntropySession {
             peer_id: peer_id.clone(),
-            connected_at: Date::now().as_millis(),
+            connected_at: self.clock.now_ms(),
         };
 
         server.serialize_attachment(&session)?;
@@ -240,7 +247,7 @@ impl EntropyPool {
         let contribution = EntropyContribution {
             peer_id: peer_id.to_string(),
             entropy: entropy.to_vec(),
-            timestamp: Date::now().as_millis(),
+            timestamp: self.clock.now_ms(),
         };
 
         // Store in Durable Object storage
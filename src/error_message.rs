@@ -0,0 +1,197 @@
+//! Error replies sent to clients
+//!
+//! Every error goes out as `{"type":"error","code":...,"message":...}`
+//! with an optional `details` object, so clients can branch on `code`
+//! instead of parsing human-readable text.
+
+use crate::message_optimizer::MessageType;
+use crate::validate::ValidationError;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Machine-readable error category
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    RateLimited,
+    TooLarge,
+    AuthFailed,
+    RoomFull,
+    PeerNotFound,
+    /// The room's policy doesn't carry this message type
+    TypeNotAllowed,
+    /// The message lacks a field its type requires
+    InvalidMessage,
+    /// The relay is at its room limit and won't create another
+    TooManyRooms,
+}
+
+/// An error reply
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename = "error")]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+#[allow(dead_code)]
+impl ErrorMessage {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn rate_limited(retry_after_ms: u64) -> Self {
+        Self::new(ErrorCode::RateLimited, "Rate limit exceeded")
+            .with_details(json!({ "retry_after_ms": retry_after_ms }))
+    }
+
+    pub fn too_large(size: usize, limit: usize) -> Self {
+        Self::new(
+            ErrorCode::TooLarge,
+            format!("Message exceeds {} bytes", limit),
+        )
+        .with_details(json!({ "size": size, "limit": limit }))
+    }
+
+    pub fn auth_failed(reason: &str) -> Self {
+        Self::new(
+            ErrorCode::AuthFailed,
+            format!("Authentication failed: {}", reason),
+        )
+    }
+
+    pub fn room_full(capacity: usize) -> Self {
+        Self::new(ErrorCode::RoomFull, "Room is full").with_details(json!({ "capacity": capacity }))
+    }
+
+    pub fn too_many_rooms(limit: usize) -> Self {
+        Self::new(ErrorCode::TooManyRooms, "Relay is at its room limit")
+            .with_details(json!({ "limit": limit }))
+    }
+
+    pub fn peer_not_found(peer_id: &str) -> Self {
+        Self::new(
+            ErrorCode::PeerNotFound,
+            format!("Peer {} not found", peer_id),
+        )
+        .with_details(json!({ "peer_id": peer_id }))
+    }
+
+    pub fn type_not_allowed(msg_type: MessageType) -> Self {
+        Self::new(
+            ErrorCode::TypeNotAllowed,
+            "Message type not allowed in this room",
+        )
+        .with_details(json!({ "message_type": msg_type }))
+    }
+
+    pub fn invalid_message(err: &ValidationError) -> Self {
+        let error = Self::new(ErrorCode::InvalidMessage, err.to_string());
+        match err.field() {
+            Some(field) => error.with_details(json!({ "field": field })),
+            None => error,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_optimizer::MessagePriority;
+
+    fn shape(err: &ErrorMessage) -> Value {
+        serde_json::from_str(&err.to_json()).unwrap()
+    }
+
+    #[test]
+    fn test_constructor_json_shapes() {
+        assert_eq!(
+            shape(&ErrorMessage::rate_limited(1_500)),
+            json!({
+                "type": "error",
+                "code": "rate_limited",
+                "message": "Rate limit exceeded",
+                "details": { "retry_after_ms": 1500 }
+            })
+        );
+        assert_eq!(
+            shape(&ErrorMessage::too_large(70_000, 65_536)),
+            json!({
+                "type": "error",
+                "code": "too_large",
+                "message": "Message exceeds 65536 bytes",
+                "details": { "size": 70000, "limit": 65536 }
+            })
+        );
+        assert_eq!(
+            shape(&ErrorMessage::auth_failed("bad signature")),
+            json!({
+                "type": "error",
+                "code": "auth_failed",
+                "message": "Authentication failed: bad signature"
+            })
+        );
+        assert_eq!(
+            shape(&ErrorMessage::room_full(10)),
+            json!({
+                "type": "error",
+                "code": "room_full",
+                "message": "Room is full",
+                "details": { "capacity": 10 }
+            })
+        );
+        assert_eq!(
+            shape(&ErrorMessage::too_many_rooms(100)),
+            json!({
+                "type": "error",
+                "code": "too_many_rooms",
+                "message": "Relay is at its room limit",
+                "details": { "limit": 100 }
+            })
+        );
+        assert_eq!(
+            shape(&ErrorMessage::peer_not_found("p1")),
+            json!({
+                "type": "error",
+                "code": "peer_not_found",
+                "message": "Peer p1 not found",
+                "details": { "peer_id": "p1" }
+            })
+        );
+        assert_eq!(
+            shape(&ErrorMessage::invalid_message(
+                &ValidationError::MissingField {
+                    msg_type: MessageType::EntropyCommit,
+                    field: "commitment",
+                }
+            )),
+            json!({
+                "type": "error",
+                "code": "invalid_message",
+                "message": "EntropyCommit message missing field commitment",
+                "details": { "field": "commitment" }
+            })
+        );
+    }
+
+    #[test]
+    fn test_errors_classified_high() {
+        let json = ErrorMessage::room_full(2).to_json();
+        assert_eq!(MessagePriority::from_message(&json), MessagePriority::High);
+    }
+}
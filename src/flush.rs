@@ -0,0 +1,1328 @@
+//! Draining a PriorityQueue into a writer
+//!
+//! `flush_to` pops messages in priority order, compresses them per the
+//! priority rules (`should_compress`), coalesces Low messages into one
+//! batch frame and writes each frame prefixed with its varint length.
+//! Every frame carries its priority in the header (`priority_from_header`)
+//! for forwarding relays downstream, and batch frames are flagged as such
+//! (`Frame::decode_messages`).
+//! A batch is also cut at `LOW_BATCH_MAX_BYTES` of JSON, and the rest of
+//! the Low band goes out in further batch frames.
+//! A message's own `"compress"` flag overrides the priority rules; a Low
+//! batch is sent uncompressed if any message in it opted out. The flag
+//! is read from the message ingest parsed (`QueuedMessage::message`), so
+//! nothing is parsed again here. A Low payload without one (not JSON, or
+//! not UTF-8) can't join a JSON array and goes out alone, uncompressed.
+//!
+//! Writers are expected to be message-oriented (like a WebSocket send):
+//! a frame is accepted whole or refused with `WouldBlock`. When a write
+//! fails the unsent messages go back to the head of their bands, so
+//! nothing is lost or reordered and the next flush picks up where this
+//! one stopped. `WouldBlock` ends the flush normally; any other error is
+//! returned.
+//!
+//! BatchingWriter is the send-as-you-go counterpart for a single peer: Low
+//! messages build up in a pending batch across calls, and a Critical or
+//! High message writes that batch out before itself so the batch can't
+//! land behind frames sent after its contents arrived. With
+//! `with_max_bytes`, a Low message that would push the batch past the cap
+//! writes the batch out first and starts the next one.
+//!
+//! `RelayMode::LowLatency` trades bandwidth for per-message delay: no
+//! batching, no compression, and a flush after every push. Expect more
+//! frames and several times the bytes on chatty or text-heavy traffic;
+//! in exchange nothing waits for a batch to fill or for deflate to run.
+//!
+//! `flush_ramped` caps non-Critical bytes per call by a peer's
+//! `SlowStart` budget; Critical frames always go out.
+//!
+//! With the `tracing` feature each flush runs in a `flush` span, and every
+//! frame emits "message compressed" and "frame sent" events with sizes
+//! and priority (`peer_span` supplies the room and peer). Payloads are
+//! never logged.
+//!
+//! Every flush takes `now_ms` and pops with `PriorityQueue::pop_at`, so
+//! overdue messages are escalated per the queue's aging policy and a
+//! queue in quiet hours keeps holding its Low traffic.
+//!
+//! `flush_and_close` is the shutdown path: drain until a deadline, abandon
+//! whatever Normal/Low traffic is left, then send a `close` frame. Low
+//! traffic quiet hours is still holding is abandoned too, since the lull
+//! it waits for won't come.
+
+use crate::batcher::{array_size, write_varint, Batcher};
+use crate::delivery::SlowStart;
+use crate::message_optimizer::{
+    maybe_compress, maybe_compress_with_override, CompressionKind, Frame, MessagePriority,
+};
+use crate::priority_queue::{PriorityQueue, QueuedMessage};
+use serde::Serialize;
+use std::io::{self, ErrorKind, Write};
+
+/// Most Low messages coalesced into one batch frame
+pub const LOW_BATCH_MAX: usize = 32;
+/// Largest batch (as uncompressed JSON) `flush_to` builds, well below
+/// the frame limits peers enforce
+pub const LOW_BATCH_MAX_BYTES: usize = 16 * 1024;
+
+/// How the relay trades bandwidth against per-message delay
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RelayMode {
+    /// Batch Low traffic, compress per the priority rules, flush on Critical
+    #[default]
+    Throughput,
+    /// Gaming/VPN profile: every message goes out alone, uncompressed,
+    /// as soon as it's queued
+    LowLatency,
+}
+
+#[allow(dead_code)]
+impl RelayMode {
+    /// Whether to flush right after pushing a message of `priority`
+    pub fn flush_on_push(self, priority: MessagePriority) -> bool {
+        match self {
+            RelayMode::Throughput => priority.is_critical(),
+            RelayMode::LowLatency => true,
+        }
+    }
+}
+
+/// Write queued messages to `w` until nothing is ready to send at `now_ms`
+/// or `w` would block, returning the number of bytes written
+#[allow(dead_code)]
+pub fn flush_to<W: Write>(queue: &mut PriorityQueue, w: &mut W, now_ms: u64) -> io::Result<usize> {
+    flush_with_mode(queue, w, RelayMode::Throughput, now_ms)
+}
+
+/// `flush_to` in the given mode; LowLatency skips batching and compression
+#[allow(dead_code)]
+pub fn flush_with_mode<W: Write>(
+    queue: &mut PriorityQueue,
+    w: &mut W,
+    mode: RelayMode,
+    now_ms: u64,
+) -> io::Result<usize> {
+    flush_limited(queue, w, mode, None, now_ms)
+}
+
+/// `flush_to` for a peer still in slow start: stops once this tick's
+/// budget of non-Critical bytes is spent (the first such frame always
+/// goes, so an oversized message can't stall). The rest stays queued.
+#[allow(dead_code)]
+pub fn flush_ramped<W: Write>(
+    queue: &mut PriorityQueue,
+    w: &mut W,
+    ramp: &SlowStart,
+    now_ms: u64,
+) -> io::Result<usize> {
+    flush_limited(
+        queue,
+        w,
+        RelayMode::Throughput,
+        ramp.tick_budget(now_ms),
+        now_ms,
+    )
+}
+
+fn flush_limited<W: Write>(
+    queue: &mut PriorityQueue,
+    w: &mut W,
+    mode: RelayMode,
+    budget: Option<usize>,
+    now_ms: u64,
+) -> io::Result<usize> {
+    #[cfg(feature = "tracing")]
+    let _flush = tracing::debug_span!("flush", mode = ?mode).entered();
+    let mut written = 0;
+    let mut throttled = 0;
+
+    while let Some((priority, msg)) = queue.pop_at(now_ms) {
+        let (frame, sent) = next_frame(queue, mode, priority, msg, now_ms);
+        let out = length_prefixed(&frame);
+        let counted = !priority.is_critical();
+        if counted && budget.is_some_and(|b| throttled > 0 && throttled + out.len() > b) {
+            for msg in sent.into_iter().rev() {
+                queue.push_front(priority, msg);
+            }
+            return Ok(written);
+        }
+        match write_frame(w, &out) {
+            Ok(()) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(
+                    priority = ?priority,
+                    messages = sent.len(),
+                    wire_bytes = out.len(),
+                    "frame sent"
+                );
+                written += out.len();
+                if counted {
+                    throttled += out.len();
+                }
+            }
+            Err(e) => {
+                for msg in sent.into_iter().rev() {
+                    queue.push_front(priority, msg);
+                }
+                return if e.kind() == ErrorKind::WouldBlock {
+                    Ok(written)
+                } else {
+                    Err(e)
+                };
+            }
+        }
+    }
+
+    Ok(written)
+}
+
+/// Frame a popped message, pulling the rest of its Low batch from `queue`.
+/// Returns the frame and the messages it carries.
+fn next_frame(
+    queue: &mut PriorityQueue,
+    mode: RelayMode,
+    priority: MessagePriority,
+    msg: QueuedMessage,
+    now_ms: u64,
+) -> (Vec<u8>, Vec<QueuedMessage>) {
+    let (frame, sent) = if mode == RelayMode::LowLatency
+        || (priority == MessagePriority::Low && !batchable(&msg))
+    {
+        (Frame::uncompressed(msg.payload.clone()), vec![msg])
+    } else if priority == MessagePriority::Low {
+        let mut content = msg.payload.len();
+        let mut batch = vec![msg];
+        while batch.len() < LOW_BATCH_MAX {
+            match queue.pop_band_at(MessagePriority::Low, now_ms) {
+                Some(next)
+                    if batchable(&next)
+                        && array_size(content + next.payload.len(), batch.len() + 1)
+                            <= LOW_BATCH_MAX_BYTES =>
+                {
+                    content += next.payload.len();
+                    batch.push(next);
+                }
+                Some(next) => {
+                    // Opens the next frame
+                    queue.push_front(MessagePriority::Low, next);
+                    break;
+                }
+                None => break,
+            }
+        }
+        (low_batch_frame(&batch), batch)
+    } else {
+        (message_frame(priority, &msg), vec![msg])
+    };
+    (frame.with_priority(priority).encode(), sent)
+}
+
+/// Last frame sent to a peer being closed
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename = "close")]
+pub struct CloseNotice {
+    /// Queued messages dropped because the close deadline passed
+    pub abandoned: usize,
+}
+
+#[allow(dead_code)]
+impl CloseNotice {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Outcome of `flush_and_close`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CloseReport {
+    /// Bytes written, including the close frame
+    pub written: usize,
+    /// Normal/Low messages shed once the deadline passed
+    pub abandoned: usize,
+}
+
+/// A `flush_and_close` that failed partway
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct CloseError {
+    pub error: io::Error,
+    /// What went out and what was shed before the failure
+    pub report: CloseReport,
+}
+
+impl std::fmt::Display for CloseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "close failed after {} bytes: {}",
+            self.report.written, self.error
+        )
+    }
+}
+
+impl std::error::Error for CloseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Close a peer: drain its queue in priority order, then send a
+/// `CloseNotice`. Once `now_ms()` reaches `deadline_ms` the remaining
+/// Normal and Low messages are shed (reported to the queue's drop hook);
+/// Critical and High ones still go out. Low messages held by quiet hours
+/// are shed as well.
+///
+/// If the writer would block, the unsent messages go back to the queue
+/// and the error is returned with the report so far, so the caller can
+/// retry until the deadline.
+#[allow(dead_code)]
+pub fn flush_and_close<W: Write>(
+    queue: &mut PriorityQueue,
+    w: &mut W,
+    deadline_ms: u64,
+    mut now_ms: impl FnMut() -> u64,
+) -> Result<CloseReport, CloseError> {
+    let mut report = CloseReport {
+        written: 0,
+        abandoned: 0,
+    };
+
+    loop {
+        let now = now_ms();
+        if now >= deadline_ms {
+            report.abandoned += queue.shed_below(MessagePriority::High);
+        }
+        let Some((priority, msg)) = queue.pop_at(now) else {
+            report.abandoned += queue.shed_below(MessagePriority::High);
+            break;
+        };
+        let (frame, sent) = next_frame(queue, RelayMode::Throughput, priority, msg, now);
+        let out = length_prefixed(&frame);
+        if let Err(e) = write_frame(w, &out) {
+            for msg in sent.into_iter().rev() {
+                queue.push_front(priority, msg);
+            }
+            return Err(CloseError { error: e, report });
+        }
+        report.written += out.len();
+    }
+
+    let notice = CloseNotice {
+        abandoned: report.abandoned,
+    };
+    let out = length_prefixed(&Frame::uncompressed(notice.to_json().into_bytes()).encode());
+    if let Err(error) = write_frame(w, &out) {
+        return Err(CloseError { error, report });
+    }
+    report.written += out.len();
+    Ok(report)
+}
+
+/// A `BatchingWriter::send` that failed partway
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct SendError {
+    pub error: io::Error,
+    /// Bytes that went out before the failure (a pending batch written
+    /// ahead of the message)
+    pub written: usize,
+    /// The message, if it was neither written nor added to the pending
+    /// batch; the caller should requeue it
+    pub unsent: Option<QueuedMessage>,
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "send failed after {} bytes: {}",
+            self.written, self.error
+        )
+    }
+}
+
+impl std::error::Error for SendError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Coalesces one peer's Low messages between sends
+#[allow(dead_code)]
+pub struct BatchingWriter {
+    pending: Vec<QueuedMessage>,
+    /// Sum of pending payload lengths
+    pending_bytes: usize,
+    max_batch: usize,
+    max_bytes: Option<usize>,
+}
+
+#[allow(dead_code)]
+impl BatchingWriter {
+    /// Write the pending batch once it holds `max_batch` messages
+    pub fn new(max_batch: usize) -> Self {
+        Self {
+            pending: Vec::new(),
+            pending_bytes: 0,
+            max_batch: max_batch.max(1),
+            max_bytes: None,
+        }
+    }
+
+    /// Keep each batch's JSON within `max_bytes`, writing the pending batch
+    /// before a message that wouldn't fit
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Send a message, returning the bytes written (0 while a Low message
+    /// only joined the batch).
+    ///
+    /// A batch that fails to write stays pending. The error says how many
+    /// bytes did go out and hands `msg` back in `unsent` unless it was
+    /// already added to the (still pending) batch.
+    pub fn send<W: Write>(
+        &mut self,
+        w: &mut W,
+        priority: MessagePriority,
+        msg: QueuedMessage,
+    ) -> Result<usize, SendError> {
+        let failed = |error, written, unsent| SendError {
+            error,
+            written,
+            unsent,
+        };
+        if priority == MessagePriority::Low && batchable(&msg) {
+            let oversized = self.max_bytes.is_some_and(|max| {
+                let content = self.pending_bytes + msg.payload.len();
+                array_size(content, self.pending.len() + 1) > max
+            });
+            let mut written = 0;
+            if oversized {
+                match self.flush_batch(w) {
+                    Ok(n) => written += n,
+                    Err(e) => return Err(failed(e, 0, Some(msg))),
+                }
+            }
+            self.pending_bytes += msg.payload.len();
+            self.pending.push(msg);
+            if self.pending.len() < self.max_batch {
+                return Ok(written);
+            }
+            return match self.flush_batch(w) {
+                Ok(n) => Ok(written + n),
+                Err(e) => Err(failed(e, written, None)),
+            };
+        }
+
+        // Critical/High, and Low that can't be batched, go after the
+        // pending batch
+        let mut written = 0;
+        if priority <= MessagePriority::High || priority == MessagePriority::Low {
+            match self.flush_batch(w) {
+                Ok(n) => written += n,
+                Err(e) => return Err(failed(e, 0, Some(msg))),
+            }
+        }
+        let frame = if priority == MessagePriority::Low {
+            Frame::uncompressed(msg.payload.clone())
+        } else {
+            message_frame(priority, &msg)
+        };
+        let out = length_prefixed(&frame.with_priority(priority).encode());
+        match write_frame(w, &out) {
+            Ok(()) => Ok(written + out.len()),
+            Err(e) => Err(failed(e, written, Some(msg))),
+        }
+    }
+
+    /// Write the pending batch, if any
+    pub fn flush_batch<W: Write>(&mut self, w: &mut W) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            return Ok(0);
+        }
+        let frame = low_batch_frame(&self.pending).with_priority(MessagePriority::Low);
+        let out = length_prefixed(&frame.encode());
+        write_frame(w, &out)?;
+        self.pending.clear();
+        self.pending_bytes = 0;
+        Ok(out.len())
+    }
+
+    /// Low messages waiting in the batch
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+fn length_prefixed(frame: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(frame.len() + 5);
+    write_varint(&mut out, frame.len() as u64);
+    out.extend_from_slice(frame);
+    out
+}
+
+/// One frame without splitting it across writes
+fn write_frame<W: Write>(w: &mut W, frame: &[u8]) -> io::Result<()> {
+    loop {
+        match w.write(frame) {
+            Ok(n) if n == frame.len() => return Ok(()),
+            Ok(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::WriteZero,
+                    "writer accepted a partial frame",
+                ))
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// The message's `"compress"` flag, if it is JSON and has one
+fn compress_override(msg: &QueuedMessage) -> Option<bool> {
+    msg.message.as_ref().and_then(|m| m.compress)
+}
+
+/// Whether a Low message can join a JSON array batch
+fn batchable(msg: &QueuedMessage) -> bool {
+    msg.message.is_some() && std::str::from_utf8(&msg.payload).is_ok()
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn compressed_frame(original_len: usize, (payload, compressed): (Vec<u8>, bool)) -> Frame {
+    let kind = if compressed {
+        CompressionKind::Gzip
+    } else {
+        CompressionKind::None
+    };
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        kind = ?kind,
+        bytes = original_len,
+        wire_bytes = payload.len(),
+        "message compressed"
+    );
+    Frame::with_kind(kind, payload)
+}
+
+/// Frame a single message, compressing it if its priority (or its own
+/// flag) allows. Non-UTF-8 payloads are sent as-is.
+fn message_frame(priority: MessagePriority, msg: &QueuedMessage) -> Frame {
+    match std::str::from_utf8(&msg.payload) {
+        Ok(text) => compressed_frame(
+            text.len(),
+            maybe_compress_with_override(text, priority, compress_override(msg)),
+        ),
+        Err(_) => Frame::uncompressed(msg.payload.clone()),
+    }
+}
+
+/// Frame Low messages as one JSON array batch, compressed as a unit.
+/// Every message must be `batchable`.
+fn low_batch_frame(batch: &[QueuedMessage]) -> Frame {
+    let mut batcher = Batcher::new(batch.len());
+    let mut opted_out = false;
+    for msg in batch {
+        let text = std::str::from_utf8(&msg.payload).unwrap_or_default();
+        opted_out |= compress_override(msg) == Some(false);
+        batcher.push(text.to_string());
+    }
+    let text = batcher.flush().unwrap_or_default();
+    if opted_out {
+        Frame::uncompressed(text.into_bytes())
+    } else {
+        compressed_frame(text.len(), maybe_compress(&text))
+    }
+    .with_batch()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batcher::{read_varint, unbatch};
+    use crate::clock::{Clock, MockClock};
+    use crate::message_optimizer::{priority_from_header, Message};
+
+    /// In-memory socket that accepts `capacity` frames, then would block
+    struct MemorySocket {
+        frames: Vec<Vec<u8>>,
+        capacity: usize,
+    }
+
+    impl Write for MemorySocket {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.frames.len() == self.capacity {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            self.frames.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn decode(frames: &[Vec<u8>]) -> Vec<String> {
+        frames
+            .iter()
+            .map(|f| {
+                let mut rest = f.as_slice();
+                let len = read_varint(&mut rest).unwrap() as usize;
+                assert_eq!(len, rest.len());
+                Frame::decode(rest).unwrap()
+            })
+            .collect()
+    }
+
+    fn parse(frame: &[u8]) -> Frame {
+        let mut rest = frame;
+        read_varint(&mut rest).unwrap();
+        Frame::parse(rest).unwrap()
+    }
+
+    fn push(queue: &mut PriorityQueue, priority: MessagePriority, text: &str) {
+        queue.push(
+            priority,
+            QueuedMessage::new(text.as_bytes().to_vec(), 0, 0).parsed(),
+        );
+    }
+
+    #[test]
+    fn test_flush_priority_order_and_low_batching() {
+        let mut queue = PriorityQueue::new();
+        let big_chat = format!(r#"{{"type":"chat","msg":"{}"}}"#, "hi ".repeat(1_000));
+        push(&mut queue, MessagePriority::Low, r#"{"type":"ping"}"#);
+        push(&mut queue, MessagePriority::Normal, &big_chat);
+        push(&mut queue, MessagePriority::Critical, r#"{"type":"auth"}"#);
+        push(&mut queue, MessagePriority::Low, r#"{"type":"pong"}"#);
+
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        let written = flush_to(&mut queue, &mut socket, 0).unwrap();
+        assert!(queue.is_empty());
+        assert_eq!(written, socket.frames.iter().map(Vec::len).sum::<usize>());
+
+        let texts = decode(&socket.frames);
+        assert_eq!(texts.len(), 3);
+        assert_eq!(texts[0], r#"{"type":"auth"}"#);
+        assert_eq!(texts[1], big_chat);
+        // Normal chat compressed on the wire
+        assert!(socket.frames[1].len() < big_chat.len());
+        // Each header names the band the frame was sent from
+        let headers: Vec<MessagePriority> = socket
+            .frames
+            .iter()
+            .map(|f| {
+                let mut rest = f.as_slice();
+                read_varint(&mut rest).unwrap();
+                priority_from_header(rest).unwrap()
+            })
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                MessagePriority::Critical,
+                MessagePriority::Normal,
+                MessagePriority::Low
+            ]
+        );
+        assert_eq!(
+            unbatch(texts[2].as_bytes()).unwrap(),
+            vec![r#"{"type":"ping"}"#, r#"{"type":"pong"}"#]
+        );
+    }
+
+    #[test]
+    fn test_low_batch_compressed_as_unit() {
+        let mut queue = PriorityQueue::new();
+        let stats: Vec<String> = (0..LOW_BATCH_MAX)
+            .map(|i| format!(r#"{{"type":"stats","peer":"peer-{}","rx":1048576}}"#, i))
+            .collect();
+        for text in &stats {
+            // Each one alone is far below the compression threshold
+            assert!(!maybe_compress(text).1);
+            push(&mut queue, MessagePriority::Low, text);
+        }
+
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        flush_to(&mut queue, &mut socket, 0).unwrap();
+        assert_eq!(socket.frames.len(), 1);
+
+        // The header tags the batch as a whole as gzip
+        let mut rest = socket.frames[0].as_slice();
+        read_varint(&mut rest).unwrap();
+        assert_eq!(Frame::parse(rest).unwrap().kind, CompressionKind::Gzip);
+        let raw_len: usize = stats.iter().map(|s| s.len() + 1).sum();
+        assert!(rest.len() * 4 < raw_len, "{} of {}", rest.len(), raw_len);
+
+        let texts = decode(&socket.frames);
+        assert_eq!(unbatch(texts[0].as_bytes()).unwrap(), stats);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_lifecycle_events_carry_peer_and_sizes() {
+        use crate::message_optimizer::process_frame;
+        use crate::priority_queue::peer_span;
+
+        let text = r#"{"type":"chat","msg":"secret words"}"#;
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        let events = crate::trace_recorder::record(|| {
+            let _peer = peer_span("room-1", "p1").entered();
+            let decoded = process_frame(&Frame::uncompressed(text.into()).encode()).unwrap();
+            let mut queue = PriorityQueue::new();
+            push(&mut queue, decoded.priority, &decoded.text);
+            flush_to(&mut queue, &mut socket, 0).unwrap();
+        });
+
+        let messages: Vec<_> = events.iter().filter_map(|e| e.message()).collect();
+        assert_eq!(
+            messages,
+            [
+                "message classified",
+                "message enqueued",
+                "message compressed",
+                "frame sent"
+            ]
+        );
+        let bytes = format!("bytes={}", text.len());
+        for (event, spans) in events.iter().zip([
+            &["peer", "receive"][..],
+            &["peer"],
+            &["peer", "flush"],
+            &["peer", "flush"],
+        ]) {
+            assert_eq!(event.spans, spans);
+            assert!(event.has("room=\"room-1\"") && event.has("peer=\"p1\""));
+            assert!(event.has("priority=Normal") || event.has("kind=None"));
+            assert!(!event.fields.iter().any(|f| f.contains("secret")));
+        }
+        assert!(events[0].has("msg_type=Chat") && events[0].has(&bytes));
+        assert!(events[1].has(&bytes) && events[2].has(&bytes));
+        let wire = format!("wire_bytes={}", socket.frames[0].len());
+        assert!(events[3].has(&wire) && events[3].has("messages=1"));
+    }
+
+    #[test]
+    fn test_low_latency_passthrough() {
+        let mut queue = PriorityQueue::new();
+        let big_stats = format!(r#"{{"type":"stats","pad":"{}"}}"#, "a".repeat(1_975));
+        assert_eq!(big_stats.len(), 2_000);
+        push(&mut queue, MessagePriority::Low, &big_stats);
+        push(&mut queue, MessagePriority::Low, r#"{"type":"ping"}"#);
+        assert!(RelayMode::LowLatency.flush_on_push(MessagePriority::Low));
+        assert!(!RelayMode::Throughput.flush_on_push(MessagePriority::Low));
+
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        flush_with_mode(&mut queue, &mut socket, RelayMode::LowLatency, 0).unwrap();
+
+        // One raw frame per message, no batch
+        assert_eq!(
+            decode(&socket.frames),
+            vec![big_stats.as_str(), r#"{"type":"ping"}"#]
+        );
+        let mut rest = socket.frames[0].as_slice();
+        read_varint(&mut rest).unwrap();
+        assert_eq!(Frame::parse(rest).unwrap().kind, CompressionKind::None);
+        assert_eq!(rest.len(), big_stats.len() + 2);
+    }
+
+    #[test]
+    fn test_critical_flushes_pending_batch_first() {
+        let mut writer = BatchingWriter::new(LOW_BATCH_MAX);
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        let low = |text: &str| QueuedMessage::new(text.as_bytes().to_vec(), 0, 0).parsed();
+
+        for text in [r#"{"type":"ping"}"#, r#"{"type":"stats","rx":1}"#] {
+            assert_eq!(
+                writer
+                    .send(&mut socket, MessagePriority::Low, low(text))
+                    .unwrap(),
+                0
+            );
+        }
+        // Normal traffic doesn't disturb the building batch
+        writer
+            .send(
+                &mut socket,
+                MessagePriority::Normal,
+                low(r#"{"type":"chat"}"#),
+            )
+            .unwrap();
+        assert_eq!(writer.pending(), 2);
+
+        writer
+            .send(
+                &mut socket,
+                MessagePriority::Critical,
+                low(r#"{"type":"auth"}"#),
+            )
+            .unwrap();
+        assert_eq!(writer.pending(), 0);
+
+        let texts = decode(&socket.frames);
+        assert_eq!(texts.len(), 3);
+        assert_eq!(texts[0], r#"{"type":"chat"}"#);
+        assert_eq!(
+            unbatch(texts[1].as_bytes()).unwrap(),
+            vec![r#"{"type":"ping"}"#, r#"{"type":"stats","rx":1}"#]
+        );
+        assert_eq!(texts[2], r#"{"type":"auth"}"#);
+
+        // The batch goes out but the message after it doesn't: the error
+        // counts the batch and hands the message back
+        for text in [r#"{"type":"ping"}"#, r#"{"type":"pong"}"#] {
+            writer
+                .send(&mut socket, MessagePriority::Low, low(text))
+                .unwrap();
+        }
+        socket.capacity = socket.frames.len() + 1;
+        let err = writer
+            .send(
+                &mut socket,
+                MessagePriority::High,
+                low(r#"{"type":"peer_join"}"#),
+            )
+            .unwrap_err();
+        assert_eq!(err.error.kind(), ErrorKind::WouldBlock);
+        assert_eq!(err.written, socket.frames[3].len());
+        assert_eq!(err.unsent, Some(low(r#"{"type":"peer_join"}"#)));
+        assert_eq!(writer.pending(), 0);
+
+        // A Low message that couldn't join after a failed pre-flush too
+        let mut capped = BatchingWriter::new(LOW_BATCH_MAX).with_max_bytes(20);
+        capped
+            .send(&mut socket, MessagePriority::Low, low(r#"{"type":"ping"}"#))
+            .unwrap();
+        let err = capped
+            .send(&mut socket, MessagePriority::Low, low(r#"{"type":"pong"}"#))
+            .unwrap_err();
+        assert_eq!((err.written, capped.pending()), (0, 1));
+        assert_eq!(err.unsent, Some(low(r#"{"type":"pong"}"#)));
+    }
+
+    #[test]
+    fn test_batches_split_at_byte_cap() {
+        let tiny = |i: usize| format!(r#"{{"type":"ping","id":{}}}"#, i);
+        let mut messages = Vec::new();
+        let mut total = 0;
+        while total < 100 * 1024 {
+            let text = tiny(messages.len());
+            total += text.len();
+            messages.push(text);
+        }
+
+        let cap = 16 * 1024;
+        let mut writer = BatchingWriter::new(usize::MAX).with_max_bytes(cap);
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        for text in &messages {
+            let msg = QueuedMessage::new(text.as_bytes().to_vec(), 0, 0).parsed();
+            writer.send(&mut socket, MessagePriority::Low, msg).unwrap();
+        }
+        writer.flush_batch(&mut socket).unwrap();
+
+        let batches = decode(&socket.frames);
+        assert!(batches.len() >= 7, "{} batches", batches.len());
+        let mut received = Vec::new();
+        for batch in &batches {
+            assert!(batch.len() <= cap, "{} byte batch", batch.len());
+            received.extend(unbatch(batch.as_bytes()).unwrap());
+        }
+        // Every batch but the last was filled close to the cap
+        assert!(batches[..batches.len() - 1]
+            .iter()
+            .all(|b| b.len() > cap - 32));
+        assert_eq!(received, messages);
+
+        // flush_to cuts its batches the same way
+        let mut queue = PriorityQueue::new();
+        let stats = format!(r#"{{"type":"stats","history":"{}"}}"#, "0".repeat(1000));
+        for _ in 0..LOW_BATCH_MAX {
+            push(&mut queue, MessagePriority::Low, &stats);
+        }
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        flush_to(&mut queue, &mut socket, 0).unwrap();
+        let batches = decode(&socket.frames);
+        assert_eq!(batches.len(), 3);
+        let counts: Vec<usize> = batches
+            .iter()
+            .map(|b| {
+                assert!(b.len() <= LOW_BATCH_MAX_BYTES);
+                unbatch(b.as_bytes()).unwrap().len()
+            })
+            .collect();
+        assert_eq!(counts, [15, 15, 2]);
+    }
+
+    #[test]
+    fn test_slow_start_limits_normal_not_critical() {
+        let mut queue = PriorityQueue::new();
+        let data = format!(r#"{{"type":"data","pad":"{}"}}"#, "x".repeat(400));
+        for _ in 0..10 {
+            push(&mut queue, MessagePriority::Normal, &data);
+        }
+        for _ in 0..3 {
+            push(
+                &mut queue,
+                MessagePriority::Critical,
+                r#"{"type":"key_exchange"}"#,
+            );
+        }
+
+        // Just resumed: room for about two data frames this tick
+        let ramp = SlowStart::new(0, 1_000, 64 * 1024, 100);
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        flush_ramped(&mut queue, &mut socket, &ramp, 0).unwrap();
+        let texts = decode(&socket.frames);
+        assert_eq!(texts.len(), 5);
+        assert!(texts[..3].iter().all(|t| t == r#"{"type":"key_exchange"}"#));
+        assert_eq!(queue.band_len(MessagePriority::Normal), 8);
+
+        // Next tick: a new Critical goes first, then the same Normal budget
+        push(&mut queue, MessagePriority::Critical, r#"{"type":"auth"}"#);
+        flush_ramped(&mut queue, &mut socket, &ramp, 50).unwrap();
+        assert_eq!(decode(&socket.frames[5..6]), vec![r#"{"type":"auth"}"#]);
+        assert_eq!(socket.frames.len(), 8);
+
+        // Ramp complete: the backlog drains
+        flush_ramped(&mut queue, &mut socket, &ramp, 10_000).unwrap();
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_close_flushes_critical_abandons_low() {
+        let mut queue = PriorityQueue::new();
+        push(&mut queue, MessagePriority::Low, r#"{"type":"ping"}"#);
+        push(&mut queue, MessagePriority::Normal, r#"{"type":"chat"}"#);
+        push(
+            &mut queue,
+            MessagePriority::High,
+            r#"{"type":"peer_leave"}"#,
+        );
+        push(
+            &mut queue,
+            MessagePriority::Critical,
+            r#"{"type":"key_exchange"}"#,
+        );
+
+        // Each write takes 40ms; the deadline passes after the first one
+        let mut clock = 0;
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        let report = flush_and_close(&mut queue, &mut socket, 50, || {
+            clock += 40;
+            clock - 40
+        })
+        .unwrap();
+
+        assert!(queue.is_empty());
+        assert_eq!(report.abandoned, 2);
+        assert_eq!(
+            decode(&socket.frames),
+            vec![
+                r#"{"type":"key_exchange"}"#,
+                r#"{"type":"peer_leave"}"#,
+                r#"{"type":"close","abandoned":2}"#,
+            ]
+        );
+        assert_eq!(queue.drops().total(), 2);
+
+        // Already past the deadline: Critical still goes out
+        push(&mut queue, MessagePriority::Low, r#"{"type":"ping"}"#);
+        push(&mut queue, MessagePriority::Critical, r#"{"type":"auth"}"#);
+        socket.frames.clear();
+        let report = flush_and_close(&mut queue, &mut socket, 50, || 1_000).unwrap();
+        assert_eq!(report.abandoned, 1);
+        assert_eq!(
+            decode(&socket.frames),
+            vec![r#"{"type":"auth"}"#, r#"{"type":"close","abandoned":1}"#]
+        );
+    }
+
+    #[test]
+    fn test_close_notice_failure_keeps_report() {
+        let mut queue = PriorityQueue::new();
+        push(&mut queue, MessagePriority::Low, r#"{"type":"ping"}"#);
+        push(
+            &mut queue,
+            MessagePriority::High,
+            r#"{"type":"peer_leave"}"#,
+        );
+
+        // Room for the High message, not the notice after it
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: 1,
+        };
+        let err = flush_and_close(&mut queue, &mut socket, 50, || 1_000).unwrap_err();
+        assert_eq!(err.error.kind(), ErrorKind::WouldBlock);
+        assert_eq!(
+            err.report,
+            CloseReport {
+                written: socket.frames[0].len(),
+                abandoned: 1,
+            }
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_close_deadline_on_mock_clock() {
+        /// A socket whose every send takes 30ms
+        struct SlowSocket<'a> {
+            frames: Vec<Vec<u8>>,
+            clock: &'a MockClock,
+        }
+
+        impl Write for SlowSocket<'_> {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.clock.advance(30);
+                self.frames.push(buf.to_vec());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // Queued just now, so none of them is old enough to age
+        let mut queue = PriorityQueue::new();
+        for n in 0..4 {
+            let data = format!(r#"{{"type":"data","n":{}}}"#, n);
+            queue.push(
+                MessagePriority::Normal,
+                QueuedMessage::new(data.into_bytes(), n, 1_000),
+            );
+        }
+        let clock = MockClock::new(1_000);
+        let mut socket = SlowSocket {
+            frames: Vec::new(),
+            clock: &clock,
+        };
+
+        // Sends finish at 1030 and 1060; the deadline passes before a third
+        let report = flush_and_close(&mut queue, &mut socket, 1_050, || clock.now_ms()).unwrap();
+        assert_eq!(report.abandoned, 2);
+        assert_eq!(
+            decode(&socket.frames),
+            vec![
+                r#"{"type":"data","n":0}"#,
+                r#"{"type":"data","n":1}"#,
+                r#"{"type":"close","abandoned":2}"#,
+            ]
+        );
+        assert_eq!(clock.now_ms(), 1_090);
+    }
+
+    #[test]
+    fn test_flush_stops_when_writer_blocks() {
+        let mut queue = PriorityQueue::new();
+        push(&mut queue, MessagePriority::High, r#"{"type":"peer_join"}"#);
+        push(
+            &mut queue,
+            MessagePriority::Normal,
+            r#"{"type":"data","n":1}"#,
+        );
+        push(
+            &mut queue,
+            MessagePriority::Normal,
+            r#"{"type":"data","n":2}"#,
+        );
+
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: 1,
+        };
+        flush_to(&mut queue, &mut socket, 0).unwrap();
+        assert_eq!(decode(&socket.frames), vec![r#"{"type":"peer_join"}"#]);
+        assert_eq!(queue.len(), 2);
+
+        // Writable again: the rest follows in order
+        socket.capacity = usize::MAX;
+        flush_to(&mut queue, &mut socket, 0).unwrap();
+        assert_eq!(
+            decode(&socket.frames[1..]),
+            vec![r#"{"type":"data","n":1}"#, r#"{"type":"data","n":2}"#]
+        );
+    }
+
+    #[test]
+    fn test_overdue_normal_overtakes_high_stream() {
+        let mut queue = PriorityQueue::new();
+        let chat = r#"{"type":"chat"}"#;
+        queue.push(
+            MessagePriority::Normal,
+            QueuedMessage::new(chat.as_bytes().to_vec(), 1, 0),
+        );
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: 0,
+        };
+
+        // One frame per tick while High keeps arriving
+        for (tick, now) in [50u64, 150, 250, 350].into_iter().enumerate() {
+            let join = format!(r#"{{"type":"peer_join","n":{}}}"#, tick);
+            queue.push(
+                MessagePriority::High,
+                QueuedMessage::new(join.into_bytes(), tick as u64 + 2, now),
+            );
+            socket.capacity += 1;
+            flush_to(&mut queue, &mut socket, now).unwrap();
+        }
+
+        // Past its 200ms max wait the chat went ahead of later High traffic
+        let sent = decode(&socket.frames);
+        assert_eq!(sent[2], chat);
+        assert_eq!(
+            priority_from_header(&socket.frames[2][1..]),
+            Ok(MessagePriority::High)
+        );
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn test_unbatchable_low_goes_alone() {
+        let mut queue = PriorityQueue::new();
+        push(&mut queue, MessagePriority::Low, r#"{"type":"ping"}"#);
+        // Raw bytes, and text that isn't a JSON message
+        queue.push(
+            MessagePriority::Low,
+            QueuedMessage::new(vec![0xff, 0xfe, b'x'], 0, 0).parsed(),
+        );
+        push(&mut queue, MessagePriority::Low, "not json");
+        push(&mut queue, MessagePriority::Low, r#"{"type":"pong"}"#);
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        flush_to(&mut queue, &mut socket, 0).unwrap();
+
+        let payloads: Vec<Frame> = socket.frames.iter().map(|f| parse(f)).collect();
+        assert_eq!(payloads.len(), 4);
+        assert_eq!(payloads[1].payload, vec![0xff, 0xfe, b'x']);
+        assert_eq!(payloads[1].kind, CompressionKind::None);
+        assert_eq!(payloads[2].payload, b"not json".to_vec());
+        assert_eq!(payloads[2].kind, CompressionKind::None);
+        let batches = decode(&[socket.frames[0].clone(), socket.frames[3].clone()]);
+        assert_eq!(
+            unbatch(batches[0].as_bytes()).unwrap(),
+            vec![r#"{"type":"ping"}"#]
+        );
+        assert_eq!(
+            unbatch(batches[1].as_bytes()).unwrap(),
+            vec![r#"{"type":"pong"}"#]
+        );
+    }
+
+    #[test]
+    fn test_writer_sends_unbatchable_low_alone() {
+        let mut writer = BatchingWriter::new(LOW_BATCH_MAX);
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        let ping = QueuedMessage::new(br#"{"type":"ping"}"#.to_vec(), 0, 0).parsed();
+        writer
+            .send(&mut socket, MessagePriority::Low, ping)
+            .unwrap();
+        let text = "plain ".repeat(1_000);
+        let raw = QueuedMessage::new(text.clone().into_bytes(), 0, 0).parsed();
+        writer.send(&mut socket, MessagePriority::Low, raw).unwrap();
+
+        // The pending batch goes first, then the raw payload as is
+        assert_eq!(writer.pending(), 0);
+        assert_eq!(socket.frames.len(), 2);
+        let frame = parse(&socket.frames[1]);
+        assert_eq!(frame.kind, CompressionKind::None);
+        assert_eq!(frame.payload, text.into_bytes());
+    }
+
+    #[test]
+    fn test_compress_flag_read_from_parsed_message() {
+        let text = format!(r#"{{"type":"data","p":"{}"}}"#, "x".repeat(4096));
+        let mut parsed = Message::parse(&text).unwrap();
+        parsed.compress = Some(false);
+        let mut queue = PriorityQueue::new();
+        queue.push(
+            MessagePriority::Normal,
+            QueuedMessage::new(text.clone().into_bytes(), 0, 0).with_message(parsed),
+        );
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        flush_to(&mut queue, &mut socket, 0).unwrap();
+        assert_eq!(parse(&socket.frames[0]).kind, CompressionKind::None);
+    }
+
+    #[test]
+    fn test_flush_records_dwell() {
+        let mut queue = PriorityQueue::new();
+        queue.push(
+            MessagePriority::High,
+            QueuedMessage::new(br#"{"type":"peer_join"}"#.to_vec(), 1, 40),
+        );
+        for (seq, at) in [(2, 10), (3, 20)] {
+            queue.push(
+                MessagePriority::Low,
+                QueuedMessage::new(br#"{"type":"ping"}"#.to_vec(), seq, at).parsed(),
+            );
+        }
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        flush_to(&mut queue, &mut socket, 50).unwrap();
+
+        // Both batched Low messages count, not just the one popped first
+        let stats = queue.stats(50);
+        assert_eq!(
+            queue
+                .dwell(MessagePriority::High)
+                .counts()
+                .iter()
+                .sum::<u64>(),
+            1
+        );
+        assert_eq!(
+            queue
+                .dwell(MessagePriority::Low)
+                .counts()
+                .iter()
+                .sum::<u64>(),
+            2
+        );
+        assert_eq!(stats.dwell(MessagePriority::High).p50, Some(15));
+    }
+
+    #[test]
+    fn test_aged_low_leaves_the_batch() {
+        use crate::priority_queue::AgingPolicy;
+
+        let aging = AgingPolicy::disabled().with_max_wait(MessagePriority::Low, Some(50));
+        let mut queue = PriorityQueue::new().with_aging(aging);
+        let old = r#"{"type":"stats","n":0}"#;
+        queue.push(
+            MessagePriority::Low,
+            QueuedMessage::new(old.as_bytes().to_vec(), 1, 0).parsed(),
+        );
+        queue.push(
+            MessagePriority::Low,
+            QueuedMessage::new(br#"{"type":"stats","n":1}"#.to_vec(), 2, 90).parsed(),
+        );
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+
+        // The overdue stats message goes alone as Normal; the fresh one
+        // still rides in a Low batch
+        let ramp = SlowStart::new(0, 64 * 1024, 64 * 1024, 100);
+        flush_ramped(&mut queue, &mut socket, &ramp, 100).unwrap();
+        assert_eq!(decode(&socket.frames)[0], old);
+        assert_eq!(
+            priority_from_header(&socket.frames[0][1..]),
+            Ok(MessagePriority::Normal)
+        );
+        assert_eq!(
+            priority_from_header(&socket.frames[1][1..]),
+            Ok(MessagePriority::Low)
+        );
+    }
+
+    #[test]
+    fn test_quiet_hours_hold_low_while_normal_flows() {
+        let mut queue = PriorityQueue::new().with_quiet_hours(500);
+        push(&mut queue, MessagePriority::Low, r#"{"type":"stats"}"#);
+        push(&mut queue, MessagePriority::Normal, r#"{"type":"chat"}"#);
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+
+        flush_to(&mut queue, &mut socket, 100).unwrap();
+        assert_eq!(decode(&socket.frames), vec![r#"{"type":"chat"}"#]);
+        assert_eq!(queue.band_len(MessagePriority::Low), 1);
+        flush_to(&mut queue, &mut socket, 599).unwrap();
+        assert_eq!(socket.frames.len(), 1);
+
+        // 500ms without Normal traffic: the Low batch goes
+        flush_to(&mut queue, &mut socket, 600).unwrap();
+        assert_eq!(
+            unbatch(decode(&socket.frames)[1].as_bytes()).unwrap(),
+            vec![r#"{"type":"stats"}"#]
+        );
+
+        // Closing doesn't wait for a lull: held Low is abandoned
+        push(&mut queue, MessagePriority::Normal, r#"{"type":"chat"}"#);
+        push(&mut queue, MessagePriority::Low, r#"{"type":"stats"}"#);
+        let report = flush_and_close(&mut queue, &mut socket, 10_000, || 700).unwrap();
+        assert_eq!(report.abandoned, 1);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_hard_write_error_keeps_messages() {
+        /// Accepts one byte of every frame
+        struct TornSocket;
+        impl Write for TornSocket {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Ok(1)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut queue = PriorityQueue::new();
+        push(&mut queue, MessagePriority::High, r#"{"type":"peer_join"}"#);
+        push(&mut queue, MessagePriority::Low, r#"{"type":"ping"}"#);
+        push(&mut queue, MessagePriority::Low, r#"{"type":"pong"}"#);
+
+        let err = flush_to(&mut queue, &mut TornSocket, 0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+        assert_eq!(queue.len(), 3);
+        queue.pop_entry();
+        // The failed Low batch went back in order too
+        assert!(flush_to(&mut queue, &mut TornSocket, 0).is_err());
+        let mut socket = MemorySocket {
+            frames: Vec::new(),
+            capacity: usize::MAX,
+        };
+        flush_to(&mut queue, &mut socket, 0).unwrap();
+        assert_eq!(
+            unbatch(decode(&socket.frames)[0].as_bytes()).unwrap(),
+            vec![r#"{"type":"ping"}"#, r#"{"type":"pong"}"#]
+        );
+    }
+}
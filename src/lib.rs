@@ -17,11 +17,48 @@
  */
 use worker::*;
 
+mod anomaly;
+mod batcher;
+mod capabilities;
+mod chunker;
+mod clock;
+#[cfg(not(target_arch = "wasm32"))]
+mod compression_bench;
+#[cfg(feature = "critical-log")]
+mod critical_log;
+mod delivery;
+#[cfg(feature = "delta")]
+mod delta;
+mod entropy_gate;
 mod entropy_pool;
+mod error_message;
+mod flush;
 mod message_optimizer;
+#[cfg(feature = "persistent-queue")]
+mod persistent_queue;
+mod pipeline;
+mod priority_queue;
+mod quota;
+mod replay;
+mod room;
+mod shard_ring;
+mod stats;
+#[cfg(feature = "test-relay")]
+mod test_relay;
+#[cfg(all(test, feature = "tracing"))]
+mod trace_recorder;
+mod validate;
 mod vpn_room;
 
+#[cfg(not(target_arch = "wasm32"))]
+pub use compression_bench::{benchmark_compression, BenchResult, CompressionAlgorithm};
 pub use entropy_pool::EntropyPool;
+pub use message_optimizer::{
+    maybe_compress, priority_from_header, process_frame, CompressionKind, DecodedMessage,
+    MessagePriority, OptimizerError, PeerCompressor,
+};
+#[cfg(feature = "test-relay")]
+pub use test_relay::TestRelay;
 pub use vpn_room::VpnRoom;
 
 #[event(fetch)]
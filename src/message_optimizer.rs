@@ -1,6 +1,11 @@
 //! Message priority and optimization utilities for VPN room
 
+use crate::capabilities::Capabilities;
+use crate::room::AuthState;
+use crate::stats::{CodecTimings, PeerStats};
+use crate::validate::ValidationError;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 
 /// Message priority levels for queue management
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -16,129 +21,3329 @@ pub enum MessagePriority {
 }
 
 impl MessagePriority {
+    /// Number of priority levels
+    pub const COUNT: usize = 4;
+
+    /// All levels, highest priority first
+    pub const ALL: [MessagePriority; Self::COUNT] = [
+        MessagePriority::Critical,
+        MessagePriority::High,
+        MessagePriority::Normal,
+        MessagePriority::Low,
+    ];
+
+    /// Zero-based band index (Critical = 0)
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// One level less urgent (Low stays Low)
+    pub fn demoted(self) -> Self {
+        match self {
+            MessagePriority::Critical => MessagePriority::High,
+            MessagePriority::High => MessagePriority::Normal,
+            MessagePriority::Normal | MessagePriority::Low => MessagePriority::Low,
+        }
+    }
+
+    /// One level more urgent (Critical stays Critical)
+    pub fn promoted(self) -> Self {
+        match self {
+            MessagePriority::Critical | MessagePriority::High => MessagePriority::Critical,
+            MessagePriority::Normal => MessagePriority::High,
+            MessagePriority::Low => MessagePriority::Normal,
+        }
+    }
+
     /// Determine priority from message content
     pub fn from_message(msg: &str) -> Self {
-        // Check message type - support both snake_case and PascalCase
-        if msg.contains("\"type\":\"auth\"")
-            || msg.contains("\"type\":\"auth_init\"")
-            || msg.contains("\"type\":\"auth_response\"")
-            || msg.contains("\"type\":\"key_exchange\"")
-            || msg.contains("KeyExchange")
-            || msg.contains("AuthInit")
-            || msg.contains("AuthResponse")
-        {
-            MessagePriority::Critical
-        } else if msg.contains("\"type\":\"entropy\"")
-            || msg.contains("\"type\":\"entropy_commit\"")
-            || msg.contains("\"type\":\"entropy_reveal\"")
-            || msg.contains("\"type\":\"peer_join\"")
-            || msg.contains("\"type\":\"peer_leave\"")
-            || msg.contains("PeerJoined")
-            || msg.contains("PeerLeft")
-        {
-            MessagePriority::High
-        } else if msg.contains("\"type\":\"ping\"")
-            || msg.contains("\"type\":\"pong\"")
-            || msg.contains("Pong")
-        {
-            MessagePriority::Low
-        } else {
-            MessagePriority::Normal
-        }
+        Self::from_message_with_default(msg, MessagePriority::Normal)
+    }
+
+    /// Like `from_message`, but unrecognized message types get `default`
+    /// (e.g. Low, for deployments that treat unknown traffic as suspect)
+    pub fn from_message_with_default(msg: &str, default: MessagePriority) -> Self {
+        Message::parse(msg)
+            .ok()
+            .and_then(|m| m.msg_type.priority())
+            .unwrap_or(default)
+    }
+
+    /// Classify by the top-level `type` value alone, without parsing or
+    /// allocating. Trusts the input to be well-formed: it doesn't validate
+    /// the JSON, and an escaped type name (`"\u0061uth"`) reads as unknown.
+    /// Use `from_message` or `Message::parse` where input must be checked.
+    pub fn peek(msg: &str) -> Self {
+        peek_type(msg)
+            .map(MessageType::from_name)
+            .and_then(MessageType::priority)
+            .unwrap_or(MessagePriority::Normal)
     }
 
     /// Check if message should skip queue (critical)
     pub fn is_critical(&self) -> bool {
         matches!(self, MessagePriority::Critical)
     }
+
+    /// Level named `name` in any case ("high", "High"), None otherwise
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|p| format!("{:?}", p).eq_ignore_ascii_case(name))
+    }
+
+    /// `deserialize_with` for priority hints: a level name, or None for
+    /// anything else, so a bad hint doesn't make the message malformed
+    fn deserialize_hint<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Self>, D::Error> {
+        let value = serde_json::Value::deserialize(d)?;
+        Ok(value.as_str().and_then(Self::from_name))
+    }
+}
+
+/// Message types the relay knows how to prioritize.
+/// Every type accepts both its snake_case and PascalCase spelling, and
+/// `from_name` (used for the `type` field) also ignores case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageType {
+    #[serde(alias = "Auth")]
+    Auth,
+    #[serde(alias = "AuthInit")]
+    AuthInit,
+    #[serde(alias = "AuthResponse")]
+    AuthResponse,
+    #[serde(alias = "KeyExchange")]
+    KeyExchange,
+    #[serde(alias = "Entropy")]
+    Entropy,
+    #[serde(alias = "EntropyCommit")]
+    EntropyCommit,
+    #[serde(alias = "EntropyReveal")]
+    EntropyReveal,
+    #[serde(alias = "PeerJoin", alias = "peer_joined", alias = "PeerJoined")]
+    PeerJoin,
+    #[serde(alias = "PeerLeave", alias = "peer_left", alias = "PeerLeft")]
+    PeerLeave,
+    /// NAT traversal: candidates and offers gate direct P2P paths
+    #[serde(alias = "IceCandidate")]
+    IceCandidate,
+    #[serde(alias = "IceOffer")]
+    IceOffer,
+    #[serde(alias = "Ping")]
+    Ping,
+    #[serde(alias = "Pong")]
+    Pong,
+    /// Observability traffic: never preempts user messages
+    #[serde(alias = "Stats")]
+    Stats,
+    #[serde(alias = "Metrics")]
+    Metrics,
+    #[serde(alias = "Telemetry")]
+    Telemetry,
+    #[serde(alias = "Chat")]
+    Chat,
+    #[serde(alias = "Data")]
+    Data,
+    /// Piece of a `data` message too large for one frame (see `Chunker`)
+    #[serde(alias = "DataChunk")]
+    DataChunk,
+    /// Waitlisted peer let into a full room (see `RoomAdmitted`)
+    #[serde(alias = "RoomAdmitted")]
+    RoomAdmitted,
+    /// Relay error reply (see `ErrorMessage`)
+    #[serde(alias = "Error")]
+    Error,
+    /// Relay notice that queued messages were shed (see `DroppedNotice`)
+    #[serde(alias = "Dropped")]
+    Dropped,
+    /// Room metadata snapshot (see `RoomMeta`)
+    #[serde(alias = "Meta")]
+    Meta,
+    /// Room's shared rate budget is spent (see `RoomRateLimited`)
+    #[serde(alias = "RoomRateLimited")]
+    RoomRateLimited,
+    /// Relay is closing the connection (see `CloseNotice`)
+    #[serde(alias = "Close")]
+    Close,
+    /// Any type not listed above
+    #[serde(other)]
+    Unknown,
+}
+
+impl MessageType {
+    /// Type for a wire name, in any accepted spelling or in any case of
+    /// its snake_case spelling, so `Auth_Init` is `auth_init` (Unknown otherwise)
+    pub fn from_name(name: &str) -> Self {
+        use serde::de::value::{BorrowedStrDeserializer, Error};
+        let lookup = |name: &str| {
+            MessageType::deserialize(BorrowedStrDeserializer::<Error>::new(name))
+                .unwrap_or(MessageType::Unknown)
+        };
+        match lookup(name) {
+            MessageType::Unknown if name.bytes().any(|b| b.is_ascii_uppercase()) => {
+                lookup(&name.to_ascii_lowercase())
+            }
+            found => found,
+        }
+    }
+
+    /// `deserialize_with` for `type` fields: a string, read by `from_name`
+    fn deserialize_name<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let name = std::borrow::Cow::<str>::deserialize(d)?;
+        Ok(MessageType::from_name(&name))
+    }
+
+    /// Authentication and key exchange, the only traffic an
+    /// unauthenticated peer may send at full priority
+    pub fn is_handshake(self) -> bool {
+        matches!(
+            self,
+            MessageType::Auth
+                | MessageType::AuthInit
+                | MessageType::AuthResponse
+                | MessageType::KeyExchange
+        )
+    }
+
+    /// Priority for this type (None for Unknown, leaving the caller's default)
+    pub fn priority(self) -> Option<MessagePriority> {
+        match self {
+            MessageType::Auth
+            | MessageType::AuthInit
+            | MessageType::AuthResponse
+            | MessageType::KeyExchange => Some(MessagePriority::Critical),
+            MessageType::Entropy
+            | MessageType::EntropyCommit
+            | MessageType::EntropyReveal
+            | MessageType::PeerJoin
+            | MessageType::PeerLeave
+            | MessageType::IceCandidate
+            | MessageType::IceOffer
+            | MessageType::RoomAdmitted
+            | MessageType::Error
+            | MessageType::Dropped
+            | MessageType::RoomRateLimited
+            | MessageType::Meta
+            | MessageType::Close => Some(MessagePriority::High),
+            MessageType::Chat | MessageType::Data | MessageType::DataChunk => {
+                Some(MessagePriority::Normal)
+            }
+            MessageType::Ping
+            | MessageType::Pong
+            | MessageType::Stats
+            | MessageType::Metrics
+            | MessageType::Telemetry => Some(MessagePriority::Low),
+            MessageType::Unknown => None,
+        }
+    }
+}
+
+/// Set of message types, one bit per variant
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct MessageTypeSet(u64);
+
+#[allow(dead_code)]
+impl MessageTypeSet {
+    pub const EMPTY: Self = Self(0);
+
+    fn bit(msg_type: MessageType) -> u64 {
+        1 << msg_type as u32
+    }
+
+    pub fn contains(self, msg_type: MessageType) -> bool {
+        self.0 & Self::bit(msg_type) != 0
+    }
+
+    /// Add `msg_type`; returns false if it was already present
+    pub fn insert(&mut self, msg_type: MessageType) -> bool {
+        let absent = !self.contains(msg_type);
+        self.0 |= Self::bit(msg_type);
+        absent
+    }
+
+    /// Remove `msg_type`; returns false if it wasn't present
+    pub fn remove(&mut self, msg_type: MessageType) -> bool {
+        let present = self.contains(msg_type);
+        self.0 &= !Self::bit(msg_type);
+        present
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = 0;
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl FromIterator<MessageType> for MessageTypeSet {
+    fn from_iter<I: IntoIterator<Item = MessageType>>(types: I) -> Self {
+        let mut set = Self::EMPTY;
+        for msg_type in types {
+            set.insert(msg_type);
+        }
+        set
+    }
+}
+
+/// Parsed view of a JSON text message (only the fields the relay inspects)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Message {
+    #[serde(rename = "type", deserialize_with = "MessageType::deserialize_name")]
+    pub msg_type: MessageType,
+    /// Relay ingest sequence number (assigned by SequenceStamper, not sent by clients)
+    #[serde(skip)]
+    pub seq: u64,
+    /// Client override of compression (see `maybe_compress_with_override`)
+    #[serde(default)]
+    pub compress: Option<bool>,
+    /// Features the sender supports, advertised in `auth_init`/`auth_response`
+    #[serde(default)]
+    pub capabilities: Option<Capabilities>,
+    /// Single-use value on handshake messages (see `NonceTracker`)
+    #[serde(default)]
+    pub nonce: Option<String>,
+    /// Client-chosen request id, echoed by replies in `reply_to`
+    #[serde(default)]
+    pub id: Option<serde_json::Value>,
+    /// Peer a message is routed to, for requests and replies between two
+    /// members (see `ReplyCorrelator`)
+    #[serde(default)]
+    pub to: Option<String>,
+    /// `id` of the message this one answers (see `ReplyCorrelator`)
+    #[serde(default)]
+    pub reply_to: Option<serde_json::Value>,
+    /// Older spelling of `reply_to`; read through `replied_id`
+    #[serde(default)]
+    pub in_reply_to: Option<serde_json::Value>,
+    /// Handshake or thread the message belongs to (see `SessionPriority`)
+    #[serde(default)]
+    pub session_id: Option<String>,
+    /// Sender's explicit priority, honored only from trusted senders (see
+    /// `RoomPolicy::classify_from`)
+    #[serde(default, deserialize_with = "MessagePriority::deserialize_hint")]
+    pub priority: Option<MessagePriority>,
+    /// Byte length of the raw JSON text (set by `parse`)
+    #[serde(skip)]
+    pub len: usize,
+    /// The whole JSON object (set by `parse`), for fields this struct
+    /// doesn't model (see `Validator`)
+    #[serde(skip)]
+    pub fields: std::sync::Arc<serde_json::Map<String, serde_json::Value>>,
+}
+
+impl Message {
+    /// Parse a JSON text message; fails if it isn't an object with a `type`
+    pub fn parse(msg: &str) -> Result<Self, OptimizerError> {
+        let fields =
+            serde_json::from_str(msg).map_err(|e| OptimizerError::Malformed(e.to_string()))?;
+        Self::from_fields(fields, msg.len())
+    }
+
+    /// `parse`, for an object already parsed from `len` bytes of JSON
+    pub fn from_fields(
+        fields: serde_json::Map<String, serde_json::Value>,
+        len: usize,
+    ) -> Result<Self, OptimizerError> {
+        let mut parsed =
+            Self::deserialize(&fields).map_err(|e| OptimizerError::Malformed(e.to_string()))?;
+        parsed.len = len;
+        parsed.fields = std::sync::Arc::new(fields);
+        Ok(parsed)
+    }
+
+    /// The id this message answers: `reply_to`, else `in_reply_to`
+    pub fn replied_id(&self) -> Option<&serde_json::Value> {
+        self.reply_to.as_ref().or(self.in_reply_to.as_ref())
+    }
+}
+
+/// Raw value of the top-level `"type"` key, skipping nested objects and
+/// string contents. Escapes are skipped over, not decoded.
+fn peek_type(msg: &str) -> Option<&str> {
+    let bytes = msg.as_bytes();
+    let mut depth = 0usize;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => {
+                let start = i + 1;
+                i = start;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                let key = bytes.get(start..i)?;
+                i += 1;
+                if depth != 1 || key != b"type" {
+                    continue;
+                }
+                // A value that happens to read "type" isn't followed by ':'
+                let Some(rest) = msg.get(i..)?.trim_start().strip_prefix(':') else {
+                    continue;
+                };
+                let value = rest.trim_start().strip_prefix('"')?;
+                return value.find('"').map(|end| &value[..end]);
+            }
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Hands out monotonically increasing ingest sequence numbers.
+///
+/// Shared across a relay so every ingested message gets a unique `seq`;
+/// sender and receiver logs can be correlated by it, and ingest order can
+/// be reconstructed after priority scheduling has reordered delivery.
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+pub struct SequenceStamper {
+    next: std::sync::atomic::AtomicU64,
+}
+
+#[allow(dead_code)]
+impl SequenceStamper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Next sequence number (starting at 1)
+    pub fn next_seq(&self) -> u64 {
+        self.next
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            .wrapping_add(1)
+    }
+
+    /// Assign the next sequence number to a parsed message
+    pub fn stamp(&self, msg: &mut Message) -> u64 {
+        msg.seq = self.next_seq();
+        msg.seq
+    }
+}
+
+/// Lets replies inherit the priority of the request they answer, so an
+/// `auth_response` mislabelled by its sender can't stall a handshake.
+///
+/// Remembers the last `capacity` requests that were classified
+/// Critical/High on their own and routed to one peer (`to`), keyed by
+/// room, requester and `id`. A message inherits at least the request's
+/// priority only if the addressed peer sends it, in the same room, back
+/// to the requester, naming the id in `reply_to` (or `in_reply_to`).
+/// Each request is answered once. Ids are compared as JSON, so `1` and
+/// `"1"` differ.
+///
+/// Inheritance happens before the sender's auth ceiling
+/// (`AuthState::cap`), so an unauthenticated peer can't reply its way
+/// into the fast lane.
+#[allow(dead_code)]
+pub struct ReplyCorrelator {
+    capacity: usize,
+    requests: HashMap<RequestKey, PendingRequest>,
+    order: VecDeque<RequestKey>,
+}
+
+/// Room, requester and request id (as JSON)
+type RequestKey = (String, String, String);
+
+struct PendingRequest {
+    addressee: String,
+    priority: MessagePriority,
+}
+
+#[allow(dead_code)]
+impl ReplyCorrelator {
+    /// Default number of request ids remembered
+    pub const DEFAULT_CAPACITY: usize = 256;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            requests: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Final priority for `msg` from `from` (in state `auth`) in `room`,
+    /// classified as `priority`: raised to its request's priority if it's
+    /// the addressed peer's reply, then capped for `auth`. Remembered if
+    /// it's a Critical/High request to one peer.
+    pub fn correlate(
+        &mut self,
+        room: &str,
+        from: &str,
+        auth: AuthState,
+        msg: &Message,
+        priority: MessagePriority,
+    ) -> MessagePriority {
+        let mut inherited = priority;
+        if let (Some(id), Some(requester)) = (msg.replied_id(), &msg.to) {
+            let key = (room.to_string(), requester.clone(), id.to_string());
+            if self
+                .requests
+                .get(&key)
+                .is_some_and(|request| request.addressee == from)
+            {
+                if let Some(request) = self.requests.remove(&key) {
+                    self.order.retain(|k| *k != key);
+                    inherited = priority.min(request.priority);
+                }
+            }
+        }
+
+        if let (Some(id), Some(addressee)) = (&msg.id, &msg.to) {
+            if priority <= MessagePriority::High {
+                let key = (room.to_string(), from.to_string(), id.to_string());
+                self.remember(
+                    key,
+                    PendingRequest {
+                        addressee: addressee.clone(),
+                        priority,
+                    },
+                );
+            }
+        }
+        auth.cap(msg, inherited)
+    }
+
+    fn remember(&mut self, key: RequestKey, request: PendingRequest) {
+        if self.requests.insert(key.clone(), request).is_some() {
+            return;
+        }
+        self.order.push_back(key);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.requests.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+impl Default for ReplyCorrelator {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+/// Sticky priority for sessions, so one handshake's messages aren't
+/// interleaved with bulk traffic and stalled behind it.
+///
+/// Once a session (by sender and `session_id`) has produced a Critical
+/// message, its sender's later messages in it are scheduled at least
+/// High. Another peer reusing the id doesn't share the elevation. That
+/// lasts until `complete` is called, or until the session has been
+/// silent for `timeout_ms`. At most `capacity` sessions are tracked:
+/// expired sessions are evicted first, then the least recently active.
+#[allow(dead_code)]
+pub struct SessionPriority {
+    capacity: usize,
+    timeout_ms: u64,
+    /// Elevated (peer, session) pairs and when each last sent a message
+    last_seen: HashMap<(String, String), u64>,
+}
+
+#[allow(dead_code)]
+impl SessionPriority {
+    /// Default number of elevated sessions tracked
+    pub const DEFAULT_CAPACITY: usize = 1024;
+    /// Default idle time after which a session loses its elevation
+    pub const DEFAULT_TIMEOUT_MS: u64 = 30_000;
+
+    pub fn new(capacity: usize, timeout_ms: u64) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            timeout_ms,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    /// Priority for a message from `peer_id` in `session_id`, classified
+    /// as `base`
+    pub fn priority_for(
+        &mut self,
+        peer_id: &str,
+        session_id: &str,
+        base: MessagePriority,
+        now_ms: u64,
+    ) -> MessagePriority {
+        let key = (peer_id.to_string(), session_id.to_string());
+        let timeout_ms = self.timeout_ms;
+        let live = |last: u64| now_ms.saturating_sub(last) <= timeout_ms;
+        match self.last_seen.get_mut(&key) {
+            Some(last) if live(*last) => {
+                *last = now_ms;
+                base.min(MessagePriority::High)
+            }
+            Some(_) => {
+                self.last_seen.remove(&key);
+                self.priority_for(peer_id, session_id, base, now_ms)
+            }
+            None => {
+                if base.is_critical() {
+                    self.evict(now_ms);
+                    self.last_seen.insert(key, now_ms);
+                }
+                base
+            }
+        }
+    }
+
+    /// `priority_for` using the message's `session_id` (messages without
+    /// one keep `base`)
+    pub fn classify(
+        &mut self,
+        peer_id: &str,
+        msg: &Message,
+        base: MessagePriority,
+        now_ms: u64,
+    ) -> MessagePriority {
+        match &msg.session_id {
+            Some(id) => self.priority_for(peer_id, id, base, now_ms),
+            None => base,
+        }
+    }
+
+    /// End `peer_id`'s elevation in a session; returns false if it wasn't
+    /// elevated
+    pub fn complete(&mut self, peer_id: &str, session_id: &str) -> bool {
+        self.last_seen
+            .remove(&(peer_id.to_string(), session_id.to_string()))
+            .is_some()
+    }
+
+    /// Make room for one more session
+    fn evict(&mut self, now_ms: u64) {
+        if self.last_seen.len() < self.capacity {
+            return;
+        }
+        let timeout_ms = self.timeout_ms;
+        self.last_seen
+            .retain(|_, last| now_ms.saturating_sub(*last) <= timeout_ms);
+        if self.last_seen.len() >= self.capacity {
+            let oldest = self
+                .last_seen
+                .iter()
+                .min_by_key(|(_, last)| **last)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                self.last_seen.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.last_seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last_seen.is_empty()
+    }
+}
+
+impl Default for SessionPriority {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY, Self::DEFAULT_TIMEOUT_MS)
+    }
 }
 
+/// Messages below this size are sent uncompressed
+const COMPRESSION_THRESHOLD: usize = 1024; // 1KB
+
 /// Compress message if it's large enough to benefit
 #[allow(dead_code)]
 pub fn maybe_compress(msg: &str) -> (Vec<u8>, bool) {
-    const COMPRESSION_THRESHOLD: usize = 1024; // 1KB
-
     if msg.len() < COMPRESSION_THRESHOLD {
         // Too small, don't compress
         (msg.as_bytes().to_vec(), false)
     } else {
-        // Try compression
-        use flate2::write::GzEncoder;
-        use flate2::Compression;
-        use std::io::Write;
-
-        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
-        if encoder.write_all(msg.as_bytes()).is_ok() {
-            if let Ok(compressed) = encoder.finish() {
-                // Only use if actually smaller
-                if compressed.len() < msg.len() {
-                    return (compressed, true);
-                }
-            }
+        match gzip_fast(msg) {
+            // Only use if actually smaller
+            Some(compressed) if compressed.len() < msg.len() => (compressed, true),
+            // Compression failed or not beneficial
+            _ => (msg.as_bytes().to_vec(), false),
         }
+    }
+}
+
+fn gzip_fast(msg: &str) -> Option<Vec<u8>> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(msg.as_bytes()).ok()?;
+    encoder.finish().ok()
+}
+
+/// Whether a message of this priority and size is worth compressing.
+/// Critical traffic (auth/key exchange) is latency-sensitive and small, so
+/// it is never compressed regardless of size.
+#[allow(dead_code)]
+pub fn should_compress(priority: MessagePriority, len: usize) -> bool {
+    should_compress_unless(priority, len, MessagePriority::Critical)
+}
+
+/// Like `should_compress`, but skips every priority at or above `skip`
+/// (e.g. `MessagePriority::High` to also send High traffic uncompressed)
+pub fn should_compress_unless(
+    priority: MessagePriority,
+    len: usize,
+    skip: MessagePriority,
+) -> bool {
+    priority > skip && len >= COMPRESSION_THRESHOLD
+}
 
-        // Compression failed or not beneficial
+/// Priority-aware `maybe_compress`: Critical messages are passed through
+#[allow(dead_code)]
+pub fn maybe_compress_for(msg: &str, priority: MessagePriority) -> (Vec<u8>, bool) {
+    if should_compress(priority, msg.len()) {
+        maybe_compress(msg)
+    } else {
         (msg.as_bytes().to_vec(), false)
     }
 }
 
+/// `maybe_compress_for`, unless the message carries a `"compress"` flag.
+///
+/// The flag takes precedence over both the size threshold and
+/// priority-based skipping: `Some(false)` always sends the message as-is
+/// (payloads the client already compressed or encrypted), `Some(true)`
+/// always compresses it, even when small or Critical. `None` applies the
+/// normal rules.
+#[allow(dead_code)]
+pub fn maybe_compress_with_override(
+    msg: &str,
+    priority: MessagePriority,
+    compress: Option<bool>,
+) -> (Vec<u8>, bool) {
+    match compress {
+        Some(false) => (msg.as_bytes().to_vec(), false),
+        Some(true) => match gzip_fast(msg) {
+            Some(compressed) => (compressed, true),
+            None => (msg.as_bytes().to_vec(), false),
+        },
+        None => maybe_compress_for(msg, priority),
+    }
+}
+
+/// Decides whether (and with which codec) a message gets compressed.
+///
+/// Policies only answer the "whether"; the compressor still does the
+/// "how", and still sends a payload raw if the codec wouldn't shrink it.
+pub trait CompressionPolicy {
+    /// Codec for this message, or None to send it uncompressed
+    fn should_compress(&self, msg: &Message) -> Option<CompressionKind>;
+}
+
+/// A boxed policy, for holders that pick one at runtime
+impl<P: CompressionPolicy + ?Sized> CompressionPolicy for Box<P> {
+    fn should_compress(&self, msg: &Message) -> Option<CompressionKind> {
+        (**self).should_compress(msg)
+    }
+}
+
+/// Default policy: gzip anything at or above a size threshold, as
+/// `maybe_compress_with_override` does. The message's `compress` flag
+/// wins, then Critical types and `no_compress_types` are sent raw.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeThresholdPolicy {
+    pub threshold: usize,
+    /// Types sent raw whatever their size: keepalives are tiny and
+    /// carry nothing deflate can use, so trying is pure overhead
+    pub no_compress_types: MessageTypeSet,
+}
+
+#[allow(dead_code)]
+impl SizeThresholdPolicy {
+    /// Types `no_compress_types` holds by default
+    pub const DEFAULT_NO_COMPRESS_TYPES: [MessageType; 2] = [MessageType::Ping, MessageType::Pong];
+
+    /// Threshold `threshold`, default `no_compress_types`
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            no_compress_types: Self::DEFAULT_NO_COMPRESS_TYPES.into_iter().collect(),
+        }
+    }
+}
+
+impl Default for SizeThresholdPolicy {
+    fn default() -> Self {
+        Self::new(COMPRESSION_THRESHOLD)
+    }
+}
+
+impl CompressionPolicy for SizeThresholdPolicy {
+    fn should_compress(&self, msg: &Message) -> Option<CompressionKind> {
+        if let Some(compress) = msg.compress {
+            return compress.then_some(CompressionKind::Gzip);
+        }
+        if msg.msg_type.priority() == Some(MessagePriority::Critical)
+            || self.no_compress_types.contains(msg.msg_type)
+        {
+            return None;
+        }
+        (msg.len >= self.threshold).then_some(CompressionKind::Gzip)
+    }
+}
+
 /// Decompress message if it was compressed
 #[allow(dead_code)]
 pub fn maybe_decompress(data: &[u8], was_compressed: bool) -> Result<String, String> {
     if !was_compressed {
-        String::from_utf8(data.to_vec()).map_err(|e| format!("UTF-8 decode error: {}", e))
+        // Validate the borrowed bytes before allocating
+        std::str::from_utf8(data)
+            .map(str::to_owned)
+            .map_err(|e| format!("UTF-8 decode error: {}", e))
+    } else if data.starts_with(&ZSTD_MAGIC) {
+        Err(OptimizerError::Unsupported("zstd").to_string())
     } else {
-        use flate2::read::GzDecoder;
+        use flate2::read::MultiGzDecoder;
         use std::io::Read;
 
-        let mut decoder = GzDecoder::new(data);
-        let mut decompressed = String::new();
+        // Every member of a concatenated stream (RFC 1952 2.2), not just the first
+        let mut decoder = MultiGzDecoder::new(data);
+        let mut decompressed = Vec::new();
         decoder
-            .read_to_string(&mut decompressed)
+            .read_to_end(&mut decompressed)
             .map_err(|e| format!("Decompression error: {}", e))?;
-        Ok(decompressed)
+        String::from_utf8(decompressed)
+            .map_err(|e| format!("Decompressed payload is not UTF-8: {}", e))
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Codec a payload is encoded with, carried in the frame marker byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CompressionKind {
+    /// Sent as-is
+    None,
+    /// gzip member (what `maybe_compress` produces)
+    Gzip,
+    /// Raw deflate stream, without gzip's 18 bytes of framing
+    Deflate,
+}
 
-    #[test]
-    fn test_priority_detection() {
-        assert_eq!(
-            MessagePriority::from_message(r#"{"type":"auth_init"}"#),
-            MessagePriority::Critical
-        );
+impl CompressionKind {
+    pub fn marker(self) -> u8 {
+        match self {
+            CompressionKind::None => MARKER_RAW,
+            CompressionKind::Gzip => MARKER_GZIP,
+            CompressionKind::Deflate => MARKER_DEFLATE,
+        }
+    }
 
-        assert_eq!(
-            MessagePriority::from_message(r#"{"type":"entropy_commit"}"#),
-            MessagePriority::High
-        );
+    pub fn from_marker(marker: u8) -> Result<Self, OptimizerError> {
+        match marker {
+            MARKER_RAW => Ok(CompressionKind::None),
+            MARKER_GZIP => Ok(CompressionKind::Gzip),
+            MARKER_DEFLATE => Ok(CompressionKind::Deflate),
+            MARKER_ZSTD => Err(OptimizerError::Unsupported("zstd")),
+            MARKER_BROTLI => Err(OptimizerError::Unsupported("brotli")),
+            m => Err(OptimizerError::UnknownMarker(m)),
+        }
+    }
+}
 
-        assert_eq!(
-            MessagePriority::from_message(r#"{"type":"ping"}"#),
-            MessagePriority::Low
-        );
+/// Output of a compression attempt
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressResult {
+    pub data: Vec<u8>,
+    pub kind: CompressionKind,
+    /// Size of the message before compression
+    pub original_len: usize,
+}
 
-        assert_eq!(
-            MessagePriority::from_message(r#"{"type":"chat","msg":"hello"}"#),
-            MessagePriority::Normal
-        );
+#[allow(dead_code)]
+impl CompressResult {
+    pub fn is_compressed(&self) -> bool {
+        self.kind != CompressionKind::None
     }
+}
 
-    #[test]
-    fn test_compression_threshold() {
-        // Small message - should not compress
-        let small = "hello";
-        let (data, compressed) = maybe_compress(small);
-        assert!(!compressed);
-        assert_eq!(data, small.as_bytes());
+/// Anything that turns an outbound message into a frame payload
+pub trait Compressor {
+    fn compress(&mut self, msg: &str) -> CompressResult;
+}
 
-        // Large message - should compress
-        let large = "x".repeat(2000);
-        let (data, compressed) = maybe_compress(&large);
-        assert!(compressed);
-        assert!(data.len() < large.len());
+/// A compressor shared between threads: each message holds the lock for
+/// its whole encode, so reused contexts never interleave
+impl<C: Compressor> Compressor for &std::sync::Mutex<C> {
+    fn compress(&mut self, msg: &str) -> CompressResult {
+        self.lock().unwrap_or_else(|e| e.into_inner()).compress(msg)
+    }
+}
+
+/// gzip member header as `GzEncoder` writes it (XFL byte filled per level)
+const GZIP_HEADER: [u8; 10] = [0x1f, 0x8b, 0x08, 0x00, 0, 0, 0, 0, 0x00, GZIP_OS_UNKNOWN];
+
+/// gzip XFL header byte for a deflate level (2 = max compression, 4 = fastest)
+fn gzip_xfl(level: u32) -> u8 {
+    match level {
+        9.. => 2,
+        0 | 1 => 4,
+        _ => 0,
+    }
+}
+
+/// gzip OS header byte for "unknown", independent of the build platform
+const GZIP_OS_UNKNOWN: u8 = 0xff;
+
+/// How a compressor is configured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionProfile {
+    /// Deflate level (1-9)
+    pub level: u32,
+    /// Identical input always yields identical bytes: the gzip mtime is
+    /// zero, the OS byte is "unknown" and the level is pinned, so
+    /// `AdaptiveLevel` (which reacts to CPU timing) can't change it
+    pub deterministic: bool,
+}
+
+#[allow(dead_code)]
+impl CompressionProfile {
+    /// Pinned profile for conformance test vectors
+    pub fn deterministic(level: u32) -> Self {
+        Self {
+            level: level.clamp(1, 9),
+            deterministic: true,
+        }
+    }
+}
+
+impl Default for CompressionProfile {
+    fn default() -> Self {
+        Self {
+            level: flate2::Compression::fast().level(),
+            deterministic: false,
+        }
+    }
+}
+
+/// `maybe_compress` at the profile's level. The gzip header never carries
+/// a timestamp or the build OS, so deterministic profiles are reproducible
+/// across runs and platforms.
+#[allow(dead_code)]
+pub fn maybe_compress_with_profile(msg: &str, profile: &CompressionProfile) -> (Vec<u8>, bool) {
+    use std::io::Write;
+
+    if msg.len() < COMPRESSION_THRESHOLD {
+        return (msg.as_bytes().to_vec(), false);
+    }
+    let mut encoder = flate2::GzBuilder::new()
+        .mtime(0)
+        .operating_system(GZIP_OS_UNKNOWN)
+        .write(
+            Vec::new(),
+            flate2::Compression::new(profile.level.clamp(1, 9)),
+        );
+    match encoder
+        .write_all(msg.as_bytes())
+        .and_then(|_| encoder.finish())
+    {
+        Ok(compressed) if compressed.len() < msg.len() => (compressed, true),
+        _ => (msg.as_bytes().to_vec(), false),
+    }
+}
+
+/// Per-peer compressor that reuses its deflate state across messages.
+///
+/// `maybe_compress` allocates a fresh encoder for every call; a peer with a
+/// steady message stream pays that setup cost once here instead. Output is
+/// a standard gzip member, so `maybe_decompress` reads it unchanged.
+/// (zstd isn't available on the Workers target, so this is gzip-only.)
+///
+/// Every message it handles is counted in its `PeerStats`, which is what
+/// egress accounting reads. Time spent compressing large messages is
+/// added to its `CodecTimings`.
+///
+/// The context is reset at the start of every message, so nothing from
+/// one message can leak into the next. It is `Send` but not shareable;
+/// a peer serviced from several threads goes through `&Mutex<PeerCompressor>`.
+#[allow(dead_code)]
+pub struct PeerCompressor {
+    deflate: flate2::Compress,
+    level: u32,
+    /// Deterministic profile: `set_level` is ignored
+    pinned: bool,
+    stats: PeerStats,
+    timings: CodecTimings,
+}
+
+#[allow(dead_code)]
+impl PeerCompressor {
+    pub fn new() -> Self {
+        Self::with_level(flate2::Compression::fast().level())
+    }
+
+    /// Compressor at an explicit deflate level (1-9)
+    pub fn with_level(level: u32) -> Self {
+        let level = level.clamp(1, 9);
+        Self {
+            deflate: flate2::Compress::new(flate2::Compression::new(level), false),
+            level,
+            pinned: false,
+            stats: PeerStats::default(),
+            timings: CodecTimings::default(),
+        }
+    }
+
+    pub fn with_profile(profile: CompressionProfile) -> Self {
+        Self {
+            pinned: profile.deterministic,
+            ..Self::with_level(profile.level)
+        }
+    }
+
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    /// Switch deflate level (e.g. from `AdaptiveLevel`).
+    /// A level change rebuilds the context; keeping the same level is free.
+    /// A deterministic profile keeps its level.
+    pub fn set_level(&mut self, level: u32) {
+        let level = level.clamp(1, 9);
+        if level != self.level && !self.pinned {
+            self.deflate = flate2::Compress::new(flate2::Compression::new(level), false);
+            self.level = level;
+        }
+    }
+
+    /// Bytes handled so far
+    pub fn stats(&self) -> &PeerStats {
+        &self.stats
+    }
+
+    /// CPU time spent per codec so far
+    pub fn timings(&self) -> &CodecTimings {
+        &self.timings
+    }
+
+    /// Compress a message with the same threshold rules as `maybe_compress`
+    pub fn compress(&mut self, msg: &str) -> CompressResult {
+        let result = self.compress_uncounted(msg);
+        self.stats.record(&result);
+        result
+    }
+
+    /// `compress` with `kind` instead of gzip (still raw below the
+    /// threshold or when it wouldn't shrink)
+    pub fn compress_as(&mut self, msg: &str, kind: CompressionKind) -> CompressResult {
+        let result = self.encode(msg, kind);
+        self.stats.record(&result);
+        result
+    }
+
+    fn compress_uncounted(&mut self, msg: &str) -> CompressResult {
+        self.encode(msg, CompressionKind::Gzip)
+    }
+
+    /// Compress with the codec `policy` picks instead of the size threshold.
+    /// Text that doesn't parse as a message falls back to `compress`.
+    pub fn compress_with_policy(
+        &mut self,
+        msg: &str,
+        policy: &impl CompressionPolicy,
+    ) -> CompressResult {
+        let Ok(parsed) = Message::parse(msg) else {
+            return self.compress(msg);
+        };
+        let kind = policy
+            .should_compress(&parsed)
+            .unwrap_or(CompressionKind::None);
+        self.compress_exact(msg, kind)
+    }
+
+    /// Encode with `kind` whatever the size, for callers that already
+    /// picked the codec (still raw if it wouldn't shrink)
+    pub fn compress_exact(&mut self, msg: &str, kind: CompressionKind) -> CompressResult {
+        let result = self.encode_as(msg, kind);
+        self.stats.record(&result);
+        result
+    }
+
+    /// Encode with a specific codec, falling back to raw when the message
+    /// is below the threshold or wouldn't shrink
+    fn encode(&mut self, msg: &str, kind: CompressionKind) -> CompressResult {
+        if msg.len() < COMPRESSION_THRESHOLD {
+            return self.encode_as(msg, CompressionKind::None);
+        }
+        self.encode_as(msg, kind)
+    }
+
+    /// Encode with a specific codec regardless of size, falling back to
+    /// raw if it wouldn't shrink
+    fn encode_as(&mut self, msg: &str, kind: CompressionKind) -> CompressResult {
+        let raw = || CompressResult {
+            data: msg.as_bytes().to_vec(),
+            kind: CompressionKind::None,
+            original_len: msg.len(),
+        };
+
+        if kind == CompressionKind::None {
+            return raw();
+        }
+        // Moved out so the closure can borrow the deflate context
+        let mut timings = std::mem::take(&mut self.timings);
+        let data = timings.time_compress(kind, msg.len(), || match kind {
+            CompressionKind::Gzip => self.gzip(msg.as_bytes()),
+            _ => {
+                let mut out = Vec::with_capacity(msg.len() / 2);
+                self.deflate_into(msg.as_bytes(), &mut out).then_some(out)
+            }
+        });
+        self.timings = timings;
+        let result = match data {
+            Some(data) if data.len() < msg.len() => CompressResult {
+                data,
+                kind,
+                original_len: msg.len(),
+            },
+            _ => raw(),
+        };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            kind = ?result.kind,
+            bytes = result.original_len,
+            wire_bytes = result.data.len(),
+            "message compressed"
+        );
+        result
+    }
+
+    fn gzip(&mut self, input: &[u8]) -> Option<Vec<u8>> {
+        let mut out = Vec::with_capacity(input.len() / 2 + GZIP_HEADER.len() + 8);
+        out.extend_from_slice(&GZIP_HEADER);
+        out[8] = gzip_xfl(self.level);
+
+        if !self.deflate_into(input, &mut out) {
+            return None;
+        }
+
+        // gzip trailer: CRC32 and input size (mod 2^32), both LE
+        out.extend_from_slice(&crc32(input).to_le_bytes());
+        out.extend_from_slice(&(input.len() as u32).to_le_bytes());
+        Some(out)
+    }
+
+    /// Append the raw deflate stream for `input` to `out`
+    fn deflate_into(&mut self, input: &[u8], out: &mut Vec<u8>) -> bool {
+        use flate2::{FlushCompress, Status};
+
+        // Reset instead of reallocating the deflate window/hash tables
+        self.deflate.reset();
+
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(input.len() / 4 + 64);
+            }
+            let consumed = self.deflate.total_in() as usize;
+            match self
+                .deflate
+                .compress_vec(&input[consumed..], out, FlushCompress::Finish)
+            {
+                Ok(Status::StreamEnd) => return true,
+                Ok(_) => continue,
+                Err(_) => return false,
+            }
+        }
+    }
+}
+
+impl Default for PeerCompressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compressor for PeerCompressor {
+    fn compress(&mut self, msg: &str) -> CompressResult {
+        PeerCompressor::compress(self, msg)
+    }
+}
+
+/// A trained preset dictionary and the id frames refer to it by
+#[derive(Debug, PartialEq, Eq)]
+pub struct Dictionary {
+    pub id: u32,
+    pub bytes: Vec<u8>,
+}
+
+/// Hot-swappable compression dictionary shared by a relay's compressors.
+///
+/// A background task deploys a newly trained dictionary with
+/// `update_dictionary`; compressions already running keep the one they
+/// started with. The last few dictionaries stay available so frames
+/// compressed just before a swap still decode.
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+pub struct SharedDictionary {
+    /// Oldest first; the back is current
+    versions: std::sync::RwLock<VecDeque<std::sync::Arc<Dictionary>>>,
+}
+
+#[allow(dead_code)]
+impl SharedDictionary {
+    /// Dictionaries kept for decoding, including the current one
+    pub const RETAINED: usize = 4;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make `new_dict` current, returning its id (ids start at 1)
+    pub fn update_dictionary(&self, new_dict: Vec<u8>) -> u32 {
+        let mut versions = self.versions.write().unwrap_or_else(|e| e.into_inner());
+        let id = versions.back().map_or(1, |d| d.id.wrapping_add(1).max(1));
+        if versions.len() == Self::RETAINED {
+            versions.pop_front();
+        }
+        versions.push_back(std::sync::Arc::new(Dictionary {
+            id,
+            bytes: new_dict,
+        }));
+        id
+    }
+
+    /// Dictionary new compressions should use
+    pub fn current(&self) -> Option<std::sync::Arc<Dictionary>> {
+        let versions = self.versions.read().unwrap_or_else(|e| e.into_inner());
+        versions.back().cloned()
+    }
+
+    /// A retained dictionary by id, for decoding
+    pub fn get(&self, id: u32) -> Option<std::sync::Arc<Dictionary>> {
+        let versions = self.versions.read().unwrap_or_else(|e| e.into_inner());
+        versions.iter().find(|d| d.id == id).cloned()
+    }
+}
+
+/// Raw-deflate compressor primed with the current shared dictionary.
+///
+/// Small, repetitive JSON is where a dictionary pays off, so unlike
+/// `PeerCompressor` there's no size threshold; a message is still sent
+/// raw if it wouldn't shrink.
+#[allow(dead_code)]
+pub struct DictionaryCompressor {
+    deflate: flate2::Compress,
+    dictionary: std::sync::Arc<SharedDictionary>,
+}
+
+#[allow(dead_code)]
+impl DictionaryCompressor {
+    pub fn new(dictionary: std::sync::Arc<SharedDictionary>) -> Self {
+        Self {
+            deflate: flate2::Compress::new(flate2::Compression::fast(), false),
+            dictionary,
+        }
+    }
+
+    /// Frame a message, recording the dictionary id in the header
+    pub fn compress(&mut self, msg: &str) -> Frame {
+        use flate2::{FlushCompress, Status};
+
+        let Some(dict) = self.dictionary.current() else {
+            return Frame::uncompressed(msg.as_bytes().to_vec());
+        };
+
+        self.deflate.reset();
+        if self.deflate.set_dictionary(&dict.bytes).is_err() {
+            return Frame::uncompressed(msg.as_bytes().to_vec());
+        }
+        let input = msg.as_bytes();
+        let mut out = Vec::with_capacity(input.len() / 2 + 64);
+        loop {
+            if out.len() == out.capacity() {
+                out.reserve(input.len() / 4 + 64);
+            }
+            let consumed = self.deflate.total_in() as usize;
+            match self
+                .deflate
+                .compress_vec(&input[consumed..], &mut out, FlushCompress::Finish)
+            {
+                Ok(Status::StreamEnd) => break,
+                Ok(_) => continue,
+                Err(_) => return Frame::uncompressed(input.to_vec()),
+            }
+        }
+
+        if out.len() < input.len() {
+            Frame::with_kind(CompressionKind::Deflate, out).with_dictionary(dict.id)
+        } else {
+            Frame::uncompressed(input.to_vec())
+        }
+    }
+}
+
+/// Payloads at least this large get gzip instead of raw deflate
+const LARGE_PAYLOAD: usize = 16 * 1024;
+
+/// What the codec policy looks at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadProfile {
+    pub len: usize,
+    /// Mostly letters and spaces (prose rather than keys, hex or base64)
+    pub text_heavy: bool,
+}
+
+impl PayloadProfile {
+    /// Bytes sampled from the start of a message to judge `text_heavy`
+    const SAMPLE: usize = 512;
+
+    pub fn of(msg: &str) -> Self {
+        let sample = &msg.as_bytes()[..msg.len().min(Self::SAMPLE)];
+        let letters = sample.iter().filter(|b| b.is_ascii_alphabetic()).count();
+        let spaces = sample.iter().filter(|&&b| b == b' ').count();
+        Self {
+            len: msg.len(),
+            // Prose runs ~15% spaces; base64 and hex have none
+            text_heavy: !sample.is_empty()
+                && (letters + spaces) * 10 >= sample.len() * 8
+                && spaces * 10 >= sample.len(),
+        }
+    }
+}
+
+/// Codec and deflate level picked for a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecChoice {
+    pub kind: CompressionKind,
+    pub level: u32,
+}
+
+/// Pick a codec from the payload profile alone, without compressing.
+///
+/// Small messages stay raw; medium ones use raw deflate, whose lack of
+/// framing matters most at that size; large ones use gzip, at the best
+/// level when they're prose. (zstd and brotli would be the natural picks
+/// here but aren't available on the Workers target.)
+pub fn select_codec(profile: &PayloadProfile) -> CodecChoice {
+    let fast = flate2::Compression::fast().level();
+    let (kind, level) = if profile.len < COMPRESSION_THRESHOLD {
+        (CompressionKind::None, fast)
+    } else if profile.len < LARGE_PAYLOAD {
+        (CompressionKind::Deflate, fast)
+    } else if profile.text_heavy {
+        (CompressionKind::Gzip, flate2::Compression::best().level())
+    } else {
+        (CompressionKind::Gzip, fast)
+    };
+    CodecChoice { kind, level }
+}
+
+/// Compressor that picks a codec per message via `select_codec`.
+/// Keeps one deflate context per level so switching codecs is free.
+#[allow(dead_code)]
+pub struct AutoCompressor {
+    fast: PeerCompressor,
+    best: PeerCompressor,
+    stats: PeerStats,
+}
+
+#[allow(dead_code)]
+impl AutoCompressor {
+    pub fn new() -> Self {
+        Self {
+            fast: PeerCompressor::new(),
+            best: PeerCompressor::with_level(flate2::Compression::best().level()),
+            stats: PeerStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &PeerStats {
+        &self.stats
+    }
+}
+
+impl Default for AutoCompressor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Compressor for AutoCompressor {
+    fn compress(&mut self, msg: &str) -> CompressResult {
+        let choice = select_codec(&PayloadProfile::of(msg));
+        let compressor = if choice.level == self.best.level() {
+            &mut self.best
+        } else {
+            &mut self.fast
+        };
+        let result = compressor.encode(msg, choice.kind);
+        self.stats.record(&result);
+        result
+    }
+}
+
+/// Deflate level controller tuned from observed compression results.
+///
+/// Every `window` samples it compares the achieved ratio (compressed /
+/// original bytes) against `target_ratio`: traffic compressing better than
+/// the target earns a higher level, as long as the CPU cost per KiB stays
+/// within `cpu_budget_us_per_kb`; traffic that barely compresses (or blows
+/// the budget) steps the level down, since extra effort is wasted on it.
+#[allow(dead_code)]
+pub struct AdaptiveLevel {
+    level: u32,
+    target_ratio: f64,
+    cpu_budget_us_per_kb: f64,
+    window: u32,
+    samples: u32,
+    bytes_in: u64,
+    bytes_out: u64,
+    elapsed_us: u64,
+}
+
+#[allow(dead_code)]
+impl AdaptiveLevel {
+    pub const MIN_LEVEL: u32 = 1;
+    pub const MAX_LEVEL: u32 = 9;
+
+    pub fn new(target_ratio: f64, cpu_budget_us_per_kb: f64, window: u32) -> Self {
+        Self {
+            level: flate2::Compression::fast().level(),
+            target_ratio,
+            cpu_budget_us_per_kb,
+            window: window.max(1),
+            samples: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            elapsed_us: 0,
+        }
+    }
+
+    /// Current level for the compress path
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    /// Record one compression: input/output sizes and time spent on it
+    pub fn observe(&mut self, original_len: usize, compressed_len: usize, elapsed_us: u64) {
+        self.samples += 1;
+        self.bytes_in += original_len as u64;
+        self.bytes_out += compressed_len as u64;
+        self.elapsed_us += elapsed_us;
+
+        if self.samples >= self.window {
+            self.adjust();
+        }
+    }
+
+    fn adjust(&mut self) {
+        if self.bytes_in > 0 {
+            let ratio = self.bytes_out as f64 / self.bytes_in as f64;
+            let cost_per_kb = self.elapsed_us as f64 * 1024.0 / self.bytes_in as f64;
+
+            if cost_per_kb > self.cpu_budget_us_per_kb || ratio >= self.target_ratio {
+                self.level = self.level.saturating_sub(1).max(Self::MIN_LEVEL);
+            } else {
+                self.level = (self.level + 1).min(Self::MAX_LEVEL);
+            }
+        }
+
+        self.samples = 0;
+        self.bytes_in = 0;
+        self.bytes_out = 0;
+        self.elapsed_us = 0;
+    }
+}
+
+/// Errors produced while decoding relayed frames
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OptimizerError {
+    /// Frame is shorter than its header (and trailer, if flagged)
+    Truncated,
+    /// Header carries a compression marker we don't know
+    UnknownMarker(u8),
+    /// Payload uses a codec this build can't decode (zstd, brotli)
+    Unsupported(&'static str),
+    /// CRC32 trailer doesn't match the payload
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// Payload failed to decompress or decode
+    Decompress(String),
+    /// Message isn't a JSON object with a `type` field
+    Malformed(String),
+    /// Room policy doesn't accept this message type
+    TypeNotAllowed(MessageType),
+    /// Message lacks a field its type requires (see `Validator`)
+    Invalid(ValidationError),
+    /// Input or its decompressed form exceeds a size cap
+    TooLarge { limit: usize },
+    /// Handshake nonce was already used by this peer
+    ReplayDetected,
+    /// Peer already has `limit` handshakes in the replay window
+    TooManyHandshakes { limit: usize },
+    /// Payload inflates to more than `max_ratio` times its own size
+    RatioExceeded { max_ratio: usize },
+}
+
+impl std::fmt::Display for OptimizerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OptimizerError::Truncated => write!(f, "Frame truncated"),
+            OptimizerError::UnknownMarker(m) => write!(f, "Unknown frame marker: {:#04x}", m),
+            OptimizerError::Unsupported(codec) => {
+                write!(f, "algorithm {} not supported in this build", codec)
+            }
+            OptimizerError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Checksum mismatch: expected {:08x}, got {:08x}",
+                expected, actual
+            ),
+            OptimizerError::Decompress(e) => write!(f, "{}", e),
+            OptimizerError::Malformed(e) => write!(f, "Malformed message: {}", e),
+            OptimizerError::TypeNotAllowed(t) => write!(f, "Message type {:?} not allowed", t),
+            OptimizerError::Invalid(e) => write!(f, "{}", e),
+            OptimizerError::TooLarge { limit } => write!(f, "Message exceeds {} bytes", limit),
+            OptimizerError::ReplayDetected => write!(f, "Replayed handshake nonce"),
+            OptimizerError::TooManyHandshakes { limit } => {
+                write!(f, "More than {} handshakes in the replay window", limit)
+            }
+            OptimizerError::RatioExceeded { max_ratio } => {
+                write!(f, "Payload inflates more than {}x", max_ratio)
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl OptimizerError {
+    /// WebSocket close code (RFC 6455 §7.4.1) for disconnecting a peer
+    /// over this error
+    pub fn close_code(&self) -> u16 {
+        match self {
+            // Protocol error: the frame itself is broken
+            OptimizerError::Truncated
+            | OptimizerError::UnknownMarker(_)
+            | OptimizerError::ChecksumMismatch { .. }
+            | OptimizerError::Malformed(_) => 1002,
+            // Unsupported data: a codec this build can't read
+            OptimizerError::Unsupported(_) => 1003,
+            // Invalid payload data: doesn't inflate, isn't UTF-8, or
+            // doesn't carry what its type requires
+            OptimizerError::Decompress(_) | OptimizerError::Invalid(_) => 1007,
+            // Policy violation
+            OptimizerError::TypeNotAllowed(_)
+            | OptimizerError::ReplayDetected
+            | OptimizerError::TooManyHandshakes { .. } => 1008,
+            // Message too big
+            OptimizerError::TooLarge { .. } | OptimizerError::RatioExceeded { .. } => 1009,
+        }
+    }
+}
+
+impl std::error::Error for OptimizerError {}
+
+/// Frame header marker: payload sent as-is
+const MARKER_RAW: u8 = 0x00;
+/// Frame header marker: payload is gzip-compressed
+const MARKER_GZIP: u8 = 0x01;
+/// Frame header marker: payload is a raw deflate stream
+const MARKER_DEFLATE: u8 = 0x02;
+/// Reserved marker for zstd payloads, which this build can't decode
+const MARKER_ZSTD: u8 = 0x03;
+/// Reserved marker for brotli payloads, which this build can't decode
+const MARKER_BROTLI: u8 = 0x04;
+/// Header flag: a CRC32 of the payload follows it as a 4-byte LE trailer
+const FLAG_CHECKSUM: u8 = 0x01;
+/// Header flag: a `ChainLink` (8-byte LE position, 32-byte hash) follows the flags
+const FLAG_CHAIN: u8 = 0x02;
+/// Header flag: the payload was deflated with a preset dictionary whose
+/// 4-byte LE id follows the flags (before any chain link)
+const FLAG_DICTIONARY: u8 = 0x04;
+/// Header flag: bits 4-5 of the flags hold the sender-side priority index
+const FLAG_PRIORITY: u8 = 0x08;
+const PRIORITY_SHIFT: u8 = 4;
+/// Header flag: the payload is a JSON array of messages (a Low batch)
+const FLAG_BATCH: u8 = 0x40;
+
+/// Position and hash of a Critical message in its room's hash chain
+/// (computed by `CriticalLog` when the `critical-log` feature is on)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChainLink {
+    pub position: u64,
+    pub hash: [u8; 32],
+}
+
+impl ChainLink {
+    const ENCODED_LEN: usize = 8 + 32;
+}
+
+/// Wire frame: `[marker][flags][dictionary id?][chain link?][payload][crc32?]`
+///
+/// The flags byte may also carry the priority the frame was scheduled at,
+/// so a relay that only forwards can read it with `priority_from_header`.
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Codec the payload is encoded with
+    pub kind: CompressionKind,
+    /// Append a CRC32 trailer covering the payload
+    pub checksum: bool,
+    /// Hash-chain link for audited Critical messages
+    pub chain: Option<ChainLink>,
+    /// Id of the `SharedDictionary` entry the payload was deflated with
+    pub dictionary: Option<u32>,
+    /// Priority carried in the header
+    pub priority: Option<MessagePriority>,
+    /// The payload is a batch of messages, not one message
+    pub batch: bool,
+    pub payload: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl Frame {
+    /// Build a frame for a message, compressing it if worthwhile
+    pub fn new(msg: &str) -> Self {
+        let (payload, compressed) = maybe_compress(msg);
+        Self {
+            kind: if compressed {
+                CompressionKind::Gzip
+            } else {
+                CompressionKind::None
+            },
+            checksum: false,
+            chain: None,
+            dictionary: None,
+            priority: None,
+            batch: false,
+            payload,
+        }
+    }
+
+    /// Frame a payload as-is, without considering compression
+    pub fn uncompressed(payload: Vec<u8>) -> Self {
+        Self::with_kind(CompressionKind::None, payload)
+    }
+
+    /// Frame a payload already encoded with `kind`
+    pub fn with_kind(kind: CompressionKind, payload: Vec<u8>) -> Self {
+        Self {
+            kind,
+            checksum: false,
+            chain: None,
+            dictionary: None,
+            priority: None,
+            batch: false,
+            payload,
+        }
+    }
+
+    /// Build a frame using a peer's reusable compressor instead of
+    /// allocating a fresh encoder per message
+    pub fn with_compressor(msg: &str, compressor: &mut impl Compressor) -> Self {
+        let result = compressor.compress(msg);
+        Self::with_kind(result.kind, result.data)
+    }
+
+    /// Enable the CRC32 trailer
+    pub fn with_checksum(mut self) -> Self {
+        self.checksum = true;
+        self
+    }
+
+    /// Attach a hash-chain link
+    pub fn with_chain(mut self, link: ChainLink) -> Self {
+        self.chain = Some(link);
+        self
+    }
+
+    /// Record the dictionary the payload was deflated with
+    pub fn with_dictionary(mut self, id: u32) -> Self {
+        self.dictionary = Some(id);
+        self
+    }
+
+    /// Carry `priority` in the header
+    pub fn with_priority(mut self, priority: MessagePriority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Mark the payload as a batch (see `decode_messages`)
+    pub fn with_batch(mut self) -> Self {
+        self.batch = true;
+        self
+    }
+
+    /// Serialize header, payload and optional trailer
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.payload.len() + 10 + ChainLink::ENCODED_LEN);
+        out.push(self.kind.marker());
+        let mut flags = 0;
+        if self.checksum {
+            flags |= FLAG_CHECKSUM;
+        }
+        if self.chain.is_some() {
+            flags |= FLAG_CHAIN;
+        }
+        if self.dictionary.is_some() {
+            flags |= FLAG_DICTIONARY;
+        }
+        if let Some(priority) = self.priority {
+            flags |= FLAG_PRIORITY | (priority.index() as u8) << PRIORITY_SHIFT;
+        }
+        if self.batch {
+            flags |= FLAG_BATCH;
+        }
+        out.push(flags);
+        if let Some(id) = self.dictionary {
+            out.extend_from_slice(&id.to_le_bytes());
+        }
+        if let Some(link) = &self.chain {
+            out.extend_from_slice(&link.position.to_le_bytes());
+            out.extend_from_slice(&link.hash);
+        }
+        out.extend_from_slice(&self.payload);
+        if self.checksum {
+            out.extend_from_slice(&crc32(&self.payload).to_le_bytes());
+        }
+        out
+    }
+
+    /// Parse header and verify the checksum trailer (no decompression)
+    pub fn parse(raw: &[u8]) -> Result<Self, OptimizerError> {
+        if raw.len() < 2 {
+            return Err(OptimizerError::Truncated);
+        }
+
+        let kind = CompressionKind::from_marker(raw[0])?;
+        let checksum = raw[1] & FLAG_CHECKSUM != 0;
+        let priority = header_priority(raw[1]);
+
+        let mut body = &raw[2..];
+        let dictionary = if raw[1] & FLAG_DICTIONARY != 0 {
+            if body.len() < 4 {
+                return Err(OptimizerError::Truncated);
+            }
+            let (id, rest) = body.split_at(4);
+            body = rest;
+            Some(u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+        } else {
+            None
+        };
+        let chain = if raw[1] & FLAG_CHAIN != 0 {
+            if body.len() < ChainLink::ENCODED_LEN {
+                return Err(OptimizerError::Truncated);
+            }
+            let (link, rest) = body.split_at(ChainLink::ENCODED_LEN);
+            body = rest;
+            let (position, hash) = link.split_at(8);
+            Some(ChainLink {
+                position: u64::from_le_bytes(position.try_into().unwrap_or_default()),
+                hash: hash.try_into().unwrap_or_default(),
+            })
+        } else {
+            None
+        };
+
+        let payload = if checksum {
+            if body.len() < 4 {
+                return Err(OptimizerError::Truncated);
+            }
+            let (payload, trailer) = body.split_at(body.len() - 4);
+            let expected = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+            let actual = crc32(payload);
+            if expected != actual {
+                return Err(OptimizerError::ChecksumMismatch { expected, actual });
+            }
+            payload
+        } else {
+            body
+        };
+
+        Ok(Self {
+            kind,
+            checksum,
+            chain,
+            dictionary,
+            priority,
+            batch: raw[1] & FLAG_BATCH != 0,
+            payload: payload.to_vec(),
+        })
+    }
+
+    /// `parse`, also accepting a legacy unframed message (sent with no
+    /// header by clients that predate framing) as an uncompressed frame.
+    ///
+    /// Input whose first byte isn't a known marker is taken as legacy if
+    /// the whole of it is valid UTF-8 JSON. That can't misread a framed
+    /// message only as long as every marker is a byte JSON text can't start
+    /// with (control characters other than tab, LF and CR); legacy payloads
+    /// that aren't JSON are still rejected as `UnknownMarker`.
+    pub fn unframe(raw: &[u8]) -> Result<Self, OptimizerError> {
+        match raw.first() {
+            Some(&marker)
+                if matches!(
+                    CompressionKind::from_marker(marker),
+                    Err(OptimizerError::UnknownMarker(_))
+                ) =>
+            {
+                // from_slice doesn't check UTF-8 inside ignored strings
+                let json = std::str::from_utf8(raw)
+                    .is_ok_and(|text| serde_json::from_str::<serde::de::IgnoredAny>(text).is_ok());
+                if !json {
+                    return Err(OptimizerError::UnknownMarker(marker));
+                }
+                Ok(Self::uncompressed(raw.to_vec()))
+            }
+            _ => Self::parse(raw),
+        }
+    }
+
+    /// Decode a raw frame back into the original message.
+    /// The checksum (if present) is verified before decompression is attempted.
+    pub fn decode(raw: &[u8]) -> Result<String, OptimizerError> {
+        Self::parse(raw)?.decompress(None)
+    }
+
+    /// `decode`, split into messages: a batch frame's members, or the
+    /// one message of any other frame
+    pub fn decode_messages(raw: &[u8]) -> Result<Vec<String>, OptimizerError> {
+        let frame = Self::parse(raw)?;
+        let text = frame.decompress(None)?;
+        if frame.batch {
+            crate::batcher::unbatch(text.as_bytes()).map_err(OptimizerError::Malformed)
+        } else {
+            Ok(vec![text])
+        }
+    }
+
+    /// `decode`, for frames that may have been deflated with one of
+    /// `dictionaries`
+    pub fn decode_with_dictionary(
+        raw: &[u8],
+        dictionaries: &SharedDictionary,
+    ) -> Result<String, OptimizerError> {
+        Self::parse(raw)?.decompress(Some(dictionaries))
+    }
+
+    fn decompress(
+        &self,
+        dictionaries: Option<&SharedDictionary>,
+    ) -> Result<String, OptimizerError> {
+        let Some(id) = self.dictionary else {
+            return decompress_capped(
+                self.kind,
+                &self.payload,
+                MAX_DECOMPRESSED_LEN,
+                MAX_DECOMPRESSION_RATIO,
+            );
+        };
+        let dict = dictionaries
+            .and_then(|d| d.get(id))
+            .ok_or_else(|| OptimizerError::Decompress(format!("Unknown dictionary {}", id)))?;
+        inflate_with_dictionary(
+            &self.payload,
+            &dict.bytes,
+            MAX_DECOMPRESSED_LEN,
+            MAX_DECOMPRESSION_RATIO,
+        )
+    }
+}
+
+/// Priority stored in a frame's flags byte, if any
+fn header_priority(flags: u8) -> Option<MessagePriority> {
+    (flags & FLAG_PRIORITY != 0)
+        .then(|| MessagePriority::ALL[usize::from(flags >> PRIORITY_SHIFT & 0x03)])
+}
+
+/// Priority of an encoded frame, read from its header alone: the payload
+/// isn't checksummed, inflated, UTF-8 validated or parsed. This is the
+/// fast path for relays that only forward. A frame whose header carries
+/// no priority is Normal, the default for messages that can't be
+/// classified.
+pub fn priority_from_header(raw: &[u8]) -> Result<MessagePriority, OptimizerError> {
+    let [marker, flags, ..] = *raw else {
+        return Err(OptimizerError::Truncated);
+    };
+    if marker > MARKER_BROTLI {
+        return Err(OptimizerError::UnknownMarker(marker));
+    }
+    Ok(header_priority(flags).unwrap_or(MessagePriority::Normal))
+}
+
+/// Largest inbound frame `process_frame` accepts
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+/// Largest message a compressed frame may inflate to
+pub const MAX_DECOMPRESSED_LEN: usize = 4 * 1024 * 1024;
+/// Largest inflated/compressed size ratio accepted. Deflate tops out near
+/// 1032:1 and real JSON rarely passes a few hundred, so a payload past
+/// this is treated as a bomb and dropped long before `MAX_DECOMPRESSED_LEN`.
+pub const MAX_DECOMPRESSION_RATIO: usize = 500;
+
+/// gzip member magic, for sniffing bare `maybe_compress` output
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// zstd frame magic, so a bare zstd payload gets a clear error
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// An inbound message after decoding and classification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedMessage {
+    pub text: String,
+    pub msg_type: MessageType,
+    pub priority: MessagePriority,
+    /// `text` as parsed for classification, so callers needn't parse it
+    /// again
+    pub message: Message,
+}
+
+/// Decode arbitrary inbound bytes into a classified message.
+///
+/// Accepts an encoded `Frame`, a bare gzip member or plain JSON text
+/// (see `Frame::unframe`), sniffed from the first bytes. Input is capped
+/// at `MAX_FRAME_LEN` and inflated output at `MAX_DECOMPRESSED_LEN`.
+/// Never panics, so it doubles as the cargo-fuzz entry point for the
+/// decode path.
+pub fn process_frame(raw: &[u8]) -> Result<DecodedMessage, OptimizerError> {
+    process_frame_timed(raw, &mut CodecTimings::default())
+}
+
+/// `process_frame`, adding the time spent decompressing to `timings`
+pub fn process_frame_timed(
+    raw: &[u8],
+    timings: &mut CodecTimings,
+) -> Result<DecodedMessage, OptimizerError> {
+    #[cfg(feature = "tracing")]
+    let _receive = tracing::debug_span!("receive", wire_bytes = raw.len()).entered();
+    if raw.len() > MAX_FRAME_LEN {
+        return Err(OptimizerError::TooLarge {
+            limit: MAX_FRAME_LEN,
+        });
+    }
+
+    let text = if raw.starts_with(&ZSTD_MAGIC) {
+        return Err(OptimizerError::Unsupported("zstd"));
+    } else if raw.starts_with(&GZIP_MAGIC) {
+        timings.time_decompress(CompressionKind::Gzip, || {
+            decompress_capped(
+                CompressionKind::Gzip,
+                raw,
+                MAX_DECOMPRESSED_LEN,
+                MAX_DECOMPRESSION_RATIO,
+            )
+        })?
+    } else {
+        let frame = Frame::unframe(raw)?;
+        timings.time_decompress(frame.kind, || frame.decompress(None))?
+    };
+
+    let msg = Message::parse(&text)?;
+    let priority = msg.msg_type.priority().unwrap_or(MessagePriority::Normal);
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        msg_type = ?msg.msg_type,
+        priority = ?priority,
+        bytes = text.len(),
+        "message classified"
+    );
+    Ok(DecodedMessage {
+        priority,
+        msg_type: msg.msg_type,
+        message: msg,
+        text,
+    })
+}
+
+/// Decode a payload, giving up once inflated output passes `limit` bytes
+/// or `max_ratio` times the size of `data`
+fn decompress_capped(
+    kind: CompressionKind,
+    data: &[u8],
+    limit: usize,
+    max_ratio: usize,
+) -> Result<String, OptimizerError> {
+    use flate2::read::{DeflateDecoder, MultiGzDecoder};
+    use std::io::Read;
+
+    let decoder: Box<dyn Read + '_> = match kind {
+        CompressionKind::None => {
+            return maybe_decompress(data, false).map_err(OptimizerError::Decompress)
+        }
+        CompressionKind::Gzip => Box::new(MultiGzDecoder::new(data)),
+        CompressionKind::Deflate => Box::new(DeflateDecoder::new(data)),
+    };
+
+    read_capped(decoder, data.len(), limit, max_ratio)
+}
+
+/// Inflate a raw deflate stream primed with a preset dictionary
+fn inflate_with_dictionary(
+    data: &[u8],
+    dictionary: &[u8],
+    limit: usize,
+    max_ratio: usize,
+) -> Result<String, OptimizerError> {
+    let mut inflate = flate2::Decompress::new(false);
+    inflate
+        .set_dictionary(dictionary)
+        .map_err(|e| OptimizerError::Decompress(format!("Decompression error: {}", e)))?;
+    read_capped(
+        flate2::read::ZlibDecoder::new_with_decompress(data, inflate),
+        data.len(),
+        limit,
+        max_ratio,
+    )
+}
+
+fn read_capped(
+    decoder: impl std::io::Read,
+    input_len: usize,
+    limit: usize,
+    max_ratio: usize,
+) -> Result<String, OptimizerError> {
+    use std::io::Read;
+
+    let ratio_limit = input_len.saturating_mul(max_ratio);
+    let cap = limit.min(ratio_limit);
+    let mut decompressed = Vec::new();
+    decoder
+        .take(cap as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| OptimizerError::Decompress(format!("Decompression error: {}", e)))?;
+    if decompressed.len() > cap {
+        return Err(if ratio_limit < limit {
+            OptimizerError::RatioExceeded { max_ratio }
+        } else {
+            OptimizerError::TooLarge { limit }
+        });
+    }
+    String::from_utf8(decompressed).map_err(|e| {
+        OptimizerError::Decompress(format!("Decompressed payload is not UTF-8: {}", e))
+    })
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priority_detection() {
+        assert_eq!(
+            MessagePriority::from_message(r#"{"type":"auth_init"}"#),
+            MessagePriority::Critical
+        );
+
+        assert_eq!(
+            MessagePriority::from_message(r#"{"type":"entropy_commit"}"#),
+            MessagePriority::High
+        );
+
+        assert_eq!(
+            MessagePriority::from_message(r#"{"type":"ping"}"#),
+            MessagePriority::Low
+        );
+
+        assert_eq!(
+            MessagePriority::from_message(r#"{"type":"chat","msg":"hello"}"#),
+            MessagePriority::Normal
+        );
+    }
+
+    #[test]
+    fn test_message_type_spellings() {
+        for (snake, pascal, ty) in [
+            (
+                "entropy_commit",
+                "EntropyCommit",
+                MessageType::EntropyCommit,
+            ),
+            ("auth_init", "AuthInit", MessageType::AuthInit),
+            ("key_exchange", "KeyExchange", MessageType::KeyExchange),
+            ("peer_join", "PeerJoined", MessageType::PeerJoin),
+            ("ping", "Ping", MessageType::Ping),
+        ] {
+            let a = format!(r#"{{"type":"{}"}}"#, snake);
+            let b = format!(r#"{{"type":"{}","extra":1}}"#, pascal);
+            assert_eq!(Message::parse(&a).unwrap().msg_type, ty);
+            assert_eq!(Message::parse(&b).unwrap().msg_type, ty);
+            assert_eq!(
+                MessagePriority::from_message(&a),
+                MessagePriority::from_message(&b)
+            );
+        }
+
+        assert_eq!(
+            MessagePriority::from_message(r#"{"type": "EntropyCommit"}"#),
+            MessagePriority::High
+        );
+        // Keywords outside the type field no longer affect classification
+        assert_eq!(
+            MessagePriority::from_message(r#"{"type":"chat","msg":"KeyExchange"}"#),
+            MessagePriority::Normal
+        );
+    }
+
+    #[test]
+    fn test_observability_types_low() {
+        for (snake, pascal, ty) in [
+            ("stats", "Stats", MessageType::Stats),
+            ("metrics", "Metrics", MessageType::Metrics),
+            ("telemetry", "Telemetry", MessageType::Telemetry),
+        ] {
+            for name in [snake, pascal] {
+                let msg = format!(r#"{{"type":"{}","rx":12}}"#, name);
+                assert_eq!(Message::parse(&msg).unwrap().msg_type, ty);
+                assert_eq!(MessagePriority::from_message(&msg), MessagePriority::Low);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ice_messages_high() {
+        let candidate = r#"{"type":"ice_candidate","candidate":"candidate:1 1 udp 2122260223 10.0.0.2 54321 typ host"}"#;
+        assert_eq!(
+            Message::parse(candidate).unwrap().msg_type,
+            MessageType::IceCandidate
+        );
+        assert_eq!(
+            MessagePriority::from_message(candidate),
+            MessagePriority::High
+        );
+        assert_eq!(
+            MessagePriority::from_message(r#"{"type":"IceOffer","sdp":"v=0"}"#),
+            MessagePriority::High
+        );
+        // Still below auth/key exchange, above chat and data
+        assert!(MessagePriority::from_message(candidate) > MessagePriority::Critical);
+        assert!(MessagePriority::from_message(candidate) < MessagePriority::Normal);
+    }
+
+    #[test]
+    fn test_parse_rejects_untyped() {
+        assert_eq!(
+            Message::parse(r#"{"type":"custom"}"#).unwrap().msg_type,
+            MessageType::Unknown
+        );
+        assert!(matches!(
+            Message::parse("ping"),
+            Err(OptimizerError::Malformed(_))
+        ));
+        assert!(Message::parse(r#"{"msg":"no type"}"#).is_err());
+    }
+
+    #[test]
+    fn test_peek_matches_from_message() {
+        for msg in [
+            r#"{"type":"auth_init"}"#,
+            r#"{ "type" : "KeyExchange", "key": "abc" }"#,
+            r#"{"type":"entropy_commit","commit":"00ff"}"#,
+            r#"{"type":"chat","msg":"hello"}"#,
+            r#"{"type":"ping"}"#,
+            r#"{"type":"Telemetry","rx":12}"#,
+            r#"{"type":"custom","msg":"hello"}"#,
+            // Nested and quoted "type"s don't count, wherever they appear
+            r#"{"meta":{"type":"auth"},"type":"chat"}"#,
+            r#"{"msg":"\"type\":\"auth\"","type":"stats"}"#,
+            r#"{"tags":["type",{"type":"ping"}],"type":"peer_join"}"#,
+            r#"{"kind":"type","type":"dropped"}"#,
+            r#"{"msg":"café","type":"data_chunk"}"#,
+        ] {
+            assert_eq!(
+                MessagePriority::peek(msg),
+                MessagePriority::from_message(msg),
+                "{}",
+                msg
+            );
+        }
+
+        // Unlike from_message, peek doesn't reject malformed input
+        assert_eq!(
+            MessagePriority::peek(r#"{"type":"auth""#),
+            MessagePriority::Critical
+        );
+        assert_eq!(MessagePriority::peek("auth"), MessagePriority::Normal);
+        assert_eq!(MessageType::from_name("PeerLeft"), MessageType::PeerLeave);
+        assert_eq!(MessageType::from_name("custom"), MessageType::Unknown);
+    }
+
+    #[test]
+    fn test_type_value_case_insensitive() {
+        for name in ["Auth_Init", "AUTH_INIT", "auth_init"] {
+            let msg = format!(r#"{{"type":"{}"}}"#, name);
+            assert_eq!(
+                MessagePriority::from_message(&msg),
+                MessagePriority::Critical
+            );
+            assert_eq!(MessagePriority::peek(&msg), MessagePriority::Critical);
+        }
+
+        // Only the type value is folded: keys and other values stay strict
+        for msg in [
+            r#"{"Type":"auth_init"}"#,
+            r#"{"type":"chat","msg":"AUTH_INIT"}"#,
+            r#"{"type":"Chat","AUTH":"Auth_Init"}"#,
+        ] {
+            assert_eq!(
+                MessagePriority::from_message(msg),
+                MessagePriority::Normal,
+                "{}",
+                msg
+            );
+        }
+        assert!(Message::parse(r#"{"type":7}"#).is_err());
+    }
+
+    #[test]
+    fn test_sequence_stamper() {
+        let stamper = SequenceStamper::new();
+        let mut a = Message::parse(r#"{"type":"chat"}"#).unwrap();
+        let mut b = Message::parse(r#"{"type":"auth_init"}"#).unwrap();
+        assert_eq!(a.seq, 0);
+
+        stamper.stamp(&mut a);
+        stamper.stamp(&mut b);
+        assert!(a.seq > 0);
+        assert!(b.seq > a.seq);
+    }
+
+    /// Classify `text` from `from` in room "r", through `correlator`
+    fn correlate(
+        correlator: &mut ReplyCorrelator,
+        from: &str,
+        auth: AuthState,
+        text: &str,
+    ) -> MessagePriority {
+        let msg = Message::parse(text).unwrap();
+        correlator.correlate("r", from, auth, &msg, MessagePriority::from_message(text))
+    }
+
+    #[test]
+    fn test_reply_inherits_request_priority() {
+        let mut correlator = ReplyCorrelator::new(2);
+        let authed = AuthState::Authenticated;
+        let classify = |correlator: &mut ReplyCorrelator, from: &str, text: &str| {
+            correlate(correlator, from, authed, text)
+        };
+
+        let request = r#"{"type":"auth_init","id":"h-1","to":"bob"}"#;
+        assert_eq!(
+            classify(&mut correlator, "alice", request),
+            MessagePriority::Critical
+        );
+
+        // Only the addressed peer's reply to the requester inherits
+        let reply = r#"{"type":"chat","reply_to":"h-1","to":"alice"}"#;
+        assert_eq!(
+            classify(&mut correlator, "mallory", reply),
+            MessagePriority::Normal
+        );
+        let misrouted = r#"{"type":"chat","reply_to":"h-1","to":"carol"}"#;
+        assert_eq!(
+            classify(&mut correlator, "bob", misrouted),
+            MessagePriority::Normal
+        );
+        // A reply labelled as chat is still Critical, once
+        assert_eq!(
+            classify(&mut correlator, "bob", reply),
+            MessagePriority::Critical
+        );
+        assert_eq!(
+            classify(&mut correlator, "bob", reply),
+            MessagePriority::Normal
+        );
+
+        // Unknown ids (compared as JSON, so 1 isn't "1") change nothing
+        classify(
+            &mut correlator,
+            "alice",
+            r#"{"type":"auth_init","id":"1","to":"bob"}"#,
+        );
+        assert_eq!(
+            classify(
+                &mut correlator,
+                "bob",
+                r#"{"type":"ping","reply_to":1,"to":"alice"}"#
+            ),
+            MessagePriority::Low
+        );
+
+        // Requests are only remembered for the priority they had on their
+        // own: neither a Normal one nor a promoted reply carrying an id
+        classify(
+            &mut correlator,
+            "alice",
+            r#"{"type":"data","id":"d-1","to":"bob"}"#,
+        );
+        assert_eq!(
+            classify(
+                &mut correlator,
+                "bob",
+                r#"{"type":"ping","reply_to":"d-1","to":"alice"}"#
+            ),
+            MessagePriority::Low
+        );
+        classify(&mut correlator, "alice", request);
+        let chained = r#"{"type":"chat","id":"c-1","reply_to":"h-1","to":"alice"}"#;
+        assert_eq!(
+            classify(&mut correlator, "bob", chained),
+            MessagePriority::Critical
+        );
+        assert_eq!(
+            classify(
+                &mut correlator,
+                "alice",
+                r#"{"type":"chat","reply_to":"c-1","to":"bob"}"#
+            ),
+            MessagePriority::Normal
+        );
+
+        // Bounded: the oldest request is forgotten first
+        classify(&mut correlator, "alice", request);
+        classify(
+            &mut correlator,
+            "alice",
+            r#"{"type":"peer_join","id":"j-1","to":"bob"}"#,
+        );
+        classify(
+            &mut correlator,
+            "alice",
+            r#"{"type":"peer_join","id":"j-2","to":"bob"}"#,
+        );
+        assert_eq!(correlator.len(), 2);
+        assert_eq!(
+            classify(&mut correlator, "bob", reply),
+            MessagePriority::Normal
+        );
+        assert_eq!(
+            classify(
+                &mut correlator,
+                "bob",
+                r#"{"type":"pong","reply_to":"j-2","to":"alice"}"#
+            ),
+            MessagePriority::High
+        );
+    }
+
+    #[test]
+    fn test_reply_scoped_to_room_and_capped_for_auth() {
+        let mut correlator = ReplyCorrelator::default();
+        let unauth = AuthState::Unauthenticated;
+        let request = r#"{"type":"auth_init","id":"h-1","to":"bob"}"#;
+        let reply = r#"{"type":"chat","reply_to":"h-1","to":"alice"}"#;
+
+        // Ids are per room
+        correlate(&mut correlator, "alice", unauth, request);
+        let msg = Message::parse(reply).unwrap();
+        let other_room = correlator.correlate(
+            "r2",
+            "bob",
+            AuthState::Authenticated,
+            &msg,
+            MessagePriority::Normal,
+        );
+        assert_eq!(other_room, MessagePriority::Normal);
+
+        // An unauthenticated peer's reply is still capped at Normal, but
+        // its handshake reply keeps Critical
+        assert_eq!(
+            correlate(&mut correlator, "bob", unauth, reply),
+            MessagePriority::Normal
+        );
+        correlate(&mut correlator, "alice", unauth, request);
+        let handshake = r#"{"type":"custom_handshake","in_reply_to":"h-1","to":"alice"}"#;
+        assert_eq!(
+            correlate(&mut correlator, "bob", AuthState::Authenticated, handshake),
+            MessagePriority::Critical
+        );
+    }
+
+    #[test]
+    fn test_in_reply_to_inherits_critical() {
+        let mut correlator = ReplyCorrelator::default();
+        let authed = AuthState::Authenticated;
+        let request = r#"{"type":"auth_init","id":7,"to":"bob"}"#;
+        correlate(&mut correlator, "alice", authed, request);
+
+        let text = r#"{"type":"custom_handshake","in_reply_to":7,"to":"alice"}"#;
+        assert_eq!(MessagePriority::from_message(text), MessagePriority::Normal);
+        assert_eq!(
+            correlate(&mut correlator, "bob", authed, text),
+            MessagePriority::Critical
+        );
+
+        // Both spellings present: still parses, and reply_to wins
+        let both = r#"{"type":"chat","reply_to":7,"in_reply_to":"other","to":"alice"}"#;
+        let reply = Message::parse(both).unwrap();
+        assert_eq!(reply.replied_id(), Some(&serde_json::json!(7)));
+        correlate(&mut correlator, "alice", authed, request);
+        assert_eq!(
+            correlate(&mut correlator, "bob", authed, both),
+            MessagePriority::Critical
+        );
+    }
+
+    #[test]
+    fn test_session_priority_elevation_and_expiry() {
+        let mut sessions = SessionPriority::new(2, 1_000);
+        let classify = |sessions: &mut SessionPriority, text: &str, now_ms: u64| {
+            let msg = Message::parse(text).unwrap();
+            sessions.classify("alice", &msg, MessagePriority::from_message(text), now_ms)
+        };
+        let chat = r#"{"type":"chat","session_id":"s-1"}"#;
+
+        // Nothing is elevated before the session's first Critical message
+        assert_eq!(classify(&mut sessions, chat, 0), MessagePriority::Normal);
+        let init = r#"{"type":"auth_init","session_id":"s-1"}"#;
+        assert_eq!(classify(&mut sessions, init, 10), MessagePriority::Critical);
+        assert_eq!(classify(&mut sessions, chat, 20), MessagePriority::High);
+        let ping = r#"{"type":"ping","session_id":"s-1"}"#;
+        assert_eq!(classify(&mut sessions, ping, 900), MessagePriority::High);
+        // Other sessions and session-less messages are unaffected
+        let other = r#"{"type":"chat","session_id":"s-2"}"#;
+        assert_eq!(classify(&mut sessions, other, 900), MessagePriority::Normal);
+        // So is another peer reusing the session id
+        let msg = Message::parse(chat).unwrap();
+        assert_eq!(
+            sessions.classify("mallory", &msg, MessagePriority::Normal, 900),
+            MessagePriority::Normal
+        );
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(
+            classify(&mut sessions, r#"{"type":"chat"}"#, 900),
+            MessagePriority::Normal
+        );
+
+        // Each message renews the timeout; a silent session expires
+        assert_eq!(classify(&mut sessions, chat, 1_800), MessagePriority::High);
+        assert_eq!(
+            classify(&mut sessions, chat, 2_801),
+            MessagePriority::Normal
+        );
+        assert!(sessions.is_empty());
+
+        // Completing a session ends its elevation
+        classify(&mut sessions, init, 3_000);
+        assert!(!sessions.complete("mallory", "s-1"));
+        assert!(sessions.complete("alice", "s-1"));
+        assert_eq!(
+            classify(&mut sessions, chat, 3_001),
+            MessagePriority::Normal
+        );
+
+        // Bounded: the least recently active session is evicted
+        for id in ["a", "b", "c"] {
+            sessions.priority_for("p", id, MessagePriority::Critical, 4_000);
+            sessions.priority_for("p", "a", MessagePriority::Low, 4_001);
+        }
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(
+            sessions.priority_for("p", "a", MessagePriority::Low, 4_002),
+            MessagePriority::High
+        );
+        assert_eq!(
+            sessions.priority_for("p", "b", MessagePriority::Low, 4_002),
+            MessagePriority::Low
+        );
+    }
+
+    #[test]
+    fn test_priority_custom_default() {
+        let unknown = r#"{"type":"custom","msg":"hello"}"#;
+        assert_eq!(
+            MessagePriority::from_message_with_default(unknown, MessagePriority::Low),
+            MessagePriority::Low
+        );
+
+        // Recognized types ignore the default
+        assert_eq!(
+            MessagePriority::from_message_with_default(
+                r#"{"type":"auth_init"}"#,
+                MessagePriority::Low
+            ),
+            MessagePriority::Critical
+        );
+        assert_eq!(
+            MessagePriority::from_message_with_default(r#"{"type":"ping"}"#, MessagePriority::High),
+            MessagePriority::Low
+        );
+    }
+
+    #[test]
+    fn test_compression_threshold() {
+        // Small message - should not compress
+        let small = "hello";
+        let (data, compressed) = maybe_compress(small);
+        assert!(!compressed);
+        assert_eq!(data, small.as_bytes());
+
+        // Large message - should compress
+        let large = "x".repeat(2000);
+        let (data, compressed) = maybe_compress(&large);
+        assert!(compressed);
+        assert!(data.len() < large.len());
+    }
+
+    #[test]
+    fn test_decompress_concatenated_gzip_members() {
+        let first = r#"{"type":"chat","msg":"first half "#;
+        let second = r#"and second half"}"#;
+        let mut gz = gzip_fast(first).unwrap();
+        gz.extend(gzip_fast(second).unwrap());
+        let whole = format!("{}{}", first, second);
+
+        assert_eq!(maybe_decompress(&gz, true).unwrap(), whole);
+        let frame = Frame::with_kind(CompressionKind::Gzip, gz).encode();
+        assert_eq!(Frame::decode(&frame).unwrap(), whole);
+    }
+
+    #[test]
+    fn test_decompress_invalid_utf8() {
+        let err = maybe_decompress(&[b'o', b'k', 0xff], false).unwrap_err();
+        assert!(err.starts_with("UTF-8 decode error"), "{}", err);
+
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(&[0xc3, 0x28, 0xff]).unwrap();
+        let gz = encoder.finish().unwrap();
+        let err = maybe_decompress(&gz, true).unwrap_err();
+        assert!(
+            err.starts_with("Decompressed payload is not UTF-8"),
+            "{}",
+            err
+        );
+
+        // Corrupt gzip is still a decompression error
+        let err = maybe_decompress(b"not gzip", true).unwrap_err();
+        assert!(err.starts_with("Decompression error"), "{}", err);
+    }
+
+    #[test]
+    fn test_should_compress_by_priority() {
+        assert!(!should_compress(MessagePriority::Critical, 10_000));
+        assert!(should_compress(MessagePriority::High, 10_000));
+        assert!(should_compress(MessagePriority::Low, COMPRESSION_THRESHOLD));
+        assert!(!should_compress(MessagePriority::Normal, 100));
+
+        assert!(!should_compress_unless(
+            MessagePriority::High,
+            10_000,
+            MessagePriority::High
+        ));
+        assert!(should_compress_unless(
+            MessagePriority::Normal,
+            10_000,
+            MessagePriority::High
+        ));
+    }
+
+    #[test]
+    fn test_compress_override() {
+        let large = format!(
+            r#"{{"type":"data","compress":false,"p":"{}"}}"#,
+            "a".repeat(4000)
+        );
+        let small = r#"{"type":"chat","compress":true,"msg":"hi"}"#;
+
+        let flag = |raw: &str| Message::parse(raw).unwrap().compress;
+        assert_eq!(flag(&large), Some(false));
+        assert_eq!(flag(small), Some(true));
+        assert_eq!(flag(r#"{"type":"chat"}"#), None);
+
+        // Opt-out beats the size threshold
+        let (data, compressed) =
+            maybe_compress_with_override(&large, MessagePriority::Normal, flag(&large));
+        assert!(!compressed);
+        assert_eq!(data, large.as_bytes());
+
+        // Force beats both the threshold and Critical skipping
+        for priority in [MessagePriority::Normal, MessagePriority::Critical] {
+            let (data, compressed) = maybe_compress_with_override(small, priority, Some(true));
+            assert!(compressed);
+            assert_eq!(maybe_decompress(&data, true).unwrap(), small);
+        }
+
+        // No flag: normal priority rules
+        let (_, compressed) = maybe_compress_with_override(&large, MessagePriority::Critical, None);
+        assert!(!compressed);
+    }
+
+    #[test]
+    fn test_large_critical_not_compressed() {
+        let kex = format!(r#"{{"type":"key_exchange","key":"{}"}}"#, "ab".repeat(2000));
+        let (data, compressed) = maybe_compress_for(&kex, MessagePriority::Critical);
+        assert!(!compressed);
+        assert_eq!(data, kex.as_bytes());
+
+        let (_, compressed) = maybe_compress_for(&kex, MessagePriority::Normal);
+        assert!(compressed);
+    }
+
+    #[test]
+    fn test_peer_compressor_matches_stateless() {
+        let mut compressor = PeerCompressor::new();
+        let messages = [
+            "short".to_string(),
+            format!(r#"{{"type":"chat","msg":"{}"}}"#, "hello ".repeat(400)),
+            format!(r#"{{"type":"stats","pad":"{}"}}"#, "0123456789".repeat(300)),
+        ];
+
+        // Reused state must not leak between messages: every output is an
+        // independent gzip member that decodes like the stateless
+        // encoder's, behind the same header
+        for msg in &messages {
+            let result = compressor.compress(msg);
+            let (data, compressed) = maybe_compress(msg);
+            assert_eq!(result.is_compressed(), compressed);
+            assert_eq!(result.original_len, msg.len());
+            assert_eq!(&maybe_decompress(&result.data, compressed).unwrap(), msg);
+            assert_eq!(&maybe_decompress(&data, compressed).unwrap(), msg);
+            if compressed {
+                assert_eq!(result.data[..10], data[..10]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shared_compressor_across_threads() {
+        let shared = std::sync::Arc::new(std::sync::Mutex::new(PeerCompressor::new()));
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    for i in 0..25 {
+                        let msg = format!(
+                            r#"{{"type":"data","t":{},"i":{},"pad":"{}"}}"#,
+                            t,
+                            i,
+                            "q".repeat(2000)
+                        );
+                        let frame = Frame::with_compressor(&msg, &mut &*shared).encode();
+                        assert_eq!(Frame::decode(&frame).unwrap(), msg);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(shared.lock().unwrap().stats().messages, 100);
+    }
+
+    #[test]
+    fn test_peer_compressor_tracks_bytes() {
+        let mut compressor = PeerCompressor::new();
+        let compressible = "z".repeat(4096);
+        let incompressible = r#"{"type":"ping"}"#;
+
+        let big = compressor.compress(&compressible);
+        assert!(big.is_compressed());
+        let small = compressor.compress(incompressible);
+        assert!(!small.is_compressed());
+
+        let stats = compressor.stats();
+        assert_eq!(stats.messages, 2);
+        assert_eq!(
+            stats.original_bytes,
+            (compressible.len() + incompressible.len()) as u64
+        );
+        assert_eq!(
+            stats.sent_bytes,
+            (big.data.len() + incompressible.len()) as u64
+        );
+        assert!(stats.compression_ratio() < 0.1);
+
+        // Changing level keeps the running totals
+        compressor.set_level(9);
+        assert_eq!(compressor.stats().messages, 2);
+    }
+
+    #[test]
+    fn test_codec_timings_per_algorithm() {
+        struct DataAsDeflate;
+        impl CompressionPolicy for DataAsDeflate {
+            fn should_compress(&self, msg: &Message) -> Option<CompressionKind> {
+                Some(match msg.msg_type {
+                    MessageType::Data => CompressionKind::Deflate,
+                    _ => CompressionKind::Gzip,
+                })
+            }
+        }
+
+        let mut compressor = PeerCompressor::new();
+        let mut timings = CodecTimings::default();
+        for i in 0..4 {
+            let chat = format!(
+                r#"{{"type":"chat","n":{},"msg":"{}"}}"#,
+                i,
+                "hi ".repeat(2000)
+            );
+            let result = compressor.compress_with_policy(&chat, &DataAsDeflate);
+            let frame = Frame::with_kind(result.kind, result.data).encode();
+            process_frame_timed(&frame, &mut timings).unwrap();
+        }
+        let data = format!(r#"{{"type":"data","payload":"{}"}}"#, "x".repeat(8192));
+        compressor.compress_with_policy(&data, &DataAsDeflate);
+        // Below the threshold: compressed, but not timed
+        compressor.compress_with_policy(r#"{"type":"chat","msg":"hi hi hi hi"}"#, &DataAsDeflate);
+
+        let gzip = compressor.timings().get(CompressionKind::Gzip);
+        // Durations are the bench's to report (benches/peer_compressor.rs)
+        assert_eq!(gzip.compressed, 4);
+        assert!(gzip.compress_bytes > 4 * 6000);
+        let deflate = compressor.timings().get(CompressionKind::Deflate);
+        assert_eq!(deflate.compressed, 1);
+        assert_eq!(deflate.compress_bytes, data.len() as u64);
+
+        let inbound = timings.get(CompressionKind::Gzip);
+        assert_eq!((inbound.decompressed, inbound.compressed), (4, 0));
+        assert_eq!(inbound.decompress_bytes, gzip.compress_bytes);
+        assert_eq!(timings.get(CompressionKind::Deflate).decompressed, 0);
+
+        timings.merge(compressor.timings());
+        let mut snapshot = crate::stats::StatsSnapshot::new();
+        timings.export(&mut snapshot);
+        assert_eq!(snapshot.get("gzip_compressed"), Some(4));
+        assert_eq!(snapshot.get("gzip_decompressed"), Some(4));
+        assert_eq!(snapshot.get("deflate_compressed"), Some(1));
+        assert_eq!(snapshot.get("deflate_decompress_ns"), Some(0));
+        assert!(snapshot.get("gzip_compress_ns").is_some());
+        assert!(snapshot.get("gzip_decompress_ns").is_some());
+    }
+
+    #[test]
+    fn test_custom_compression_policy() {
+        struct ChatOnly;
+        impl CompressionPolicy for ChatOnly {
+            fn should_compress(&self, msg: &Message) -> Option<CompressionKind> {
+                (msg.msg_type == MessageType::Chat).then_some(CompressionKind::Deflate)
+            }
+        }
+
+        let small_chat = format!(r#"{{"type":"chat","msg":"{}"}}"#, "hello ".repeat(40));
+        let large_data = format!(r#"{{"type":"data","payload":"{}"}}"#, "x".repeat(4096));
+        assert!(small_chat.len() < COMPRESSION_THRESHOLD);
+
+        let mut compressor = PeerCompressor::new();
+        let chat = compressor.compress_with_policy(&small_chat, &ChatOnly);
+        assert_eq!(chat.kind, CompressionKind::Deflate);
+        let frame = Frame::with_kind(chat.kind, chat.data).encode();
+        assert_eq!(Frame::decode(&frame).unwrap(), small_chat);
+
+        let data = compressor.compress_with_policy(&large_data, &ChatOnly);
+        assert!(!data.is_compressed());
+        assert_eq!(compressor.stats().messages, 2);
+
+        // The default policy matches the plain threshold
+        let default = SizeThresholdPolicy::default();
+        assert!(!compressor
+            .compress_with_policy(&small_chat, &default)
+            .is_compressed());
+        assert_eq!(
+            compressor.compress_with_policy(&large_data, &default).kind,
+            CompressionKind::Gzip
+        );
+    }
+
+    #[test]
+    fn test_message_type_set() {
+        // Every variant needs its own bit
+        assert!((MessageType::Unknown as u32) < u64::BITS);
+
+        let mut set: MessageTypeSet = [MessageType::Ping, MessageType::Unknown]
+            .into_iter()
+            .collect();
+        assert!(set.contains(MessageType::Ping) && set.contains(MessageType::Unknown));
+        assert!(!set.contains(MessageType::Pong));
+        assert!(!set.insert(MessageType::Ping));
+        assert!(set.insert(MessageType::Auth));
+        assert!(set.remove(MessageType::Ping));
+        assert!(!set.remove(MessageType::Ping));
+        assert!(!set.contains(MessageType::Ping));
+        set.clear();
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_no_compress_types_skip_size_check() {
+        let padding = "x".repeat(4096);
+        let ping = format!(r#"{{"type":"ping","pad":"{}"}}"#, padding);
+        let chat = format!(r#"{{"type":"chat","pad":"{}"}}"#, padding);
+        assert_eq!(ping.len(), chat.len());
+
+        let mut compressor = PeerCompressor::new();
+        let policy = SizeThresholdPolicy::default();
+        let sent = compressor.compress_with_policy(&ping, &policy);
+        assert!(!sent.is_compressed());
+        assert_eq!(sent.data, ping.as_bytes());
+        assert!(!compressor
+            .compress_with_policy(&ping.replace("ping", "pong"), &policy)
+            .is_compressed());
+        assert!(compressor
+            .compress_with_policy(&chat, &policy)
+            .is_compressed());
+        // No deflate run for the keepalives
+        assert_eq!(
+            compressor.timings().get(CompressionKind::Gzip).compressed,
+            1
+        );
+
+        // The list is the policy's to change
+        let mut policy = SizeThresholdPolicy::new(64);
+        policy.no_compress_types.clear();
+        policy.no_compress_types.insert(MessageType::Chat);
+        assert!(compressor
+            .compress_with_policy(&ping, &policy)
+            .is_compressed());
+        assert!(!compressor
+            .compress_with_policy(&chat, &policy)
+            .is_compressed());
+    }
+
+    #[test]
+    fn test_size_threshold_policy_override_and_critical() {
+        let policy = SizeThresholdPolicy::default();
+        let parse = |text: String| Message::parse(&text).unwrap();
+        let pad = "x".repeat(4096);
+
+        let auth = parse(format!(r#"{{"type":"auth_init","pad":"{}"}}"#, pad));
+        assert_eq!(policy.should_compress(&auth), None);
+        let opted_out = parse(format!(
+            r#"{{"type":"chat","compress":false,"p":"{}"}}"#,
+            pad
+        ));
+        assert_eq!(policy.should_compress(&opted_out), None);
+
+        // The flag beats both the Critical skip and the threshold
+        let opted_in = parse(r#"{"type":"auth_init","compress":true}"#.to_string());
+        assert_eq!(
+            policy.should_compress(&opted_in),
+            Some(CompressionKind::Gzip)
+        );
+        let ping = parse(r#"{"type":"ping","compress":true}"#.to_string());
+        assert_eq!(policy.should_compress(&ping), Some(CompressionKind::Gzip));
+    }
+
+    #[test]
+    fn test_deterministic_profile() {
+        let msg = format!(r#"{{"type":"data","p":"{}"}}"#, "conformance ".repeat(200));
+        let profile = CompressionProfile::deterministic(6);
+
+        let (a, compressed) = maybe_compress_with_profile(&msg, &profile);
+        let (b, _) = maybe_compress_with_profile(&msg, &profile);
+        assert!(compressed);
+        assert_eq!(a, b);
+        // No mtime, OS "unknown"
+        assert_eq!(&a[4..8], &[0, 0, 0, 0]);
+        assert_eq!(a[9], GZIP_OS_UNKNOWN);
+
+        // The reusable compressor produces the same bytes and keeps its level
+        let mut compressor = PeerCompressor::with_profile(profile);
+        compressor.set_level(1);
+        assert_eq!(compressor.level(), 6);
+        assert_eq!(compressor.compress(&msg).data, a);
+        assert_eq!(compressor.compress(&msg).data, a);
+    }
+
+    #[test]
+    fn test_adaptive_level_follows_compressibility() {
+        fn settle(payload: &str) -> u32 {
+            let mut adaptive = AdaptiveLevel::new(0.5, 1_000.0, 4);
+            let mut compressor = PeerCompressor::new();
+            for _ in 0..64 {
+                let result = compressor.compress(payload);
+                adaptive.observe(result.original_len, result.data.len(), 1);
+                compressor.set_level(adaptive.level());
+            }
+            assert_eq!(compressor.level(), adaptive.level());
+            adaptive.level()
+        }
+
+        let compressible = format!(r#"{{"type":"stats","pad":"{}"}}"#, "abc".repeat(2000));
+        // Hex of a pseudo-random byte stream: gzip can't get near 50%
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let noisy: String = (0..4096)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                char::from(b'!' + (seed % 90) as u8)
+            })
+            .collect();
+
+        let high = settle(&compressible);
+        let low = settle(&noisy);
+        assert_eq!(high, AdaptiveLevel::MAX_LEVEL);
+        assert_eq!(low, AdaptiveLevel::MIN_LEVEL);
+    }
+
+    #[test]
+    fn test_adaptive_level_respects_cpu_budget() {
+        let mut adaptive = AdaptiveLevel::new(0.5, 10.0, 1);
+        adaptive.observe(1024, 100, 5);
+        assert_eq!(adaptive.level(), 2);
+        // Well-compressing but too slow: back off
+        adaptive.observe(1024, 100, 50);
+        assert_eq!(adaptive.level(), 1);
+    }
+
+    #[test]
+    fn test_peer_compressor_levels_decode() {
+        let msg = format!(r#"{{"type":"chat","msg":"{}"}}"#, "hello ".repeat(400));
+        let mut compressor = PeerCompressor::new();
+        for level in [1, 5, 9] {
+            compressor.set_level(level);
+            let result = compressor.compress(&msg);
+            assert!(result.is_compressed());
+            assert_eq!(maybe_decompress(&result.data, true).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let large = "y".repeat(2000);
+        for msg in ["hello", large.as_str()] {
+            let plain = Frame::new(msg).encode();
+            assert_eq!(Frame::decode(&plain).unwrap(), msg);
+
+            let checked = Frame::new(msg).with_checksum().encode();
+            assert_eq!(checked.len(), plain.len() + 4);
+            assert_eq!(Frame::decode(&checked).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_batch_flag_splits_messages() {
+        let array = br#"[{"type":"ping"},{"type":"pong"}]"#.to_vec();
+        let single = Frame::uncompressed(array.clone()).encode();
+        assert_eq!(
+            Frame::decode_messages(&single).unwrap(),
+            [r#"[{"type":"ping"},{"type":"pong"}]"#]
+        );
+        let batch = Frame::uncompressed(array).with_batch().encode();
+        assert!(Frame::parse(&batch).unwrap().batch);
+        assert_eq!(
+            Frame::decode_messages(&batch).unwrap(),
+            [r#"{"type":"ping"}"#, r#"{"type":"pong"}"#]
+        );
+    }
+
+    #[test]
+    fn test_frames_share_compressor_state() {
+        let mut compressor = PeerCompressor::new();
+        let first = format!(r#"{{"type":"chat","msg":"{}"}}"#, "first ".repeat(300));
+        let second = format!(r#"{{"type":"data","msg":"{}"}}"#, "second ".repeat(300));
+
+        let a = Frame::with_compressor(&first, &mut compressor).encode();
+        let b = Frame::with_compressor(&second, &mut compressor).encode();
+
+        // Each frame decodes on its own, in any order
+        assert_eq!(Frame::decode(&b).unwrap(), second);
+        assert_eq!(Frame::decode(&a).unwrap(), first);
+        let stateless = Frame::new(&first).encode();
+        assert_eq!(a[..2], stateless[..2]);
+        assert_eq!(Frame::decode(&stateless).unwrap(), first);
+    }
+
+    #[test]
+    fn test_codec_policy() {
+        let choose = |len, text_heavy| select_codec(&PayloadProfile { len, text_heavy }).kind;
+        assert_eq!(choose(100, true), CompressionKind::None);
+        assert_eq!(choose(4_000, false), CompressionKind::Deflate);
+        assert_eq!(choose(4_000, true), CompressionKind::Deflate);
+        assert_eq!(choose(LARGE_PAYLOAD, false), CompressionKind::Gzip);
+
+        let prose = select_codec(&PayloadProfile {
+            len: LARGE_PAYLOAD,
+            text_heavy: true,
+        });
+        assert_eq!(prose.kind, CompressionKind::Gzip);
+        assert_eq!(prose.level, 9);
+
+        assert!(PayloadProfile::of(&"the quick brown fox ".repeat(50)).text_heavy);
+        assert!(!PayloadProfile::of(&"3q2+7w==".repeat(100)).text_heavy);
+        assert!(!PayloadProfile::of("").text_heavy);
+    }
+
+    #[test]
+    fn test_auto_compressor_marks_codec() {
+        let mut auto = AutoCompressor::new();
+        let cases = [
+            (r#"{"type":"ping"}"#.to_string(), CompressionKind::None),
+            (
+                format!(r#"{{"type":"data","p":"{}"}}"#, "ab01".repeat(1_000)),
+                CompressionKind::Deflate,
+            ),
+            (
+                format!(
+                    r#"{{"type":"chat","msg":"{}"}}"#,
+                    "lorem ipsum ".repeat(2_000)
+                ),
+                CompressionKind::Gzip,
+            ),
+        ];
+
+        for (msg, kind) in &cases {
+            let raw = Frame::with_compressor(msg, &mut auto).encode();
+            assert_eq!(raw[0], kind.marker());
+            assert_eq!(&Frame::decode(&raw).unwrap(), msg);
+            assert_eq!(&process_frame(&raw).unwrap().text, msg);
+        }
+        assert_eq!(auto.stats().messages, 3);
+    }
+
+    #[test]
+    fn test_frame_chain_link_roundtrip() {
+        let link = ChainLink {
+            position: 7,
+            hash: [0xab; 32],
+        };
+        let raw = Frame::uncompressed(br#"{"type":"auth"}"#.to_vec())
+            .with_chain(link)
+            .with_checksum()
+            .encode();
+        let frame = Frame::parse(&raw).unwrap();
+        assert_eq!(frame.chain, Some(link));
+        assert_eq!(frame.payload, br#"{"type":"auth"}"#);
+
+        // Flag set but the link is cut short
+        assert_eq!(
+            Frame::parse(&[MARKER_RAW, FLAG_CHAIN, 0, 0, 0]),
+            Err(OptimizerError::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_priority_from_header() {
+        for priority in MessagePriority::ALL {
+            let raw = Frame::new(&"x".repeat(4096))
+                .with_priority(priority)
+                .with_checksum()
+                .with_dictionary(9)
+                .encode();
+            assert_eq!(priority_from_header(&raw), Ok(priority));
+            let frame = Frame::parse(&raw).unwrap();
+            assert_eq!(
+                (frame.priority, frame.dictionary, frame.checksum),
+                (Some(priority), Some(9), true)
+            );
+        }
+
+        // Only the header is read: the payload can be anything
+        let garbage = [MARKER_GZIP, FLAG_PRIORITY | 1 << PRIORITY_SHIFT, 0xff, 0xfe];
+        assert_eq!(priority_from_header(&garbage), Ok(MessagePriority::High));
+        let untagged = Frame::uncompressed(b"{}".to_vec()).encode();
+        assert_eq!(priority_from_header(&untagged), Ok(MessagePriority::Normal));
+
+        assert_eq!(priority_from_header(&[]), Err(OptimizerError::Truncated));
+        assert_eq!(
+            priority_from_header(&[MARKER_RAW]),
+            Err(OptimizerError::Truncated)
+        );
+        assert_eq!(
+            priority_from_header(br#"{"type":"auth"}"#),
+            Err(OptimizerError::UnknownMarker(b'{'))
+        );
+    }
+
+    #[test]
+    fn test_frame_checksum_mismatch() {
+        let mut raw = Frame::new(&"z".repeat(2000)).with_checksum().encode();
+        raw[5] ^= 0xff;
+
+        assert!(matches!(
+            Frame::decode(&raw),
+            Err(OptimizerError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_dictionary_swap_mid_stream() {
+        let shared = std::sync::Arc::new(SharedDictionary::new());
+        let mut compressor = DictionaryCompressor::new(shared.clone());
+        let msg = r#"{"type":"chat","room":"lobby","msg":"hello everyone in the lobby"}"#;
+
+        // No dictionary deployed yet: sent raw
+        let frame = compressor.compress(msg);
+        assert_eq!(
+            (frame.kind, frame.dictionary),
+            (CompressionKind::None, None)
+        );
+
+        let v1 = shared.update_dictionary(br#"{"type":"chat","room":"lobby","msg":""#.to_vec());
+        let first = compressor.compress(msg).encode();
+        let v2 = shared.update_dictionary(b"hello everyone in the lobby".repeat(2));
+        let second = compressor.compress(msg).encode();
+        assert_ne!(v1, v2);
+
+        // Each frame names the dictionary that was current when it was made
+        assert_eq!(Frame::parse(&first).unwrap().dictionary, Some(v1));
+        assert_eq!(Frame::parse(&second).unwrap().dictionary, Some(v2));
+        assert!(first.len() < msg.len());
+        for raw in [&first, &second] {
+            assert_eq!(Frame::decode_with_dictionary(raw, &shared).unwrap(), msg);
+            assert!(matches!(
+                Frame::decode(raw),
+                Err(OptimizerError::Decompress(_))
+            ));
+        }
+
+        // Frames outlive their dictionary only for a few swaps
+        for _ in 0..SharedDictionary::RETAINED {
+            shared.update_dictionary(b"unrelated".to_vec());
+        }
+        assert!(Frame::decode_with_dictionary(&first, &shared).is_err());
+    }
+
+    #[test]
+    fn test_frame_truncated() {
+        assert_eq!(Frame::decode(&[]), Err(OptimizerError::Truncated));
+        assert_eq!(
+            Frame::decode(&[MARKER_RAW, FLAG_CHECKSUM, 0x01]),
+            Err(OptimizerError::Truncated)
+        );
+        assert_eq!(
+            Frame::decode(&[0x7f, 0x00]),
+            Err(OptimizerError::UnknownMarker(0x7f))
+        );
+    }
+
+    #[test]
+    fn test_unsupported_codecs() {
+        let zstd_frame = [MARKER_ZSTD, 0x00, 0x28, 0xb5, 0x2f, 0xfd, 0x00];
+        assert_eq!(
+            Frame::decode(&zstd_frame),
+            Err(OptimizerError::Unsupported("zstd"))
+        );
+        assert_eq!(
+            process_frame(&[MARKER_BROTLI, 0x00, 0x0b, 0x02]),
+            Err(OptimizerError::Unsupported("brotli"))
+        );
+        let bare_zstd = [0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x00];
+        assert_eq!(
+            process_frame(&bare_zstd),
+            Err(OptimizerError::Unsupported("zstd"))
+        );
+        assert_eq!(
+            maybe_decompress(&bare_zstd, true).unwrap_err(),
+            "algorithm zstd not supported in this build"
+        );
+        assert_eq!(
+            OptimizerError::Unsupported("brotli").to_string(),
+            "algorithm brotli not supported in this build"
+        );
+    }
+
+    #[test]
+    fn test_error_close_codes() {
+        for (err, code) in [
+            (OptimizerError::Truncated, 1002),
+            (OptimizerError::UnknownMarker(0x7f), 1002),
+            (
+                OptimizerError::ChecksumMismatch {
+                    expected: 1,
+                    actual: 2,
+                },
+                1002,
+            ),
+            (OptimizerError::Malformed("no type".into()), 1002),
+            (OptimizerError::Unsupported("zstd"), 1003),
+            (OptimizerError::Decompress("bad inflate".into()), 1007),
+            (
+                OptimizerError::Invalid(crate::validate::ValidationError::MissingField {
+                    msg_type: MessageType::IceOffer,
+                    field: "sdp",
+                }),
+                1007,
+            ),
+            (OptimizerError::TypeNotAllowed(MessageType::Chat), 1008),
+            (OptimizerError::ReplayDetected, 1008),
+            (OptimizerError::TooManyHandshakes { limit: 64 }, 1008),
+            (OptimizerError::TooLarge { limit: 1 }, 1009),
+            (OptimizerError::RatioExceeded { max_ratio: 500 }, 1009),
+        ] {
+            assert_eq!(err.close_code(), code, "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn test_process_frame_formats() {
+        let chat = format!(r#"{{"type":"chat","msg":"{}"}}"#, "hello ".repeat(400));
+        let expected = DecodedMessage {
+            text: chat.clone(),
+            msg_type: MessageType::Chat,
+            priority: MessagePriority::Normal,
+            message: Message::parse(&chat).unwrap(),
+        };
+
+        let frame = Frame::new(&chat).with_checksum().encode();
+        assert_eq!(process_frame(&frame).unwrap(), expected);
+        let (gz, compressed) = maybe_compress(&chat);
+        assert!(compressed);
+        assert_eq!(process_frame(&gz).unwrap(), expected);
+        assert_eq!(process_frame(chat.as_bytes()).unwrap(), expected);
+
+        let ping = process_frame(br#"{"type":"ping"}"#).unwrap();
+        assert_eq!(ping.priority, MessagePriority::Low);
+    }
+
+    #[test]
+    fn test_unframe_legacy_raw() {
+        let small = r#"{"type":"ping"}"#;
+        let large = format!(r#"{{"type":"chat","msg":"{}"}}"#, "hello ".repeat(400));
+        let inputs: Vec<(Vec<u8>, &str)> = vec![
+            (Frame::new(small).encode(), small),
+            (small.as_bytes().to_vec(), small),
+            (Frame::new(&large).with_checksum().encode(), &large),
+            (large.as_bytes().to_vec(), &large),
+            // Leading whitespace is still JSON
+            (b"\n {\"type\":\"ping\"}".to_vec(), "\n {\"type\":\"ping\"}"),
+            (
+                br#"["not","an","object"]"#.to_vec(),
+                r#"["not","an","object"]"#,
+            ),
+        ];
+        for (raw, text) in &inputs {
+            let frame = Frame::unframe(raw).unwrap();
+            assert_eq!(frame.decompress(None).unwrap(), *text);
+        }
+
+        // A framed message is never mistaken for legacy text, so its
+        // marker byte is stripped rather than kept
+        let framed = Frame::unframe(&Frame::new(small).encode()).unwrap();
+        assert_eq!(framed.payload, small.as_bytes());
+
+        // Neither a known marker nor JSON
+        assert_eq!(
+            Frame::unframe(b"ping"),
+            Err(OptimizerError::UnknownMarker(b'p'))
+        );
+        assert_eq!(
+            Frame::unframe(b"{\"type\":\"ping\""),
+            Err(OptimizerError::UnknownMarker(b'{'))
+        );
+        assert_eq!(
+            Frame::unframe(&[b'"', 0xff, b'"']),
+            Err(OptimizerError::UnknownMarker(b'"'))
+        );
+        assert_eq!(Frame::unframe(&[]), Err(OptimizerError::Truncated));
+    }
+
+    #[test]
+    fn test_process_frame_caps() {
+        assert_eq!(
+            process_frame(&vec![b' '; MAX_FRAME_LEN + 1]).unwrap_err(),
+            OptimizerError::TooLarge {
+                limit: MAX_FRAME_LEN
+            }
+        );
+
+        // Small on the wire, too big once inflated
+        let bomb = format!(
+            r#"{{"type":"data","p":"{}"}}"#,
+            "0".repeat(MAX_DECOMPRESSED_LEN)
+        );
+        let (gz, _) = maybe_compress(&bomb);
+        assert!(gz.len() < MAX_FRAME_LEN);
+        assert_eq!(
+            process_frame(&gz).unwrap_err(),
+            OptimizerError::TooLarge {
+                limit: MAX_DECOMPRESSED_LEN
+            }
+        );
+    }
+
+    #[test]
+    fn test_decompression_ratio_guard() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let gzip_best = |text: &str| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(text.as_bytes()).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        // Ordinary repetitive traffic: around 10x
+        let stats: String = (0..200)
+            .map(|i| {
+                format!(
+                    r#"{{"peer":"p-{}","rx_bytes":1048576,"tx_bytes":524288,"rtt_ms":42}}"#,
+                    i
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let legit = format!(r#"{{"type":"stats","peers":[{}]}}"#, stats);
+        let gz = gzip_best(&legit);
+        let ratio = legit.len() / gz.len();
+        assert!((5..50).contains(&ratio), "{}", ratio);
+        assert_eq!(process_frame(&gz).unwrap().text, legit);
+
+        // Near deflate's limit, though far under the absolute cap
+        let bomb = format!(r#"{{"type":"data","p":"{}"}}"#, "0".repeat(1024 * 1024));
+        let gz = gzip_best(&bomb);
+        assert!(bomb.len() / gz.len() > 900);
+        assert!(bomb.len() < MAX_DECOMPRESSED_LEN);
+        assert_eq!(
+            process_frame(&gz).unwrap_err(),
+            OptimizerError::RatioExceeded {
+                max_ratio: MAX_DECOMPRESSION_RATIO
+            }
+        );
+        // Without the ratio guard it would have inflated in full
+        assert_eq!(
+            decompress_capped(CompressionKind::Gzip, &gz, MAX_DECOMPRESSED_LEN, usize::MAX)
+                .unwrap(),
+            bomb
+        );
+    }
+
+    #[test]
+    fn test_process_frame_garbage_never_panics() {
+        // xorshift64, so failures are reproducible without a rand dependency
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        let valid = Frame::new(&format!(r#"{{"type":"data","p":"{}"}}"#, "x".repeat(2000)))
+            .with_checksum()
+            .encode();
+
+        for _ in 0..2_000 {
+            let len = (next() % 64) as usize;
+            let mut input: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            // Bias towards inputs that get past the sniffing stage
+            match next() % 4 {
+                0 => input.insert(0, MARKER_GZIP),
+                1 => drop(input.splice(0..0, GZIP_MAGIC)),
+                _ => {}
+            }
+            assert!(process_frame(&input).is_err());
+
+            // Truncated or bit-flipped valid frames
+            let mut mutated = valid.clone();
+            mutated.truncate((next() as usize) % valid.len());
+            let _ = process_frame(&mutated);
+            let mut flipped = valid.clone();
+            let at = (next() as usize) % flipped.len();
+            flipped[at] ^= 1 << (next() % 8);
+            let _ = process_frame(&flipped);
+        }
     }
 }
@@ -0,0 +1,417 @@
+//! Restart-safe journal of queued Critical/High messages (`persistent-queue` feature)
+//!
+//! A relay restarted mid-deploy loses whatever sat in its in-memory
+//! queues. Losing a chat line is tolerable; losing a handshake message
+//! stalls the session. PersistentQueue journals Critical and High entries
+//! to an append-only file so they can be replayed on startup. Normal and
+//! Low traffic is never written.
+//!
+//! Records are binary: a tag byte, then varint-prefixed fields.
+//!
+//! - `0`: the message was queued. Fields: priority index, seq, room, peer, payload.
+//! - `1`: the message was delivered. Field: seq.
+//! - `2`: `recover` ran. Sequence numbers start over after a restart, so
+//!   a delivery only cancels a queued record since the last marker.
+//!
+//! `record` and `delivered` only buffer. `persist` appends the buffer and
+//! syncs the file, so callers choose how often to pay for the fsync. A torn
+//! record at the end of the file (the crash happened mid-write) is cut off;
+//! an unreadable record anywhere else is an `InvalidData` error.
+//!
+//! Recovery leaves the journal alone until the entries are safe again. The
+//! caller requeues what `recover` returned, journals it with `record`,
+//! `persist`s, and then calls `compact`, which rewrites the journal without
+//! the recovered copies. A crash before `compact` replays them again:
+//! recovery is at-least-once.
+
+use crate::batcher::{read_varint, write_varint};
+use crate::message_optimizer::{MessagePriority, OptimizerError};
+use crate::priority_queue::QueuedMessage;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+const TAG_QUEUED: u8 = 0;
+const TAG_DELIVERED: u8 = 1;
+const TAG_RECOVERED: u8 = 2;
+
+/// Journal of one relay's durable queue entries
+#[allow(dead_code)]
+pub struct PersistentQueue {
+    path: PathBuf,
+    file: File,
+    /// Records not yet written by `persist`
+    pending: Vec<u8>,
+    /// Journal bytes handed out by `recover`, dropped by `compact`
+    recovered_len: usize,
+}
+
+/// A queued record read back from the journal
+struct Journaled {
+    priority: MessagePriority,
+    seq: u64,
+    /// Recovery markers before the record: its sequence-number namespace
+    segment: usize,
+    room: String,
+    peer: String,
+    payload: Vec<u8>,
+}
+
+#[allow(dead_code)]
+impl PersistentQueue {
+    /// Open (or create) the journal at `path`, keeping existing records
+    /// for `recover`
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            pending: Vec::new(),
+            recovered_len: 0,
+        })
+    }
+
+    /// Journal `msg`, queued for `peer` in `room`, if it's Critical or High.
+    /// Returns whether it was journaled.
+    pub fn record(
+        &mut self,
+        room: &str,
+        peer: &str,
+        priority: MessagePriority,
+        msg: &QueuedMessage,
+    ) -> bool {
+        if priority > MessagePriority::High {
+            return false;
+        }
+        write_queued(
+            &mut self.pending,
+            priority,
+            msg.seq,
+            room,
+            peer,
+            &msg.payload,
+        );
+        true
+    }
+
+    /// Mark the message with ingest sequence `seq` as sent, so it isn't
+    /// replayed
+    pub fn delivered(&mut self, seq: u64) {
+        self.pending.push(TAG_DELIVERED);
+        write_varint(&mut self.pending, seq);
+    }
+
+    /// Append buffered records and sync them to disk. After an error the
+    /// records not yet written stay buffered for the next call.
+    pub fn persist(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        while !self.pending.is_empty() {
+            match self.file.write(&self.pending) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.file.sync_data()
+    }
+
+    /// Undelivered entries from before the restart, highest priority first
+    /// and in ingest order within a priority. Entries for which
+    /// `is_live(room, peer)` is false are discarded, because their room or
+    /// peer is gone.
+    ///
+    /// Call once at startup. The journal keeps the entries until
+    /// `compact`: requeue them, `record` and `persist` them again, then
+    /// compact.
+    pub fn recover(
+        &mut self,
+        mut is_live: impl FnMut(&str, &str) -> bool,
+    ) -> io::Result<Vec<(MessagePriority, Vec<u8>)>> {
+        self.persist()?;
+        let raw = self.read_journal()?;
+        let (entries, valid_len) = parse_journal(&raw)?;
+        if valid_len < raw.len() {
+            self.file.set_len(valid_len as u64)?;
+        }
+        self.pending.push(TAG_RECOVERED);
+        self.persist()?;
+        self.recovered_len = valid_len + 1;
+
+        let mut recovered: Vec<_> = entries
+            .into_iter()
+            .filter(|entry| is_live(&entry.room, &entry.peer))
+            .map(|entry| (entry.priority, entry.payload))
+            .collect();
+        recovered.sort_by_key(|(priority, _)| *priority);
+        Ok(recovered)
+    }
+
+    /// Rewrite the journal to just the undelivered entries recorded since
+    /// `recover`. The new journal is written beside the old one and renamed
+    /// over it, so a crash leaves one or the other.
+    pub fn compact(&mut self) -> io::Result<()> {
+        self.persist()?;
+        let raw = self.read_journal()?;
+        let since = raw.get(self.recovered_len..).unwrap_or_default();
+        let (entries, _) = parse_journal(since)?;
+
+        let mut compacted = Vec::new();
+        let mut segment = entries.first().map_or(0, |e| e.segment);
+        for e in &entries {
+            if e.segment != segment {
+                compacted.push(TAG_RECOVERED);
+                segment = e.segment;
+            }
+            write_queued(
+                &mut compacted,
+                e.priority,
+                e.seq,
+                &e.room,
+                &e.peer,
+                &e.payload,
+            );
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&compacted)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        #[cfg(unix)]
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.recovered_len = 0;
+        Ok(())
+    }
+
+    fn read_journal(&self) -> io::Result<Vec<u8>> {
+        let mut raw = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut raw)?;
+        Ok(raw)
+    }
+}
+
+fn write_queued(
+    out: &mut Vec<u8>,
+    priority: MessagePriority,
+    seq: u64,
+    room: &str,
+    peer: &str,
+    payload: &[u8],
+) {
+    out.push(TAG_QUEUED);
+    write_varint(out, priority.index() as u64);
+    write_varint(out, seq);
+    for field in [room.as_bytes(), peer.as_bytes(), payload] {
+        write_varint(out, field.len() as u64);
+        out.extend_from_slice(field);
+    }
+}
+
+/// Undelivered entries in `raw` in journal order, and how many bytes of
+/// it are whole records
+fn parse_journal(raw: &[u8]) -> io::Result<(Vec<Journaled>, usize)> {
+    let invalid = |reason: String| io::Error::new(ErrorKind::InvalidData, reason);
+    let mut entries = Vec::new();
+    let mut queued = BTreeMap::new();
+    let mut segment = 0;
+    let mut rest = raw;
+    while let Some(&tag) = rest.first() {
+        let start = raw.len() - rest.len();
+        let mut record = &rest[1..];
+        let parsed = match tag {
+            TAG_QUEUED => read_queued(&mut record, segment).map(|entry| {
+                queued.insert(entry.seq, entry);
+            }),
+            TAG_DELIVERED => read_varint(&mut record).map(|seq| {
+                queued.remove(&seq);
+            }),
+            TAG_RECOVERED => {
+                entries.extend(std::mem::take(&mut queued).into_values());
+                segment += 1;
+                Ok(())
+            }
+            _ => return Err(invalid(format!("unknown journal record tag {}", tag))),
+        };
+        match parsed {
+            Ok(()) => rest = record,
+            // Torn final record: the crash came mid-write
+            Err(OptimizerError::Truncated) => {
+                entries.extend(queued.into_values());
+                return Ok((entries, start));
+            }
+            Err(e) => return Err(invalid(format!("journal record at {}: {}", start, e))),
+        }
+    }
+    entries.extend(queued.into_values());
+    Ok((entries, raw.len()))
+}
+
+fn read_queued(rest: &mut &[u8], segment: usize) -> Result<Journaled, OptimizerError> {
+    let priority = usize::try_from(read_varint(rest)?)
+        .ok()
+        .and_then(|i| MessagePriority::ALL.get(i).copied())
+        .ok_or_else(|| OptimizerError::Malformed("bad priority".to_string()))?;
+    let seq = read_varint(rest)?;
+    let mut field = || -> Result<Vec<u8>, OptimizerError> {
+        let len = usize::try_from(read_varint(rest)?)
+            .map_err(|_| OptimizerError::Malformed("field too long".to_string()))?;
+        let bytes = rest.get(..len).ok_or(OptimizerError::Truncated)?.to_vec();
+        *rest = &rest[len..];
+        Ok(bytes)
+    };
+    let utf8 = |bytes| {
+        String::from_utf8(bytes).map_err(|_| OptimizerError::Malformed("not UTF-8".to_string()))
+    };
+    let room = utf8(field()?)?;
+    let peer = utf8(field()?)?;
+    let payload = field()?;
+    Ok(Journaled {
+        priority,
+        seq,
+        segment,
+        room,
+        peer,
+        payload,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("zks-relay-{}-{}.journal", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn msg(text: &str, seq: u64) -> QueuedMessage {
+        QueuedMessage::new(text.as_bytes().to_vec(), seq, 0)
+    }
+
+    #[test]
+    fn test_recover_high_priority_after_restart() {
+        let path = journal_path("restart");
+        {
+            let mut journal = PersistentQueue::open(&path).unwrap();
+            let queued = [
+                (MessagePriority::High, msg(r#"{"type":"peer_join"}"#, 1)),
+                (MessagePriority::Normal, msg(r#"{"type":"chat"}"#, 2)),
+                (MessagePriority::Critical, msg(r#"{"type":"auth_init"}"#, 3)),
+                (MessagePriority::Low, msg(r#"{"type":"ping"}"#, 4)),
+                (
+                    MessagePriority::Critical,
+                    msg(r#"{"type":"key_exchange"}"#, 5),
+                ),
+            ];
+            for (priority, m) in &queued {
+                let durable = journal.record("room-1", "p1", *priority, m);
+                assert_eq!(durable, *priority <= MessagePriority::High);
+            }
+            journal.record(
+                "room-gone",
+                "p2",
+                MessagePriority::Critical,
+                &msg(r#"{"type":"auth_response"}"#, 6),
+            );
+            journal.persist().unwrap();
+            journal.delivered(5);
+            journal.persist().unwrap();
+            // The relay dies here
+        }
+
+        let mut journal = PersistentQueue::open(&path).unwrap();
+        let recovered = journal.recover(|room, _| room == "room-1").unwrap();
+        assert_eq!(
+            recovered,
+            vec![
+                (
+                    MessagePriority::Critical,
+                    br#"{"type":"auth_init"}"#.to_vec()
+                ),
+                (MessagePriority::High, br#"{"type":"peer_join"}"#.to_vec()),
+            ]
+        );
+
+        // The relay dies again before requeueing: nothing is lost
+        drop(journal);
+        let mut journal = PersistentQueue::open(&path).unwrap();
+        assert_eq!(
+            journal.recover(|room, _| room == "room-1").unwrap(),
+            recovered
+        );
+
+        // Requeued under new sequence numbers that collide with the old
+        // ones, journaled again, then compacted
+        for (seq, (priority, payload)) in recovered.iter().enumerate() {
+            let m = QueuedMessage::new(payload.clone(), seq as u64 + 1, 0);
+            journal.record("room-1", "p1", *priority, &m);
+        }
+        journal.persist().unwrap();
+        journal.delivered(1);
+        journal.compact().unwrap();
+        drop(journal);
+
+        let mut journal = PersistentQueue::open(&path).unwrap();
+        assert_eq!(
+            journal.recover(|_, _| true).unwrap(),
+            vec![(MessagePriority::High, br#"{"type":"peer_join"}"#.to_vec())]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_tail_ignored() {
+        let path = journal_path("torn");
+        let mut journal = PersistentQueue::open(&path).unwrap();
+        journal.record("r", "p", MessagePriority::Critical, &msg("first", 1));
+        journal.record("r", "p", MessagePriority::Critical, &msg("second", 2));
+        journal.persist().unwrap();
+
+        let len = std::fs::metadata(&path).unwrap().len();
+        journal.file.set_len(len - 3).unwrap();
+        let recovered = journal.recover(|_, _| true).unwrap();
+        assert_eq!(
+            recovered,
+            vec![(MessagePriority::Critical, b"first".to_vec())]
+        );
+
+        // The torn bytes were cut off, so records after them still parse
+        journal.record("r", "p", MessagePriority::High, &msg("third", 1));
+        journal.persist().unwrap();
+        drop(journal);
+        let mut journal = PersistentQueue::open(&path).unwrap();
+        assert_eq!(journal.recover(|_, _| true).unwrap().len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_record_mid_journal_is_an_error() {
+        let path = journal_path("corrupt");
+        let mut journal = PersistentQueue::open(&path).unwrap();
+        journal.record("r", "p", MessagePriority::Critical, &msg("first", 1));
+        journal.record("r", "p", MessagePriority::Critical, &msg("second", 2));
+        journal.persist().unwrap();
+
+        // Priority index of the first record, out of range
+        let mut raw = std::fs::read(&path).unwrap();
+        raw[1] = 9;
+        std::fs::write(&path, &raw).unwrap();
+        let err = journal.recover(|_, _| true).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        // Nothing was cut off
+        assert_eq!(std::fs::read(&path).unwrap(), raw);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,469 @@
+//! One entry point for the message path
+//!
+//! Assembling the relay by hand means wiring the room policy,
+//! classification defaults, compression policy, rate limits and queue
+//! policies separately, and getting one of them wrong is easy.
+//! OptimizerBuilder collects those settings, with the same defaults the
+//! pieces have on their own, and builds a MessageOptimizer that runs them
+//! in order:
+//!
+//! - `ingest` decodes and classifies an inbound frame (`process_frame`),
+//!   applies the room policy and the validator, if one is set, caps the
+//!   priority for the sender's auth state (`RoomPolicy::classify_from`),
+//!   and charges the rate limits.
+//! - `enqueue` queues the message for a recipient.
+//! - `next_frame` pops the recipient's next message, aged per the aging
+//!   policy, and encodes it under the compression policy. The message's
+//!   `compress` flag and the Critical skip come first, as in
+//!   `maybe_compress_with_override`.
+
+use crate::message_optimizer::{
+    process_frame_timed, CompressionKind, CompressionPolicy, DecodedMessage, Frame,
+    MessagePriority, MessageType, OptimizerError, PeerCompressor, SizeThresholdPolicy,
+};
+use crate::priority_queue::{AgingPolicy, PriorityQueue, QueuedMessage, RetryPolicy};
+use crate::quota::{RateCost, RateDecision, RateLimit, RateLimiter};
+use crate::room::{AuthState, RoomPolicy};
+use crate::stats::CodecTimings;
+use crate::validate::Validator;
+use std::collections::HashMap;
+
+/// Why `ingest` refused a frame
+#[derive(Debug, PartialEq)]
+pub enum IngestError {
+    /// Undecodable, malformed or not allowed in the room
+    Rejected(OptimizerError),
+    /// Over the sender's or the room's rate limit
+    RateLimited(RateDecision),
+}
+
+impl std::fmt::Display for IngestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IngestError::Rejected(e) => write!(f, "{}", e),
+            IngestError::RateLimited(decision) => write!(f, "Rate limited ({:?})", decision),
+        }
+    }
+}
+
+impl std::error::Error for IngestError {}
+
+impl From<OptimizerError> for IngestError {
+    fn from(e: OptimizerError) -> Self {
+        IngestError::Rejected(e)
+    }
+}
+
+/// Settings for a MessageOptimizer
+pub struct OptimizerBuilder {
+    room_policy: RoomPolicy,
+    unknown_priority: MessagePriority,
+    compression: Box<dyn CompressionPolicy>,
+    compression_level: Option<u32>,
+    rate_limits: Option<(RateLimit, RateLimit)>,
+    rate_cost: RateCost,
+    retry_policy: RetryPolicy,
+    aging: AgingPolicy,
+    quiet_idle_ms: Option<u64>,
+    validator: Option<Validator>,
+}
+
+impl Default for OptimizerBuilder {
+    fn default() -> Self {
+        Self {
+            room_policy: RoomPolicy::allow_all(),
+            unknown_priority: MessagePriority::Normal,
+            compression: Box::new(SizeThresholdPolicy::default()),
+            compression_level: None,
+            rate_limits: None,
+            rate_cost: RateCost::Messages,
+            retry_policy: RetryPolicy::default(),
+            aging: AgingPolicy::default(),
+            quiet_idle_ms: None,
+            validator: None,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl OptimizerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Types the room accepts and its priority floor (default: allow all)
+    pub fn room_policy(mut self, policy: RoomPolicy) -> Self {
+        self.room_policy = policy;
+        self
+    }
+
+    /// Priority for unrecognized message types (default Normal)
+    pub fn unknown_priority(mut self, priority: MessagePriority) -> Self {
+        self.unknown_priority = priority;
+        self
+    }
+
+    /// Gzip messages of at least `threshold` bytes (default 1KB)
+    pub fn compression_threshold(self, threshold: usize) -> Self {
+        self.compression_policy(SizeThresholdPolicy::new(threshold))
+    }
+
+    /// Decide compression per message with `policy` instead of by size
+    pub fn compression_policy(mut self, policy: impl CompressionPolicy + 'static) -> Self {
+        self.compression = Box::new(policy);
+        self
+    }
+
+    /// Deflate level for every recipient's compressor (default: fast)
+    pub fn compression_level(mut self, level: u32) -> Self {
+        self.compression_level = Some(level);
+        self
+    }
+
+    /// Per-peer and room-wide rate limits (default: unlimited)
+    pub fn rate_limits(mut self, peer: RateLimit, room: RateLimit) -> Self {
+        self.rate_limits = Some((peer, room));
+        self
+    }
+
+    /// Whether the rate limits count messages (default) or wire bytes
+    pub fn rate_cost(mut self, cost: RateCost) -> Self {
+        self.rate_cost = cost;
+        self
+    }
+
+    /// When failed deliveries are demoted and dropped
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// When waiting messages are escalated
+    pub fn aging(mut self, aging: AgingPolicy) -> Self {
+        self.aging = aging;
+        self
+    }
+
+    /// Hold each recipient's Low traffic until its Normal-or-higher traffic
+    /// has been idle for `idle_ms` (default: off)
+    pub fn quiet_hours(mut self, idle_ms: u64) -> Self {
+        self.quiet_idle_ms = Some(idle_ms);
+        self
+    }
+
+    /// Reject messages missing the fields their type requires (default:
+    /// no checks)
+    pub fn validator(mut self, validator: Validator) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    pub fn build(self, now_ms: u64) -> MessageOptimizer {
+        MessageOptimizer {
+            limiter: self
+                .rate_limits
+                .map(|(peer, room)| RateLimiter::new(peer, room, now_ms).with_cost(self.rate_cost)),
+            settings: self,
+            recipients: HashMap::new(),
+            timings: CodecTimings::default(),
+        }
+    }
+}
+
+/// A recipient's outbound state
+struct Recipient {
+    queue: PriorityQueue,
+    compressor: PeerCompressor,
+}
+
+/// Classification, rate limiting, queueing and compression for one room
+#[allow(dead_code)]
+pub struct MessageOptimizer {
+    settings: OptimizerBuilder,
+    limiter: Option<RateLimiter>,
+    recipients: HashMap<String, Recipient>,
+    /// Decompression time on ingest
+    timings: CodecTimings,
+}
+
+#[allow(dead_code)]
+impl MessageOptimizer {
+    pub fn builder() -> OptimizerBuilder {
+        OptimizerBuilder::new()
+    }
+
+    /// Decode a frame from `from`, a peer in state `auth`, and give it
+    /// its final priority
+    pub fn ingest(
+        &mut self,
+        from: &str,
+        auth: AuthState,
+        raw: &[u8],
+        now_ms: u64,
+    ) -> Result<DecodedMessage, IngestError> {
+        let mut decoded = process_frame_timed(raw, &mut self.timings)?;
+        let msg = &decoded.message;
+        let policy = &self.settings.room_policy;
+        policy.check(msg)?;
+        if let Some(validator) = &self.settings.validator {
+            validator.check(msg).map_err(OptimizerError::Invalid)?;
+        }
+        decoded.priority = match msg.msg_type {
+            MessageType::Unknown => {
+                auth.cap(msg, policy.apply_floor(self.settings.unknown_priority))
+            }
+            _ => policy.classify_from(msg, auth),
+        };
+
+        if let Some(limiter) = &mut self.limiter {
+            // Charged for the frame as received, compressed or not
+            let decision = limiter.try_acquire(from, decoded.priority, raw.len(), now_ms);
+            if !decision.is_allowed() {
+                return Err(IngestError::RateLimited(decision));
+            }
+        }
+        Ok(decoded)
+    }
+
+    /// Queue an ingested message (ingest sequence `seq`) for `to`
+    pub fn enqueue(&mut self, to: &str, msg: &DecodedMessage, seq: u64, now_ms: u64) {
+        let queued = QueuedMessage::new(msg.text.clone().into_bytes(), seq, now_ms)
+            .with_message(msg.message.clone());
+        self.recipient(to).queue.push(msg.priority, queued);
+    }
+
+    /// `to`'s next message, encoded under the compression policy
+    pub fn next_frame(&mut self, to: &str, now_ms: u64) -> Option<(MessagePriority, Frame)> {
+        let recipient = self.recipients.get_mut(to)?;
+        let (priority, msg) = recipient.queue.pop_at(now_ms)?;
+        let (Some(parsed), Ok(text)) = (&msg.message, std::str::from_utf8(&msg.payload)) else {
+            return Some((priority, Frame::uncompressed(msg.payload)));
+        };
+        let policy = &self.settings.compression;
+        let kind = match parsed.compress {
+            Some(false) => CompressionKind::None,
+            Some(true) => policy
+                .should_compress(parsed)
+                .unwrap_or(CompressionKind::Gzip),
+            None if priority == MessagePriority::Critical => CompressionKind::None,
+            None => policy
+                .should_compress(parsed)
+                .unwrap_or(CompressionKind::None),
+        };
+        let result = recipient.compressor.compress_exact(text, kind);
+        Some((priority, Frame::with_kind(result.kind, result.data)))
+    }
+
+    /// CPU time per codec: decompression on ingest plus every current
+    /// recipient's compression
+    pub fn codec_timings(&self) -> CodecTimings {
+        let mut total = self.timings;
+        for recipient in self.recipients.values() {
+            total.merge(recipient.compressor.timings());
+        }
+        total
+    }
+
+    /// Drop a peer's queue and rate-limit state
+    pub fn remove_peer(&mut self, peer_id: &str) {
+        self.recipients.remove(peer_id);
+        if let Some(limiter) = &mut self.limiter {
+            limiter.remove(peer_id);
+        }
+    }
+
+    fn recipient(&mut self, peer_id: &str) -> &mut Recipient {
+        let settings = &self.settings;
+        self.recipients
+            .entry(peer_id.to_string())
+            .or_insert_with(|| {
+                let queue = PriorityQueue::new()
+                    .with_retry_policy(settings.retry_policy)
+                    .with_aging(settings.aging);
+                Recipient {
+                    queue: match settings.quiet_idle_ms {
+                        Some(idle_ms) => queue.with_quiet_hours(idle_ms),
+                        None => queue,
+                    },
+                    compressor: settings
+                        .compression_level
+                        .map_or_else(PeerCompressor::new, PeerCompressor::with_level),
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_customized_optimizer_end_to_end() {
+        let mut optimizer = MessageOptimizer::builder()
+            .room_policy(RoomPolicy::allow_all().with_priority_floor(MessagePriority::Normal))
+            .unknown_priority(MessagePriority::Low)
+            .compression_threshold(64)
+            .compression_level(9)
+            .rate_limits(RateLimit::new(2, 0.0), RateLimit::new(100, 0.0))
+            .build(0);
+
+        // Unknown types get the configured default, raised to the floor
+        let custom = optimizer
+            .ingest(
+                "alice",
+                AuthState::Authenticated,
+                br#"{"type":"custom"}"#,
+                0,
+            )
+            .unwrap();
+        assert_eq!(custom.priority, MessagePriority::Normal);
+
+        let text = format!(r#"{{"type":"chat","msg":"{}"}}"#, "hello ".repeat(20));
+        let raw = Frame::uncompressed(text.clone().into_bytes()).encode();
+        let chat = optimizer
+            .ingest("alice", AuthState::Authenticated, &raw, 0)
+            .unwrap();
+        assert_eq!(chat.msg_type, MessageType::Chat);
+        optimizer.enqueue("bob", &chat, 1, 0);
+
+        // Above the lowered threshold, so it goes out gzipped
+        let (priority, frame) = optimizer.next_frame("bob", 0).unwrap();
+        assert_eq!(priority, MessagePriority::Normal);
+        assert_eq!(frame.kind, CompressionKind::Gzip);
+        assert_eq!(Frame::decode(&frame.encode()).unwrap(), text);
+        assert!(optimizer.next_frame("bob", 0).is_none());
+
+        // alice's burst of two is spent; Critical traffic is exempt
+        assert!(matches!(
+            optimizer.ingest("alice", AuthState::Authenticated, &raw, 0),
+            Err(IngestError::RateLimited(RateDecision::PeerLimited { .. }))
+        ));
+        let auth = optimizer.ingest(
+            "alice",
+            AuthState::Authenticated,
+            br#"{"type":"auth_init"}"#,
+            0,
+        );
+        assert_eq!(auth.unwrap().priority, MessagePriority::Critical);
+    }
+
+    #[test]
+    fn test_next_frame_applies_override_rules() {
+        let mut optimizer = MessageOptimizer::builder().build(0);
+        let pad = "abc ".repeat(500);
+        let texts = [
+            format!(r#"{{"type":"auth_init","pad":"{}"}}"#, pad),
+            format!(r#"{{"type":"chat","compress":false,"msg":"{}"}}"#, pad),
+            format!(
+                r#"{{"type":"chat","compress":true,"msg":"{}"}}"#,
+                &pad[..400]
+            ),
+        ];
+        for (seq, text) in texts.iter().enumerate() {
+            let msg = optimizer
+                .ingest("alice", AuthState::Authenticated, text.as_bytes(), 0)
+                .unwrap();
+            optimizer.enqueue("bob", &msg, seq as u64, 0);
+        }
+
+        // Critical, then the opt-out, both raw despite their size; the
+        // small opt-in is gzipped below the threshold
+        let mut kinds = Vec::new();
+        while let Some((_, frame)) = optimizer.next_frame("bob", 0) {
+            kinds.push(frame.kind);
+        }
+        assert_eq!(
+            kinds,
+            vec![
+                CompressionKind::None,
+                CompressionKind::None,
+                CompressionKind::Gzip
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ingest_caps_by_sender_auth() {
+        let mut optimizer = MessageOptimizer::builder()
+            .room_policy(RoomPolicy::allow_all().with_priority_floor(MessagePriority::High))
+            .build(0);
+        let ingest = |optimizer: &mut MessageOptimizer, auth, raw: &[u8]| {
+            optimizer.ingest("p", auth, raw, 0).unwrap().priority
+        };
+        let chat = br#"{"type":"chat"}"#;
+        let hinted = br#"{"type":"chat","priority":"critical"}"#;
+
+        // The room floor only lifts a sender past its handshake
+        assert_eq!(
+            ingest(&mut optimizer, AuthState::Unauthenticated, chat),
+            MessagePriority::Normal
+        );
+        assert_eq!(
+            ingest(&mut optimizer, AuthState::Authenticated, chat),
+            MessagePriority::High
+        );
+        assert_eq!(
+            ingest(
+                &mut optimizer,
+                AuthState::Unauthenticated,
+                br#"{"type":"custom"}"#
+            ),
+            MessagePriority::Normal
+        );
+        assert_eq!(
+            ingest(
+                &mut optimizer,
+                AuthState::Unauthenticated,
+                br#"{"type":"auth_init"}"#
+            ),
+            MessagePriority::Critical
+        );
+
+        // Only a Trusted sender's hint is taken at face value
+        assert_eq!(
+            ingest(&mut optimizer, AuthState::Authenticated, hinted),
+            MessagePriority::High
+        );
+        assert_eq!(
+            ingest(&mut optimizer, AuthState::Trusted, hinted),
+            MessagePriority::Critical
+        );
+    }
+
+    #[test]
+    fn test_policy_rejection() {
+        let mut optimizer = OptimizerBuilder::new()
+            .room_policy(RoomPolicy::signaling_only())
+            .build(0);
+        assert_eq!(
+            optimizer.ingest("p", AuthState::Authenticated, br#"{"type":"chat"}"#, 0),
+            Err(IngestError::Rejected(OptimizerError::TypeNotAllowed(
+                MessageType::Chat
+            )))
+        );
+        assert!(matches!(
+            optimizer.ingest("p", AuthState::Authenticated, b"\x09", 0),
+            Err(IngestError::Rejected(_))
+        ));
+
+        let mut optimizer = OptimizerBuilder::new()
+            .validator(Validator::default())
+            .build(0);
+        assert!(matches!(
+            optimizer.ingest(
+                "p",
+                AuthState::Authenticated,
+                br#"{"type":"entropy_commit"}"#,
+                0
+            ),
+            Err(IngestError::Rejected(OptimizerError::Invalid(_)))
+        ));
+        let commit = format!(
+            r#"{{"type":"entropy_commit","commitment":"{}"}}"#,
+            "0".repeat(64)
+        );
+        assert!(optimizer
+            .ingest("p", AuthState::Authenticated, commit.as_bytes(), 0)
+            .is_ok());
+    }
+}
@@ -0,0 +1,1518 @@
+//! Outbound message queue ordered by MessagePriority
+//!
+//! One FIFO band per priority level. Pops always drain the highest
+//! non-empty band first, preserving arrival order within a band.
+//!
+//! Messages that fail delivery are handed back via `requeue_failed`; after
+//! repeated failures they are demoted a level, and past `max_retries` they
+//! are dropped, so an undeliverable Critical message can't hold the head
+//! of the queue forever.
+//!
+//! The opposite problem, a Normal/Low message starving behind a steady
+//! Critical/High stream, is handled by aging: `pop_at` escalates any
+//! band head that waited longer than its band's max wait by one level.
+//! The flush paths pop with `pop_at`, and `RoomQueue::pop` ages every
+//! peer's queue before it picks a band.
+//!
+//! Quiet hours (`with_quiet_hours`) defers Low traffic instead: while
+//! anything Normal or higher is being queued or sent, Low messages are
+//! held, and `pop_at` releases them only once nothing above Low has moved
+//! for the idle period. The send paths (`flush_to`, `RoomQueue::pop`)
+//! honor the hold too. Heartbeats and stats then go out in bursts during
+//! lulls. Held Low messages don't age, since escalating them would defeat
+//! the hold. Critical and High are scheduled as usual.
+//!
+//! RoomQueue schedules across the peers of a room: the highest non-empty
+//! band always goes first, and peers with traffic in that band take turns
+//! in proportion to their weight. Peers are keyed by any `Eq + Hash +
+//! Clone` id (String by default), so binary keys need no stringifying.
+//! `SchedulerOrdering::Deterministic` replaces the turns with a fixed
+//! order for tests: within the band, the lowest peer id goes first.
+//!
+//! With the `tracing` feature, pushes, drops, reorders and escalations
+//! emit events with a `priority` field. A queue doesn't know whose it is,
+//! so callers drive a peer's queue inside its `peer_span`.
+//!
+//! A drop hook sees every shed or abandoned message as a `DropEvent`; the
+//! relay batches them into `dropped` notices for the original senders.
+//!
+//! For failover, `snapshot` captures a queue's messages (every band, in
+//! order, with their timestamps, retry counts and senders) as a
+//! serializable QueueSnapshot, and `restore` loads one into a queue on the
+//! standby relay. Policies and the drop hook belong to the queue that
+//! restores it; drop metrics and dwell histograms start over there.
+//!
+//! `peek_summary` is the read-only view for admin tooling: per band, how
+//! many messages wait and what type the head one is. It doesn't pop,
+//! age or otherwise touch the queue.
+
+use crate::message_optimizer::{Message, MessagePriority, MessageType};
+use crate::stats::{DwellHistogram, DwellPercentiles};
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::hash::Hash;
+
+/// A queued message, its ingest sequence number and when it was enqueued
+#[allow(dead_code)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    /// Hex in serialized form
+    #[serde(with = "hex_payload")]
+    pub payload: Vec<u8>,
+    /// Ingest order (see `SequenceStamper`), preserved across reordering
+    pub seq: u64,
+    pub enqueued_at: u64,
+    /// Failed delivery attempts so far
+    pub retry_count: u32,
+    /// Peer the message came from, to notify if it's dropped
+    pub sender: Option<String>,
+    /// The payload as ingest parsed it, so sending it doesn't parse it
+    /// again. None for payloads that aren't a JSON message: those are
+    /// never batched. Not serialized; `restore` parses the payload
+    /// again.
+    #[serde(skip)]
+    pub message: Option<Box<Message>>,
+}
+
+#[allow(dead_code)]
+impl QueuedMessage {
+    pub fn new(payload: Vec<u8>, seq: u64, enqueued_at: u64) -> Self {
+        Self {
+            payload,
+            seq,
+            enqueued_at,
+            retry_count: 0,
+            sender: None,
+            message: None,
+        }
+    }
+
+    pub fn with_sender(mut self, peer_id: &str) -> Self {
+        self.sender = Some(peer_id.to_string());
+        self
+    }
+
+    /// Attach the payload's parsed form (e.g. `DecodedMessage::message`)
+    pub fn with_message(mut self, msg: Message) -> Self {
+        self.message = Some(Box::new(msg));
+        self
+    }
+
+    /// Parse the payload and attach it, for messages that didn't come
+    /// through ingest; a payload that isn't a JSON message stays raw
+    pub fn parsed(mut self) -> Self {
+        self.message = std::str::from_utf8(&self.payload)
+            .ok()
+            .and_then(|text| Message::parse(text).ok())
+            .map(Box::new);
+        self
+    }
+}
+
+mod hex_payload {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(payload: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(payload))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        hex::decode(hex).map_err(serde::de::Error::custom)
+    }
+}
+
+/// One band's messages, head first
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandSnapshot {
+    pub priority: MessagePriority,
+    pub messages: Vec<QueuedMessage>,
+}
+
+/// A PriorityQueue's contents, for moving it to another relay
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueueSnapshot {
+    /// Non-empty bands, highest priority first
+    pub bands: Vec<BandSnapshot>,
+    /// Highest seq delivered so far, for reorder detection
+    pub delivered_watermark: u64,
+    /// Last Normal+ activity (quiet hours)
+    pub last_busy_ms: Option<u64>,
+}
+
+/// How failed deliveries are retried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Demote one priority level every `demote_after` failures
+    pub demote_after: u32,
+    /// Drop once a message has failed more than this many times
+    pub max_retries: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            demote_after: 2,
+            max_retries: 5,
+        }
+    }
+}
+
+/// Per-band max wait before a message is escalated one level
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AgingPolicy {
+    /// Indexed by MessagePriority; None = never escalate out of that band
+    pub max_wait_ms: [Option<u64>; MessagePriority::COUNT],
+}
+
+#[allow(dead_code)]
+impl AgingPolicy {
+    /// No escalation at all
+    pub fn disabled() -> Self {
+        Self {
+            max_wait_ms: [None; MessagePriority::COUNT],
+        }
+    }
+
+    pub fn with_max_wait(mut self, priority: MessagePriority, max_wait_ms: Option<u64>) -> Self {
+        self.max_wait_ms[priority.index()] = max_wait_ms;
+        self
+    }
+}
+
+impl Default for AgingPolicy {
+    /// Normal waits at most 200ms and Low 1s; High is never escalated, so
+    /// aged traffic can't jump ahead of Critical
+    fn default() -> Self {
+        Self::disabled()
+            .with_max_wait(MessagePriority::Normal, Some(200))
+            .with_max_wait(MessagePriority::Low, Some(1_000))
+    }
+}
+
+/// Result of handing a failed message back to the queue
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryOutcome {
+    /// Queued again at this priority
+    Requeued(MessagePriority),
+    /// Gave up after too many attempts
+    Dropped,
+}
+
+/// Messages the queue has given up on
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DropMetrics {
+    /// Dropped messages per band, indexed by MessagePriority
+    pub dropped: [u64; MessagePriority::COUNT],
+    /// Sum of retry counts of all dropped messages
+    pub retries: u64,
+}
+
+#[allow(dead_code)]
+impl DropMetrics {
+    pub fn total(&self) -> u64 {
+        self.dropped.iter().sum()
+    }
+
+    fn record(&mut self, priority: MessagePriority, msg: &QueuedMessage) {
+        self.dropped[priority.index()] += 1;
+        self.retries += u64::from(msg.retry_count);
+    }
+}
+
+/// Why the queue gave up on a message
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropReason {
+    /// Evicted by `shed_below` under congestion
+    Shed,
+    /// Failed delivery more than `max_retries` times
+    RetriesExhausted,
+}
+
+/// A message the queue dropped, as reported to the drop hook
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DropEvent {
+    pub priority: MessagePriority,
+    pub seq: u64,
+    pub sender: Option<String>,
+    /// The message's own `"id"` field, if it had one
+    pub id: Option<serde_json::Value>,
+    pub reason: DropReason,
+}
+
+/// One entry of a `dropped` notice
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct DroppedEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    pub reason: DropReason,
+}
+
+/// Tells a sender which of its messages were dropped; a congestion event
+/// can shed many at once, so they travel in one batch per sender
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename = "dropped")]
+pub struct DroppedNotice {
+    pub drops: Vec<DroppedEntry>,
+}
+
+#[allow(dead_code)]
+impl DroppedNotice {
+    /// Group drop events into one notice per sender, in first-drop order.
+    /// Events without a sender have no one to notify and are skipped.
+    pub fn batch(events: impl IntoIterator<Item = DropEvent>) -> Vec<(String, DroppedNotice)> {
+        let mut notices: Vec<(String, DroppedNotice)> = Vec::new();
+        for event in events {
+            let Some(sender) = event.sender else {
+                continue;
+            };
+            let entry = DroppedEntry {
+                id: event.id,
+                reason: event.reason,
+            };
+            match notices.iter_mut().find(|(peer, _)| *peer == sender) {
+                Some((_, notice)) => notice.drops.push(entry),
+                None => notices.push((sender, DroppedNotice { drops: vec![entry] })),
+            }
+        }
+        notices
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// The `"id"` of a JSON message, if any
+fn message_id(payload: &[u8]) -> Option<serde_json::Value> {
+    #[derive(Deserialize)]
+    struct IdField {
+        id: Option<serde_json::Value>,
+    }
+    serde_json::from_slice::<IdField>(payload).ok()?.id
+}
+
+/// Span to handle one peer's traffic in, so the lifecycle events of
+/// `process_frame`, the queue and the flush path carry its room and peer
+#[cfg(feature = "tracing")]
+#[allow(dead_code)]
+pub fn peer_span(room_id: &str, peer_id: &str) -> tracing::Span {
+    tracing::info_span!("peer", room = room_id, peer = peer_id)
+}
+
+/// Backlog of a single priority band
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BandStats {
+    pub len: usize,
+    /// Age of the oldest queued message in ms (None if the band is empty)
+    pub oldest_age_ms: Option<u64>,
+}
+
+/// Per-band backlog snapshot, indexed by MessagePriority
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub bands: [BandStats; MessagePriority::COUNT],
+    /// Time popped messages spent queued, per band
+    pub dwell: [DwellPercentiles; MessagePriority::COUNT],
+}
+
+#[allow(dead_code)]
+impl QueueStats {
+    pub fn band(&self, priority: MessagePriority) -> &BandStats {
+        &self.bands[priority.index()]
+    }
+
+    pub fn dwell(&self, priority: MessagePriority) -> &DwellPercentiles {
+        &self.dwell[priority.index()]
+    }
+}
+
+/// One band as `peek_summary` saw it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BandSummary {
+    pub priority: MessagePriority,
+    pub len: usize,
+    /// Type of the band's head message (None if empty or not JSON)
+    pub head_type: Option<MessageType>,
+    pub head_seq: Option<u64>,
+}
+
+/// A queue's contents at a glance, every band highest first
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct QueueSummary {
+    pub bands: Vec<BandSummary>,
+    pub total: usize,
+}
+
+#[allow(dead_code)]
+impl QueueSummary {
+    pub fn band(&self, priority: MessagePriority) -> &BandSummary {
+        &self.bands[priority.index()]
+    }
+
+    /// The highest non-empty band: what plain `pop` would take from next
+    pub fn head(&self) -> Option<&BandSummary> {
+        self.bands.iter().find(|band| band.len > 0)
+    }
+}
+
+/// A popped message and whether it left ingest order
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScheduledMessage {
+    pub priority: MessagePriority,
+    pub msg: QueuedMessage,
+    /// Delivered ahead of an earlier-ingested message still queued, or
+    /// after a later-ingested one was already delivered
+    pub reordered: bool,
+}
+
+/// Per-peer outbound queue with one FIFO per priority band
+#[allow(dead_code)]
+#[derive(Default)]
+pub struct PriorityQueue {
+    bands: [VecDeque<QueuedMessage>; MessagePriority::COUNT],
+    retry_policy: RetryPolicy,
+    aging: AgingPolicy,
+    drops: DropMetrics,
+    dwell: [DwellHistogram; MessagePriority::COUNT],
+    /// Highest `seq` handed out by `pop_scheduled`
+    delivered_watermark: u64,
+    /// Quiet hours: how long Normal+ traffic must be idle before Low goes
+    quiet_idle_ms: Option<u64>,
+    /// When Normal+ traffic was last queued or sent (quiet hours only)
+    last_busy_ms: Option<u64>,
+    drop_hook: Option<Box<dyn FnMut(DropEvent)>>,
+}
+
+#[allow(dead_code)]
+impl PriorityQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    pub fn with_aging(mut self, aging: AgingPolicy) -> Self {
+        self.aging = aging;
+        self
+    }
+
+    /// Hold Low messages until no Normal-or-higher traffic has been
+    /// queued or sent for `idle_ms` (see the module docs)
+    pub fn with_quiet_hours(mut self, idle_ms: u64) -> Self {
+        self.quiet_idle_ms = Some(idle_ms);
+        self
+    }
+
+    /// Call `hook` for every message the queue drops
+    pub fn with_drop_hook(mut self, hook: impl FnMut(DropEvent) + 'static) -> Self {
+        self.drop_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Queue a message.
+    /// Returns true if the writer should flush immediately instead of
+    /// waiting for its coalescing delay (i.e. a Critical message arrived).
+    pub fn push(&mut self, priority: MessagePriority, msg: QueuedMessage) -> bool {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            priority = ?priority,
+            seq = msg.seq,
+            bytes = msg.payload.len(),
+            "message enqueued"
+        );
+        if self.quiet_idle_ms.is_some() && priority < MessagePriority::Low {
+            self.mark_busy(msg.enqueued_at);
+        }
+        self.bands[priority.index()].push_back(msg);
+        priority.is_critical()
+    }
+
+    /// Put a message back at the head of its band without counting a
+    /// retry (e.g. the writer would block before it was sent)
+    pub fn push_front(&mut self, priority: MessagePriority, msg: QueuedMessage) {
+        self.bands[priority.index()].push_front(msg);
+    }
+
+    /// Pop the oldest message from the highest non-empty band
+    pub fn pop(&mut self) -> Option<(MessagePriority, Vec<u8>)> {
+        self.pop_entry().map(|(p, m)| (p, m.payload))
+    }
+
+    /// Like `pop`, but only if the highest queued message is at or above
+    /// `min`; lower bands stay queued (e.g. Critical/High only while the
+    /// socket is congested)
+    pub fn pop_at_least(&mut self, min: MessagePriority) -> Option<(MessagePriority, Vec<u8>)> {
+        MessagePriority::ALL[..=min.index()]
+            .iter()
+            .find_map(|&p| self.bands[p.index()].pop_front().map(|m| (p, m.payload)))
+    }
+
+    /// Pop the oldest message of one band
+    pub fn pop_band(&mut self, priority: MessagePriority) -> Option<QueuedMessage> {
+        self.bands[priority.index()].pop_front()
+    }
+
+    pub fn band_len(&self, priority: MessagePriority) -> usize {
+        self.bands[priority.index()].len()
+    }
+
+    /// Like `pop`, but keeps the sequence number and enqueue time
+    pub fn pop_entry(&mut self) -> Option<(MessagePriority, QueuedMessage)> {
+        MessagePriority::ALL
+            .iter()
+            .find_map(|&p| self.bands[p.index()].pop_front().map(|m| (p, m)))
+    }
+
+    /// Like `pop_entry`, but in quiet hours Low stays queued while
+    /// `low_held` at `now_ms`
+    pub fn pop_released(&mut self, now_ms: u64) -> Option<(MessagePriority, QueuedMessage)> {
+        let held = self.low_held(now_ms);
+        let priority = *MessagePriority::ALL
+            .iter()
+            .find(|&&p| self.band_len(p) > 0 && !(held && p == MessagePriority::Low))?;
+        self.pop_band_at(priority, now_ms)
+            .map(|msg| (priority, msg))
+    }
+
+    /// Like `pop_band`, but in quiet hours Low stays queued while
+    /// `low_held` at `now_ms`, and popping Normal or higher counts as
+    /// traffic. Records the message's dwell time under `priority`; every
+    /// timed pop (`pop_released`, `pop_at`, the flush paths and
+    /// `RoomQueue::pop`) goes through here.
+    pub fn pop_band_at(&mut self, priority: MessagePriority, now_ms: u64) -> Option<QueuedMessage> {
+        if priority == MessagePriority::Low && self.low_held(now_ms) {
+            return None;
+        }
+        let msg = self.bands[priority.index()].pop_front()?;
+        if self.quiet_idle_ms.is_some() && priority < MessagePriority::Low {
+            self.mark_busy(now_ms);
+        }
+        self.dwell[priority.index()].record(now_ms.saturating_sub(msg.enqueued_at));
+        Some(msg)
+    }
+
+    /// Like `pop_released`, but first escalates band heads that waited
+    /// longer than their band's max wait as of `now_ms`
+    pub fn pop_at(&mut self, now_ms: u64) -> Option<(MessagePriority, QueuedMessage)> {
+        self.escalate_aged(now_ms);
+        self.pop_released(now_ms)
+    }
+
+    /// Whether quiet hours is holding Low traffic at `now_ms`: something
+    /// above Low is queued, or was queued or sent less than the idle
+    /// period ago. Always false without quiet hours.
+    pub fn low_held(&self, now_ms: u64) -> bool {
+        let Some(idle_ms) = self.quiet_idle_ms else {
+            return false;
+        };
+        self.bands[..MessagePriority::Low.index()]
+            .iter()
+            .any(|band| !band.is_empty())
+            || self
+                .last_busy_ms
+                .is_some_and(|busy| now_ms.saturating_sub(busy) < idle_ms)
+    }
+
+    fn mark_busy(&mut self, at_ms: u64) {
+        self.last_busy_ms = Some(self.last_busy_ms.map_or(at_ms, |busy| busy.max(at_ms)));
+    }
+
+    /// Dwell histogram of one band
+    pub fn dwell(&self, priority: MessagePriority) -> &DwellHistogram {
+        &self.dwell[priority.index()]
+    }
+
+    /// Like `pop_at`, but annotates whether priority scheduling moved the
+    /// message out of ingest order (for reordering-rate metrics)
+    pub fn pop_scheduled(&mut self, now_ms: u64) -> Option<ScheduledMessage> {
+        let (priority, msg) = self.pop_at(now_ms)?;
+        let overtook = self
+            .bands
+            .iter()
+            .filter_map(|band| band.front())
+            .any(|m| m.seq < msg.seq);
+        let reordered = overtook || msg.seq < self.delivered_watermark;
+        #[cfg(feature = "tracing")]
+        if reordered {
+            tracing::debug!(priority = ?priority, seq = msg.seq, "message reordered");
+        }
+        self.delivered_watermark = self.delivered_watermark.max(msg.seq);
+        Some(ScheduledMessage {
+            priority,
+            msg,
+            reordered,
+        })
+    }
+
+    /// Move overdue messages up one band, returning how many moved.
+    /// They're slotted into the higher band by enqueue time, so an aged
+    /// message goes ahead of anything that arrived after it.
+    pub fn escalate_aged(&mut self, now_ms: u64) -> usize {
+        let mut escalated = 0;
+        for &priority in &MessagePriority::ALL[1..] {
+            let Some(max_wait) = self.aging.max_wait_ms[priority.index()] else {
+                continue;
+            };
+            if priority == MessagePriority::Low && self.quiet_idle_ms.is_some() {
+                continue;
+            }
+            let target = priority.promoted().index();
+            while let Some(msg) = self.bands[priority.index()]
+                .pop_front_if(|m| now_ms.saturating_sub(m.enqueued_at) > max_wait)
+            {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(
+                    priority = ?priority,
+                    to = ?priority.promoted(),
+                    waited_ms = now_ms.saturating_sub(msg.enqueued_at),
+                    "message escalated"
+                );
+                let band = &mut self.bands[target];
+                let at = band.partition_point(|m| m.enqueued_at <= msg.enqueued_at);
+                band.insert(at, msg);
+                escalated += 1;
+            }
+        }
+        escalated
+    }
+
+    /// Hand back a message whose delivery failed.
+    /// It goes back to the head of its band (keeping order), demoted a
+    /// level every `demote_after` failures and dropped past `max_retries`.
+    pub fn requeue_failed(
+        &mut self,
+        priority: MessagePriority,
+        mut msg: QueuedMessage,
+    ) -> RetryOutcome {
+        msg.retry_count = msg.retry_count.saturating_add(1);
+
+        if msg.retry_count > self.retry_policy.max_retries {
+            self.dropped(priority, msg, DropReason::RetriesExhausted);
+            return RetryOutcome::Dropped;
+        }
+
+        let demote_after = self.retry_policy.demote_after.max(1);
+        let priority = if msg.retry_count.is_multiple_of(demote_after) {
+            priority.demoted()
+        } else {
+            priority
+        };
+
+        self.bands[priority.index()].push_front(msg);
+        RetryOutcome::Requeued(priority)
+    }
+
+    /// Messages dropped so far (retry exhaustion and shedding)
+    pub fn drops(&self) -> &DropMetrics {
+        &self.drops
+    }
+
+    /// Per-band length and oldest-message age as of `now_ms`
+    pub fn stats(&self, now_ms: u64) -> QueueStats {
+        let mut stats = QueueStats::default();
+        for (band, out) in self.bands.iter().zip(stats.bands.iter_mut()) {
+            out.len = band.len();
+            out.oldest_age_ms = band.front().map(|m| now_ms.saturating_sub(m.enqueued_at));
+        }
+        for (histogram, out) in self.dwell.iter().zip(stats.dwell.iter_mut()) {
+            *out = histogram.percentiles();
+        }
+        stats
+    }
+
+    /// Drop every queued message below `floor`, returning how many were shed
+    pub fn shed_below(&mut self, floor: MessagePriority) -> usize {
+        let mut shed = 0;
+        for &priority in &MessagePriority::ALL[floor.index() + 1..] {
+            for msg in std::mem::take(&mut self.bands[priority.index()]) {
+                self.dropped(priority, msg, DropReason::Shed);
+                shed += 1;
+            }
+        }
+        shed
+    }
+
+    /// Count a dropped message and report it to the hook
+    fn dropped(&mut self, priority: MessagePriority, msg: QueuedMessage, reason: DropReason) {
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            priority = ?priority,
+            retries = msg.retry_count,
+            reason = ?reason,
+            "message dropped"
+        );
+        self.drops.record(priority, &msg);
+        if let Some(hook) = &mut self.drop_hook {
+            hook(DropEvent {
+                priority,
+                seq: msg.seq,
+                id: message_id(&msg.payload),
+                sender: msg.sender,
+                reason,
+            });
+        }
+    }
+
+    /// Whether any Critical message is waiting
+    pub fn has_critical(&self) -> bool {
+        !self.bands[MessagePriority::Critical.index()].is_empty()
+    }
+
+    /// Message counts and head types per band, without consuming anything
+    pub fn peek_summary(&self) -> QueueSummary {
+        let bands: Vec<BandSummary> = MessagePriority::ALL
+            .iter()
+            .map(|&priority| {
+                let band = &self.bands[priority.index()];
+                let head = band.front();
+                BandSummary {
+                    priority,
+                    len: band.len(),
+                    head_type: head.and_then(|msg| {
+                        let text = std::str::from_utf8(&msg.payload).ok()?;
+                        Message::parse(text).ok().map(|m| m.msg_type)
+                    }),
+                    head_seq: head.map(|msg| msg.seq),
+                }
+            })
+            .collect();
+        QueueSummary {
+            total: bands.iter().map(|band| band.len).sum(),
+            bands,
+        }
+    }
+
+    /// Copy of the queue's messages and delivery state
+    pub fn snapshot(&self) -> QueueSnapshot {
+        QueueSnapshot {
+            bands: MessagePriority::ALL
+                .iter()
+                .filter(|p| self.band_len(**p) > 0)
+                .map(|&priority| BandSnapshot {
+                    priority,
+                    messages: self.bands[priority.index()].iter().cloned().collect(),
+                })
+                .collect(),
+            delivered_watermark: self.delivered_watermark,
+            last_busy_ms: self.last_busy_ms,
+        }
+    }
+
+    /// Replace the queue's messages and delivery state with `snapshot`'s.
+    /// A band listed twice keeps both lists, in order.
+    pub fn restore(&mut self, snapshot: QueueSnapshot) {
+        for band in &mut self.bands {
+            band.clear();
+        }
+        for band in snapshot.bands {
+            self.bands[band.priority.index()]
+                .extend(band.messages.into_iter().map(QueuedMessage::parsed));
+        }
+        self.delivered_watermark = snapshot.delivered_watermark;
+        self.last_busy_ms = snapshot.last_busy_ms;
+    }
+
+    pub fn len(&self) -> usize {
+        self.bands.iter().map(|b| b.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bands.iter().all(|b| b.is_empty())
+    }
+}
+
+/// How RoomQueue picks among peers with traffic in the same band
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SchedulerOrdering {
+    /// Weighted turns, in the order peers were first seen
+    #[default]
+    RoundRobin,
+    /// Lowest peer id first, each peer's messages in queue order (by
+    /// ingest seq). Ignores weights and can starve higher ids; meant for
+    /// tests that assert on the exact interleaving.
+    Deterministic,
+}
+
+/// One peer's queue and scheduling weight within a room
+struct RoomPeer<P> {
+    peer_id: P,
+    weight: u32,
+    queue: PriorityQueue,
+}
+
+/// Outbound queues for every peer in a room, popped round-robin.
+///
+/// Weights only affect scheduling among peers in the same priority band:
+/// a weight-3 peer gets three pops for every one of a weight-1 peer while
+/// both have messages in that band, but a Critical message from any peer
+/// still goes before everyone's High traffic.
+#[allow(dead_code)]
+pub struct RoomQueue<P = String> {
+    peers: Vec<RoomPeer<P>>,
+    /// Per band: index of the peer whose turn it is
+    cursor: [usize; MessagePriority::COUNT],
+    /// Per band: pops left in the current peer's turn (None = turn not started)
+    credit: [Option<u32>; MessagePriority::COUNT],
+    /// Peer id order under `SchedulerOrdering::Deterministic`
+    sorted_by: Option<fn(&P, &P) -> Ordering>,
+    /// Quiet hours for every peer queue (see `with_quiet_hours`)
+    quiet_idle_ms: Option<u64>,
+}
+
+impl<P> Default for RoomQueue<P> {
+    fn default() -> Self {
+        Self {
+            peers: Vec::new(),
+            cursor: [0; MessagePriority::COUNT],
+            credit: [None; MessagePriority::COUNT],
+            sorted_by: None,
+            quiet_idle_ms: None,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<P: Ord> RoomQueue<P> {
+    pub fn with_ordering(mut self, ordering: SchedulerOrdering) -> Self {
+        self.sorted_by = match ordering {
+            SchedulerOrdering::RoundRobin => None,
+            SchedulerOrdering::Deterministic => Some(P::cmp),
+        };
+        self
+    }
+}
+
+#[allow(dead_code)]
+impl<P: Eq + Hash + Clone> RoomQueue<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run every peer's queue in quiet hours: a peer's Low traffic waits
+    /// until its own Normal-or-higher traffic has been idle for `idle_ms`
+    pub fn with_quiet_hours(mut self, idle_ms: u64) -> Self {
+        self.quiet_idle_ms = Some(idle_ms);
+        for peer in &mut self.peers {
+            peer.queue.quiet_idle_ms = Some(idle_ms);
+        }
+        self
+    }
+
+    fn peer_index<Q>(&mut self, peer_id: &Q) -> usize
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq + ToOwned<Owned = P>,
+    {
+        match self.position(peer_id) {
+            Some(i) => i,
+            None => {
+                let queue = match self.quiet_idle_ms {
+                    Some(idle_ms) => PriorityQueue::new().with_quiet_hours(idle_ms),
+                    None => PriorityQueue::new(),
+                };
+                self.peers.push(RoomPeer {
+                    peer_id: peer_id.to_owned(),
+                    weight: 1,
+                    queue,
+                });
+                self.peers.len() - 1
+            }
+        }
+    }
+
+    fn position<Q>(&self, peer_id: &Q) -> Option<usize>
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.peers
+            .iter()
+            .position(|p| p.peer_id.borrow() == peer_id)
+    }
+
+    /// Set a peer's share of same-priority pops (default 1, minimum 1)
+    pub fn set_weight<Q>(&mut self, peer_id: &Q, weight: u32)
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq + ToOwned<Owned = P>,
+    {
+        let i = self.peer_index(peer_id);
+        self.peers[i].weight = weight.max(1);
+    }
+
+    pub fn weight<Q>(&self, peer_id: &Q) -> Option<u32>
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.position(peer_id).map(|i| self.peers[i].weight)
+    }
+
+    /// Queue a message for a peer; returns true if it was Critical
+    pub fn push<Q>(&mut self, peer_id: &Q, priority: MessagePriority, msg: QueuedMessage) -> bool
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq + ToOwned<Owned = P>,
+    {
+        let i = self.peer_index(peer_id);
+        self.peers[i].queue.push(priority, msg)
+    }
+
+    /// Drop a peer and its backlog
+    pub fn remove_peer<Q>(&mut self, peer_id: &Q) -> Option<PriorityQueue>
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let i = self.position(peer_id)?;
+        let peer = self.peers.remove(i);
+        for (cursor, credit) in self.cursor.iter_mut().zip(self.credit.iter_mut()) {
+            if *cursor > i {
+                *cursor -= 1;
+            } else if *cursor == i {
+                *credit = None;
+            }
+        }
+        Some(peer.queue)
+    }
+
+    /// Pop from the highest non-empty band, taking turns across peers,
+    /// after escalating each peer's overdue messages as of `now_ms`. A
+    /// peer's Low band counts as empty while its queue's quiet hours hold
+    /// it at `now_ms`.
+    pub fn pop(&mut self, now_ms: u64) -> Option<(P, MessagePriority, QueuedMessage)> {
+        for peer in &mut self.peers {
+            peer.queue.escalate_aged(now_ms);
+        }
+        let ready = |queue: &PriorityQueue, p: MessagePriority| {
+            queue.band_len(p) > 0 && !(p == MessagePriority::Low && queue.low_held(now_ms))
+        };
+        let priority = *MessagePriority::ALL
+            .iter()
+            .find(|&&p| self.peers.iter().any(|peer| ready(&peer.queue, p)))?;
+        if let Some(cmp) = self.sorted_by {
+            let peer = self
+                .peers
+                .iter_mut()
+                .filter(|peer| ready(&peer.queue, priority))
+                .min_by(|a, b| cmp(&a.peer_id, &b.peer_id))?;
+            let msg = peer.queue.pop_band_at(priority, now_ms)?;
+            return Some((peer.peer_id.clone(), priority, msg));
+        }
+        let b = priority.index();
+        let n = self.peers.len();
+
+        // At most one full lap before reaching a peer with traffic
+        for _ in 0..=n {
+            let i = self.cursor[b] % n;
+            let peer = &mut self.peers[i];
+            let credit = self.credit[b].get_or_insert(peer.weight);
+            if let Some(msg) = peer.queue.pop_band_at(priority, now_ms) {
+                *credit -= 1;
+                let peer_id = peer.peer_id.clone();
+                if *credit == 0 {
+                    self.cursor[b] = (i + 1) % n;
+                    self.credit[b] = None;
+                }
+                return Some((peer_id, priority, msg));
+            }
+            self.cursor[b] = (i + 1) % n;
+            self.credit[b] = None;
+        }
+        None
+    }
+
+    pub fn ordering(&self) -> SchedulerOrdering {
+        match self.sorted_by {
+            Some(_) => SchedulerOrdering::Deterministic,
+            None => SchedulerOrdering::RoundRobin,
+        }
+    }
+
+    /// Messages waiting for one peer (0 for a peer never queued to)
+    pub fn peer_len<Q>(&self, peer_id: &Q) -> usize
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.position(peer_id)
+            .map_or(0, |i| self.peers[i].queue.len())
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.iter().map(|p| p.queue.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.iter().all(|p| p.queue.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(payload: &[u8], enqueued_at: u64) -> QueuedMessage {
+        QueuedMessage::new(payload.to_vec(), 0, enqueued_at)
+    }
+
+    #[test]
+    fn test_pop_order() {
+        let mut queue = PriorityQueue::new();
+        queue.push(MessagePriority::Low, msg(b"low", 0));
+        queue.push(MessagePriority::Normal, msg(b"normal-1", 0));
+        queue.push(MessagePriority::Critical, msg(b"critical", 0));
+        queue.push(MessagePriority::Normal, msg(b"normal-2", 0));
+
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop()).map(|(_, m)| m).collect();
+        assert_eq!(
+            order,
+            vec![
+                b"critical".to_vec(),
+                b"normal-1".to_vec(),
+                b"normal-2".to_vec(),
+                b"low".to_vec()
+            ]
+        );
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_restore_round_trip() {
+        let mut primary = PriorityQueue::new().with_aging(AgingPolicy::disabled());
+        primary.push(MessagePriority::Low, msg(b"low", 5));
+        primary.push(
+            MessagePriority::Critical,
+            msg(b"key_exchange", 10).with_sender("alice"),
+        );
+        primary.push(MessagePriority::Normal, msg(&[0xff, 0x00, 0x7f], 20));
+        primary.push(MessagePriority::High, msg(b"peer_join", 30));
+        primary.push(MessagePriority::Critical, msg(b"auth", 40));
+        let mut failed = msg(b"retried", 1);
+        failed.retry_count = 1;
+        primary.push_front(MessagePriority::Normal, failed);
+        primary.pop_scheduled(50);
+
+        // Over the wire to the standby
+        let json = serde_json::to_string(&primary.snapshot()).unwrap();
+        let snapshot: QueueSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot, primary.snapshot());
+        let mut standby = PriorityQueue::new().with_aging(AgingPolicy::disabled());
+        standby.restore(snapshot);
+        assert_eq!(standby.len(), 5);
+
+        let drain = |queue: &mut PriorityQueue| {
+            std::iter::from_fn(|| queue.pop_at(1_000_000)).collect::<Vec<_>>()
+        };
+        let moved = drain(&mut standby);
+        assert_eq!(moved, drain(&mut primary));
+        assert_eq!(moved[0].1.payload, b"auth");
+        assert_eq!(moved[1].0, MessagePriority::High);
+        assert_eq!(moved[2].1.retry_count, 1);
+        assert_eq!(moved[3].1.enqueued_at, 20);
+
+        // Restoring replaces what was there
+        standby.push(MessagePriority::Low, msg(b"stale", 0));
+        standby.restore(QueueSnapshot::default());
+        assert!(standby.is_empty());
+    }
+
+    #[test]
+    fn test_peek_summary_does_not_consume() {
+        let mut queue = PriorityQueue::new();
+        assert_eq!(queue.peek_summary().head(), None);
+        let mut push = |priority, text: &str, seq| {
+            queue.push(
+                priority,
+                QueuedMessage::new(text.as_bytes().to_vec(), seq, 0),
+            );
+        };
+        push(MessagePriority::Normal, r#"{"type":"chat","msg":"a"}"#, 1);
+        push(MessagePriority::High, r#"{"type":"peer_join"}"#, 2);
+        push(MessagePriority::Normal, r#"{"type":"data"}"#, 3);
+        push(MessagePriority::Low, "not json", 4);
+        push(MessagePriority::Low, r#"{"type":"ping"}"#, 5);
+
+        let summary = queue.peek_summary();
+        assert_eq!(summary, queue.peek_summary());
+        assert_eq!(summary.total, 5);
+        assert_eq!(summary.band(MessagePriority::Critical).len, 0);
+        assert_eq!(
+            summary.head(),
+            Some(&BandSummary {
+                priority: MessagePriority::High,
+                len: 1,
+                head_type: Some(MessageType::PeerJoin),
+                head_seq: Some(2),
+            })
+        );
+        let normal = summary.band(MessagePriority::Normal);
+        assert_eq!((normal.len, normal.head_type), (2, Some(MessageType::Chat)));
+        let low = summary.band(MessagePriority::Low);
+        assert_eq!((low.len, low.head_type, low.head_seq), (2, None, Some(4)));
+
+        // Nothing moved: the queue pops exactly as before
+        let order: Vec<u64> = std::iter::from_fn(|| queue.pop_entry())
+            .map(|(_, m)| m.seq)
+            .collect();
+        assert_eq!(order, [2, 1, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_flush_signal_only_for_critical() {
+        let mut queue = PriorityQueue::new();
+        assert!(!queue.push(MessagePriority::High, msg(&[1], 0)));
+        assert!(!queue.push(MessagePriority::Normal, msg(&[2], 0)));
+        assert!(!queue.push(MessagePriority::Low, msg(&[3], 0)));
+        assert!(!queue.has_critical());
+
+        assert!(queue.push(MessagePriority::Critical, msg(&[0], 0)));
+        assert!(queue.has_critical());
+
+        queue.pop();
+        assert!(!queue.has_critical());
+        assert_eq!(queue.len(), 3);
+    }
+
+    #[test]
+    fn test_stats_oldest_age() {
+        let mut queue = PriorityQueue::new();
+        queue.push(MessagePriority::Critical, msg(&[0], 1_000));
+        queue.push(MessagePriority::Critical, msg(&[1], 1_200));
+        queue.push(MessagePriority::Low, msg(&[2], 1_100));
+
+        let stats = queue.stats(1_250);
+        assert_eq!(
+            *stats.band(MessagePriority::Critical),
+            BandStats {
+                len: 2,
+                oldest_age_ms: Some(250)
+            }
+        );
+        assert_eq!(stats.band(MessagePriority::Low).oldest_age_ms, Some(150));
+        assert_eq!(*stats.band(MessagePriority::Normal), BandStats::default());
+
+        // Oldest age tracks the new head once the first message is sent
+        queue.pop();
+        assert_eq!(
+            queue
+                .stats(1_250)
+                .band(MessagePriority::Critical)
+                .oldest_age_ms,
+            Some(50)
+        );
+    }
+
+    #[test]
+    fn test_dwell_per_priority() {
+        let mut queue = PriorityQueue::new().with_aging(AgingPolicy::disabled());
+        for (p, at) in [
+            (MessagePriority::Critical, 995),
+            (MessagePriority::Low, 0),
+            (MessagePriority::Low, 900),
+            (MessagePriority::Low, 990),
+        ] {
+            queue.push(p, msg(&[], at));
+        }
+        while queue.pop_at(1_000).is_some() {}
+
+        let critical = queue.dwell(MessagePriority::Critical).counts();
+        assert_eq!(critical[3], 1); // 5ms
+        let low = queue.dwell(MessagePriority::Low).counts();
+        assert_eq!((low[4], low[7], low[10]), (1, 1, 1)); // 10, 100, 1000ms
+
+        let stats = queue.stats(1_000);
+        assert_eq!(stats.dwell(MessagePriority::Critical).p99, Some(7));
+        assert_eq!(stats.dwell(MessagePriority::Low).p50, Some(127));
+        assert_eq!(stats.dwell(MessagePriority::Normal).p50, None);
+    }
+
+    #[test]
+    fn test_shed_below() {
+        let mut queue = PriorityQueue::new();
+        for p in MessagePriority::ALL {
+            queue.push(p, msg(&[p as u8], 0));
+            queue.push(p, msg(&[p as u8], 0));
+        }
+
+        assert_eq!(queue.shed_below(MessagePriority::Critical), 6);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.drops().total(), 6);
+        assert!(queue.has_critical());
+        assert_eq!(queue.shed_below(MessagePriority::Low), 0);
+    }
+
+    #[test]
+    fn test_pop_at_least_keeps_lower_bands() {
+        let mut queue = PriorityQueue::new();
+        queue.push(MessagePriority::Low, msg(b"ping", 0));
+        queue.push(MessagePriority::Normal, msg(b"chat", 0));
+        queue.push(MessagePriority::High, msg(b"join", 0));
+
+        assert_eq!(
+            queue.pop_at_least(MessagePriority::High),
+            Some((MessagePriority::High, b"join".to_vec()))
+        );
+        assert_eq!(queue.pop_at_least(MessagePriority::High), None);
+        assert_eq!(queue.len(), 2);
+
+        assert_eq!(
+            queue.pop_at_least(MessagePriority::Low),
+            Some((MessagePriority::Normal, b"chat".to_vec()))
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_dropped_low_emits_event() {
+        let events = crate::trace_recorder::record(|| {
+            let _peer = peer_span("room-1", "p1").entered();
+            let mut queue = PriorityQueue::new();
+            queue.push(MessagePriority::Low, msg(b"ping", 0));
+            queue.shed_below(MessagePriority::Normal);
+        });
+
+        let dropped: Vec<_> = events
+            .iter()
+            .filter(|e| e.message() == Some("message dropped"))
+            .collect();
+        assert_eq!(dropped.len(), 1);
+        for field in ["peer=\"p1\"", "priority=Low", "reason=Shed"] {
+            assert!(dropped[0].has(field), "{:?}", dropped[0]);
+        }
+    }
+
+    #[test]
+    fn test_eviction_reports_drop_events() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let sink = events.clone();
+        let mut queue = PriorityQueue::new().with_drop_hook(move |e| sink.borrow_mut().push(e));
+
+        let from =
+            |payload: &str, seq, peer| QueuedMessage::new(payload.into(), seq, 0).with_sender(peer);
+        queue.push(
+            MessagePriority::Normal,
+            from(r#"{"type":"chat","id":"m1"}"#, 1, "alice"),
+        );
+        queue.push(
+            MessagePriority::Low,
+            from(r#"{"type":"stats","id":7}"#, 2, "bob"),
+        );
+        queue.push(MessagePriority::Low, from(r#"{"type":"ping"}"#, 3, "alice"));
+        queue.push(
+            MessagePriority::High,
+            from(r#"{"type":"peer_join","id":"j"}"#, 4, "alice"),
+        );
+
+        assert_eq!(queue.shed_below(MessagePriority::High), 3);
+        let events = events.take();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].id, Some(serde_json::json!("m1")));
+        assert_eq!(events[0].reason, DropReason::Shed);
+        assert_eq!(events[0].priority, MessagePriority::Normal);
+
+        // One notice per sender
+        let notices = DroppedNotice::batch(events.iter().cloned());
+        assert_eq!(notices.len(), 2);
+        assert_eq!(notices[0].0, "alice");
+        assert_eq!(
+            notices[0].1.to_json(),
+            r#"{"type":"dropped","drops":[{"id":"m1","reason":"shed"},{"reason":"shed"}]}"#
+        );
+        assert_eq!(
+            notices[1].1.to_json(),
+            r#"{"type":"dropped","drops":[{"id":7,"reason":"shed"}]}"#
+        );
+    }
+
+    #[test]
+    fn test_ingest_order_recoverable() {
+        let mut queue = PriorityQueue::new();
+        queue.push(MessagePriority::Low, QueuedMessage::new(vec![], 1, 0));
+        queue.push(MessagePriority::Normal, QueuedMessage::new(vec![], 2, 0));
+        queue.push(MessagePriority::Critical, QueuedMessage::new(vec![], 3, 0));
+
+        let delivered: Vec<u64> = std::iter::from_fn(|| queue.pop_entry())
+            .map(|(_, m)| m.seq)
+            .collect();
+        assert_eq!(delivered, vec![3, 2, 1]);
+
+        let mut ingest = delivered.clone();
+        ingest.sort_unstable();
+        assert_eq!(ingest, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_reordered_flag() {
+        let mut queue = PriorityQueue::new();
+        queue.push(
+            MessagePriority::Normal,
+            QueuedMessage::new(b"n".to_vec(), 1, 0),
+        );
+        queue.push(
+            MessagePriority::Critical,
+            QueuedMessage::new(b"c".to_vec(), 2, 0),
+        );
+        queue.push(
+            MessagePriority::Normal,
+            QueuedMessage::new(b"n2".to_vec(), 3, 0),
+        );
+
+        let flags: Vec<_> = std::iter::from_fn(|| queue.pop_scheduled(0))
+            .map(|s| (s.msg.seq, s.reordered))
+            .collect();
+        // Critical overtook seq 1; seq 1 then arrived after seq 2
+        assert_eq!(flags, vec![(2, true), (1, true), (3, false)]);
+
+        // Ingest order preserved: nothing flagged
+        for seq in 4..8 {
+            queue.push(MessagePriority::High, QueuedMessage::new(vec![], seq, 0));
+        }
+        assert!(std::iter::from_fn(|| queue.pop_scheduled(0)).all(|s| !s.reordered));
+    }
+
+    #[test]
+    fn test_retry_demotion_and_drop() {
+        let mut queue = PriorityQueue::new().with_retry_policy(RetryPolicy {
+            demote_after: 2,
+            max_retries: 4,
+        });
+        queue.push(MessagePriority::Critical, msg(b"stuck", 0));
+
+        let mut outcomes = Vec::new();
+        while let Some((priority, entry)) = queue.pop_entry() {
+            outcomes.push(queue.requeue_failed(priority, entry));
+        }
+
+        assert_eq!(
+            outcomes,
+            vec![
+                RetryOutcome::Requeued(MessagePriority::Critical),
+                RetryOutcome::Requeued(MessagePriority::High),
+                RetryOutcome::Requeued(MessagePriority::High),
+                RetryOutcome::Requeued(MessagePriority::Normal),
+                RetryOutcome::Dropped,
+            ]
+        );
+        assert_eq!(queue.drops().dropped[MessagePriority::Normal.index()], 1);
+        assert_eq!(queue.drops().retries, 5);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_aged_normal_escalates() {
+        let mut queue = PriorityQueue::new();
+        queue.push(MessagePriority::Normal, msg(b"normal", 0));
+
+        // Not yet overdue: the High message wins
+        queue.push(MessagePriority::High, msg(b"high-1", 100));
+        assert_eq!(queue.pop_at(150).unwrap().1.payload, b"high-1".to_vec());
+
+        queue.push(MessagePriority::High, msg(b"high-2", 250));
+        let (priority, entry) = queue.pop_at(250).unwrap();
+        assert_eq!(priority, MessagePriority::High);
+        assert_eq!(entry.payload, b"normal".to_vec());
+        assert_eq!(queue.pop_at(250).unwrap().1.payload, b"high-2".to_vec());
+    }
+
+    #[test]
+    fn test_quiet_hours_defers_low() {
+        let mut queue = PriorityQueue::new().with_quiet_hours(500);
+        queue.push(MessagePriority::Low, msg(b"stats", 0));
+        queue.push(MessagePriority::Normal, msg(b"chat-1", 0));
+
+        assert_eq!(queue.pop_at(0).unwrap().1.payload, b"chat-1".to_vec());
+        // Normal traffic just flowed, so Low waits out the idle gap
+        assert!(queue.low_held(100));
+        assert_eq!(queue.pop_at(100), None);
+
+        queue.push(MessagePriority::Normal, msg(b"chat-2", 300));
+        assert_eq!(queue.pop_at(300).unwrap().1.payload, b"chat-2".to_vec());
+        assert_eq!(queue.pop_at(799), None);
+
+        // High and Critical aren't held, but do count as traffic
+        queue.push(MessagePriority::High, msg(b"peer_join", 700));
+        assert_eq!(queue.pop_at(700).unwrap().1.payload, b"peer_join".to_vec());
+        assert_eq!(queue.pop_at(1_100), None);
+
+        // Idle for 500ms: Low goes out, still Low despite its 1.2s wait
+        queue.push(MessagePriority::Low, msg(b"heartbeat", 900));
+        let released: Vec<_> = std::iter::from_fn(|| queue.pop_at(1_200)).collect();
+        assert_eq!(
+            released,
+            vec![
+                (MessagePriority::Low, msg(b"stats", 0)),
+                (MessagePriority::Low, msg(b"heartbeat", 900)),
+            ]
+        );
+
+        // Without quiet hours Low goes out as soon as nothing is ahead of it
+        let mut queue = PriorityQueue::new();
+        queue.push(MessagePriority::Normal, msg(b"chat", 0));
+        queue.push(MessagePriority::Low, msg(b"stats", 0));
+        queue.pop_at(0);
+        assert!(!queue.low_held(0));
+        assert!(queue.pop_at(0).is_some());
+    }
+
+    #[test]
+    fn test_aging_thresholds_configurable() {
+        let aging = AgingPolicy::disabled().with_max_wait(MessagePriority::Low, Some(50));
+        let mut queue = PriorityQueue::new().with_aging(aging);
+        queue.push(MessagePriority::Normal, msg(b"normal", 0));
+        queue.push(MessagePriority::Low, msg(b"low", 0));
+
+        // Low escalates to Normal but never further, and Normal doesn't age
+        assert_eq!(queue.escalate_aged(10_000), 1);
+        assert_eq!(queue.escalate_aged(20_000), 0);
+        assert_eq!(queue.stats(20_000).band(MessagePriority::Normal).len, 2);
+
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop_at(20_000))
+            .map(|(p, m)| (p, m.payload))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (MessagePriority::Normal, b"normal".to_vec()),
+                (MessagePriority::Normal, b"low".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_room_queue_deterministic_order() {
+        let fill = |room: &mut RoomQueue| {
+            room.push("zed", MessagePriority::Normal, msg(b"z", 0));
+            room.push("amy", MessagePriority::Normal, msg(b"a", 0));
+        };
+        let drain = |room: &mut RoomQueue| {
+            std::iter::from_fn(|| room.pop(0).map(|(peer, _, _)| peer)).collect::<Vec<_>>()
+        };
+
+        // Round-robin goes in first-seen order
+        let mut room = RoomQueue::new();
+        fill(&mut room);
+        assert_eq!(drain(&mut room), ["zed", "amy"]);
+
+        let mut room = RoomQueue::new().with_ordering(SchedulerOrdering::Deterministic);
+        assert_eq!(room.ordering(), SchedulerOrdering::Deterministic);
+        fill(&mut room);
+        assert_eq!(drain(&mut room), ["amy", "zed"]);
+
+        // Bands still come first; ids only order peers within one
+        fill(&mut room);
+        room.push("zed", MessagePriority::High, msg(b"z-high", 0));
+        assert_eq!(drain(&mut room), ["zed", "amy", "zed"]);
+    }
+
+    #[test]
+    fn test_room_queue_weighted_share() {
+        let mut room = RoomQueue::new();
+        room.set_weight("premium", 3);
+        assert_eq!(room.weight("basic"), None);
+        for i in 0..400 {
+            room.push("premium", MessagePriority::Normal, msg(&[], i));
+            room.push("basic", MessagePriority::Normal, msg(&[], i));
+        }
+        assert_eq!(room.weight("basic"), Some(1));
+
+        let mut premium = 0;
+        for _ in 0..400 {
+            if room.pop(0).unwrap().0 == "premium" {
+                premium += 1;
+            }
+        }
+        assert_eq!(premium, 300);
+
+        // Premium drained first; basic gets every remaining turn
+        assert_eq!(room.len(), 400);
+        let rest: Vec<_> = std::iter::from_fn(|| room.pop(0))
+            .map(|(p, ..)| p)
+            .collect();
+        assert_eq!(rest.iter().filter(|p| *p == "basic").count(), 300);
+    }
+
+    #[test]
+    fn test_room_queue_weights_within_priority_only() {
+        let mut room = RoomQueue::new();
+        room.set_weight("premium", 3);
+        room.push("premium", MessagePriority::High, msg(b"p-high", 0));
+        room.push("basic", MessagePriority::Low, msg(b"b-low", 0));
+        assert!(room.push("basic", MessagePriority::Critical, msg(b"b-crit", 0)));
+
+        let order: Vec<_> = std::iter::from_fn(|| room.pop(0))
+            .map(|(peer, _, m)| (peer, m.payload))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("basic".to_string(), b"b-crit".to_vec()),
+                ("premium".to_string(), b"p-high".to_vec()),
+                ("basic".to_string(), b"b-low".to_vec()),
+            ]
+        );
+
+        room.push("basic", MessagePriority::Low, msg(b"gone", 0));
+        assert_eq!(room.remove_peer("basic").unwrap().len(), 1);
+        assert!(room.is_empty());
+    }
+
+    #[test]
+    fn test_room_queue_binary_peer_ids() {
+        let alice = [0xaa; 32];
+        let bob = [0xbb; 32];
+        let mut room: RoomQueue<[u8; 32]> = RoomQueue::new();
+        room.set_weight(&alice, 2);
+        for i in 0..3u8 {
+            room.push(&alice, MessagePriority::Normal, msg(&[i], 0));
+            room.push(&bob, MessagePriority::Normal, msg(&[i], 0));
+        }
+
+        let order: Vec<[u8; 32]> =
+            std::iter::from_fn(|| room.pop(0).map(|(peer, _, _)| peer)).collect();
+        assert_eq!(order, vec![alice, alice, bob, alice, bob, bob]);
+        assert_eq!(room.weight(&bob), Some(1));
+        assert!(room.remove_peer(&alice).is_some());
+    }
+
+    #[test]
+    fn test_room_queue_quiet_hours() {
+        let mut room = RoomQueue::new().with_quiet_hours(500);
+        room.push("alice", MessagePriority::Low, msg(b"stats-a", 0));
+        room.push("alice", MessagePriority::Normal, msg(b"chat", 0));
+        room.push("bob", MessagePriority::Low, msg(b"stats-b", 0));
+
+        // Bob's queue has been quiet, alice's Low waits out her own chat
+        let (peer, _, m) = room.pop(0).unwrap();
+        assert_eq!((peer.as_str(), m.payload), ("alice", b"chat".to_vec()));
+        assert_eq!(room.pop(100).unwrap().0, "bob");
+        assert_eq!(room.pop(499), None);
+        assert_eq!(room.pop(500).unwrap().2.payload, b"stats-a".to_vec());
+        assert!(room.is_empty());
+    }
+
+    #[test]
+    fn test_room_queue_ages_peer_queues() {
+        let mut room = RoomQueue::new();
+        room.push("alice", MessagePriority::Normal, msg(b"chat", 0));
+        room.push("bob", MessagePriority::High, msg(b"join-1", 100));
+        room.push("bob", MessagePriority::High, msg(b"join-2", 300));
+
+        assert_eq!(room.pop(150).unwrap().2.payload, b"join-1".to_vec());
+        // 300ms in, alice's chat is overdue and goes as High
+        let (peer, priority, m) = room.pop(300).unwrap();
+        assert_eq!((peer.as_str(), priority), ("alice", MessagePriority::High));
+        assert_eq!(m.payload, b"chat".to_vec());
+    }
+}
@@ -0,0 +1,482 @@
+//! Per-peer budgets for the Critical fast path
+//!
+//! Critical messages skip compression and jump every queue, which makes
+//! them attractive to a misbehaving peer: flooding `auth_init` would
+//! starve everyone else. Each peer gets a token bucket of Critical sends,
+//! separate from any general rate limiting; once it's empty, further
+//! Critical messages from that peer are demoted to High until it refills.
+//! A normal handshake uses a handful of tokens and never notices.
+//!
+//! RateLimiter is the general limit on everything else. Each peer has its
+//! own bucket, and the room has one shared by all its peers, so a group of
+//! peers each under its own limit still can't flood the relay together.
+//! While the room bucket is empty, non-Critical messages are throttled
+//! room-wide and the first one throttled carries a `room_rate_limited`
+//! notice for the relay to broadcast.
+//!
+//! By default a bucket counts messages. With `RateCost::Bytes` it counts
+//! wire bytes instead (after compression, which is what the link carries),
+//! and `try_acquire` charges each message its size. A peer sending many
+//! tiny messages and one sending a few large ones are then limited by the
+//! same measure.
+
+use crate::message_optimizer::MessagePriority;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Token bucket refilled continuously at `refill_per_sec`
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    refill_per_sec: f64,
+    tokens: f64,
+    last_refill_ms: u64,
+}
+
+#[allow(dead_code)]
+impl TokenBucket {
+    /// Starts full
+    pub fn new(capacity: u32, refill_per_sec: f64, now_ms: u64) -> Self {
+        Self {
+            capacity: f64::from(capacity),
+            refill_per_sec,
+            tokens: f64::from(capacity),
+            last_refill_ms: now_ms,
+        }
+    }
+
+    /// Take one token if available
+    pub fn try_take(&mut self, now_ms: u64) -> bool {
+        self.try_take_n(1, now_ms)
+    }
+
+    /// Take `cost` tokens if available. A cost above the capacity is
+    /// charged as the capacity, so it passes once the bucket is full.
+    pub fn try_take_n(&mut self, cost: u32, now_ms: u64) -> bool {
+        self.tokens = self.available(now_ms);
+        self.last_refill_ms = now_ms.max(self.last_refill_ms);
+
+        let cost = self.clamp_cost(cost);
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn clamp_cost(&self, cost: u32) -> f64 {
+        f64::from(cost).min(self.capacity)
+    }
+
+    /// Tokens (possibly fractional) the bucket holds as of `now_ms`
+    pub fn available(&self, now_ms: u64) -> f64 {
+        let elapsed_ms = now_ms.saturating_sub(self.last_refill_ms);
+        (self.tokens + elapsed_ms as f64 * self.refill_per_sec / 1000.0).min(self.capacity)
+    }
+
+    /// Whole tokens left as of `now_ms`
+    pub fn remaining(&self, now_ms: u64) -> u32 {
+        self.available(now_ms) as u32
+    }
+
+    /// Time until the next whole token, 0 if one is available
+    pub fn retry_after_ms(&self, now_ms: u64) -> u64 {
+        self.retry_after_n_ms(1, now_ms)
+    }
+
+    /// Time until `cost` tokens are available, 0 if they are now
+    pub fn retry_after_n_ms(&self, cost: u32, now_ms: u64) -> u64 {
+        let missing = self.clamp_cost(cost) - self.available(now_ms);
+        if missing <= 0.0 {
+            0
+        } else if self.refill_per_sec <= 0.0 {
+            u64::MAX
+        } else {
+            (missing * 1000.0 / self.refill_per_sec).ceil() as u64
+        }
+    }
+}
+
+/// Burst size and sustained rate of a token bucket, in messages or bytes
+/// per the limiter's RateCost
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub refill_per_sec: f64,
+}
+
+#[allow(dead_code)]
+impl RateLimit {
+    pub fn new(burst: u32, refill_per_sec: f64) -> Self {
+        Self {
+            burst,
+            refill_per_sec,
+        }
+    }
+
+    fn bucket(self, now_ms: u64) -> TokenBucket {
+        TokenBucket::new(self.burst, self.refill_per_sec, now_ms)
+    }
+}
+
+/// What a RateLimiter token stands for
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RateCost {
+    /// One token per message, whatever its size
+    #[default]
+    Messages,
+    /// One token per wire byte; limits are in bytes and bytes/sec
+    Bytes,
+}
+
+/// Sent to a room's peers when its shared budget runs out
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename = "room_rate_limited")]
+pub struct RoomRateLimited {
+    pub retry_after_ms: u64,
+}
+
+#[allow(dead_code)]
+impl RoomRateLimited {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Outcome of `RateLimiter::check`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RateDecision {
+    Allowed,
+    /// The sender is over its own limit
+    PeerLimited {
+        retry_after_ms: u64,
+    },
+    /// The room as a whole is over its limit; `notice` is set on the first
+    /// message throttled since the room last had budget
+    RoomLimited {
+        retry_after_ms: u64,
+        notice: Option<RoomRateLimited>,
+    },
+}
+
+#[allow(dead_code)]
+impl RateDecision {
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, RateDecision::Allowed)
+    }
+}
+
+/// Message rate limits for one room: a bucket per peer plus one shared by
+/// the whole room. Critical traffic is exempt (see `CriticalQuota`).
+#[allow(dead_code)]
+pub struct RateLimiter {
+    cost: RateCost,
+    peer_limit: RateLimit,
+    peers: HashMap<String, TokenBucket>,
+    room: TokenBucket,
+    room_limited: bool,
+}
+
+#[allow(dead_code)]
+impl RateLimiter {
+    pub fn new(peer_limit: RateLimit, room_limit: RateLimit, now_ms: u64) -> Self {
+        Self {
+            cost: RateCost::Messages,
+            peer_limit,
+            peers: HashMap::new(),
+            room: room_limit.bucket(now_ms),
+            room_limited: false,
+        }
+    }
+
+    /// Count tokens in `cost` units; the limits are read in that unit
+    pub fn with_cost(mut self, cost: RateCost) -> Self {
+        self.cost = cost;
+        self
+    }
+
+    /// Charge a message from `peer_id` against both buckets. Nothing is
+    /// taken from either unless both have a token. The size isn't known
+    /// here, so under `RateCost::Bytes` this charges a single byte; use
+    /// `try_acquire` there.
+    pub fn check(&mut self, peer_id: &str, priority: MessagePriority, now_ms: u64) -> RateDecision {
+        self.try_acquire(peer_id, priority, 1, now_ms)
+    }
+
+    /// `check` for a message of `wire_bytes` (its size after compression).
+    /// Under `RateCost::Bytes` it costs that many tokens, otherwise one.
+    /// A message larger than a bucket's burst passes once that bucket is
+    /// full, and empties it.
+    pub fn try_acquire(
+        &mut self,
+        peer_id: &str,
+        priority: MessagePriority,
+        wire_bytes: usize,
+        now_ms: u64,
+    ) -> RateDecision {
+        if priority.is_critical() {
+            return RateDecision::Allowed;
+        }
+        let cost = match self.cost {
+            RateCost::Messages => 1,
+            RateCost::Bytes => u32::try_from(wire_bytes).unwrap_or(u32::MAX),
+        };
+        let peer_limit = self.peer_limit;
+        let peer = self
+            .peers
+            .entry(peer_id.to_string())
+            .or_insert_with(|| peer_limit.bucket(now_ms));
+
+        if peer.retry_after_n_ms(cost, now_ms) > 0 {
+            return RateDecision::PeerLimited {
+                retry_after_ms: peer.retry_after_n_ms(cost, now_ms),
+            };
+        }
+        if !self.room.try_take_n(cost, now_ms) {
+            let retry_after_ms = self.room.retry_after_n_ms(cost, now_ms);
+            let notice = (!self.room_limited).then_some(RoomRateLimited { retry_after_ms });
+            if notice.is_some() {
+                #[cfg(feature = "tracing")]
+                tracing::info!(peer = peer_id, retry_after_ms, "room rate limited");
+            }
+            self.room_limited = true;
+            return RateDecision::RoomLimited {
+                retry_after_ms,
+                notice,
+            };
+        }
+        peer.try_take_n(cost, now_ms);
+        self.room_limited = false;
+        RateDecision::Allowed
+    }
+
+    /// Tokens `peer_id` has left (None before its first message)
+    pub fn peer_remaining(&self, peer_id: &str, now_ms: u64) -> Option<u32> {
+        self.peers.get(peer_id).map(|b| b.remaining(now_ms))
+    }
+
+    /// Tokens left in the room's shared bucket
+    pub fn room_remaining(&self, now_ms: u64) -> u32 {
+        self.room.remaining(now_ms)
+    }
+
+    /// Forget a peer that left
+    pub fn remove(&mut self, peer_id: &str) {
+        self.peers.remove(peer_id);
+    }
+}
+
+/// Critical-message budget for every peer in a room
+#[allow(dead_code)]
+pub struct CriticalQuota {
+    burst: u32,
+    refill_per_sec: f64,
+    buckets: HashMap<String, TokenBucket>,
+}
+
+#[allow(dead_code)]
+impl CriticalQuota {
+    /// Default burst: a full handshake several times over
+    pub const DEFAULT_BURST: u32 = 16;
+    /// Default sustained rate of Critical messages per second
+    pub const DEFAULT_REFILL_PER_SEC: f64 = 2.0;
+
+    pub fn new(burst: u32, refill_per_sec: f64) -> Self {
+        Self {
+            burst,
+            refill_per_sec,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Final priority for a classified message from `peer_id`: Critical
+    /// only while the peer has budget left, High otherwise
+    pub fn apply(
+        &mut self,
+        peer_id: &str,
+        priority: MessagePriority,
+        now_ms: u64,
+    ) -> MessagePriority {
+        if !priority.is_critical() {
+            return priority;
+        }
+        let (burst, refill) = (self.burst, self.refill_per_sec);
+        let bucket = self
+            .buckets
+            .entry(peer_id.to_string())
+            .or_insert_with(|| TokenBucket::new(burst, refill, now_ms));
+        if bucket.try_take(now_ms) {
+            priority
+        } else {
+            #[cfg(feature = "tracing")]
+            tracing::info!(peer = peer_id, priority = ?priority, "critical budget exhausted");
+            priority.demoted()
+        }
+    }
+
+    /// Forget a peer that left
+    pub fn remove(&mut self, peer_id: &str) {
+        self.buckets.remove(peer_id);
+    }
+}
+
+impl Default for CriticalQuota {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_BURST, Self::DEFAULT_REFILL_PER_SEC)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flooding_peer_demoted() {
+        let mut quota = CriticalQuota::default();
+        let auth_init = MessagePriority::from_message(r#"{"type":"auth_init"}"#);
+        assert_eq!(auth_init, MessagePriority::Critical);
+
+        // 1000 auth_init frames within one second
+        let demoted = (0..1000u64)
+            .map(|i| quota.apply("flooder", auth_init, i))
+            .filter(|p| *p == MessagePriority::High)
+            .count();
+        // Burst plus at most the two tokens refilled during that second
+        assert!((1000 - 18..=1000 - 16).contains(&demoted), "{}", demoted);
+
+        // A normal handshake from another peer is unaffected
+        for i in 0..4 {
+            assert_eq!(
+                quota.apply("client", auth_init, 500 + i),
+                MessagePriority::Critical
+            );
+        }
+        // Non-Critical traffic never consumes budget
+        assert_eq!(
+            quota.apply("flooder", MessagePriority::Normal, 1_000),
+            MessagePriority::Normal
+        );
+    }
+
+    #[test]
+    fn test_room_bucket_shared_by_peers() {
+        let mut limiter = RateLimiter::new(RateLimit::new(10, 1.0), RateLimit::new(15, 5.0), 0);
+        let normal = MessagePriority::Normal;
+
+        // Each peer stays under its own limit of 10...
+        for _ in 0..8 {
+            assert!(limiter.check("alice", normal, 0).is_allowed());
+        }
+        for _ in 0..7 {
+            assert!(limiter.check("bob", normal, 0).is_allowed());
+        }
+        assert_eq!(limiter.peer_remaining("alice", 0), Some(2));
+        assert_eq!(limiter.peer_remaining("bob", 0), Some(3));
+        assert_eq!(limiter.room_remaining(0), 0);
+
+        // ...but together they've used up the room's 15
+        let notice = RoomRateLimited {
+            retry_after_ms: 200,
+        };
+        assert_eq!(
+            limiter.check("bob", normal, 0),
+            RateDecision::RoomLimited {
+                retry_after_ms: 200,
+                notice: Some(notice.clone()),
+            }
+        );
+        assert_eq!(
+            notice.to_json(),
+            r#"{"type":"room_rate_limited","retry_after_ms":200}"#
+        );
+        // Throttled room-wide, notice sent once, no peer tokens spent
+        assert_eq!(
+            limiter.check("alice", MessagePriority::Low, 100),
+            RateDecision::RoomLimited {
+                retry_after_ms: 100,
+                notice: None,
+            }
+        );
+        assert_eq!(limiter.peer_remaining("bob", 0), Some(3));
+        assert!(limiter
+            .check("alice", MessagePriority::Critical, 100)
+            .is_allowed());
+
+        // Room refills at 5/s
+        assert!(limiter.check("carol", normal, 200).is_allowed());
+        assert_eq!(limiter.peer_remaining("carol", 200), Some(9));
+    }
+
+    #[test]
+    fn test_peer_limit_checked_first() {
+        let mut limiter = RateLimiter::new(RateLimit::new(2, 1.0), RateLimit::new(100, 0.0), 0);
+        assert!(limiter.check("p", MessagePriority::Normal, 0).is_allowed());
+        assert!(limiter.check("p", MessagePriority::Normal, 0).is_allowed());
+        assert_eq!(
+            limiter.check("p", MessagePriority::Normal, 0),
+            RateDecision::PeerLimited {
+                retry_after_ms: 1_000
+            }
+        );
+        // A peer over its own limit doesn't drain the room
+        assert_eq!(limiter.room_remaining(0), 98);
+    }
+
+    #[test]
+    fn test_byte_cost_vs_message_cost() {
+        let normal = MessagePriority::Normal;
+        let room = RateLimit::new(1_000_000, 0.0);
+        // "tiny" sends 100 messages of 20 bytes, "bulk" 5 of 4000 bytes
+        let run = |limiter: &mut RateLimiter| {
+            let tiny = (0..100)
+                .filter(|_| limiter.try_acquire("tiny", normal, 20, 0).is_allowed())
+                .count();
+            let bulk = (0..5)
+                .filter(|_| limiter.try_acquire("bulk", normal, 4_000, 0).is_allowed())
+                .count();
+            (tiny, bulk)
+        };
+
+        // Counting messages: the tiny sender is cut off, bulk sails through
+        let mut by_count = RateLimiter::new(RateLimit::new(10, 1.0), room, 0);
+        assert_eq!(run(&mut by_count), (10, 5));
+
+        // Counting bytes: 2000 bytes of tiny traffic fit an 8KB burst, and
+        // the bulk sender gets two messages' worth
+        let mut by_bytes =
+            RateLimiter::new(RateLimit::new(8_192, 1_024.0), room, 0).with_cost(RateCost::Bytes);
+        assert_eq!(run(&mut by_bytes), (100, 2));
+        assert_eq!(by_bytes.peer_remaining("bulk", 0), Some(192));
+        assert_eq!(
+            by_bytes.try_acquire("bulk", normal, 4_000, 0),
+            RateDecision::PeerLimited {
+                retry_after_ms: 3_719
+            }
+        );
+        assert!(by_bytes
+            .try_acquire("bulk", normal, 4_000, 3_719)
+            .is_allowed());
+
+        // A message over the burst waits for a full bucket, then passes
+        assert!(!by_bytes.try_acquire("tiny", normal, 50_000, 0).is_allowed());
+        assert!(by_bytes
+            .try_acquire("tiny", normal, 50_000, 8_000)
+            .is_allowed());
+        assert_eq!(by_bytes.peer_remaining("tiny", 8_000), Some(0));
+    }
+
+    #[test]
+    fn test_budget_refills() {
+        let mut quota = CriticalQuota::new(2, 1.0);
+        let critical = MessagePriority::Critical;
+        assert_eq!(quota.apply("p", critical, 0), critical);
+        assert_eq!(quota.apply("p", critical, 0), critical);
+        assert_eq!(quota.apply("p", critical, 0), MessagePriority::High);
+
+        assert_eq!(quota.apply("p", critical, 1_000), critical);
+        assert_eq!(quota.apply("p", critical, 1_000), MessagePriority::High);
+    }
+}
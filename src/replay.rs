@@ -0,0 +1,210 @@
+//! Replay protection for handshake messages
+//!
+//! A captured `auth_init` (or any handshake message) can be replayed to
+//! impersonate its sender. Handshake messages carry a `nonce`, and
+//! NonceTracker rejects one a peer has already used within the window.
+//! This is separate from any general dedup: it only looks at handshake
+//! traffic, and nonces are compared in constant time so a probe can't
+//! learn how much of a stored nonce it matched.
+//!
+//! Each peer keeps at most `MAX_NONCES_PER_PEER`. A nonce is never
+//! forgotten before its window ends, since that would let it be
+//! replayed; instead a peer with a full set gets `TooManyHandshakes` for
+//! its new handshakes until old ones expire. No client handshakes that
+//! often. Peers whose nonces have all expired are dropped once per
+//! window, so the tracker doesn't grow with every peer it has seen.
+
+use crate::message_optimizer::{Message, OptimizerError};
+use std::collections::{HashMap, VecDeque};
+
+/// Nonces remembered per peer
+pub const MAX_NONCES_PER_PEER: usize = 64;
+
+/// Recently seen handshake nonces, per peer
+#[allow(dead_code)]
+pub struct NonceTracker {
+    window_ms: u64,
+    seen: HashMap<String, VecDeque<(Vec<u8>, u64)>>,
+    /// When every peer's set was last pruned
+    swept_at: u64,
+}
+
+#[allow(dead_code)]
+impl NonceTracker {
+    /// Default replay window: longer than any handshake takes
+    pub const DEFAULT_WINDOW_MS: u64 = 5 * 60 * 1000;
+
+    pub fn new(window_ms: u64) -> Self {
+        Self {
+            window_ms,
+            seen: HashMap::new(),
+            swept_at: 0,
+        }
+    }
+
+    /// Record `nonce` for `peer_id`, failing if it was already seen within
+    /// the window or the peer's set is full
+    pub fn check(
+        &mut self,
+        peer_id: &str,
+        nonce: &[u8],
+        now_ms: u64,
+    ) -> Result<(), OptimizerError> {
+        if now_ms.saturating_sub(self.swept_at) >= self.window_ms {
+            self.sweep(now_ms);
+        }
+        let window_ms = self.window_ms;
+        let seen = self.seen.entry(peer_id.to_string()).or_default();
+        expire(seen, window_ms, now_ms);
+
+        // Compare against every entry, so timing doesn't reveal which matched
+        let replayed = seen
+            .iter()
+            .fold(false, |hit, (old, _)| hit | constant_time_eq(old, nonce));
+        if replayed {
+            #[cfg(feature = "tracing")]
+            tracing::info!(peer = peer_id, "replayed handshake nonce");
+            return Err(OptimizerError::ReplayDetected);
+        }
+
+        if seen.len() >= MAX_NONCES_PER_PEER {
+            return Err(OptimizerError::TooManyHandshakes {
+                limit: MAX_NONCES_PER_PEER,
+            });
+        }
+        seen.push_back((nonce.to_vec(), now_ms));
+        Ok(())
+    }
+
+    /// Drop expired nonces of every peer, and peers left with none
+    pub fn sweep(&mut self, now_ms: u64) {
+        let window_ms = self.window_ms;
+        self.seen.retain(|_, seen| {
+            expire(seen, window_ms, now_ms);
+            !seen.is_empty()
+        });
+        self.swept_at = now_ms;
+    }
+
+    /// Peers with a nonce still in the window (as of the last prune)
+    pub fn peers(&self) -> usize {
+        self.seen.len()
+    }
+
+    /// `check` a parsed message's nonce. Only handshake messages are
+    /// checked, and ones without a nonce (older clients) pass.
+    pub fn check_message(
+        &mut self,
+        peer_id: &str,
+        msg: &Message,
+        now_ms: u64,
+    ) -> Result<(), OptimizerError> {
+        match &msg.nonce {
+            Some(nonce) if msg.msg_type.is_handshake() => {
+                self.check(peer_id, nonce.as_bytes(), now_ms)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Forget a peer that left
+    pub fn remove(&mut self, peer_id: &str) {
+        self.seen.remove(peer_id);
+    }
+}
+
+impl Default for NonceTracker {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_WINDOW_MS)
+    }
+}
+
+/// Drop the nonces that have left the window, oldest first
+fn expire(seen: &mut VecDeque<(Vec<u8>, u64)>, window_ms: u64, now_ms: u64) {
+    while seen
+        .front()
+        .is_some_and(|&(_, at)| now_ms.saturating_sub(at) >= window_ms)
+    {
+        seen.pop_front();
+    }
+}
+
+/// Equality whose running time depends only on the lengths
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replayed_auth_init_rejected() {
+        let mut tracker = NonceTracker::new(60_000);
+        let first = Message::parse(r#"{"type":"auth_init","nonce":"3f9a01"}"#).unwrap();
+        let second = Message::parse(r#"{"type":"auth_init","nonce":"77c2e4"}"#).unwrap();
+
+        assert_eq!(tracker.check_message("alice", &first, 0), Ok(()));
+        assert_eq!(tracker.check_message("alice", &second, 10), Ok(()));
+        assert_eq!(
+            tracker.check_message("alice", &first, 20),
+            Err(OptimizerError::ReplayDetected)
+        );
+        // Nonces are per peer
+        assert_eq!(tracker.check_message("bob", &first, 20), Ok(()));
+
+        // Non-handshake messages and nonce-less handshakes aren't tracked
+        let chat = Message::parse(r#"{"type":"chat","nonce":"3f9a01"}"#).unwrap();
+        assert_eq!(tracker.check_message("alice", &chat, 30), Ok(()));
+        let legacy = Message::parse(r#"{"type":"auth_init"}"#).unwrap();
+        assert_eq!(tracker.check_message("alice", &legacy, 30), Ok(()));
+        assert_eq!(tracker.check_message("alice", &legacy, 31), Ok(()));
+
+        // Outside the window the nonce is forgotten
+        assert_eq!(tracker.check_message("alice", &first, 60_000), Ok(()));
+    }
+
+    #[test]
+    fn test_nonces_capped_per_peer() {
+        let mut tracker = NonceTracker::new(1_000);
+        for i in 0..MAX_NONCES_PER_PEER as u32 {
+            tracker.check("p", &i.to_le_bytes(), i.into()).unwrap();
+        }
+        // A full set refuses new handshakes rather than forgetting one
+        let fresh = (MAX_NONCES_PER_PEER as u32).to_le_bytes();
+        assert_eq!(
+            tracker.check("p", &fresh, 100),
+            Err(OptimizerError::TooManyHandshakes {
+                limit: MAX_NONCES_PER_PEER
+            })
+        );
+        assert_eq!(
+            tracker.check("p", &0u32.to_le_bytes(), 100),
+            Err(OptimizerError::ReplayDetected)
+        );
+        assert_eq!(tracker.check("q", &fresh, 100), Ok(()));
+
+        // Once the oldest leaves the window there is room again
+        assert_eq!(tracker.check("p", &fresh, 1_000), Ok(()));
+        assert!(tracker.check("p", &1u32.to_le_bytes(), 1_000).is_err());
+        assert!(constant_time_eq(b"nonce", b"nonce"));
+        assert!(!constant_time_eq(b"nonce", b"nonc"));
+    }
+
+    #[test]
+    fn test_idle_peers_pruned() {
+        let mut tracker = NonceTracker::new(1_000);
+        tracker.check("alice", b"a1", 0).unwrap();
+        tracker.check("bob", b"b1", 500).unwrap();
+        assert_eq!(tracker.peers(), 2);
+
+        // A window later, the next handshake sweeps out alice
+        tracker.check("carol", b"c1", 1_200).unwrap();
+        assert_eq!(tracker.peers(), 2);
+        tracker.sweep(1_500);
+        assert_eq!(tracker.peers(), 1);
+    }
+}
@@ -0,0 +1,1525 @@
+//! Room membership model
+//!
+//! Tracks who is in a room and in which order they joined. VpnRoom
+//! rebuilds it from hibernated sessions; the logic lives here so it can be
+//! exercised without a Durable Object.
+//!
+//! Peers are keyed by any `Eq + Hash + Clone` id, String by default; a
+//! deployment keyed on raw public keys can use `Room<[u8; 32]>` without
+//! stringifying them.
+//!
+//! Joining is idempotent: a retried join (flaky connection) only refreshes
+//! the member's activity time and reports `AlreadyPresent`, so the relay
+//! can skip re-announcing the peer.
+//!
+//! Every departure carries a LeaveReason: the client closed, it timed out
+//! (`evict_idle`, or its connection dropped), or it broke the protocol.
+//! `LeaveReason::from_close` maps a websocket close onto one. `leave`
+//! returns the `peer_leave` message to broadcast, with that reason, so
+//! peers and logs can tell the cases apart.
+//!
+//! A room may be capped at `max_peers`. Peers that arrive while it's full
+//! wait in a bounded admission waitlist instead of being rejected; after
+//! each leave the relay calls `promote_waiting` and sends every admitted
+//! peer a `room_admitted` message. Only a full waitlist rejects a join.
+//!
+//! RoomPolicy decides which message types a room carries (e.g. data-only
+//! rooms vs chat lobbies); disallowed messages are rejected on ingest,
+//! before they are queued, and the sender gets a `type_not_allowed`
+//! error. `signaling_only` is the preset for control rooms that carry
+//! the handshake and entropy but no chat or data. It can also set a
+//! priority floor, e.g. so every message in an admin room jumps the queue
+//! as at least High.
+//!
+//! Until a member completes key exchange it is Unauthenticated, and
+//! `classify_from` caps everything it sends except the handshake itself
+//! at Normal, so an unauthenticated peer can't crowd the fast path.
+//!
+//! A message may name its own priority (`"priority":"high"`). The hint
+//! replaces the content-based classification, but it is only taken at
+//! face value from a Trusted member: an internal component the relay has
+//! marked with `set_auth_state`. From anyone else the hint is clamped to
+//! Normal, so it can lower a message but never put it in the High or
+//! Critical lanes.
+//!
+//! Rooms also hold a small metadata map (string keys, JSON values) for
+//! static parameters such as negotiated crypto settings. Every change
+//! yields a `meta` message to broadcast, and `meta()` is the same message
+//! for a newly joined peer. Its total size is capped at `metadata_limit`.
+//!
+//! `broadcast` queues a message for every member but its sender on the
+//! room's RoomQueue. `broadcast_excluding` also skips a set of peers, e.g.
+//! ones that already got the message over another path.
+//!
+//! RoomRegistry holds the rooms of a relay that runs them in one process
+//! (the Workers deployment gets one Durable Object per room instead). It
+//! can be capped at `max_rooms`: creating a room past the cap fails with
+//! TooManyRooms, and the relay answers the would-be creator with a
+//! `too_many_rooms` error (`JoinError::to_error_message`; TestRelay's
+//! `connect` is the reference wiring). `reap_idle` drops rooms nobody
+//! has been active in, which frees their slots. `RoomRegistry::join`
+//! creates the room if needed and joins it: duplicate joins carry no
+//! announcement, so retried `peer_join` frames don't reach the room as
+//! repeated High broadcasts. Every real join gets the next join
+//! generation of the room, which tells a rejoin after a leave apart
+//! from a duplicate.
+
+use crate::capabilities::Capabilities;
+use crate::error_message::ErrorMessage;
+use crate::message_optimizer::{
+    Message, MessagePriority, MessageType, MessageTypeSet, OptimizerError,
+};
+use crate::priority_queue::{QueuedMessage, RoomQueue};
+use serde::Serialize;
+use serde_json::Value;
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+
+/// Whether a member has completed key exchange
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthState {
+    #[default]
+    Unauthenticated,
+    Authenticated,
+    /// Authenticated internal component whose priority hints are honored
+    Trusted,
+}
+
+impl AuthState {
+    /// Ceiling on `priority` for `msg` from a peer in this state:
+    /// Unauthenticated peers get at most Normal, except for handshake
+    /// messages
+    pub fn cap(self, msg: &Message, priority: MessagePriority) -> MessagePriority {
+        if self == AuthState::Unauthenticated && !msg.msg_type.is_handshake() {
+            priority.max(MessagePriority::Normal)
+        } else {
+            priority
+        }
+    }
+}
+
+/// A peer currently in the room
+#[derive(Clone, Debug)]
+struct RoomMember<P> {
+    peer_id: P,
+    joined_at: u64,
+    last_active: u64,
+    auth: AuthState,
+    /// Negotiated in the handshake (see `set_capabilities`)
+    capabilities: Capabilities,
+    /// Room-wide count of real joins when this one happened
+    generation: u64,
+}
+
+/// Result of `Room::join`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinOutcome {
+    /// New member at this join index
+    Joined(usize),
+    /// Already a member at this join index; only its activity was refreshed
+    AlreadyPresent(usize),
+    /// Room is full; waiting at this 1-based waitlist position
+    Queued { position: usize },
+}
+
+#[allow(dead_code)]
+impl JoinOutcome {
+    /// Join index if the peer is a member (None while waitlisted)
+    pub fn join_index(self) -> Option<usize> {
+        match self {
+            JoinOutcome::Joined(i) | JoinOutcome::AlreadyPresent(i) => Some(i),
+            JoinOutcome::Queued { .. } => None,
+        }
+    }
+
+    /// Whether the join should be announced to the rest of the room
+    pub fn is_new(self) -> bool {
+        matches!(self, JoinOutcome::Joined(_))
+    }
+}
+
+/// Presence entry sent to a newly joined peer
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PresenceInfo<P = String> {
+    pub peer_id: P,
+    /// Position in join order among current members (0 = earliest)
+    pub join_index: usize,
+    /// What the peer negotiated; the legacy set until its handshake
+    pub capabilities: Capabilities,
+}
+
+/// Room and its waitlist are both full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RoomFull {
+    pub capacity: usize,
+}
+
+impl std::fmt::Display for RoomFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Room is full ({} peers)", self.capacity)
+    }
+}
+
+impl std::error::Error for RoomFull {}
+
+/// A metadata write would take the room past its size cap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MetadataFull {
+    /// Total size the write would have produced
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for MetadataFull {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Room metadata would be {} bytes (limit {})",
+            self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for MetadataFull {}
+
+/// A new room would take the registry past `max_rooms`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TooManyRooms {
+    pub limit: usize,
+}
+
+impl std::fmt::Display for TooManyRooms {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Too many rooms (limit {})", self.limit)
+    }
+}
+
+impl std::error::Error for TooManyRooms {}
+
+/// Why `RoomRegistry::join` failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinError {
+    TooManyRooms(TooManyRooms),
+    RoomFull(RoomFull),
+}
+
+impl std::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JoinError::TooManyRooms(e) => write!(f, "{}", e),
+            JoinError::RoomFull(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+#[allow(dead_code)]
+impl JoinError {
+    /// The reply for the peer that tried to join
+    pub fn to_error_message(self) -> ErrorMessage {
+        match self {
+            JoinError::TooManyRooms(e) => ErrorMessage::too_many_rooms(e.limit),
+            JoinError::RoomFull(e) => ErrorMessage::room_full(e.capacity),
+        }
+    }
+}
+
+/// Result of `RoomRegistry::join`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomJoin<P = String> {
+    pub outcome: JoinOutcome,
+    /// The member's join generation (None while waitlisted)
+    pub generation: Option<u64>,
+    /// What to broadcast to the room: only for a real join
+    pub announce: Option<PeerJoin<P>>,
+}
+
+/// The room's current metadata, sent on join and after every change
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename = "meta")]
+pub struct RoomMeta {
+    pub metadata: BTreeMap<String, Value>,
+}
+
+#[allow(dead_code)]
+impl RoomMeta {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Why a peer left the room
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaveReason {
+    /// The client closed its connection
+    #[default]
+    ClientClose,
+    /// Evicted after going silent (see `Room::evict_idle`), or the
+    /// connection dropped without a clean close
+    Timeout,
+    /// Disconnected for going over its rate limit
+    RateLimited,
+    /// Evicted for sending something the relay can't accept
+    ProtocolError,
+}
+
+impl LeaveReason {
+    /// Reason for a websocket close with `code`. A clean close is the
+    /// client's doing; an unclean one is a protocol error when the code
+    /// says so (1002 protocol, 1003 unsupported data, 1007 bad payload,
+    /// 1008 policy, 1009 too big) and a dropped connection otherwise.
+    pub fn from_close(code: usize, was_clean: bool) -> Self {
+        match (was_clean, code) {
+            (true, _) => Self::ClientClose,
+            (false, 1002 | 1003 | 1007 | 1008 | 1009) => Self::ProtocolError,
+            (false, _) => Self::Timeout,
+        }
+    }
+}
+
+/// Broadcast to the room when a peer really joins (not on a duplicate)
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename = "peer_join")]
+pub struct PeerJoin<P = String> {
+    pub peer_id: P,
+    pub join_index: usize,
+    pub generation: u64,
+}
+
+#[allow(dead_code)]
+impl<P: Serialize> PeerJoin<P> {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Broadcast to the room when a member leaves
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename = "peer_leave")]
+pub struct PeerLeave<P = String> {
+    pub peer_id: P,
+    pub reason: LeaveReason,
+}
+
+#[allow(dead_code)]
+impl<P: Serialize> PeerLeave<P> {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Sent to a waitlisted peer once it has been let in
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename = "room_admitted")]
+pub struct RoomAdmitted<P = String> {
+    pub peer_id: P,
+    pub join_index: usize,
+}
+
+#[allow(dead_code)]
+impl<P: Serialize> RoomAdmitted<P> {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// A member as `Room::debug_snapshot` saw it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PeerDebug<P = String> {
+    pub peer_id: P,
+    pub join_index: usize,
+    pub joined_at: u64,
+    pub last_active: u64,
+    pub auth: AuthState,
+    pub generation: u64,
+    /// Messages waiting in the peer's outbound queue
+    pub queue_depth: usize,
+}
+
+/// Read-only view of a room for admin tooling
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RoomDebug<P = String> {
+    /// Members in join order
+    pub peers: Vec<PeerDebug<P>>,
+    /// Waitlisted peers, oldest first
+    pub waiting: Vec<P>,
+    pub metadata_size: usize,
+}
+
+/// Occupancy snapshot
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct RoomStats {
+    pub members: usize,
+    /// None = unbounded
+    pub max_peers: Option<usize>,
+    pub waiting: usize,
+    pub waitlist_capacity: usize,
+}
+
+/// Members of a room in join order
+pub struct Room<P = String> {
+    members: Vec<RoomMember<P>>,
+    /// None = unbounded
+    max_peers: Option<usize>,
+    /// Peers waiting for a slot, with the time they asked
+    waitlist: VecDeque<(P, u64)>,
+    waitlist_capacity: usize,
+    metadata: BTreeMap<String, Value>,
+    /// Key bytes plus serialized value bytes, over all entries
+    metadata_size: usize,
+    metadata_limit: usize,
+    /// Real joins so far, including admissions from the waitlist
+    joins: u64,
+}
+
+impl<P> Default for Room<P> {
+    fn default() -> Self {
+        Self {
+            members: Vec::new(),
+            max_peers: None,
+            waitlist: VecDeque::new(),
+            waitlist_capacity: 0,
+            metadata: BTreeMap::new(),
+            metadata_size: 0,
+            metadata_limit: DEFAULT_METADATA_LIMIT,
+            joins: 0,
+        }
+    }
+}
+
+/// Default cap on a room's metadata: key bytes plus serialized values
+pub const DEFAULT_METADATA_LIMIT: usize = 8 * 1024;
+
+fn meta_entry_size(key: &str, value: &Value) -> usize {
+    key.len() + value.to_string().len()
+}
+
+impl<P: Eq + Hash + Clone> Room<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Room capped at `max_peers`, with up to `waitlist_capacity` waiting
+    #[allow(dead_code)]
+    pub fn with_capacity(max_peers: usize, waitlist_capacity: usize) -> Self {
+        Self {
+            max_peers: Some(max_peers),
+            waitlist_capacity,
+            ..Self::default()
+        }
+    }
+
+    /// Cap the room's metadata at `limit` bytes instead of the default
+    #[allow(dead_code)]
+    pub fn with_metadata_limit(mut self, limit: usize) -> Self {
+        self.metadata_limit = limit;
+        self
+    }
+
+    fn is_full(&self) -> bool {
+        self.max_peers.is_some_and(|max| self.members.len() >= max)
+    }
+
+    /// Add a peer, or refresh its activity if it's already a member.
+    /// When the room is full the peer is waitlisted; only a full waitlist
+    /// is an error.
+    pub fn join<Q>(&mut self, peer_id: &Q, now_ms: u64) -> Result<JoinOutcome, RoomFull>
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq + ToOwned<Owned = P>,
+    {
+        if let Some(i) = self
+            .members
+            .iter()
+            .position(|m| m.peer_id.borrow() == peer_id)
+        {
+            self.members[i].last_active = now_ms;
+            return Ok(JoinOutcome::AlreadyPresent(i));
+        }
+        if let Some(i) = self
+            .waitlist
+            .iter()
+            .position(|(id, _)| id.borrow() == peer_id)
+        {
+            return Ok(JoinOutcome::Queued { position: i + 1 });
+        }
+
+        if self.is_full() {
+            if self.waitlist.len() >= self.waitlist_capacity {
+                return Err(RoomFull {
+                    capacity: self.max_peers.unwrap_or_default(),
+                });
+            }
+            self.waitlist.push_back((peer_id.to_owned(), now_ms));
+            return Ok(JoinOutcome::Queued {
+                position: self.waitlist.len(),
+            });
+        }
+
+        Ok(JoinOutcome::Joined(self.admit(peer_id.to_owned(), now_ms)))
+    }
+
+    fn admit(&mut self, peer_id: P, now_ms: u64) -> usize {
+        self.joins += 1;
+        self.members.push(RoomMember {
+            peer_id,
+            joined_at: now_ms,
+            last_active: now_ms,
+            auth: AuthState::default(),
+            capabilities: Capabilities::legacy(),
+            generation: self.joins,
+        });
+        self.members.len() - 1
+    }
+
+    /// Remove a peer, returning the `peer_leave` message to broadcast if it
+    /// was a member. Waitlisted peers were never announced, so removing
+    /// one yields None, as does a peer that isn't present.
+    #[allow(dead_code)]
+    pub fn leave<Q>(&mut self, peer_id: &Q, reason: LeaveReason) -> Option<PeerLeave<P>>
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.waitlist.retain(|(id, _)| id.borrow() != peer_id);
+        let i = self
+            .members
+            .iter()
+            .position(|m| m.peer_id.borrow() == peer_id)?;
+        Some(PeerLeave {
+            peer_id: self.members.remove(i).peer_id,
+            reason,
+        })
+    }
+
+    /// Remove every member inactive for more than `timeout_ms`, returning a
+    /// `Timeout` notice for each
+    #[allow(dead_code)]
+    pub fn evict_idle(&mut self, now_ms: u64, timeout_ms: u64) -> Vec<PeerLeave<P>> {
+        let mut evicted = Vec::new();
+        self.members.retain(|m| {
+            let idle = now_ms.saturating_sub(m.last_active) > timeout_ms;
+            if idle {
+                evicted.push(PeerLeave {
+                    peer_id: m.peer_id.clone(),
+                    reason: LeaveReason::Timeout,
+                });
+            }
+            !idle
+        });
+        evicted
+    }
+
+    /// Admit waitlisted peers into free slots, oldest first; run after
+    /// every leave and send each result to its peer
+    #[allow(dead_code)]
+    pub fn promote_waiting(&mut self, now_ms: u64) -> Vec<RoomAdmitted<P>> {
+        let mut admitted = Vec::new();
+        while !self.is_full() {
+            let Some((peer_id, _)) = self.waitlist.pop_front() else {
+                break;
+            };
+            let join_index = self.admit(peer_id.clone(), now_ms);
+            admitted.push(RoomAdmitted {
+                peer_id,
+                join_index,
+            });
+        }
+        admitted
+    }
+
+    #[allow(dead_code)]
+    pub fn stats(&self) -> RoomStats {
+        RoomStats {
+            members: self.members.len(),
+            max_peers: self.max_peers,
+            waiting: self.waitlist.len(),
+            waitlist_capacity: self.waitlist_capacity,
+        }
+    }
+
+    /// Current members with their join order
+    pub fn presence(&self) -> Vec<PresenceInfo<P>> {
+        self.members
+            .iter()
+            .enumerate()
+            .map(|(i, m)| PresenceInfo {
+                peer_id: m.peer_id.clone(),
+                join_index: i,
+                capabilities: m.capabilities,
+            })
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    pub fn joined_at<Q>(&self, peer_id: &Q) -> Option<u64>
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.members
+            .iter()
+            .find(|m| m.peer_id.borrow() == peer_id)
+            .map(|m| m.joined_at)
+    }
+
+    /// Last join (or retried join) of a member
+    #[allow(dead_code)]
+    pub fn last_active<Q>(&self, peer_id: &Q) -> Option<u64>
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.members
+            .iter()
+            .find(|m| m.peer_id.borrow() == peer_id)
+            .map(|m| m.last_active)
+    }
+
+    /// Auth state of a member (Unauthenticated for non-members)
+    #[allow(dead_code)]
+    pub fn auth_state<Q>(&self, peer_id: &Q) -> AuthState
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.members
+            .iter()
+            .find(|m| m.peer_id.borrow() == peer_id)
+            .map_or(AuthState::Unauthenticated, |m| m.auth)
+    }
+
+    /// Record a member's auth progress; returns false for non-members
+    #[allow(dead_code)]
+    pub fn set_auth_state<Q>(&mut self, peer_id: &Q, auth: AuthState) -> bool
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        match self
+            .members
+            .iter_mut()
+            .find(|m| m.peer_id.borrow() == peer_id)
+        {
+            Some(member) => {
+                member.auth = auth;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record what a member negotiated, normally `PeerCapabilities::get`
+    /// after its handshake; returns false for non-members
+    #[allow(dead_code)]
+    pub fn set_capabilities<Q>(&mut self, peer_id: &Q, capabilities: Capabilities) -> bool
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        match self
+            .members
+            .iter_mut()
+            .find(|m| m.peer_id.borrow() == peer_id)
+        {
+            Some(member) => {
+                member.capabilities = capabilities;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Set a metadata entry, returning the `meta` message to broadcast.
+    /// Nothing changes if the room would exceed its metadata limit.
+    #[allow(dead_code)]
+    pub fn set_meta(&mut self, key: &str, value: Value) -> Result<RoomMeta, MetadataFull> {
+        let replaced = self
+            .metadata
+            .get(key)
+            .map_or(0, |old| meta_entry_size(key, old));
+        let size = self.metadata_size - replaced + meta_entry_size(key, &value);
+        if size > self.metadata_limit {
+            return Err(MetadataFull {
+                size,
+                limit: self.metadata_limit,
+            });
+        }
+        self.metadata.insert(key.to_string(), value);
+        self.metadata_size = size;
+        Ok(self.meta())
+    }
+
+    #[allow(dead_code)]
+    pub fn get_meta(&self, key: &str) -> Option<&Value> {
+        self.metadata.get(key)
+    }
+
+    /// Remove a metadata entry, returning the `meta` message to broadcast
+    /// (None if there was no such key)
+    #[allow(dead_code)]
+    pub fn remove_meta(&mut self, key: &str) -> Option<RoomMeta> {
+        let old = self.metadata.remove(key)?;
+        self.metadata_size -= meta_entry_size(key, &old);
+        Some(self.meta())
+    }
+
+    /// Current metadata, for a newly joined peer
+    #[allow(dead_code)]
+    pub fn meta(&self) -> RoomMeta {
+        RoomMeta {
+            metadata: self.metadata.clone(),
+        }
+    }
+
+    /// Queue `msg` at `priority` for every member except `sender`.
+    /// Returns the number of members it was queued for.
+    #[allow(dead_code)]
+    pub fn broadcast<Q>(
+        &self,
+        queue: &mut RoomQueue<P>,
+        sender: &Q,
+        priority: MessagePriority,
+        msg: &QueuedMessage,
+    ) -> usize
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.broadcast_excluding(queue, sender, &HashSet::new(), priority, msg)
+    }
+
+    /// `broadcast`, also skipping every member in `exclude`
+    #[allow(dead_code)]
+    pub fn broadcast_excluding<Q>(
+        &self,
+        queue: &mut RoomQueue<P>,
+        sender: &Q,
+        exclude: &HashSet<P>,
+        priority: MessagePriority,
+        msg: &QueuedMessage,
+    ) -> usize
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        let mut queued = 0;
+        for member in &self.members {
+            if member.peer_id.borrow() != sender && !exclude.contains::<P>(&member.peer_id) {
+                queue.push::<P>(&member.peer_id, priority, msg.clone());
+                queued += 1;
+            }
+        }
+        queued
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Join generation of a member: the room's count of real joins when
+    /// it joined, so a rejoin after leaving always gets a higher one
+    #[allow(dead_code)]
+    pub fn generation<Q>(&self, peer_id: &Q) -> Option<u64>
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq,
+    {
+        self.members
+            .iter()
+            .find(|m| m.peer_id.borrow() == peer_id)
+            .map(|m| m.generation)
+    }
+
+    /// Members, their activity and the depth of their queues in `queue`
+    #[allow(dead_code)]
+    pub fn debug_snapshot(&self, queue: &RoomQueue<P>) -> RoomDebug<P> {
+        RoomDebug {
+            peers: self
+                .members
+                .iter()
+                .enumerate()
+                .map(|(join_index, m)| PeerDebug {
+                    peer_id: m.peer_id.clone(),
+                    join_index,
+                    joined_at: m.joined_at,
+                    last_active: m.last_active,
+                    auth: m.auth,
+                    generation: m.generation,
+                    queue_depth: queue.peer_len::<P>(&m.peer_id),
+                })
+                .collect(),
+            waiting: self.waitlist.iter().map(|(id, _)| id.clone()).collect(),
+            metadata_size: self.metadata_size,
+        }
+    }
+
+    /// Latest activity of any member
+    fn last_activity(&self) -> Option<u64> {
+        self.members.iter().map(|m| m.last_active).max()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+}
+
+/// A room and when the registry last handed it out
+struct RegisteredRoom<P> {
+    room: Room<P>,
+    touched_ms: u64,
+}
+
+/// Rooms by id, optionally capped at `max_rooms`
+#[allow(dead_code)]
+pub struct RoomRegistry<P = String> {
+    rooms: HashMap<String, RegisteredRoom<P>>,
+    /// None = unbounded
+    max_rooms: Option<usize>,
+}
+
+impl<P> Default for RoomRegistry<P> {
+    fn default() -> Self {
+        Self {
+            rooms: HashMap::new(),
+            max_rooms: None,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<P: Eq + Hash + Clone> RoomRegistry<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry that holds at most `max_rooms` rooms
+    pub fn with_max_rooms(max_rooms: usize) -> Self {
+        Self {
+            max_rooms: Some(max_rooms),
+            ..Self::default()
+        }
+    }
+
+    /// The room `room_id`, created empty if it doesn't exist yet. Creating
+    /// one past `max_rooms` fails; existing rooms are always returned.
+    pub fn get_or_create(
+        &mut self,
+        room_id: &str,
+        now_ms: u64,
+    ) -> Result<&mut Room<P>, TooManyRooms> {
+        if !self.rooms.contains_key(room_id) {
+            if let Some(limit) = self.max_rooms.filter(|&max| self.rooms.len() >= max) {
+                return Err(TooManyRooms { limit });
+            }
+        }
+        let entry = self
+            .rooms
+            .entry(room_id.to_string())
+            .or_insert_with(|| RegisteredRoom {
+                room: Room::new(),
+                touched_ms: now_ms,
+            });
+        entry.touched_ms = entry.touched_ms.max(now_ms);
+        Ok(&mut entry.room)
+    }
+
+    /// Join `peer_id` to `room_id`, creating the room if needed. A peer
+    /// that is already a member keeps its state and generation, and
+    /// nothing is announced.
+    pub fn join<Q>(
+        &mut self,
+        room_id: &str,
+        peer_id: &Q,
+        now_ms: u64,
+    ) -> Result<RoomJoin<P>, JoinError>
+    where
+        P: Borrow<Q>,
+        Q: ?Sized + Eq + ToOwned<Owned = P>,
+    {
+        let room = self
+            .get_or_create(room_id, now_ms)
+            .map_err(JoinError::TooManyRooms)?;
+        let outcome = room.join(peer_id, now_ms).map_err(JoinError::RoomFull)?;
+        let generation = room.generation(peer_id);
+        let announce = match (outcome, generation) {
+            (JoinOutcome::Joined(join_index), Some(generation)) => Some(PeerJoin {
+                peer_id: peer_id.to_owned(),
+                join_index,
+                generation,
+            }),
+            _ => None,
+        };
+        Ok(RoomJoin {
+            outcome,
+            generation,
+            announce,
+        })
+    }
+
+    pub fn get(&self, room_id: &str) -> Option<&Room<P>> {
+        self.rooms.get(room_id).map(|r| &r.room)
+    }
+
+    pub fn get_mut(&mut self, room_id: &str) -> Option<&mut Room<P>> {
+        self.rooms.get_mut(room_id).map(|r| &mut r.room)
+    }
+
+    pub fn remove(&mut self, room_id: &str) -> Option<Room<P>> {
+        self.rooms.remove(room_id).map(|r| r.room)
+    }
+
+    /// Drop every room with no activity (from its members, or from
+    /// `get_or_create`) for more than `idle_ms`, returning their ids
+    pub fn reap_idle(&mut self, now_ms: u64, idle_ms: u64) -> Vec<String> {
+        let mut reaped = Vec::new();
+        self.rooms.retain(|room_id, r| {
+            let last = r
+                .room
+                .last_activity()
+                .map_or(r.touched_ms, |t| t.max(r.touched_ms));
+            let idle = now_ms.saturating_sub(last) > idle_ms;
+            if idle {
+                reaped.push(room_id.clone());
+            }
+            !idle
+        });
+        reaped.sort();
+        reaped
+    }
+
+    /// Rooms currently held
+    pub fn len(&self) -> usize {
+        self.rooms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rooms.is_empty()
+    }
+
+    pub fn max_rooms(&self) -> Option<usize> {
+        self.max_rooms
+    }
+}
+
+/// Per-room ingest rules
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RoomPolicy {
+    /// None = every type allowed
+    allowed_types: Option<MessageTypeSet>,
+    /// Minimum priority for every message in the room (raises, never lowers)
+    room_priority_floor: Option<MessagePriority>,
+}
+
+#[allow(dead_code)]
+impl RoomPolicy {
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+
+    /// Only accept the listed types
+    pub fn allow_only(types: impl IntoIterator<Item = MessageType>) -> Self {
+        Self {
+            allowed_types: Some(types.into_iter().collect()),
+            room_priority_floor: None,
+        }
+    }
+
+    /// Control traffic only: auth, key exchange and entropy
+    pub fn signaling_only() -> Self {
+        Self::allow_only([
+            MessageType::Auth,
+            MessageType::AuthInit,
+            MessageType::AuthResponse,
+            MessageType::KeyExchange,
+            MessageType::Entropy,
+            MessageType::EntropyCommit,
+            MessageType::EntropyReveal,
+        ])
+    }
+
+    pub fn with_priority_floor(mut self, floor: MessagePriority) -> Self {
+        self.room_priority_floor = Some(floor);
+        self
+    }
+
+    /// Raise an already classified priority to the room's floor
+    pub fn apply_floor(&self, priority: MessagePriority) -> MessagePriority {
+        match self.room_priority_floor {
+            Some(floor) => priority.min(floor),
+            None => priority,
+        }
+    }
+
+    /// Queue priority for a message admitted to this room
+    pub fn classify(&self, msg: &Message) -> MessagePriority {
+        self.apply_floor(msg.msg_type.priority().unwrap_or(MessagePriority::Normal))
+    }
+
+    /// `classify` for a message from a peer in state `auth`.
+    /// Unauthenticated peers are capped at Normal (after the room floor)
+    /// except for handshake messages, which keep their priority.
+    ///
+    /// A `priority` hint in the message replaces `classify`'s result. A
+    /// Trusted sender gets the hinted level (raised to the room floor);
+    /// from any other sender the hint is first clamped to Normal.
+    pub fn classify_from(&self, msg: &Message, auth: AuthState) -> MessagePriority {
+        let priority = match msg.priority {
+            Some(hint) if auth == AuthState::Trusted => return self.apply_floor(hint),
+            Some(hint) => self.apply_floor(hint.max(MessagePriority::Normal)),
+            None => self.classify(msg),
+        };
+        auth.cap(msg, priority)
+    }
+
+    pub fn check(&self, msg: &Message) -> Result<(), OptimizerError> {
+        match self.allowed_types {
+            Some(allowed) if !allowed.contains(msg.msg_type) => {
+                Err(OptimizerError::TypeNotAllowed(msg.msg_type))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Parse and check an inbound text message
+    pub fn admit(&self, raw: &str) -> Result<Message, OptimizerError> {
+        let msg = Message::parse(raw)?;
+        self.check(&msg)?;
+        Ok(msg)
+    }
+
+    /// Reply for a message `check` rejected; the message itself is dropped
+    pub fn rejection(&self, msg: &Message) -> Option<ErrorMessage> {
+        self.check(msg)
+            .err()
+            .map(|_| ErrorMessage::type_not_allowed(msg.msg_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error_message::ErrorCode;
+    use serde_json::json;
+
+    #[test]
+    fn test_presence_after_joins_and_leave() {
+        let mut room = Room::new();
+        assert_eq!(room.join("alice", 100), Ok(JoinOutcome::Joined(0)));
+        assert_eq!(room.join("bob", 200), Ok(JoinOutcome::Joined(1)));
+        assert_eq!(room.join("carol", 300), Ok(JoinOutcome::Joined(2)));
+
+        assert!(room.leave("bob", LeaveReason::ClientClose).is_some());
+        assert!(room.leave("bob", LeaveReason::ClientClose).is_none());
+        assert_eq!(room.join("dave", 400), Ok(JoinOutcome::Joined(2)));
+
+        let presence = room.presence();
+        let entries: Vec<(&str, usize)> = presence
+            .iter()
+            .map(|p| (p.peer_id.as_str(), p.join_index))
+            .collect();
+        assert_eq!(entries, vec![("alice", 0), ("carol", 1), ("dave", 2)]);
+        assert_eq!(room.joined_at("carol"), Some(300));
+
+        // Capabilities are legacy until the handshake is recorded
+        assert!(room.set_capabilities("carol", Capabilities::DEFLATE));
+        assert!(!room.set_capabilities("bob", Capabilities::DEFLATE));
+        let presence = room.presence();
+        assert_eq!(presence[0].capabilities, Capabilities::legacy());
+        assert_eq!(presence[1].capabilities, Capabilities::DEFLATE);
+        assert_eq!(
+            serde_json::to_value(&presence[1]).unwrap(),
+            json!({"peer_id": "carol", "join_index": 1, "capabilities": ["deflate"]})
+        );
+        assert_eq!(room.len(), 3);
+    }
+
+    #[test]
+    fn test_join_idempotent() {
+        let mut room = Room::new();
+        room.join("alice", 50).unwrap();
+
+        // A retried peer_join: one membership, one announcement
+        let announced = [room.join("bob", 100), room.join("bob", 250)]
+            .iter()
+            .filter(|o| o.unwrap().is_new())
+            .count();
+        assert_eq!(announced, 1);
+        assert_eq!(room.join("bob", 300), Ok(JoinOutcome::AlreadyPresent(1)));
+
+        assert_eq!(room.len(), 2);
+        assert_eq!(room.joined_at("bob"), Some(100));
+        assert_eq!(room.last_active("bob"), Some(300));
+    }
+
+    #[test]
+    fn test_waitlist_fill_queue_promote() {
+        let mut room = Room::with_capacity(2, 1);
+        assert_eq!(room.join("alice", 0), Ok(JoinOutcome::Joined(0)));
+        assert_eq!(room.join("bob", 0), Ok(JoinOutcome::Joined(1)));
+
+        assert_eq!(
+            room.join("carol", 10),
+            Ok(JoinOutcome::Queued { position: 1 })
+        );
+        // Retrying while waitlisted keeps the place
+        assert_eq!(
+            room.join("carol", 20),
+            Ok(JoinOutcome::Queued { position: 1 })
+        );
+        assert_eq!(room.join("dave", 30), Err(RoomFull { capacity: 2 }));
+        assert_eq!(
+            room.stats(),
+            RoomStats {
+                members: 2,
+                max_peers: Some(2),
+                waiting: 1,
+                waitlist_capacity: 1,
+            }
+        );
+
+        // Nothing to promote while full
+        assert!(room.promote_waiting(40).is_empty());
+
+        assert!(room.leave("alice", LeaveReason::ClientClose).is_some());
+        let admitted = room.promote_waiting(50);
+        assert_eq!(
+            admitted,
+            vec![RoomAdmitted {
+                peer_id: "carol".to_string(),
+                join_index: 1,
+            }]
+        );
+        assert_eq!(
+            admitted[0].to_json(),
+            r#"{"type":"room_admitted","peer_id":"carol","join_index":1}"#
+        );
+        assert_eq!(
+            MessagePriority::from_message(&admitted[0].to_json()),
+            MessagePriority::High
+        );
+        assert_eq!(room.stats().waiting, 0);
+        assert_eq!(room.joined_at("carol"), Some(50));
+    }
+
+    #[test]
+    fn test_policy_data_only_room() {
+        let policy = RoomPolicy::allow_only([MessageType::Data, MessageType::Ping]);
+
+        assert!(policy.admit(r#"{"type":"data","payload":"AAEC"}"#).is_ok());
+        assert_eq!(
+            policy.admit(r#"{"type":"chat","msg":"hi"}"#).unwrap_err(),
+            OptimizerError::TypeNotAllowed(MessageType::Chat)
+        );
+        assert!(matches!(
+            policy.admit("not json"),
+            Err(OptimizerError::Malformed(_))
+        ));
+
+        assert!(RoomPolicy::allow_all()
+            .admit(r#"{"type":"chat","msg":"hi"}"#)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_priority_floor_raises_only() {
+        use crate::priority_queue::{PriorityQueue, QueuedMessage};
+
+        let admin = RoomPolicy::allow_all().with_priority_floor(MessagePriority::High);
+        let mut queue = PriorityQueue::new();
+        for raw in [
+            r#"{"type":"chat","msg":"ban user"}"#,
+            r#"{"type":"ping"}"#,
+            r#"{"type":"key_exchange"}"#,
+        ] {
+            let msg = admin.admit(raw).unwrap();
+            queue.push(
+                admin.classify(&msg),
+                QueuedMessage::new(raw.as_bytes().to_vec(), 0, 0),
+            );
+        }
+
+        let bands = queue.stats(0).bands;
+        assert_eq!(bands[MessagePriority::Critical.index()].len, 1);
+        assert_eq!(bands[MessagePriority::High.index()].len, 2);
+        assert_eq!(queue.len(), 3);
+
+        // Without a floor the type decides
+        let chat = Message::parse(r#"{"type":"chat"}"#).unwrap();
+        assert_eq!(
+            RoomPolicy::allow_all().classify(&chat),
+            MessagePriority::Normal
+        );
+    }
+
+    #[test]
+    fn test_unauthenticated_ceiling() {
+        let mut room = Room::new();
+        room.join("alice", 0).unwrap();
+        let policy = RoomPolicy::allow_all().with_priority_floor(MessagePriority::High);
+
+        let chat = Message::parse(r#"{"type":"chat","msg":"hi"}"#).unwrap();
+        let auth_init = Message::parse(r#"{"type":"auth_init"}"#).unwrap();
+        let ping = Message::parse(r#"{"type":"ping"}"#).unwrap();
+
+        // The room floor would lift chat to High; the ceiling wins
+        let auth = room.auth_state("alice");
+        assert_eq!(auth, AuthState::Unauthenticated);
+        assert_eq!(policy.classify_from(&chat, auth), MessagePriority::Normal);
+        assert_eq!(
+            RoomPolicy::allow_all().classify_from(&ping, auth),
+            MessagePriority::Low
+        );
+        assert_eq!(
+            policy.classify_from(&auth_init, auth),
+            MessagePriority::Critical
+        );
+
+        assert!(room.set_auth_state("alice", AuthState::Authenticated));
+        // Retried joins keep the auth state
+        room.join("alice", 10).unwrap();
+        let auth = room.auth_state("alice");
+        assert_eq!(policy.classify_from(&chat, auth), MessagePriority::High);
+        assert!(!room.set_auth_state("mallory", AuthState::Authenticated));
+    }
+
+    #[test]
+    fn test_priority_hint_needs_trust() {
+        let policy = RoomPolicy::allow_all();
+        let hinted = Message::parse(r#"{"type":"chat","priority":"high"}"#).unwrap();
+        let critical = Message::parse(r#"{"type":"data","priority":"Critical"}"#).unwrap();
+        let demoted = Message::parse(r#"{"type":"peer_join","priority":"low"}"#).unwrap();
+
+        assert_eq!(
+            policy.classify_from(&hinted, AuthState::Trusted),
+            MessagePriority::High
+        );
+        assert_eq!(
+            policy.classify_from(&critical, AuthState::Trusted),
+            MessagePriority::Critical
+        );
+        assert_eq!(
+            policy.classify_from(&demoted, AuthState::Trusted),
+            MessagePriority::Low
+        );
+
+        // Everyone else is clamped to Normal, even for a handshake
+        for auth in [AuthState::Authenticated, AuthState::Unauthenticated] {
+            assert_eq!(policy.classify_from(&hinted, auth), MessagePriority::Normal);
+            assert_eq!(
+                policy.classify_from(&critical, auth),
+                MessagePriority::Normal
+            );
+        }
+        let auth_init = Message::parse(r#"{"type":"auth_init","priority":"critical"}"#).unwrap();
+        assert_eq!(
+            policy.classify_from(&auth_init, AuthState::Unauthenticated),
+            MessagePriority::Normal
+        );
+        // Lowering is allowed; a bad hint is no hint
+        assert_eq!(
+            policy.classify_from(&demoted, AuthState::Authenticated),
+            MessagePriority::Low
+        );
+        let junk = Message::parse(r#"{"type":"peer_join","priority":7}"#).unwrap();
+        assert_eq!(junk.priority, None);
+        assert_eq!(
+            policy.classify_from(&junk, AuthState::Authenticated),
+            MessagePriority::High
+        );
+
+        // The room floor still applies to trusted hints
+        let floored = RoomPolicy::allow_all().with_priority_floor(MessagePriority::High);
+        assert_eq!(
+            floored.classify_from(&demoted, AuthState::Trusted),
+            MessagePriority::High
+        );
+    }
+
+    #[test]
+    fn test_broadcast_excludes_sender_and_set() {
+        let mut room = Room::new();
+        for peer in ["a", "b", "c", "d", "e"] {
+            room.join(peer, 0).unwrap();
+        }
+        let msg = QueuedMessage::new(br#"{"type":"chat"}"#.to_vec(), 1, 0);
+
+        let recipients = |queue: &mut RoomQueue| {
+            let mut got = Vec::new();
+            while let Some((to, _, m)) = queue.pop(0) {
+                assert_eq!(m, msg);
+                got.push(to);
+            }
+            got.sort();
+            got
+        };
+
+        // Five members, two excluded: only the other three get it
+        let exclude: HashSet<String> = ["b", "d"].iter().map(|p| p.to_string()).collect();
+        let mut queue = RoomQueue::new();
+        let queued =
+            room.broadcast_excluding(&mut queue, "relay", &exclude, MessagePriority::Normal, &msg);
+        assert_eq!(queued, 3);
+        assert_eq!(recipients(&mut queue), ["a", "c", "e"]);
+
+        // A member sender is skipped as well
+        room.broadcast_excluding(&mut queue, "a", &exclude, MessagePriority::Normal, &msg);
+        assert_eq!(recipients(&mut queue), ["c", "e"]);
+
+        // The convenience form skips only the sender
+        assert_eq!(
+            room.broadcast(&mut queue, "c", MessagePriority::High, &msg),
+            4
+        );
+        assert_eq!(recipients(&mut queue), ["a", "b", "d", "e"]);
+    }
+
+    #[test]
+    fn test_registry_room_cap() {
+        let mut rooms: RoomRegistry = RoomRegistry::with_max_rooms(2);
+        rooms
+            .get_or_create("lobby", 0)
+            .unwrap()
+            .join("a", 0)
+            .unwrap();
+        rooms.get_or_create("quiet", 0).unwrap();
+        assert_eq!(rooms.len(), 2);
+
+        // Full: a third room is refused, existing ones are still handed out
+        assert_eq!(
+            rooms.get_or_create("third", 10).err(),
+            Some(TooManyRooms { limit: 2 })
+        );
+        assert!(rooms.get_or_create("lobby", 10).is_ok());
+        assert_eq!(rooms.len(), 2);
+
+        // a keeps lobby alive; quiet was last touched at 0
+        rooms.get_mut("lobby").unwrap().join("a", 5_000).unwrap();
+        assert_eq!(rooms.reap_idle(6_000, 2_000), ["quiet"]);
+        assert!(rooms.get("quiet").is_none());
+
+        assert!(rooms.get_or_create("third", 6_000).is_ok());
+        assert_eq!(rooms.len(), 2);
+        assert!(rooms.get_or_create("fourth", 6_000).is_err());
+    }
+
+    #[test]
+    fn test_registry_join_idempotent() {
+        let mut rooms: RoomRegistry = RoomRegistry::new();
+        let mut queue = RoomQueue::new();
+        let mut announce = |rooms: &RoomRegistry, join: RoomJoin| {
+            if let Some(msg) = join.announce {
+                let msg = QueuedMessage::new(msg.to_json().into_bytes(), 0, 0);
+                let room = rooms.get("lobby").unwrap();
+                room.broadcast(&mut queue, "a", MessagePriority::High, &msg);
+            }
+        };
+
+        rooms.join("lobby", "b", 0).unwrap();
+        let first = rooms.join("lobby", "a", 0).unwrap();
+        assert_eq!(first.outcome, JoinOutcome::Joined(1));
+        assert_eq!(first.generation, Some(2));
+        announce(&rooms, first);
+
+        // The retried frame keeps a's state and announces nothing
+        let again = rooms.join("lobby", "a", 100).unwrap();
+        assert_eq!(again.outcome, JoinOutcome::AlreadyPresent(1));
+        assert_eq!(again.generation, Some(2));
+        assert_eq!(again.announce, None);
+        announce(&rooms, again);
+        assert_eq!(queue.len(), 1);
+        let (to, _, msg) = queue.pop(0).unwrap();
+        assert_eq!(to, "b");
+        assert_eq!(
+            msg.payload,
+            br#"{"type":"peer_join","peer_id":"a","join_index":1,"generation":2}"#
+        );
+
+        // A real rejoin after leaving is announced, with a new generation
+        rooms
+            .get_mut("lobby")
+            .unwrap()
+            .leave("a", LeaveReason::ClientClose);
+        let rejoin = rooms.join("lobby", "a", 200).unwrap();
+        assert_eq!(rejoin.announce.unwrap().generation, 3);
+
+        let mut full = RoomRegistry::with_max_rooms(1);
+        full.join("lobby", "a", 0).unwrap();
+        let err = full.join("other", "a", 0).unwrap_err();
+        assert_eq!(err, JoinError::TooManyRooms(TooManyRooms { limit: 1 }));
+        assert_eq!(err.to_error_message().code, ErrorCode::TooManyRooms);
+    }
+
+    #[test]
+    fn test_debug_snapshot() {
+        let mut room = Room::with_capacity(2, 1);
+        room.join("alice", 10).unwrap();
+        room.join("bob", 20).unwrap();
+        room.join("carol", 30).unwrap();
+        room.join("alice", 40).unwrap();
+        room.set_auth_state("bob", AuthState::Authenticated);
+
+        let mut queue = RoomQueue::new();
+        let msg = QueuedMessage::new(br#"{"type":"chat"}"#.to_vec(), 1, 0);
+        room.broadcast(&mut queue, "bob", MessagePriority::Normal, &msg);
+        room.broadcast(&mut queue, "carol", MessagePriority::Normal, &msg);
+
+        let debug = room.debug_snapshot(&queue);
+        assert_eq!(debug, room.debug_snapshot(&queue));
+        assert_eq!(debug.waiting, ["carol"]);
+        let alice = &debug.peers[0];
+        assert_eq!((alice.peer_id.as_str(), alice.joined_at), ("alice", 10));
+        assert_eq!((alice.last_active, alice.queue_depth), (40, 2));
+        let bob = &debug.peers[1];
+        assert_eq!((bob.join_index, bob.queue_depth), (1, 1));
+        assert_eq!(bob.auth, AuthState::Authenticated);
+        assert_eq!(queue.len(), 3);
+
+        let json: Value = serde_json::from_str(&serde_json::to_string(&debug).unwrap()).unwrap();
+        assert_eq!(json["peers"][1]["auth"], "authenticated");
+    }
+
+    #[test]
+    fn test_idle_eviction_reports_timeout() {
+        let mut room = Room::new();
+        room.join("alice", 0).unwrap();
+        room.join("bob", 0).unwrap();
+        // A retried join counts as activity
+        room.join("bob", 4_000).unwrap();
+
+        let evicted = room.evict_idle(5_001, 5_000);
+        assert_eq!(
+            evicted,
+            vec![PeerLeave {
+                peer_id: "alice".to_string(),
+                reason: LeaveReason::Timeout,
+            }]
+        );
+        assert_eq!(
+            evicted[0].to_json(),
+            r#"{"type":"peer_leave","peer_id":"alice","reason":"timeout"}"#
+        );
+        assert_eq!(
+            MessagePriority::from_message(&evicted[0].to_json()),
+            MessagePriority::High
+        );
+        assert_eq!(room.len(), 1);
+
+        let left = room.leave("bob", LeaveReason::ProtocolError).unwrap();
+        assert!(left.to_json().contains(r#""reason":"protocol_error""#));
+        assert!(room.evict_idle(u64::MAX, 0).is_empty());
+    }
+
+    #[test]
+    fn test_leave_reason_from_close() {
+        assert_eq!(
+            LeaveReason::from_close(1000, true),
+            LeaveReason::ClientClose
+        );
+        assert_eq!(
+            LeaveReason::from_close(1002, true),
+            LeaveReason::ClientClose
+        );
+        assert_eq!(
+            LeaveReason::from_close(1002, false),
+            LeaveReason::ProtocolError
+        );
+        assert_eq!(
+            LeaveReason::from_close(1009, false),
+            LeaveReason::ProtocolError
+        );
+        assert_eq!(LeaveReason::from_close(1006, false), LeaveReason::Timeout);
+        assert_eq!(LeaveReason::from_close(1001, false), LeaveReason::Timeout);
+    }
+
+    #[test]
+    fn test_room_binary_peer_ids() {
+        let mut room: Room<[u8; 32]> = Room::with_capacity(1, 1);
+        assert_eq!(room.join(&[1; 32], 0), Ok(JoinOutcome::Joined(0)));
+        assert_eq!(
+            room.join(&[2; 32], 10),
+            Ok(JoinOutcome::Queued { position: 1 })
+        );
+
+        assert!(room.leave(&[1; 32], LeaveReason::ClientClose).is_some());
+        let admitted = room.promote_waiting(20);
+        assert_eq!(admitted[0].peer_id, [2; 32]);
+        assert_eq!(room.joined_at(&[2; 32]), Some(20));
+    }
+
+    #[test]
+    fn test_signaling_only_room() {
+        let policy = RoomPolicy::signaling_only();
+        let entropy = Message::parse(r#"{"type":"entropy_commit","commit":"ab"}"#).unwrap();
+        assert!(policy.check(&entropy).is_ok());
+        assert_eq!(policy.rejection(&entropy), None);
+
+        let chat = Message::parse(r#"{"type":"chat","msg":"hi"}"#).unwrap();
+        assert_eq!(
+            policy.rejection(&chat).unwrap().to_json(),
+            r#"{"type":"error","code":"type_not_allowed","message":"Message type not allowed in this room","details":{"message_type":"chat"}}"#
+        );
+
+        // Default rooms carry everything
+        assert_eq!(RoomPolicy::default().rejection(&chat), None);
+    }
+
+    #[test]
+    fn test_metadata_set_get() {
+        let mut room: Room = Room::new();
+        assert_eq!(room.get_meta("cipher"), None);
+
+        let meta = room
+            .set_meta("cipher", json!({"suite": "x25519-chacha20", "v": 2}))
+            .unwrap();
+        assert_eq!(
+            meta.to_json(),
+            r#"{"type":"meta","metadata":{"cipher":{"suite":"x25519-chacha20","v":2}}}"#
+        );
+        room.set_meta("mtu", json!(1280)).unwrap();
+        room.set_meta("mtu", json!(1400)).unwrap();
+        assert_eq!(room.get_meta("mtu"), Some(&json!(1400)));
+
+        // A late joiner gets everything in one message
+        room.join("late", 100).unwrap();
+        assert_eq!(room.meta().metadata.len(), 2);
+
+        assert!(room.remove_meta("mtu").is_some());
+        assert!(room.remove_meta("mtu").is_none());
+        assert_eq!(room.meta().metadata.keys().collect::<Vec<_>>(), ["cipher"]);
+    }
+
+    #[test]
+    fn test_metadata_size_cap() {
+        // Key plus quoted value: 1 + 12 = 13 bytes
+        let mut room: Room = Room::new().with_metadata_limit(30);
+        room.set_meta("a", json!("0123456789")).unwrap();
+        room.set_meta("b", json!("0123456789")).unwrap();
+        assert_eq!(
+            room.set_meta("c", json!("0123456789")),
+            Err(MetadataFull {
+                size: 39,
+                limit: 30
+            })
+        );
+        assert_eq!(room.get_meta("c"), None);
+
+        // Replacing an entry only counts the difference
+        room.set_meta("a", json!("0123456789abc")).unwrap();
+        assert!(room.set_meta("a", json!("0123456789abcde")).is_err());
+        assert_eq!(room.get_meta("a"), Some(&json!("0123456789abc")));
+
+        // Removing frees space
+        room.remove_meta("b");
+        room.set_meta("c", json!("0123456789")).unwrap();
+    }
+}
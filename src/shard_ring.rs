@@ -0,0 +1,247 @@
+//! Room-to-instance assignment for a clustered relay
+//!
+//! ShardRing places every relay node on a hash ring at `vnodes` points
+//! (virtual nodes) and gives each room to the node at the first point at
+//! or after the room id's hash. Adding or removing a node only moves the
+//! rooms whose arc it gains or loses, about 1/N of them, and `rebalance`
+//! lists exactly those moves.
+//!
+//! Every instance must compute the same ring, so the hash is spelled out
+//! here (FNV-1a, then a 64-bit finalizer to spread similar ids) instead
+//! of using std's hasher, which may change between Rust releases.
+//!
+//! This is only the routing primitive: it knows nothing about the network
+//! or about which rooms exist. Callers pass the room ids to `rebalance`.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A relay instance, as named in the cluster configuration
+pub type NodeId = String;
+
+/// A room whose owner changed in a `rebalance`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomMove {
+    pub room_id: String,
+    pub from: NodeId,
+    pub to: NodeId,
+}
+
+/// Consistent-hash ring of relay nodes
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct ShardRing {
+    vnodes: usize,
+    /// Ring point -> node owning the arc that ends there
+    ring: BTreeMap<u64, NodeId>,
+    nodes: BTreeSet<NodeId>,
+}
+
+#[allow(dead_code)]
+impl ShardRing {
+    /// Points per node. More points even out the load, at the cost of a
+    /// larger ring to search and rebuild.
+    pub const DEFAULT_VNODES: usize = 128;
+
+    pub fn new(vnodes: usize) -> Self {
+        Self {
+            vnodes: vnodes.max(1),
+            ring: BTreeMap::new(),
+            nodes: BTreeSet::new(),
+        }
+    }
+
+    pub fn with_nodes(vnodes: usize, nodes: impl IntoIterator<Item = NodeId>) -> Self {
+        let mut ring = Self::new(vnodes);
+        for node in nodes {
+            ring.add_node(node);
+        }
+        ring
+    }
+
+    /// Put `node` on the ring; returns false if it was already there
+    pub fn add_node(&mut self, node: NodeId) -> bool {
+        if !self.nodes.insert(node.clone()) {
+            return false;
+        }
+        for point in points(&node, self.vnodes) {
+            // On the (astronomically rare) collision the smaller id wins,
+            // so every instance agrees regardless of insertion order
+            let owner = self.ring.entry(point).or_insert_with(|| node.clone());
+            if node < *owner {
+                *owner = node.clone();
+            }
+        }
+        true
+    }
+
+    /// Take `node` off the ring; returns false if it wasn't there
+    pub fn remove_node(&mut self, node: &str) -> bool {
+        if !self.nodes.remove(node) {
+            return false;
+        }
+        self.ring.retain(|_, owner| owner != node);
+        // Re-add points a colliding node lost to the removed one
+        let nodes: Vec<NodeId> = self.nodes.iter().cloned().collect();
+        for other in nodes {
+            for point in points(&other, self.vnodes) {
+                self.ring.entry(point).or_insert_with(|| other.clone());
+            }
+        }
+        true
+    }
+
+    /// Node that owns `room_id` (None while the ring is empty)
+    pub fn owner(&self, room_id: &str) -> Option<&NodeId> {
+        let point = hash(room_id.as_bytes());
+        self.ring
+            .range(point..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, node)| node)
+    }
+
+    /// Apply a membership change and return the rooms, out of `rooms`,
+    /// that now belong to a different node. Rooms that have no owner before
+    /// or after the change (the ring was or became empty) are left out.
+    pub fn rebalance<'a>(
+        &mut self,
+        added: &[NodeId],
+        removed: &[NodeId],
+        rooms: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<RoomMove> {
+        let rooms: Vec<&str> = rooms.into_iter().collect();
+        let before: Vec<Option<NodeId>> = rooms.iter().map(|r| self.owner(r).cloned()).collect();
+        for node in removed {
+            self.remove_node(node);
+        }
+        for node in added {
+            self.add_node(node.clone());
+        }
+        rooms
+            .into_iter()
+            .zip(before)
+            .filter_map(|(room_id, from)| {
+                let from = from?;
+                let to = self.owner(room_id)?;
+                (*to != from).then(|| RoomMove {
+                    room_id: room_id.to_string(),
+                    from,
+                    to: to.clone(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &NodeId> {
+        self.nodes.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+impl Default for ShardRing {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_VNODES)
+    }
+}
+
+/// Ring points of `node`'s `vnodes` virtual nodes
+fn points(node: &str, vnodes: usize) -> impl Iterator<Item = u64> + '_ {
+    (0..vnodes).map(move |i| hash(format!("{}#{}", node, i).as_bytes()))
+}
+
+/// FNV-1a followed by the murmur3 64-bit finalizer
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in bytes {
+        h ^= u64::from(b);
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(names: &[&str]) -> Vec<NodeId> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn rooms() -> Vec<String> {
+        (0..2000).map(|i| format!("room-{}", i)).collect()
+    }
+
+    #[test]
+    fn test_adding_node_moves_only_its_share() {
+        let mut ring = ShardRing::with_nodes(
+            ShardRing::DEFAULT_VNODES,
+            nodes(&["relay-a", "relay-b", "relay-c", "relay-d"]),
+        );
+        let rooms = rooms();
+        let before: Vec<NodeId> = rooms
+            .iter()
+            .map(|r| ring.owner(r).unwrap().clone())
+            .collect();
+
+        // Roughly even load
+        for node in ["relay-a", "relay-b", "relay-c", "relay-d"] {
+            let owned = before.iter().filter(|n| *n == node).count();
+            assert!((350..=650).contains(&owned), "{} owns {}", node, owned);
+        }
+
+        let moves = ring.rebalance(&nodes(&["relay-e"]), &[], rooms.iter().map(String::as_str));
+        // About a fifth of the rooms move, all of them to the new node
+        assert!((250..=550).contains(&moves.len()), "{} moves", moves.len());
+        assert!(moves.iter().all(|m| m.to == "relay-e" && m.from != m.to));
+        for (room, old) in rooms.iter().zip(&before) {
+            let moved = moves.iter().find(|m| m.room_id == *room);
+            match moved {
+                Some(m) => assert_eq!(m.from, *old),
+                None => assert_eq!(ring.owner(room), Some(old)),
+            }
+        }
+
+        // Re-adding is a no-op
+        assert!(ring
+            .rebalance(&nodes(&["relay-e"]), &[], rooms.iter().map(String::as_str))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_removing_node_moves_only_its_rooms() {
+        let mut ring = ShardRing::with_nodes(16, nodes(&["a", "b", "c"]));
+        let rooms = rooms();
+        let owned_by_b: Vec<&String> = rooms
+            .iter()
+            .filter(|r| ring.owner(r).unwrap() == "b")
+            .collect();
+
+        let moves = ring.rebalance(&[], &nodes(&["b"]), rooms.iter().map(String::as_str));
+        assert_eq!(moves.len(), owned_by_b.len());
+        assert!(moves.iter().all(|m| m.from == "b" && m.to != "b"));
+        assert_eq!(ring.nodes().collect::<Vec<_>>(), ["a", "c"]);
+
+        // Same membership, same ring, whatever the order
+        let reordered = ShardRing::with_nodes(16, nodes(&["c", "a"]));
+        for room in &rooms {
+            assert_eq!(ring.owner(room), reordered.owner(room));
+        }
+
+        // No owners once empty, and nothing to report
+        let moves = ring.rebalance(&[], &nodes(&["a", "c"]), rooms.iter().map(String::as_str));
+        assert!(moves.is_empty());
+        assert_eq!(ring.owner("room-1"), None);
+        assert!(ring.is_empty());
+    }
+}
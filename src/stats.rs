@@ -0,0 +1,629 @@
+//! Relay statistics and their wire encoding
+//!
+//! Heartbeat stats are Low priority and highly repetitive, so instead of
+//! resending every counter each cycle the reporter sends only the counters
+//! that changed, with a full snapshot every Nth cycle to resync receivers.
+//!
+//! PeerStats counts a peer's egress before and after compression; QoS
+//! tiers bill on `sent_bytes`, so compressed traffic is credited for what
+//! it actually cost. It also holds the peer's last round-trip time, which
+//! PingTracker measures by matching each `pong` to the `ping` that carried
+//! the same `id`.
+//!
+//! CodecTimings accumulates the CPU time each codec spends compressing
+//! and decompressing, so codecs can be compared by cost and not only by
+//! ratio. Compression is timed only for messages of at least its
+//! threshold; smaller ones are cheap and would be mostly clock overhead.
+//! Decompression is timed for every compressed payload. The Workers
+//! runtime has no usable clock (`Instant` panics there, and `Date.now()`
+//! doesn't advance while a request runs), so on wasm only the byte and
+//! call counters move.
+//!
+//! SizeHistogram records the pre-compression size of ingested messages,
+//! for tuning the compression threshold. DwellHistogram records how long
+//! messages waited in a queue, in power-of-two millisecond buckets.
+
+use crate::message_optimizer::{
+    CompressResult, CompressionKind, Message, MessagePriority, MessageType,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+
+/// Named counters at a point in time
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    pub counters: BTreeMap<String, u64>,
+}
+
+/// Changes between two snapshots (or a full snapshot when `full`)
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsDelta {
+    /// Receiver should replace its state instead of patching it
+    pub full: bool,
+    pub changed: BTreeMap<String, u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<String>,
+}
+
+#[allow(dead_code)]
+impl StatsSnapshot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, name: &str, value: u64) {
+        self.counters.insert(name.to_string(), value);
+    }
+
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.counters.get(name).copied()
+    }
+
+    /// Counters that differ from `previous`
+    pub fn diff(&self, previous: &StatsSnapshot) -> StatsDelta {
+        StatsDelta {
+            full: false,
+            changed: self
+                .counters
+                .iter()
+                .filter(|(k, v)| previous.counters.get(*k) != Some(*v))
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+            removed: previous
+                .counters
+                .keys()
+                .filter(|k| !self.counters.contains_key(*k))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Delta carrying every counter
+    pub fn full(&self) -> StatsDelta {
+        StatsDelta {
+            full: true,
+            changed: self.counters.clone(),
+            removed: Vec::new(),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl StatsDelta {
+    /// Reconstruct the snapshot this delta was computed from
+    pub fn apply(&self, previous: &StatsSnapshot) -> StatsSnapshot {
+        let mut counters = if self.full {
+            BTreeMap::new()
+        } else {
+            previous.counters.clone()
+        };
+        for name in &self.removed {
+            counters.remove(name);
+        }
+        counters.extend(self.changed.iter().map(|(k, v)| (k.clone(), *v)));
+        StatsSnapshot { counters }
+    }
+}
+
+/// Produces heartbeat deltas, forcing a full snapshot every `full_every` cycles
+#[allow(dead_code)]
+pub struct StatsReporter {
+    full_every: u32,
+    cycle: u32,
+    last: Option<StatsSnapshot>,
+}
+
+#[allow(dead_code)]
+impl StatsReporter {
+    pub fn new(full_every: u32) -> Self {
+        Self {
+            full_every: full_every.max(1),
+            cycle: 0,
+            last: None,
+        }
+    }
+
+    /// Delta to send for this cycle's snapshot
+    pub fn next(&mut self, current: StatsSnapshot) -> StatsDelta {
+        let delta = match &self.last {
+            Some(last) if !self.cycle.is_multiple_of(self.full_every) => current.diff(last),
+            _ => current.full(),
+        };
+        self.cycle = self.cycle.wrapping_add(1);
+        self.last = Some(current);
+        delta
+    }
+}
+
+/// Egress totals for one peer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PeerStats {
+    pub messages: u64,
+    /// Message bytes before compression
+    pub original_bytes: u64,
+    /// Payload bytes actually sent
+    pub sent_bytes: u64,
+    /// Most recent ping round trip (see `PingTracker`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_rtt_ms: Option<u64>,
+}
+
+#[allow(dead_code)]
+impl PeerStats {
+    pub fn record(&mut self, result: &CompressResult) {
+        self.messages += 1;
+        self.original_bytes += result.original_len as u64;
+        self.sent_bytes += result.data.len() as u64;
+    }
+
+    /// Sent bytes per original byte (1.0 until anything was sent)
+    pub fn compression_ratio(&self) -> f64 {
+        if self.original_bytes == 0 {
+            1.0
+        } else {
+            self.sent_bytes as f64 / self.original_bytes as f64
+        }
+    }
+
+    pub fn bytes_saved(&self) -> u64 {
+        self.original_bytes.saturating_sub(self.sent_bytes)
+    }
+}
+
+/// Measures a peer's round-trip time from the relay's pings and the
+/// peer's pongs, which echo the ping's `id`.
+///
+/// At most `capacity` pings are awaited. A new ping beyond that forgets
+/// the oldest one, so a peer that never answers can't grow the table.
+#[allow(dead_code)]
+pub struct PingTracker {
+    capacity: usize,
+    /// Unanswered ping ids (as JSON) and when each was sent
+    pending: VecDeque<(String, u64)>,
+}
+
+#[allow(dead_code)]
+impl PingTracker {
+    /// Default number of unanswered pings remembered
+    pub const DEFAULT_CAPACITY: usize = 8;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Note a `ping` sent to the peer at `now_ms`. Pings without an `id`
+    /// can't be matched and are ignored.
+    pub fn ping_sent(&mut self, msg: &Message, now_ms: u64) {
+        let (MessageType::Ping, Some(id)) = (msg.msg_type, &msg.id) else {
+            return;
+        };
+        if self.pending.len() == self.capacity {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((id.to_string(), now_ms));
+    }
+
+    /// Match a `pong` received at `now_ms` to its ping and record the round
+    /// trip in `stats`. Returns the RTT, or None if nothing matched.
+    pub fn pong_received(
+        &mut self,
+        msg: &Message,
+        now_ms: u64,
+        stats: &mut PeerStats,
+    ) -> Option<u64> {
+        let (MessageType::Pong, Some(id)) = (msg.msg_type, &msg.id) else {
+            return None;
+        };
+        let id = id.to_string();
+        let i = self
+            .pending
+            .iter()
+            .position(|(pending, _)| *pending == id)?;
+        let (_, sent_at) = self.pending.remove(i)?;
+        let rtt = now_ms.saturating_sub(sent_at);
+        stats.last_rtt_ms = Some(rtt);
+        Some(rtt)
+    }
+
+    /// Pings still awaiting a pong
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+impl Default for PingTracker {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_CAPACITY)
+    }
+}
+
+/// CPU time one codec spent, and how much it handled in that time
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct CodecTime {
+    /// Timed compressions
+    pub compressed: u64,
+    /// Input bytes of the timed compressions
+    pub compress_bytes: u64,
+    pub compress_ns: u64,
+    pub decompressed: u64,
+    /// Output bytes of the successful decompressions
+    pub decompress_bytes: u64,
+    pub decompress_ns: u64,
+}
+
+impl CodecTime {
+    fn merge(&mut self, other: &CodecTime) {
+        self.compressed += other.compressed;
+        self.compress_bytes += other.compress_bytes;
+        self.compress_ns += other.compress_ns;
+        self.decompressed += other.decompressed;
+        self.decompress_bytes += other.decompress_bytes;
+        self.decompress_ns += other.decompress_ns;
+    }
+}
+
+/// Cumulative compress/decompress time per codec
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CodecTimings {
+    /// Smallest input whose compression is timed
+    threshold: usize,
+    gzip: CodecTime,
+    deflate: CodecTime,
+}
+
+#[allow(dead_code)]
+impl CodecTimings {
+    /// Same as the default compression threshold: below it, messages are
+    /// sent raw unless a policy says otherwise
+    pub const DEFAULT_THRESHOLD: usize = 1024;
+
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            gzip: CodecTime::default(),
+            deflate: CodecTime::default(),
+        }
+    }
+
+    /// Totals for `kind` (always zero for `None`)
+    pub fn get(&self, kind: CompressionKind) -> CodecTime {
+        match kind {
+            CompressionKind::None => CodecTime::default(),
+            CompressionKind::Gzip => self.gzip,
+            CompressionKind::Deflate => self.deflate,
+        }
+    }
+
+    fn entry(&mut self, kind: CompressionKind) -> Option<&mut CodecTime> {
+        match kind {
+            CompressionKind::None => None,
+            CompressionKind::Gzip => Some(&mut self.gzip),
+            CompressionKind::Deflate => Some(&mut self.deflate),
+        }
+    }
+
+    /// Run `compress` over `input_len` bytes with `kind`, timing it if the
+    /// input is at least the threshold
+    pub fn time_compress<T>(
+        &mut self,
+        kind: CompressionKind,
+        input_len: usize,
+        compress: impl FnOnce() -> T,
+    ) -> T {
+        let threshold = self.threshold;
+        match self.entry(kind) {
+            Some(entry) if input_len >= threshold => {
+                let (out, ns) = timed(compress);
+                entry.compressed += 1;
+                entry.compress_bytes += input_len as u64;
+                entry.compress_ns += ns;
+                out
+            }
+            _ => compress(),
+        }
+    }
+
+    /// Run `decompress` on a `kind` payload, timing it
+    pub fn time_decompress<E>(
+        &mut self,
+        kind: CompressionKind,
+        decompress: impl FnOnce() -> Result<String, E>,
+    ) -> Result<String, E> {
+        let Some(entry) = self.entry(kind) else {
+            return decompress();
+        };
+        let (out, ns) = timed(decompress);
+        entry.decompressed += 1;
+        entry.decompress_ns += ns;
+        if let Ok(text) = &out {
+            entry.decompress_bytes += text.len() as u64;
+        }
+        out
+    }
+
+    /// Add `other`'s totals (e.g. to sum every peer's compressor)
+    pub fn merge(&mut self, other: &CodecTimings) {
+        self.gzip.merge(&other.gzip);
+        self.deflate.merge(&other.deflate);
+    }
+
+    /// Write the totals into `snapshot` as `<codec>_<counter>`, e.g.
+    /// `gzip_compress_ns`
+    pub fn export(&self, snapshot: &mut StatsSnapshot) {
+        for (codec, time) in [("gzip", &self.gzip), ("deflate", &self.deflate)] {
+            let counters = [
+                ("compressed", time.compressed),
+                ("compress_bytes", time.compress_bytes),
+                ("compress_ns", time.compress_ns),
+                ("decompressed", time.decompressed),
+                ("decompress_bytes", time.decompress_bytes),
+                ("decompress_ns", time.decompress_ns),
+            ];
+            for (name, value) in counters {
+                snapshot.set(&format!("{}_{}", codec, name), value);
+            }
+        }
+    }
+}
+
+impl Default for CodecTimings {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_THRESHOLD)
+    }
+}
+
+/// Run `f` and return how long it took in ns
+#[cfg(not(target_arch = "wasm32"))]
+fn timed<T>(f: impl FnOnce() -> T) -> (T, u64) {
+    let start = std::time::Instant::now();
+    let out = f();
+    let ns = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+    (out, ns)
+}
+
+/// No clock on Workers; see the module docs
+#[cfg(target_arch = "wasm32")]
+fn timed<T>(f: impl FnOnce() -> T) -> (T, u64) {
+    (f(), 0)
+}
+
+/// Count of messages up to `le` bytes (None = larger than every bound)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct SizeBucket {
+    pub le: Option<usize>,
+    pub count: u64,
+}
+
+/// Message size distribution over fixed bucket bounds
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct SizeHistogram {
+    /// Inclusive upper bounds, ascending
+    bounds: Vec<usize>,
+    /// One per bound plus the overflow bucket
+    counts: Vec<u64>,
+}
+
+#[allow(dead_code)]
+impl SizeHistogram {
+    /// Default bounds, bracketing the 1KB compression threshold
+    pub const DEFAULT_BOUNDS: [usize; 7] = [128, 256, 512, 1024, 4096, 16384, 65536];
+
+    pub fn new(mut bounds: Vec<usize>) -> Self {
+        bounds.sort_unstable();
+        bounds.dedup();
+        let counts = vec![0; bounds.len() + 1];
+        Self { bounds, counts }
+    }
+
+    pub fn record(&mut self, len: usize) {
+        let i = self.bounds.partition_point(|&b| b < len);
+        self.counts[i] += 1;
+    }
+
+    /// Ingest hook: record `msg`'s size and classify it
+    pub fn ingest(&mut self, msg: &str) -> MessagePriority {
+        self.record(msg.len());
+        MessagePriority::from_message(msg)
+    }
+
+    pub fn snapshot(&self) -> Vec<SizeBucket> {
+        self.bounds
+            .iter()
+            .map(|&b| Some(b))
+            .chain(std::iter::once(None))
+            .zip(&self.counts)
+            .map(|(le, &count)| SizeBucket { le, count })
+            .collect()
+    }
+}
+
+impl Default for SizeHistogram {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_BOUNDS.to_vec())
+    }
+}
+
+/// Queue dwell times in exponential buckets: bucket 0 holds 0ms, bucket
+/// `i` holds `[2^(i-1), 2^i)` ms, and the last bucket everything longer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DwellHistogram {
+    counts: [u64; DwellHistogram::BUCKETS],
+}
+
+/// Dwell percentiles in ms (upper bound of the bucket each falls in)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DwellPercentiles {
+    pub p50: Option<u64>,
+    pub p90: Option<u64>,
+    pub p99: Option<u64>,
+}
+
+#[allow(dead_code)]
+impl DwellHistogram {
+    /// Up to 2^22 ms (about 70 minutes) before the overflow bucket
+    pub const BUCKETS: usize = 24;
+
+    pub fn record(&mut self, dwell_ms: u64) {
+        let bucket = (u64::BITS - dwell_ms.leading_zeros()) as usize;
+        self.counts[bucket.min(Self::BUCKETS - 1)] += 1;
+    }
+
+    pub fn counts(&self) -> &[u64; Self::BUCKETS] {
+        &self.counts
+    }
+
+    /// Largest dwell bucket `i` can hold, in ms (None for overflow)
+    pub fn bucket_bound(i: usize) -> Option<u64> {
+        match i {
+            0 => Some(0),
+            i if i < Self::BUCKETS - 1 => Some((1u64 << i) - 1),
+            _ => None,
+        }
+    }
+
+    /// Dwell at `quantile` (0.0–1.0), as its bucket's upper bound;
+    /// None if nothing was recorded or it falls in the overflow bucket
+    pub fn percentile(&self, quantile: f64) -> Option<u64> {
+        let total: u64 = self.counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::bucket_bound(i);
+            }
+        }
+        None
+    }
+
+    pub fn percentiles(&self) -> DwellPercentiles {
+        DwellPercentiles {
+            p50: self.percentile(0.5),
+            p90: self.percentile(0.9),
+            p99: self.percentile(0.99),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(pairs: &[(&str, u64)]) -> StatsSnapshot {
+        let mut s = StatsSnapshot::new();
+        for (k, v) in pairs {
+            s.set(k, *v);
+        }
+        s
+    }
+
+    #[test]
+    fn test_delta_reconstructs_snapshot() {
+        let previous = snapshot(&[("rx", 10), ("tx", 5), ("peers", 2), ("drops", 1)]);
+        let current = snapshot(&[("rx", 12), ("tx", 5), ("peers", 3)]);
+
+        let delta = current.diff(&previous);
+        assert!(!delta.full);
+        assert_eq!(delta.changed.len(), 2);
+        assert_eq!(delta.removed, vec!["drops".to_string()]);
+        assert_eq!(delta.apply(&previous), current);
+    }
+
+    #[test]
+    fn test_reporter_periodic_full() {
+        let mut reporter = StatsReporter::new(3);
+        let mut receiver = StatsSnapshot::new();
+
+        for i in 0..6u64 {
+            let current = snapshot(&[("uptime", 100), ("rx", i)]);
+            let delta = reporter.next(current.clone());
+            assert_eq!(delta.full, i % 3 == 0, "cycle {}", i);
+            if !delta.full {
+                // Unchanged counters are omitted
+                assert!(!delta.changed.contains_key("uptime"));
+            }
+            receiver = delta.apply(&receiver);
+            assert_eq!(receiver, current);
+        }
+    }
+
+    #[test]
+    fn test_ping_pong_rtt() {
+        let mut tracker = PingTracker::new(2);
+        let mut stats = PeerStats::default();
+        let parse = |text: &str| Message::parse(text).unwrap();
+
+        tracker.ping_sent(&parse(r#"{"type":"ping","id":1,"ts":1000}"#), 1_000);
+        tracker.ping_sent(&parse(r#"{"type":"ping","id":2,"ts":1500}"#), 1_500);
+        // Unmatched ids and non-pongs are ignored
+        let none = tracker.pong_received(&parse(r#"{"type":"pong","id":9}"#), 1_600, &mut stats);
+        assert_eq!(none, None);
+        let chat = parse(r#"{"type":"chat","id":1}"#);
+        assert_eq!(tracker.pong_received(&chat, 1_600, &mut stats), None);
+        assert_eq!(stats.last_rtt_ms, None);
+
+        let pong = parse(r#"{"type":"pong","id":1}"#);
+        assert_eq!(tracker.pong_received(&pong, 1_042, &mut stats), Some(42));
+        assert_eq!(stats.last_rtt_ms, Some(42));
+        // Each ping is answered once
+        assert_eq!(tracker.pong_received(&pong, 1_100, &mut stats), None);
+        assert_eq!(tracker.pending(), 1);
+
+        // Bounded: the oldest unanswered ping is forgotten
+        tracker.ping_sent(&parse(r#"{"type":"ping","id":3}"#), 2_000);
+        tracker.ping_sent(&parse(r#"{"type":"ping","id":4}"#), 2_000);
+        let late = parse(r#"{"type":"pong","id":2}"#);
+        assert_eq!(tracker.pong_received(&late, 2_100, &mut stats), None);
+        assert_eq!(stats.last_rtt_ms, Some(42));
+    }
+
+    #[test]
+    fn test_size_histogram_buckets() {
+        let mut histogram = SizeHistogram::new(vec![1024, 100]);
+        for len in [0, 100, 101, 1024, 1025, 50_000] {
+            histogram.record(len);
+        }
+        let chat = format!(r#"{{"type":"chat","msg":"{}"}}"#, "x".repeat(200));
+        assert_eq!(histogram.ingest(&chat), MessagePriority::Normal);
+
+        let counts: Vec<(Option<usize>, u64)> = histogram
+            .snapshot()
+            .iter()
+            .map(|b| (b.le, b.count))
+            .collect();
+        assert_eq!(counts, vec![(Some(100), 2), (Some(1024), 3), (None, 2)]);
+    }
+
+    #[test]
+    fn test_dwell_histogram_buckets() {
+        let mut dwell = DwellHistogram::default();
+        assert_eq!(dwell.percentile(0.5), None);
+
+        // 0ms, 1ms, 2-3ms, 4-7ms, ..., 512-1023ms
+        for ms in [0, 1, 3, 3, 5, 600] {
+            dwell.record(ms);
+        }
+        dwell.record(u64::MAX);
+        let counts = dwell.counts();
+        assert_eq!(counts[..5], [1, 1, 2, 1, 0]);
+        assert_eq!(counts[10], 1);
+        assert_eq!(counts[DwellHistogram::BUCKETS - 1], 1);
+
+        assert_eq!(
+            dwell.percentiles(),
+            DwellPercentiles {
+                p50: Some(3),
+                p90: None,
+                p99: None,
+            }
+        );
+        assert_eq!(dwell.percentile(0.8), Some(1023));
+    }
+}
@@ -0,0 +1,546 @@
+//! In-memory relay for integration tests (`test-relay` feature)
+//!
+//! TestRelay runs the real ingest path without sockets or a Durable
+//! Object. Rooms live in a RoomRegistry, as on a relay that hosts them in
+//! one process. Each simulated peer is a pair of channels. `pump` drains
+//! every peer's inbound frames, decodes and classifies them
+//! (`process_frame`, whose parsed message is used from then on),
+//! applies the room's policy and auth caps, fans each message out to the
+//! other members through the room's RoomQueue scheduler, and flushes
+//! every recipient's share with `flush_to`. Peers therefore see the same
+//! wire frames a live relay sends: varint-length-prefixed Frames, with
+//! Low traffic batched and large messages compressed. `messages` turns
+//! one of those frames back into JSON texts, splitting a frame flagged
+//! as a batch. A peer's join is announced to the members already there
+//! with `peer_join`. `connect_as` sets the auth state it's classified
+//! under (`connect` leaves it Unauthenticated). Capabilities a peer
+//! advertises in its handshake are recorded on its room membership, so
+//! `presence` reports what it negotiated.
+//!
+//! Time doesn't pass: everything is queued at 0ms. Frames that fail to
+//! decode are dropped and counted in `rejected`. Messages the room's
+//! policy refuses get a `type_not_allowed` error reply, and, in a room
+//! with a validator (`set_validator`), ones missing required fields get
+//! `invalid_message`.
+//!
+//! With rate limits set (`set_rate_limits`), a peer over its own limit
+//! gets a `rate_limited` error and is disconnected, and a message over
+//! the room's budget is dropped; its sender gets the `room_rate_limited`
+//! notice. Every departure is announced to the peers that remain with a
+//! `peer_leave` carrying its reason.
+//!
+//! `with_max_rooms` caps the registry. A peer that would create a room past
+//! the cap gets a `too_many_rooms` error and a closed channel. A room is
+//! dropped from the registry once its last peer is gone, which frees its
+//! slot; its policy and validator stay set.
+
+use crate::batcher::read_varint;
+use crate::capabilities::PeerCapabilities;
+use crate::error_message::ErrorMessage;
+use crate::flush::flush_to;
+use crate::message_optimizer::{
+    process_frame, Frame, MessagePriority, OptimizerError, SequenceStamper,
+};
+use crate::priority_queue::{PriorityQueue, QueuedMessage, RoomQueue};
+use crate::quota::{RateDecision, RateLimit, RateLimiter};
+use crate::room::{AuthState, LeaveReason, PresenceInfo, Room, RoomPolicy, RoomRegistry};
+use crate::validate::Validator;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Write};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+
+/// Writer that hands each frame to a peer's receiving channel
+struct ChannelWriter<'a>(&'a Sender<Vec<u8>>);
+
+impl Write for ChannelWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A simulated connection, as the relay sees it
+struct Connection {
+    peer_id: String,
+    inbound: Receiver<Vec<u8>>,
+    outbound: Sender<Vec<u8>>,
+}
+
+/// A room's relay-side state; its membership lives in the registry
+#[derive(Default)]
+struct TestRoom {
+    policy: RoomPolicy,
+    validator: Option<Validator>,
+    limiter: Option<RateLimiter>,
+    queue: RoomQueue,
+    capabilities: PeerCapabilities,
+    connections: Vec<Connection>,
+}
+
+/// Rooms and their connected peers, driven by `pump`
+#[derive(Default)]
+pub struct TestRelay {
+    /// Membership of every room
+    registry: RoomRegistry,
+    /// Policy, queue and connections per room id
+    rooms: HashMap<String, TestRoom>,
+    stamper: SequenceStamper,
+    rejected: usize,
+}
+
+impl TestRelay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Relay that holds at most `max_rooms` rooms at once
+    pub fn with_max_rooms(max_rooms: usize) -> Self {
+        Self {
+            registry: RoomRegistry::with_max_rooms(max_rooms),
+            ..Self::default()
+        }
+    }
+
+    /// Use `policy` for `room_id` instead of `RoomPolicy::allow_all`
+    pub fn set_policy(&mut self, room_id: &str, policy: RoomPolicy) {
+        self.rooms.entry(room_id.to_string()).or_default().policy = policy;
+    }
+
+    /// Check messages in `room_id` against `validator` before fanning
+    /// them out
+    pub fn set_validator(&mut self, room_id: &str, validator: Validator) {
+        self.rooms.entry(room_id.to_string()).or_default().validator = Some(validator);
+    }
+
+    /// Charge messages in `room_id` against per-peer and room-wide limits.
+    /// No time passes, so the buckets never refill.
+    pub fn set_rate_limits(&mut self, room_id: &str, peer: RateLimit, room: RateLimit) {
+        self.rooms.entry(room_id.to_string()).or_default().limiter =
+            Some(RateLimiter::new(peer, room, 0));
+    }
+
+    /// Join `peer_id` to `room_id`. Returns the channel the peer sends frames
+    /// on and the channel it receives the relay's frames on. When the
+    /// peer drops its sender, the next `pump` removes it from the room.
+    /// If the room can't be joined, the peer receives the error and then
+    /// the relay hangs up.
+    pub fn connect(
+        &mut self,
+        room_id: &str,
+        peer_id: &str,
+    ) -> (Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+        self.connect_as(room_id, peer_id, AuthState::Unauthenticated)
+    }
+
+    /// `connect`, for a peer already in state `auth`
+    pub fn connect_as(
+        &mut self,
+        room_id: &str,
+        peer_id: &str,
+        auth: AuthState,
+    ) -> (Sender<Vec<u8>>, Receiver<Vec<u8>>) {
+        let (to_relay, inbound) = channel();
+        let (outbound, from_relay) = channel();
+        let joined = match self.registry.join(room_id, peer_id, 0) {
+            Ok(joined) => joined,
+            Err(e) => {
+                let reply = e.to_error_message().to_json().into_bytes();
+                send_now(&outbound, reply, self.stamper.next_seq());
+                return (to_relay, from_relay);
+            }
+        };
+        let room = self.rooms.entry(room_id.to_string()).or_default();
+        if let Some(members) = self.registry.get_mut(room_id) {
+            members.set_auth_state(peer_id, auth);
+            if let Some(announce) = joined.announce {
+                let notice =
+                    QueuedMessage::new(announce.to_json().into_bytes(), self.stamper.next_seq(), 0)
+                        .parsed();
+                members.broadcast(&mut room.queue, peer_id, MessagePriority::High, &notice);
+            }
+        }
+        room.connections.push(Connection {
+            peer_id: peer_id.to_string(),
+            inbound,
+            outbound,
+        });
+        (to_relay, from_relay)
+    }
+
+    /// Process every frame sent so far and deliver the results. Returns the
+    /// number of inbound frames handled.
+    pub fn pump(&mut self) -> usize {
+        let mut handled = 0;
+        let mut emptied = Vec::new();
+        for (room_id, room) in &mut self.rooms {
+            let Some(members) = self.registry.get_mut(room_id) else {
+                continue;
+            };
+            let mut received = Vec::new();
+            room.connections.retain(|conn| loop {
+                match conn.inbound.try_recv() {
+                    Ok(raw) => received.push((conn.peer_id.clone(), raw)),
+                    Err(TryRecvError::Empty) => break true,
+                    Err(TryRecvError::Disconnected) => break false,
+                }
+            });
+            let connected: Vec<String> =
+                room.connections.iter().map(|c| c.peer_id.clone()).collect();
+            for member in members.presence() {
+                if !connected.contains(&member.peer_id) {
+                    let seq = self.stamper.next_seq();
+                    depart(
+                        members,
+                        room,
+                        &member.peer_id,
+                        LeaveReason::ClientClose,
+                        seq,
+                    );
+                }
+            }
+
+            for (from, raw) in received {
+                handled += 1;
+                if !room.connections.iter().any(|c| c.peer_id == from) {
+                    // Disconnected earlier in this pump
+                    continue;
+                }
+                let Ok(decoded) = process_frame(&raw) else {
+                    self.rejected += 1;
+                    continue;
+                };
+                let msg = &decoded.message;
+                let seq = self.stamper.next_seq();
+                let invalid = room.validator.as_ref().and_then(|v| v.check(msg).err());
+                let reply = if room.policy.check(msg).is_err() {
+                    Some(ErrorMessage::type_not_allowed(msg.msg_type))
+                } else {
+                    invalid.map(|e| ErrorMessage::invalid_message(&e))
+                };
+                if let Some(reply) = reply {
+                    room.queue.push(
+                        from.as_str(),
+                        MessagePriority::High,
+                        QueuedMessage::new(reply.to_json().into_bytes(), seq, 0),
+                    );
+                    continue;
+                }
+                room.capabilities.observe(&from, msg);
+                members.set_capabilities(from.as_str(), room.capabilities.get(&from));
+                let priority = room
+                    .policy
+                    .classify_from(msg, members.auth_state(from.as_str()));
+                let decision = match &mut room.limiter {
+                    Some(limiter) => limiter.try_acquire(&from, priority, raw.len(), 0),
+                    None => RateDecision::Allowed,
+                };
+                match decision {
+                    RateDecision::Allowed => {}
+                    RateDecision::PeerLimited { retry_after_ms } => {
+                        let reply = ErrorMessage::rate_limited(retry_after_ms).to_json();
+                        if let Some(conn) = room.connections.iter().find(|c| c.peer_id == from) {
+                            send_now(&conn.outbound, reply.into_bytes(), seq);
+                        }
+                        room.connections.retain(|c| c.peer_id != from);
+                        depart(members, room, &from, LeaveReason::RateLimited, seq);
+                        continue;
+                    }
+                    RateDecision::RoomLimited { notice, .. } => {
+                        if let Some(notice) = notice {
+                            room.queue.push(
+                                from.as_str(),
+                                MessagePriority::High,
+                                QueuedMessage::new(notice.to_json().into_bytes(), seq, 0),
+                            );
+                        }
+                        continue;
+                    }
+                }
+                let queued = QueuedMessage::new(decoded.text.clone().into_bytes(), seq, 0)
+                    .with_sender(&from)
+                    .with_message(msg.clone());
+                members.broadcast(&mut room.queue, from.as_str(), priority, &queued);
+            }
+
+            if members.is_empty() {
+                emptied.push(room_id.clone());
+                continue;
+            }
+
+            // Scheduler order decides what each recipient's flush carries
+            let mut outgoing: HashMap<String, PriorityQueue> = HashMap::new();
+            while let Some((to, priority, msg)) = room.queue.pop(0) {
+                outgoing.entry(to).or_default().push(priority, msg);
+            }
+            for conn in &room.connections {
+                if let Some(queue) = outgoing.get_mut(&conn.peer_id) {
+                    // A peer that hung up is removed on the next pump
+                    let _ = flush_to(queue, &mut ChannelWriter(&conn.outbound), 0);
+                }
+            }
+        }
+        for room_id in emptied {
+            self.registry.remove(&room_id);
+        }
+        handled
+    }
+
+    /// Members of `room_id`, in join order
+    pub fn members(&self, room_id: &str) -> Vec<String> {
+        self.registry.get(room_id).map_or_else(Vec::new, |room| {
+            room.presence().into_iter().map(|p| p.peer_id).collect()
+        })
+    }
+
+    /// The presence snapshot a peer joining `room_id` would get
+    pub fn presence(&self, room_id: &str) -> Vec<PresenceInfo> {
+        self.registry
+            .get(room_id)
+            .map_or_else(Vec::new, |room| room.presence())
+    }
+
+    /// Inbound frames dropped because they didn't decode
+    pub fn rejected(&self) -> usize {
+        self.rejected
+    }
+
+    /// The JSON messages in a frame received from the relay (several for a
+    /// Low batch)
+    pub fn messages(frame: &[u8]) -> Result<Vec<String>, OptimizerError> {
+        let mut rest = frame;
+        let len = read_varint(&mut rest)?;
+        if len != rest.len() as u64 {
+            return Err(OptimizerError::Truncated);
+        }
+        Frame::decode_messages(rest)
+    }
+}
+
+/// Write one High message to a peer ahead of its queue, before the relay
+/// hangs up on it
+fn send_now(outbound: &Sender<Vec<u8>>, reply: Vec<u8>, seq: u64) {
+    let mut queue = PriorityQueue::new();
+    queue.push(MessagePriority::High, QueuedMessage::new(reply, seq, 0));
+    let _ = flush_to(&mut queue, &mut ChannelWriter(outbound), 0);
+}
+
+/// Remove `peer_id` from the room and announce why to everyone left
+fn depart(members: &mut Room, room: &mut TestRoom, peer_id: &str, reason: LeaveReason, seq: u64) {
+    room.queue.remove_peer(peer_id);
+    room.capabilities.remove(peer_id);
+    if let Some(limiter) = &mut room.limiter {
+        limiter.remove(peer_id);
+    }
+    if let Some(leave) = members.leave(peer_id, reason) {
+        let notice = QueuedMessage::new(leave.to_json().into_bytes(), seq, 0).parsed();
+        members.broadcast(&mut room.queue, peer_id, MessagePriority::High, &notice);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn received(rx: &Receiver<Vec<u8>>) -> Vec<String> {
+        rx.try_iter()
+            .flat_map(|frame| TestRelay::messages(&frame).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_two_peers_exchange_chat() {
+        let mut relay = TestRelay::new();
+        let (alice_tx, alice_rx) = relay.connect("lobby", "alice");
+        let (bob_tx, bob_rx) = relay.connect("lobby", "bob");
+        assert_eq!(relay.members("lobby"), ["alice", "bob"]);
+
+        let hello = r#"{"type":"chat","msg":"hi bob"}"#;
+        alice_tx.send(hello.as_bytes().to_vec()).unwrap();
+        assert_eq!(relay.pump(), 1);
+        assert_eq!(received(&bob_rx), [hello]);
+        // alice was already there, so she hears about bob
+        let joins = received(&alice_rx);
+        assert_eq!(joins.len(), 1);
+        assert!(
+            joins[0].starts_with(r#"{"type":"peer_join","peer_id":"bob""#),
+            "{}",
+            joins[0]
+        );
+
+        // Framed and compressed input takes the same path
+        let reply = format!(r#"{{"type":"chat","msg":"{}"}}"#, "hi alice ".repeat(200));
+        bob_tx
+            .send(Frame::uncompressed(reply.clone().into_bytes()).encode())
+            .unwrap();
+        bob_tx.send(b"\x00not json".to_vec()).unwrap();
+        relay.pump();
+        assert_eq!(received(&alice_rx), [reply]);
+        assert_eq!(relay.rejected(), 1);
+
+        drop(bob_tx);
+        relay.pump();
+        assert_eq!(relay.members("lobby"), ["alice"]);
+        assert_eq!(
+            received(&alice_rx),
+            [r#"{"type":"peer_leave","peer_id":"bob","reason":"client_close"}"#]
+        );
+    }
+
+    #[test]
+    fn test_rate_limited_peer_is_disconnected() {
+        let mut relay = TestRelay::new();
+        relay.set_rate_limits("lobby", RateLimit::new(2, 0.0), RateLimit::new(100, 0.0));
+        let (alice_tx, alice_rx) = relay.connect("lobby", "alice");
+        let (_bob_tx, bob_rx) = relay.connect("lobby", "bob");
+
+        let chat = r#"{"type":"chat","msg":"hi"}"#;
+        for _ in 0..4 {
+            alice_tx.send(chat.as_bytes().to_vec()).unwrap();
+        }
+        relay.pump();
+
+        // The third message is over alice's burst: she hears why and is
+        // cut off, and the fourth is never read
+        assert_eq!(relay.members("lobby"), ["bob"]);
+        let reply = received(&alice_rx);
+        assert_eq!(reply.len(), 1);
+        assert!(reply[0].contains("rate_limited"), "{}", reply[0]);
+        assert!(alice_rx.recv().is_err());
+        assert_eq!(
+            received(&bob_rx),
+            [
+                r#"{"type":"peer_leave","peer_id":"alice","reason":"rate_limited"}"#,
+                chat,
+                chat
+            ]
+        );
+    }
+
+    #[test]
+    fn test_presence_reports_negotiated_capabilities() {
+        use crate::capabilities::Capabilities;
+
+        let mut relay = TestRelay::new();
+        let (alice_tx, _alice_rx) = relay.connect("lobby", "alice");
+        let (_bob_tx, _bob_rx) = relay.connect("lobby", "bob");
+        alice_tx
+            .send(br#"{"type":"auth_init","capabilities":["deflate","zstd"]}"#.to_vec())
+            .unwrap();
+        relay.pump();
+
+        let presence = relay.presence("lobby");
+        assert_eq!(presence[0].peer_id, "alice");
+        assert_eq!(presence[0].capabilities, Capabilities::DEFLATE);
+        assert_eq!(presence[1].capabilities, Capabilities::legacy());
+    }
+
+    #[test]
+    fn test_priority_order_and_policy() {
+        let mut relay = TestRelay::new();
+        relay.set_policy("control", RoomPolicy::signaling_only());
+        let (a_tx, a_rx) = relay.connect("control", "a");
+        let (_b_tx, b_rx) = relay.connect("control", "b");
+        relay.pump();
+        assert_eq!(received(&a_rx).len(), 1);
+
+        a_tx.send(br#"{"type":"entropy_commit"}"#.to_vec()).unwrap();
+        a_tx.send(br#"{"type":"chat","msg":"x"}"#.to_vec()).unwrap();
+        a_tx.send(br#"{"type":"auth_init"}"#.to_vec()).unwrap();
+        relay.pump();
+
+        // auth_init was sent last but goes first
+        assert_eq!(
+            received(&b_rx),
+            [r#"{"type":"auth_init"}"#, r#"{"type":"entropy_commit"}"#]
+        );
+        let errors = received(&a_rx);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("type_not_allowed"), "{}", errors[0]);
+
+        // With a validator, an incomplete commit bounces instead
+        relay.set_validator("control", Validator::default());
+        let commit = format!(
+            r#"{{"type":"entropy_commit","commitment":"{}"}}"#,
+            "f".repeat(64)
+        );
+        a_tx.send(br#"{"type":"entropy_commit"}"#.to_vec()).unwrap();
+        a_tx.send(commit.clone().into_bytes()).unwrap();
+        relay.pump();
+        assert_eq!(received(&b_rx), [commit]);
+        let errors = received(&a_rx);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("invalid_message"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn test_auth_state_set_on_connect() {
+        let mut relay = TestRelay::new();
+        let (_carol_tx, carol_rx) = relay.connect("lobby", "carol");
+        let (mallory_tx, _mallory_rx) = relay.connect("lobby", "mallory");
+        let (alice_tx, _alice_rx) = relay.connect_as("lobby", "alice", AuthState::Trusted);
+        relay.pump();
+        received(&carol_rx);
+
+        // Only the Trusted sender's hint is honored, so alice overtakes
+        let from = |who: &str| {
+            format!(
+                r#"{{"type":"chat","priority":"critical","from":"{}"}}"#,
+                who
+            )
+        };
+        mallory_tx.send(from("mallory").into_bytes()).unwrap();
+        alice_tx.send(from("alice").into_bytes()).unwrap();
+        relay.pump();
+        assert_eq!(received(&carol_rx), [from("alice"), from("mallory")]);
+    }
+
+    #[test]
+    fn test_low_batch_decoded_by_flag() {
+        let mut relay = TestRelay::new();
+        let (a_tx, _a_rx) = relay.connect("lobby", "a");
+        let (_b_tx, b_rx) = relay.connect("lobby", "b");
+        a_tx.send(br#"{"type":"ping"}"#.to_vec()).unwrap();
+        a_tx.send(br#"{"type":"stats","rx":1}"#.to_vec()).unwrap();
+        relay.pump();
+
+        let frames: Vec<Vec<u8>> = b_rx.try_iter().collect();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(
+            TestRelay::messages(&frames[0]).unwrap(),
+            [r#"{"type":"ping"}"#, r#"{"type":"stats","rx":1}"#]
+        );
+    }
+
+    #[test]
+    fn test_room_cap_rejects_creator() {
+        let mut relay = TestRelay::with_max_rooms(1);
+        let (a_tx, _a_rx) = relay.connect("lobby", "a");
+        let (b_tx, _b_rx) = relay.connect("lobby", "b");
+
+        // A second room is over the cap: the peer hears why, then EOF
+        let (_c_tx, c_rx) = relay.connect("side", "c");
+        let reply = received(&c_rx);
+        assert_eq!(reply.len(), 1);
+        assert!(reply[0].contains("too_many_rooms"), "{}", reply[0]);
+        let reply: serde_json::Value = serde_json::from_str(&reply[0]).unwrap();
+        assert_eq!(reply["details"]["limit"], 1);
+        assert!(c_rx.recv().is_err());
+        assert!(relay.members("side").is_empty());
+
+        // Once the lobby empties, its slot is free again
+        drop(a_tx);
+        drop(b_tx);
+        relay.pump();
+        assert!(relay.members("lobby").is_empty());
+        let (_d_tx, d_rx) = relay.connect("side", "d");
+        assert_eq!(relay.members("side"), ["d"]);
+        relay.pump();
+        assert!(received(&d_rx).is_empty());
+    }
+}
@@ -0,0 +1,94 @@
+//! Test subscriber that records every tracing event with its span context
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+
+/// One event: names of the spans it was emitted in (outermost first) and
+/// "name=value" fields of those spans followed by its own
+#[derive(Clone, Debug)]
+pub struct RecordedEvent {
+    pub spans: Vec<&'static str>,
+    pub fields: Vec<String>,
+}
+
+impl RecordedEvent {
+    pub fn has(&self, field: &str) -> bool {
+        self.fields.iter().any(|f| f == field)
+    }
+
+    pub fn message(&self) -> Option<&str> {
+        self.fields.iter().find_map(|f| f.strip_prefix("message="))
+    }
+}
+
+struct Fields<'a>(&'a mut Vec<String>);
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.push(format!("{}={:?}", field.name(), value));
+    }
+}
+
+#[derive(Default)]
+struct Recorder {
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, (&'static str, Vec<String>)>>,
+    stack: Mutex<Vec<u64>>,
+    events: Arc<Mutex<Vec<RecordedEvent>>>,
+}
+
+impl tracing::Subscriber for Recorder {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut fields = Vec::new();
+        span.record(&mut Fields(&mut fields));
+        self.spans
+            .lock()
+            .unwrap()
+            .insert(id, (span.metadata().name(), fields));
+        Id::from_u64(id)
+    }
+
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &tracing::Event<'_>) {
+        let spans = self.spans.lock().unwrap();
+        let mut recorded = RecordedEvent {
+            spans: Vec::new(),
+            fields: Vec::new(),
+        };
+        for id in self.stack.lock().unwrap().iter() {
+            let (name, fields) = &spans[id];
+            recorded.spans.push(name);
+            recorded.fields.extend(fields.iter().cloned());
+        }
+        event.record(&mut Fields(&mut recorded.fields));
+        self.events.lock().unwrap().push(recorded);
+    }
+
+    fn enter(&self, id: &Id) {
+        self.stack.lock().unwrap().push(id.into_u64());
+    }
+
+    fn exit(&self, _: &Id) {
+        self.stack.lock().unwrap().pop();
+    }
+}
+
+/// Run `f` with a recording subscriber and return the events it emitted
+pub fn record(f: impl FnOnce()) -> Vec<RecordedEvent> {
+    let recorder = Recorder::default();
+    let events = recorder.events.clone();
+    tracing::subscriber::with_default(recorder, f);
+    let events = events.lock().unwrap();
+    events.clone()
+}
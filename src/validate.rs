@@ -0,0 +1,317 @@
+//! Required fields per message type
+//!
+//! `Message::parse` only checks for an object with a `type`. An
+//! `entropy_commit` without a commitment still parses, and gets queued and
+//! fanned out before a peer discovers it's useless. Validator holds, per
+//! type, the fields a message must carry and what they must look like, and
+//! rejects a message that falls short before it's enqueued. The relay
+//! answers with an `invalid_message` error naming the field. The check
+//! reads the JSON object `Message::parse` kept, so the text isn't parsed
+//! twice.
+//!
+//! The schemas live here, in code. `Validator::default()` has the rules
+//! for the entropy round and ICE negotiation. Types without rules pass,
+//! and so do fields a message carries beyond the required ones.
+
+use crate::message_optimizer::{Message, MessageType};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Hex characters in a SHA-256 commitment or a 32-byte entropy share
+pub const ENTROPY_HEX_LEN: usize = 64;
+
+/// What a required field must hold
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldKind {
+    /// A non-empty string
+    String,
+    /// A string of exactly `len` hex digits
+    Hex {
+        len: usize,
+    },
+    Number,
+    Object,
+    Array,
+}
+
+impl FieldKind {
+    fn name(self) -> &'static str {
+        match self {
+            FieldKind::String => "string",
+            FieldKind::Hex { .. } => "hex string",
+            FieldKind::Number => "number",
+            FieldKind::Object => "object",
+            FieldKind::Array => "array",
+        }
+    }
+}
+
+/// A field a message type must carry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FieldRule {
+    pub field: &'static str,
+    pub kind: FieldKind,
+}
+
+/// Why a message failed validation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// A required field is absent or null
+    MissingField {
+        msg_type: MessageType,
+        field: &'static str,
+    },
+    /// A required field holds the wrong kind of value
+    WrongType {
+        msg_type: MessageType,
+        field: &'static str,
+        expected: FieldKind,
+    },
+    /// A hex field has the wrong number of digits
+    WrongLength {
+        msg_type: MessageType,
+        field: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+#[allow(dead_code)]
+impl ValidationError {
+    /// The offending field, if the error is about one
+    pub fn field(&self) -> Option<&'static str> {
+        match self {
+            ValidationError::MissingField { field, .. }
+            | ValidationError::WrongType { field, .. }
+            | ValidationError::WrongLength { field, .. } => Some(field),
+        }
+    }
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::MissingField { msg_type, field } => {
+                write!(f, "{:?} message missing field {}", msg_type, field)
+            }
+            ValidationError::WrongType {
+                msg_type,
+                field,
+                expected,
+            } => write!(
+                f,
+                "{:?} message field {} must be a {}",
+                msg_type,
+                field,
+                expected.name()
+            ),
+            ValidationError::WrongLength {
+                msg_type,
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{:?} message field {} must be {} hex digits, got {}",
+                msg_type, field, expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Required fields for each message type
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub struct Validator {
+    schemas: HashMap<MessageType, Vec<FieldRule>>,
+}
+
+#[allow(dead_code)]
+impl Validator {
+    /// A validator with no rules: every message passes
+    pub fn empty() -> Self {
+        Self {
+            schemas: HashMap::new(),
+        }
+    }
+
+    /// Require `field` of kind `kind` on every `msg_type` message
+    pub fn with_rule(
+        mut self,
+        msg_type: MessageType,
+        field: &'static str,
+        kind: FieldKind,
+    ) -> Self {
+        let rules = self.schemas.entry(msg_type).or_default();
+        rules.retain(|rule| rule.field != field);
+        rules.push(FieldRule { field, kind });
+        self
+    }
+
+    /// Rules for `msg_type`, in the order they're checked
+    pub fn rules(&self, msg_type: MessageType) -> &[FieldRule] {
+        self.schemas.get(&msg_type).map_or(&[], Vec::as_slice)
+    }
+
+    /// Check `msg` against its type's rules, on the fields `parse` kept
+    pub fn check(&self, msg: &Message) -> Result<(), ValidationError> {
+        for rule in self.rules(msg.msg_type) {
+            check_field(msg.msg_type, rule, msg.fields.get(rule.field))?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Validator {
+    /// Entropy rounds and ICE negotiation
+    fn default() -> Self {
+        let entropy = FieldKind::Hex {
+            len: ENTROPY_HEX_LEN,
+        };
+        Self::empty()
+            .with_rule(MessageType::EntropyCommit, "commitment", entropy)
+            .with_rule(MessageType::EntropyReveal, "entropy", entropy)
+            .with_rule(MessageType::Entropy, "entropy", FieldKind::String)
+            .with_rule(MessageType::IceCandidate, "candidate", FieldKind::String)
+            .with_rule(MessageType::IceOffer, "sdp", FieldKind::String)
+    }
+}
+
+fn check_field(
+    msg_type: MessageType,
+    rule: &FieldRule,
+    value: Option<&Value>,
+) -> Result<(), ValidationError> {
+    let field = rule.field;
+    let wrong_type = || ValidationError::WrongType {
+        msg_type,
+        field,
+        expected: rule.kind,
+    };
+    let value = match value {
+        None | Some(Value::Null) => return Err(ValidationError::MissingField { msg_type, field }),
+        Some(value) => value,
+    };
+    let ok = match rule.kind {
+        FieldKind::String => value.as_str().is_some_and(|s| !s.is_empty()),
+        FieldKind::Hex { len } => {
+            let s = value.as_str().ok_or_else(wrong_type)?;
+            if s.len() != len {
+                return Err(ValidationError::WrongLength {
+                    msg_type,
+                    field,
+                    expected: len,
+                    actual: s.len(),
+                });
+            }
+            s.bytes().all(|b| b.is_ascii_hexdigit())
+        }
+        FieldKind::Number => value.is_number(),
+        FieldKind::Object => value.is_object(),
+        FieldKind::Array => value.is_array(),
+    };
+    if ok {
+        Ok(())
+    } else {
+        Err(wrong_type())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(validator: &Validator, text: &str) -> Result<(), ValidationError> {
+        validator.check(&Message::parse(text).unwrap())
+    }
+
+    #[test]
+    fn test_entropy_messages() {
+        let validator = Validator::default();
+        let hex = "ab".repeat(32);
+        let commit = format!(r#"{{"type":"entropy_commit","commitment":"{}"}}"#, hex);
+        assert_eq!(check(&validator, &commit), Ok(()));
+        let reveal = format!(
+            r#"{{"type":"entropy_reveal","entropy":"{}","round":3}}"#,
+            hex
+        );
+        assert_eq!(check(&validator, &reveal), Ok(()));
+
+        assert_eq!(
+            check(&validator, r#"{"type":"entropy_commit"}"#),
+            Err(ValidationError::MissingField {
+                msg_type: MessageType::EntropyCommit,
+                field: "commitment",
+            })
+        );
+        assert_eq!(
+            check(&validator, r#"{"type":"entropy_commit","commitment":null}"#)
+                .unwrap_err()
+                .field(),
+            Some("commitment")
+        );
+        assert_eq!(
+            check(&validator, r#"{"type":"entropy_commit","commitment":"aa"}"#),
+            Err(ValidationError::WrongLength {
+                msg_type: MessageType::EntropyCommit,
+                field: "commitment",
+                expected: 64,
+                actual: 2,
+            })
+        );
+        let not_hex = format!(
+            r#"{{"type":"entropy_commit","commitment":"{}"}}"#,
+            "zz".repeat(32)
+        );
+        assert!(matches!(
+            check(&validator, &not_hex),
+            Err(ValidationError::WrongType { .. })
+        ));
+        assert!(matches!(
+            check(&validator, r#"{"type":"entropy_reveal","entropy":42}"#),
+            Err(ValidationError::WrongType {
+                msg_type: MessageType::EntropyReveal,
+                ..
+            })
+        ));
+        assert!(check(&validator, r#"{"type":"entropy","entropy":""}"#).is_err());
+    }
+
+    #[test]
+    fn test_ice_and_unchecked_types() {
+        let validator = Validator::default();
+        assert_eq!(
+            check(
+                &validator,
+                r#"{"type":"ice_candidate","candidate":"candidate:1 1 udp"}"#
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(&validator, r#"{"type":"ice_offer","sdp":"v=0"}"#),
+            Ok(())
+        );
+        assert_eq!(
+            check(&validator, r#"{"type":"ice_candidate","sdp":"v=0"}"#)
+                .unwrap_err()
+                .to_string(),
+            "IceCandidate message missing field candidate"
+        );
+        assert!(check(&validator, r#"{"type":"ice_offer","sdp":["v=0"]}"#).is_err());
+
+        // Types without rules pass as they are
+        assert_eq!(check(&validator, r#"{"type":"chat"}"#), Ok(()));
+        assert_eq!(check(&validator, r#"{"type":"ping"}"#), Ok(()));
+
+        // Rules are configurable per type, and replace one for the same field
+        let validator = Validator::empty()
+            .with_rule(MessageType::Chat, "msg", FieldKind::String)
+            .with_rule(MessageType::Chat, "msg", FieldKind::Object);
+        assert_eq!(validator.rules(MessageType::Chat).len(), 1);
+        assert!(check(&validator, r#"{"type":"chat","msg":"hi"}"#).is_err());
+        assert_eq!(check(&validator, r#"{"type":"chat","msg":{}}"#), Ok(()));
+        assert_eq!(check(&validator, r#"{"type":"entropy_commit"}"#), Ok(()));
+    }
+}
@@ -1,4 +1,7 @@
+use crate::clock::{Clock, SystemClock};
+use crate::error_message::ErrorMessage;
 use crate::message_optimizer::MessagePriority;
+use crate::room::{JoinOutcome, LeaveReason, PresenceInfo, Room};
 /**
  * VpnRoom - ZKS-VPN Durable Object for P2P VPN Relay
  *
@@ -31,6 +34,9 @@ struct PeerSession {
     addrs: Vec<String>, // Multiaddrs for P2P connectivity
     joined_at: u64,
     last_heartbeat: u64, // Last ping/pong timestamp for health monitoring
+    /// Completed a Swarm Join (missing in sessions hibernated before this field)
+    #[serde(default)]
+    swarm_joined: bool,
 }
 
 /// Inbound messages from clients (matches client's SignalingRequest)
@@ -62,10 +68,15 @@ enum ServerEvent {
     Joined { your_id: String },
     /// List of peers in room (Swarm mode)
     Peers { peers: Vec<PeerInfo> },
+    /// Current room members in join order, sent after Joined (Swarm mode)
+    Presence { peers: Vec<PresenceInfo> },
     /// New peer joined (Swarm mode)
     PeerJoined { peer: PeerInfo },
     /// Peer left (Swarm mode)
-    PeerLeft { peer_id: String },
+    PeerLeft {
+        peer_id: String,
+        reason: LeaveReason,
+    },
     /// Swarm entropy
     SwarmEntropy { entropy: String },
     /// Hole-punch coordination
@@ -73,8 +84,6 @@ enum ServerEvent {
         timestamp_ms: u64,
         target_addrs: Vec<String>,
     },
-    /// Error message
-    Error { message: String },
 
     // Legacy VPN mode events (backwards compatible)
     #[serde(rename = "welcome")]
@@ -86,7 +95,11 @@ enum ServerEvent {
     #[serde(rename = "peer_join")]
     LegacyPeerJoin { peer_id: String, role: String },
     #[serde(rename = "peer_leave")]
-    LegacyPeerLeave { peer_id: String, role: String },
+    LegacyPeerLeave {
+        peer_id: String,
+        role: String,
+        reason: LeaveReason,
+    },
     #[serde(rename = "pong")]
     Pong,
 }
@@ -104,11 +117,17 @@ pub struct VpnRoom {
     state: State,
     #[allow(dead_code)]
     env: Env,
+    /// Join, heartbeat and punch times
+    clock: Box<dyn Clock>,
 }
 
 impl DurableObject for VpnRoom {
     fn new(state: State, env: Env) -> Self {
-        Self { state, env }
+        Self {
+            state,
+            env,
+            clock: Box::new(SystemClock),
+        }
     }
 
     async fn fetch(&self, req: Request) -> Result<Response> {
@@ -165,8 +184,9 @@ impl DurableObject for VpnRoom {
             peer_id: peer_id.clone(),
             role,
             addrs: vec![],
-            joined_at: Date::now().as_millis(),
-            last_heartbeat: Date::now().as_millis(), // Initialize heartbeat
+            joined_at: self.clock.now_ms(),
+            last_heartbeat: self.clock.now_ms(), // Initialize heartbeat
+            swarm_joined: false,
         };
 
         // Store session for hibernation recovery
@@ -216,7 +236,7 @@ impl DurableObject for VpnRoom {
         };
 
         // Update heartbeat on any message (cost optimization: passive heartbeat)
-        session.last_heartbeat = Date::now().as_millis();
+        session.last_heartbeat = self.clock.now_ms();
         ws.serialize_attachment(&session)?;
 
         match message {
@@ -229,9 +249,17 @@ impl DurableObject for VpnRoom {
                 if let Ok(msg) = serde_json::from_str::<ClientMessage>(&text) {
                     match msg {
                         ClientMessage::Join { peer_id, addrs, .. } => {
+                            // A retried Join (flaky connection) must not be announced twice
+                            // (Swarm rooms are unbounded, so join can't fail here)
+                            let announce = self
+                                .joined_swarm_room()
+                                .join(&peer_id, self.clock.now_ms())
+                                .is_ok_and(JoinOutcome::is_new);
+
                             // Update session with peer info
                             session.peer_id = peer_id.clone();
                             session.addrs = addrs.clone();
+                            session.swarm_joined = true;
                             ws.serialize_attachment(&session)?;
 
                             console_log!(
@@ -247,16 +275,31 @@ impl DurableObject for VpnRoom {
                             .unwrap_or_default();
                             let _ = ws.send_with_str(&joined);
 
-                            // Notify other Swarm peers
-                            let peer_info = PeerInfo {
-                                peer_id: peer_id.clone(),
-                                addrs,
-                                role: Some("swarm".to_string()),
-                            };
-                            let notify =
-                                serde_json::to_string(&ServerEvent::PeerJoined { peer: peer_info })
-                                    .unwrap_or_default();
-                            self.broadcast_to_swarm(&notify, Some(&session.peer_id));
+                            // Tell the new peer who is already here (itself included)
+                            let presence = serde_json::to_string(&ServerEvent::Presence {
+                                peers: self.joined_swarm_room().presence(),
+                            })
+                            .unwrap_or_default();
+                            let _ = ws.send_with_str(&presence);
+
+                            if announce {
+                                // Notify other Swarm peers
+                                let peer_info = PeerInfo {
+                                    peer_id: peer_id.clone(),
+                                    addrs,
+                                    role: Some("swarm".to_string()),
+                                };
+                                let notify = serde_json::to_string(&ServerEvent::PeerJoined {
+                                    peer: peer_info,
+                                })
+                                .unwrap_or_default();
+                                self.broadcast_to_swarm(&notify, Some(&session.peer_id));
+                            } else {
+                                console_log!(
+                                    "[VpnRoom] Duplicate Join from {}, not re-announcing",
+                                    peer_id
+                                );
+                            }
                         }
 
                         ClientMessage::GetPeers => {
@@ -301,17 +344,14 @@ impl DurableObject for VpnRoom {
                                 .find(|s| s.peer_id == target_peer_id)
                             {
                                 let response = serde_json::to_string(&ServerEvent::PunchAt {
-                                    timestamp_ms: Date::now().as_millis() + 500, // 500ms from now
+                                    timestamp_ms: self.clock.now_ms() + 500, // 500ms from now
                                     target_addrs: target.addrs,
                                 })
                                 .unwrap_or_default();
                                 let _ = ws.send_with_str(&response);
                             } else {
-                                let err = serde_json::to_string(&ServerEvent::Error {
-                                    message: format!("Peer {} not found", target_peer_id),
-                                })
-                                .unwrap_or_default();
-                                let _ = ws.send_with_str(&err);
+                                let err = ErrorMessage::peer_not_found(&target_peer_id);
+                                let _ = ws.send_with_str(err.to_json());
                             }
                         }
 
@@ -338,29 +378,13 @@ impl DurableObject for VpnRoom {
     async fn websocket_close(
         &self,
         ws: WebSocket,
-        _code: usize,
+        code: usize,
         _reason: String,
-        _was_clean: bool,
+        was_clean: bool,
     ) -> Result<()> {
         if let Ok(Some(session)) = ws.deserialize_attachment::<PeerSession>() {
             console_log!("[VpnRoom] {:?} left: {}", session.role, session.peer_id);
-
-            if session.role == PeerRole::Swarm {
-                // Notify Swarm peers
-                let leave_msg = serde_json::to_string(&ServerEvent::PeerLeft {
-                    peer_id: session.peer_id.clone(),
-                })
-                .unwrap_or_default();
-                self.broadcast_to_swarm(&leave_msg, None);
-            } else {
-                // Legacy VPN mode
-                let leave_msg = serde_json::to_string(&ServerEvent::LegacyPeerLeave {
-                    peer_id: session.peer_id,
-                    role: format!("{:?}", session.role),
-                })
-                .unwrap_or_default();
-                self.broadcast_text(&leave_msg, None);
-            }
+            self.announce_leave(session, LeaveReason::from_close(code, was_clean));
         }
 
         Ok(())
@@ -370,20 +394,8 @@ impl DurableObject for VpnRoom {
         console_error!("[VpnRoom] WebSocket error: {:?}", error);
 
         if let Ok(Some(session)) = ws.deserialize_attachment::<PeerSession>() {
-            if session.role == PeerRole::Swarm {
-                let leave_msg = serde_json::to_string(&ServerEvent::PeerLeft {
-                    peer_id: session.peer_id.clone(),
-                })
-                .unwrap_or_default();
-                self.broadcast_to_swarm(&leave_msg, None);
-            } else {
-                let leave_msg = serde_json::to_string(&ServerEvent::LegacyPeerLeave {
-                    peer_id: session.peer_id,
-                    role: format!("{:?}", session.role),
-                })
-                .unwrap_or_default();
-                self.broadcast_text(&leave_msg, None);
-            }
+            // The connection broke without a close handshake
+            self.announce_leave(session, LeaveReason::Timeout);
         }
 
         Ok(())
@@ -391,6 +403,28 @@ impl DurableObject for VpnRoom {
 }
 
 impl VpnRoom {
+    /// Tell the remaining peers that `session` left, and why
+    fn announce_leave(&self, session: PeerSession, reason: LeaveReason) {
+        if session.role == PeerRole::Swarm {
+            // Notify Swarm peers
+            let leave_msg = serde_json::to_string(&ServerEvent::PeerLeft {
+                peer_id: session.peer_id,
+                reason,
+            })
+            .unwrap_or_default();
+            self.broadcast_to_swarm(&leave_msg, None);
+        } else {
+            // Legacy VPN mode
+            let leave_msg = serde_json::to_string(&ServerEvent::LegacyPeerLeave {
+                peer_id: session.peer_id,
+                role: format!("{:?}", session.role),
+                reason,
+            })
+            .unwrap_or_default();
+            self.broadcast_text(&leave_msg, None);
+        }
+    }
+
     fn get_all_sessions(&self) -> Vec<PeerSession> {
         self.state
             .get_websockets()
@@ -399,6 +433,27 @@ impl VpnRoom {
             .collect()
     }
 
+    /// Swarm peers that have completed a Join, in join order (by
+    /// connection time)
+    fn joined_swarm_room(&self) -> Room {
+        self.swarm_room_where(|s| s.swarm_joined)
+    }
+
+    fn swarm_room_where(&self, include: impl Fn(&PeerSession) -> bool) -> Room {
+        let mut sessions: Vec<PeerSession> = self
+            .get_all_sessions()
+            .into_iter()
+            .filter(|s| s.role == PeerRole::Swarm && include(s))
+            .collect();
+        sessions.sort_by_key(|s| s.joined_at);
+
+        let mut room = Room::new();
+        for s in &sessions {
+            let _ = room.join(&s.peer_id, s.joined_at);
+        }
+        room
+    }
+
     /// Send message with retry logic for robustness
     /// Retries up to 2 times with exponential backoff
     fn send_with_retry(&self, ws: &WebSocket, msg: &str) -> bool {